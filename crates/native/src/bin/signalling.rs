@@ -0,0 +1,142 @@
+use std::{
+    net::SocketAddr,
+    process::exit,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use decentralized_p2p_streaming_native::{WsConfig, WsTransport};
+use protocol::{NodeKey, SignallingServer};
+
+const USAGE: &str = "usage: signalling <bind_addr>
+  nodes connect with ws urls like ws://127.0.0.1:3000";
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Bootstrap signalling server over WebSocket, it relays signals between nodes which have no route
+/// to each other over the overlay yet, like a new node and its first neighbour
+fn main() {
+    env_logger::init();
+    let bind = std::env::args()
+        .nth(1)
+        .and_then(|a| a.parse::<SocketAddr>().ok())
+        .unwrap_or_else(|| {
+            eprintln!("{}", USAGE);
+            exit(1);
+        });
+    // the server is not part of the overlay, its key only binds the proofs of the clients to it
+    let key = NodeKey::generate();
+    let mut transport = WsTransport::new(key.node_id(), WsConfig::default());
+    if let Err(e) = transport.listen(bind) {
+        eprintln!("Listen {} error {:?}", bind, e);
+        exit(1);
+    }
+    log::info!("Signalling server listen on {}", bind);
+
+    let mut server = SignallingServer::new(key, transport);
+    let started = Instant::now();
+    loop {
+        server.poll(started.elapsed().as_millis() as u64);
+        sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::{NodeId, Signal, SignallingChannel, TransportSignalling};
+
+    use super::*;
+
+    fn server() -> (SignallingServer<WsTransport>, String) {
+        let key = NodeKey::from_secret([9; 32]);
+        let mut transport = WsTransport::new(key.node_id(), WsConfig::default());
+        transport
+            .listen("127.0.0.1:0".parse().expect("valid addr"))
+            .expect("listen");
+        let url = format!("ws://{}", transport.local_addr().expect("addr"));
+        (SignallingServer::new(key, transport), url)
+    }
+
+    /// Client which claims the node id of the transport and signs with the key
+    fn client(key: NodeKey, node: NodeId, url: &str) -> TransportSignalling<WsTransport> {
+        TransportSignalling::new(key, WsTransport::new(node, WsConfig::default()), url.into())
+    }
+
+    #[test]
+    fn relay_signal_between_clients() {
+        let (mut server, url) = server();
+        let (key_a, key_b) = (NodeKey::from_secret([1; 32]), NodeKey::from_secret([2; 32]));
+        let (node_a, node_b) = (key_a.node_id(), key_b.node_id());
+        let mut a = client(key_a, node_a, &url);
+        let mut b = client(key_b, node_b, &url);
+
+        let offer = Signal::Offer {
+            session: 7,
+            sdp: "sdp".to_string(),
+        };
+        let mut received = None;
+        for _ in 0..500 {
+            server.poll(0);
+            // a signal to a node which is not connected yet is dropped by the server
+            if server.clients() == 2 && received.is_none() {
+                a.send_signal(0, node_b, offer.clone());
+            }
+            let _ = a.pop_signal(0);
+            received = b.pop_signal(0);
+            if received.is_some() {
+                break;
+            }
+            sleep(Duration::from_millis(2));
+        }
+        assert_eq!(received, Some((node_a, offer)));
+    }
+
+    #[test]
+    fn client_without_key_is_refused() {
+        let (mut server, url) = server();
+        let key_b = NodeKey::from_secret([2; 32]);
+        let node_b = key_b.node_id();
+        let mut b = client(key_b, node_b, &url);
+        // the impostor claims the node id of b, but can only sign with its own key
+        let mut impostor = client(NodeKey::from_secret([3; 32]), node_b, &url);
+        for _ in 0..500 {
+            server.poll(0);
+            let _ = b.pop_signal(0);
+            if server.clients() == 1 {
+                break;
+            }
+            sleep(Duration::from_millis(2));
+        }
+        assert!(b.is_connected());
+
+        for _ in 0..100 {
+            server.poll(0);
+            let _ = b.pop_signal(0);
+            let _ = impostor.pop_signal(0);
+            sleep(Duration::from_millis(2));
+        }
+        assert_eq!(server.clients(), 1);
+        // b is still the registered client, so it gets the signals to its node id
+        let key_a = NodeKey::from_secret([1; 32]);
+        let node_a = key_a.node_id();
+        let mut a = client(key_a, node_a, &url);
+        let offer = Signal::Offer {
+            session: 7,
+            sdp: "sdp".to_string(),
+        };
+        let mut received = None;
+        for _ in 0..500 {
+            server.poll(0);
+            if server.clients() == 2 && received.is_none() {
+                a.send_signal(0, node_b, offer.clone());
+            }
+            let _ = a.pop_signal(0);
+            assert!(impostor.pop_signal(0).is_none());
+            received = b.pop_signal(0);
+            if received.is_some() {
+                break;
+            }
+            sleep(Duration::from_millis(2));
+        }
+        assert_eq!(received, Some((node_a, offer)));
+    }
+}
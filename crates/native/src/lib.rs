@@ -1,14 +1,6 @@
-pub fn add(left: usize, right: usize) -> usize {
-    left + right
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
-    }
-}
+mod rtc;
+mod udp;
+mod ws;
+pub use rtc::{RtcConfig, RtcTransport};
+pub use udp::{SocketStats, UdpConfig, UdpTransport};
+pub use ws::{WsConfig, WsTransport};
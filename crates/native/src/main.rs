@@ -1 +1,142 @@
-fn main() {}
+use std::{
+    fs,
+    net::SocketAddr,
+    process::exit,
+    thread::sleep,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use decentralized_p2p_streaming_native::{UdpConfig, UdpTransport, WsConfig, WsTransport};
+use protocol::{
+    BootstrapConfig, Capability, NodeKey, OutputEvent, P2pStreamDriver, P2pStreamRunner, Transport,
+};
+
+const USAGE: &str = "usage: native [--ws] [--seed] <key_file> <bind_addr> [seed ...]
+  the key file holds the secret key of the node, it is created with a new key if it doesn't exist
+  seeds are udp addresses like 127.0.0.1:3000, or ws urls like ws://127.0.0.1:3000 with --ws
+  with --seed the node is a seed node, it only accepts joining nodes and doesn't join itself";
+const TICK_INTERVAL_MS: u64 = 1000;
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+struct Args {
+    ws: bool,
+    seed: bool,
+    key_file: String,
+    bind: SocketAddr,
+    seeds: Vec<String>,
+}
+
+fn parse_args() -> Option<Args> {
+    let mut args = std::env::args().skip(1).peekable();
+    let ws = args.next_if(|a| a == "--ws").is_some();
+    let seed = args.next_if(|a| a == "--seed").is_some();
+    let key_file = args.next()?;
+    let bind = args.next()?.parse().ok()?;
+    let seeds = args.collect::<Vec<_>>();
+    if seed && !seeds.is_empty() {
+        return None;
+    }
+    Some(Args {
+        ws,
+        seed,
+        key_file,
+        bind,
+        seeds,
+    })
+}
+
+/// Relay node over UDP or WebSocket, it only forwards routes and channel data of other nodes.
+/// A seed node is a relay which doesn't join, new nodes join over it and get a sample of the nodes it knows.
+fn main() {
+    env_logger::init();
+    let args = parse_args().unwrap_or_else(|| {
+        eprintln!("{}", USAGE);
+        exit(1);
+    });
+    let key = load_key(&args.key_file).unwrap_or_else(|e| {
+        eprintln!("Key file {} error {:?}", args.key_file, e);
+        exit(1);
+    });
+    let node = key.node_id();
+    let role = if args.seed { "Seed" } else { "Relay" };
+    log::info!("{} {:?} listen on {}", role, node, args.bind);
+    if args.ws {
+        let mut transport = WsTransport::new(node, WsConfig::default());
+        if let Err(e) = transport.listen(args.bind) {
+            eprintln!("Listen {} error {:?}", args.bind, e);
+            exit(1);
+        }
+        run(key, args.seed, transport, args.seeds);
+    } else {
+        let transport =
+            UdpTransport::bind(node, args.bind, UdpConfig::default()).unwrap_or_else(|e| {
+                eprintln!("Bind {} error {:?}", args.bind, e);
+                exit(1);
+            });
+        let seeds = args
+            .seeds
+            .iter()
+            .map(|p| p.parse())
+            .collect::<Result<Vec<SocketAddr>, _>>()
+            .unwrap_or_else(|e| {
+                eprintln!("Invalid seed address {:?}", e);
+                exit(1);
+            });
+        run(key, args.seed, transport, seeds);
+    }
+}
+
+/// The NodeId is derived from the key, so the key is stored to keep the same NodeId over restarts
+fn load_key(path: &str) -> std::io::Result<NodeKey> {
+    match fs::read(path) {
+        Ok(secret) => {
+            let secret = secret.try_into().map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, "key must be 32 bytes")
+            })?;
+            Ok(NodeKey::from_secret(secret))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let key = NodeKey::generate();
+            fs::write(path, key.secret())?;
+            log::info!("Created a new key in {}", path);
+            Ok(key)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn run<T: Transport>(key: NodeKey, seed: bool, transport: T, seeds: Vec<T::Addr>) {
+    let mut runner = P2pStreamRunner::new(key);
+    runner.add_capability(Capability::Relay);
+    if seed {
+        runner.add_capability(Capability::Seed);
+    }
+    let mut driver = P2pStreamDriver::new(runner, transport);
+    if !seeds.is_empty() {
+        driver.join(seeds, BootstrapConfig::default());
+    }
+
+    let mut next_tick_ms = 0;
+    loop {
+        // unix time, channel announcements of other nodes expire in it
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        if now_ms >= next_tick_ms {
+            next_tick_ms = now_ms + TICK_INTERVAL_MS;
+            driver.on_tick(now_ms);
+        } else {
+            driver.poll(now_ms);
+        }
+        // a relay doesn't subscribe to any channel, so there is no local data
+        while let Some(event) = driver.pop_output() {
+            match event {
+                OutputEvent::Joined => log::info!("Joined the network"),
+                OutputEvent::Left => log::warn!("Left the network, joining again"),
+                OutputEvent::Banned(node) => log::warn!("Banned misbehaving node {:?}", node),
+                _ => {}
+            }
+        }
+        sleep(POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,711 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use protocol::{
+    Connection, ConnectionStats, NetworkPkt, NodeId, Signal, Transport, TransportEvent,
+};
+use tokio::{
+    runtime::{Handle, Runtime},
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        OnceCell,
+    },
+};
+use webrtc::{
+    api::{setting_engine::SettingEngine, APIBuilder, API},
+    data_channel::{data_channel_init::RTCDataChannelInit, RTCDataChannel},
+    ice::mdns::MulticastDnsMode,
+    ice_transport::{ice_candidate::RTCIceCandidateInit, ice_server::RTCIceServer},
+    peer_connection::{
+        configuration::RTCConfiguration, peer_connection_state::RTCPeerConnectionState,
+        sdp::session_description::RTCSessionDescription, RTCPeerConnection,
+    },
+    stats::StatsReportType,
+};
+
+/// Reliable and ordered channel for routing and pubsub control messages
+const CONTROL_LABEL: &str = "control";
+/// Unordered channel without retransmission for channel data
+const DATA_LABEL: &str = "data";
+const CONTROL_ID: u16 = 0;
+const DATA_ID: u16 = 1;
+
+/// Frame: kind (1 byte) followed by the payload.
+/// Hello is sent on the control channel when it opens and the remote acks it, the peer is connected
+/// when its Hello is acked, so both directions of the association are known to work.
+const KIND_HELLO: u8 = 1;
+const KIND_DATA: u8 = 2;
+const KIND_HELLO_ACK: u8 = 3;
+
+#[derive(Debug, Clone)]
+pub struct RtcConfig {
+    /// STUN and TURN urls, only host candidates are used when empty
+    pub ice_servers: Vec<String>,
+    /// Peer which is not connected within this timeout is closed
+    pub connect_timeout_ms: u64,
+    /// Offer which doesn't give a working connection within this timeout is replaced by a new one
+    pub attempt_timeout_ms: u64,
+    pub stats_interval_ms: u64,
+    /// Data channels can't measure the link capacity, so this value is reported in the stats
+    pub bandwidth_kbps: u32,
+    /// Peers which are not connected yet, offers above this are dropped since each peer gathers candidates
+    pub max_pending_peers: usize,
+}
+
+impl Default for RtcConfig {
+    fn default() -> Self {
+        Self {
+            ice_servers: vec![],
+            connect_timeout_ms: 10_000,
+            attempt_timeout_ms: 3000,
+            stats_interval_ms: 1000,
+            bandwidth_kbps: 100_000,
+            max_pending_peers: 32,
+        }
+    }
+}
+
+enum RtcEvent {
+    Opened(Connection),
+    Recv(Connection, Vec<u8>),
+    /// Peer connection is closed or a send failed
+    Closed(Connection),
+}
+
+/// State which is written by the webrtc tasks and read by the transport
+#[derive(Default)]
+struct Shared {
+    events: VecDeque<RtcEvent>,
+    signals: VecDeque<(NodeId, Signal)>,
+    stats: HashMap<Connection, ConnectionStats>,
+}
+
+#[derive(Default)]
+struct Channels {
+    control: Option<Arc<RTCDataChannel>>,
+    data: Option<Arc<RTCDataChannel>>,
+    opened: usize,
+}
+
+/// How the peer task starts the session
+enum Setup {
+    Offer,
+    /// Answer to the remote offer sdp
+    Answer(String),
+}
+
+struct Peer {
+    /// Set by the peer task when the peer connection is created
+    pc: Arc<OnceCell<Arc<RTCPeerConnection>>>,
+    outgoing: bool,
+    connected: bool,
+    started_ms: u64,
+    deadline_ms: u64,
+    /// Frames are written by a single task per peer, so that their order is kept.
+    /// The task closes the peer connection when this is dropped.
+    writer: UnboundedSender<(bool, Bytes)>,
+}
+
+/// Transport over WebRTC data channels, built on webrtc-rs which runs on a tokio runtime.
+/// Connecting to a node creates an offer which must be delivered to the node as a signal,
+/// the driver relays it over the overlay or its signalling channel.
+/// The transport never blocks on the runtime, so it can also be polled from a task of the same runtime.
+/// Descriptions are sent after the candidates are gathered (no trickle ICE), candidates of trickling peers
+/// are still applied.
+pub struct RtcTransport {
+    config: RtcConfig,
+    runtime: Handle,
+    /// Runtime which is owned by the transport when it is not given one
+    _runtime: Option<Runtime>,
+    api: Arc<API>,
+    next_session: u32,
+    last_stats_ms: u64,
+    peers: HashMap<Connection, Peer>,
+    shared: Arc<Mutex<Shared>>,
+    events: VecDeque<TransportEvent>,
+}
+
+impl RtcTransport {
+    /// Transport with an own runtime, it must not be dropped inside an async context
+    pub fn new(config: RtcConfig) -> std::io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()?;
+        let mut transport = Self::with_runtime(config, runtime.handle().clone());
+        transport._runtime = Some(runtime);
+        Ok(transport)
+    }
+
+    /// Transport which runs its webrtc tasks on the runtime of the handle, which must enable io and time
+    pub fn with_runtime(config: RtcConfig, runtime: Handle) -> Self {
+        let mut setting = SettingEngine::default();
+        setting.set_ice_multicast_dns_mode(MulticastDnsMode::Disabled);
+        let api = APIBuilder::new().with_setting_engine(setting).build();
+        // sessions of different runs should not collide when the remote still remembers the old ones
+        let next_session = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        Self {
+            config,
+            runtime,
+            _runtime: None,
+            api: Arc::new(api),
+            next_session,
+            last_stats_ms: 0,
+            peers: HashMap::new(),
+            shared: Arc::new(Mutex::new(Shared::default())),
+            events: VecDeque::new(),
+        }
+    }
+
+    fn pending_peers(&self) -> usize {
+        self.peers.values().filter(|p| !p.connected).count()
+    }
+
+    /// Start the task of a peer, which creates the peer connection with its data channels, exchanges
+    /// the session descriptions and then writes the frames
+    fn create_peer(&mut self, now_ms: u64, conn: Connection, deadline_ms: u64, setup: Setup) {
+        let config = RTCConfiguration {
+            ice_servers: self
+                .config
+                .ice_servers
+                .iter()
+                .map(|url| RTCIceServer {
+                    urls: vec![url.clone()],
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let outgoing = matches!(setup, Setup::Offer);
+        let (writer, frames) = unbounded_channel::<(bool, Bytes)>();
+        let pc = Arc::new(OnceCell::new());
+        let (api, shared, cell) = (self.api.clone(), self.shared.clone(), pc.clone());
+        self.runtime.spawn(async move {
+            let channels = Arc::new(tokio::sync::Mutex::new(Channels::default()));
+            let pc = match new_peer(&api, config, &shared, &channels, conn).await {
+                Ok(pc) => pc,
+                Err(e) => {
+                    log::warn!("Create peer for {:?} error {:?}", conn, e);
+                    push_event(&shared, RtcEvent::Closed(conn));
+                    return;
+                }
+            };
+            let _ = cell.set(pc.clone());
+            match describe(&pc, setup).await {
+                Ok(Some(desc)) => {
+                    let signal = if outgoing {
+                        Signal::Offer {
+                            session: conn.session(),
+                            sdp: desc.sdp,
+                        }
+                    } else {
+                        Signal::Answer {
+                            session: conn.session(),
+                            sdp: desc.sdp,
+                        }
+                    };
+                    push_signal(&shared, conn.node(), signal);
+                }
+                Ok(None) => log::warn!("No local description for {:?}", conn),
+                Err(e) => log::warn!("Description for {:?} error {:?}", conn, e),
+            }
+            write_frames(&shared, &channels, conn, frames).await;
+            let _ = pc.close().await;
+        });
+
+        self.peers.insert(
+            conn,
+            Peer {
+                pc,
+                outgoing,
+                connected: false,
+                started_ms: now_ms,
+                deadline_ms,
+                writer,
+            },
+        );
+    }
+
+    fn on_rtc_event(&mut self, now_ms: u64, event: RtcEvent) {
+        match event {
+            RtcEvent::Opened(conn) => {
+                if let Some(peer) = self.peers.get(&conn) {
+                    let _ = peer.writer.send((true, frame(KIND_HELLO, &[])));
+                }
+            }
+            RtcEvent::Recv(conn, received) => match received.split_first() {
+                Some((&KIND_HELLO, _)) => {
+                    if let Some(peer) = self.peers.get(&conn) {
+                        let _ = peer.writer.send((true, frame(KIND_HELLO_ACK, &[])));
+                    }
+                }
+                Some((&KIND_HELLO_ACK, _)) => self.on_connected(conn),
+                Some((&KIND_DATA, data)) => {
+                    // the remote only sends data when connected, and data may overtake its Hello ack
+                    self.on_connected(conn);
+                    if self.peers.contains_key(&conn) {
+                        self.events.push_back(TransportEvent::Recv(NetworkPkt {
+                            conn,
+                            data: data.to_vec(),
+                        }));
+                    }
+                }
+                _ => log::debug!("Unexpected frame from {:?}", conn),
+            },
+            RtcEvent::Closed(conn) => match self.peers.get(&conn) {
+                Some(peer) if peer.outgoing && !peer.connected => self.retry(now_ms, conn),
+                _ => self.remove_peer(conn),
+            },
+        }
+    }
+
+    fn on_connected(&mut self, conn: Connection) {
+        if let Some(peer) = self.peers.get_mut(&conn) {
+            if !peer.connected {
+                peer.connected = true;
+                log::info!("Connected {:?}", conn);
+                if peer.outgoing {
+                    self.events.push_back(TransportEvent::Connected(conn));
+                } else {
+                    self.events.push_back(TransportEvent::Accepted(conn));
+                }
+            }
+        }
+    }
+
+    /// Replace the outgoing peer with a new offer, webrtc-rs sometimes fails to establish the SCTP association
+    /// when both sides start it at the same time
+    fn retry(&mut self, now_ms: u64, conn: Connection) {
+        let deadline_ms = match self.peers.get(&conn) {
+            Some(peer) => peer.deadline_ms,
+            None => return,
+        };
+        self.remove_peer(conn);
+        if now_ms >= deadline_ms {
+            log::info!("Connect {:?} timeout", conn);
+            return;
+        }
+        log::debug!("Retry connect {:?}", conn);
+        if let Err(e) = self.offer(now_ms, conn.node(), deadline_ms) {
+            log::warn!("Retry connect {:?} error {:?}", conn, e);
+        }
+    }
+
+    fn on_timer(&mut self, now_ms: u64) {
+        let attempt_timeout_ms = self.config.attempt_timeout_ms;
+        let timeouts = self
+            .peers
+            .iter()
+            .filter(|(_, p)| {
+                !p.connected
+                    && (now_ms >= p.deadline_ms
+                        || (p.outgoing && now_ms >= p.started_ms + attempt_timeout_ms))
+            })
+            .map(|(conn, _)| *conn)
+            .collect::<Vec<_>>();
+        for conn in timeouts {
+            match self.peers.get(&conn) {
+                Some(peer) if peer.outgoing => self.retry(now_ms, conn),
+                _ => {
+                    log::info!("Accept {:?} timeout", conn);
+                    self.remove_peer(conn);
+                }
+            }
+        }
+
+        if now_ms >= self.last_stats_ms + self.config.stats_interval_ms {
+            self.last_stats_ms = now_ms;
+            let bandwidth_kbps = self.config.bandwidth_kbps;
+            for (conn, peer) in self.peers.iter().filter(|(_, p)| p.connected) {
+                let pc = match peer.pc.get() {
+                    Some(pc) => pc.clone(),
+                    None => continue,
+                };
+                let (conn, shared) = (*conn, self.shared.clone());
+                self.runtime.spawn(async move {
+                    let report = pc.get_stats().await;
+                    let pair = report.reports.values().find_map(|r| match r {
+                        StatsReportType::CandidatePair(pair) if pair.nominated => Some(pair),
+                        _ => None,
+                    });
+                    if let (Some(pair), Ok(mut shared)) = (pair, shared.lock()) {
+                        shared.stats.insert(
+                            conn,
+                            ConnectionStats {
+                                rtt_ms: (pair.current_round_trip_time * 1000.0) as u32,
+                                // SCTP doesn't expose the loss, it shows up as rtt instead
+                                lost_percent: 0.0.into(),
+                                jitter_ms: 0,
+                                bandwidth_kbps,
+                            },
+                        );
+                    }
+                });
+            }
+        }
+    }
+
+    fn offer(&mut self, now_ms: u64, node: NodeId, deadline_ms: u64) -> Result<(), webrtc::Error> {
+        if self.pending_peers() >= self.config.max_pending_peers {
+            return Err(webrtc::Error::new("too many pending peers".to_string()));
+        }
+        let session = self.next_session;
+        self.next_session = self.next_session.wrapping_add(1);
+        let conn = Connection::from_parts(node, session);
+        self.create_peer(now_ms, conn, deadline_ms, Setup::Offer);
+        Ok(())
+    }
+
+    fn remove_peer(&mut self, conn: Connection) {
+        if let Some(peer) = self.peers.remove(&conn) {
+            if let Ok(mut shared) = self.shared.lock() {
+                shared.stats.remove(&conn);
+            }
+            // dropping the writer ends the peer task, which closes the peer connection
+            if peer.connected {
+                self.events.push_back(TransportEvent::Disconnected(conn));
+            }
+        }
+    }
+}
+
+/// Create the peer connection, channels are negotiated with fixed ids, so both sides create them
+/// and no open message is exchanged
+async fn new_peer(
+    api: &API,
+    config: RTCConfiguration,
+    shared: &Arc<Mutex<Shared>>,
+    channels: &Arc<tokio::sync::Mutex<Channels>>,
+    conn: Connection,
+) -> Result<Arc<RTCPeerConnection>, webrtc::Error> {
+    let pc = Arc::new(api.new_peer_connection(config).await?);
+    let state_shared = shared.clone();
+    pc.on_peer_connection_state_change(Box::new(move |state| {
+        if matches!(
+            state,
+            RTCPeerConnectionState::Disconnected
+                | RTCPeerConnectionState::Failed
+                | RTCPeerConnectionState::Closed
+        ) {
+            push_event(&state_shared, RtcEvent::Closed(conn));
+        }
+        Box::pin(async {})
+    }));
+    let control = pc
+        .create_data_channel(
+            CONTROL_LABEL,
+            Some(RTCDataChannelInit {
+                negotiated: Some(CONTROL_ID),
+                ..Default::default()
+            }),
+        )
+        .await?;
+    let data = pc
+        .create_data_channel(
+            DATA_LABEL,
+            Some(RTCDataChannelInit {
+                ordered: Some(false),
+                max_retransmits: Some(0),
+                negotiated: Some(DATA_ID),
+                ..Default::default()
+            }),
+        )
+        .await?;
+    on_channel(shared.clone(), channels.clone(), conn, control).await;
+    on_channel(shared.clone(), channels.clone(), conn, data).await;
+    Ok(pc)
+}
+
+/// Create the local description, it is complete when the candidates are gathered
+async fn describe(
+    pc: &RTCPeerConnection,
+    setup: Setup,
+) -> Result<Option<RTCSessionDescription>, webrtc::Error> {
+    let desc = match setup {
+        Setup::Offer => pc.create_offer(None).await?,
+        Setup::Answer(sdp) => {
+            pc.set_remote_description(RTCSessionDescription::offer(sdp)?)
+                .await?;
+            pc.create_answer(None).await?
+        }
+    };
+    let mut gathered = pc.gathering_complete_promise().await;
+    pc.set_local_description(desc).await?;
+    let _ = gathered.recv().await;
+    Ok(pc.local_description().await)
+}
+
+/// Write the frames of the peer until the transport drops it
+async fn write_frames(
+    shared: &Mutex<Shared>,
+    channels: &tokio::sync::Mutex<Channels>,
+    conn: Connection,
+    mut frames: UnboundedReceiver<(bool, Bytes)>,
+) {
+    while let Some((reliable, frame)) = frames.recv().await {
+        let channels = channels.lock().await;
+        let channel = if reliable {
+            channels.control.clone()
+        } else {
+            channels.data.clone()
+        };
+        drop(channels);
+        if let Some(channel) = channel {
+            if let Err(e) = channel.send(&frame).await {
+                // the association is broken, it never recovers
+                log::debug!("Send to {:?} error {:?}", conn, e);
+                push_event(shared, RtcEvent::Closed(conn));
+                return;
+            }
+        }
+    }
+}
+
+/// Register a data channel of the peer, the peer is opened when both channels are open
+async fn on_channel(
+    shared: Arc<Mutex<Shared>>,
+    channels: Arc<tokio::sync::Mutex<Channels>>,
+    conn: Connection,
+    channel: Arc<RTCDataChannel>,
+) {
+    let recv_shared = shared.clone();
+    channel.on_message(Box::new(move |msg| {
+        push_event(&recv_shared, RtcEvent::Recv(conn, msg.data.to_vec()));
+        Box::pin(async {})
+    }));
+    let open_channels = channels.clone();
+    channel.on_open(Box::new(move || {
+        Box::pin(async move {
+            let mut channels = open_channels.lock().await;
+            channels.opened += 1;
+            if channels.opened == 2 {
+                push_event(&shared, RtcEvent::Opened(conn));
+            }
+        })
+    }));
+    let mut channels = channels.lock().await;
+    match channel.label() {
+        CONTROL_LABEL => channels.control = Some(channel),
+        DATA_LABEL => channels.data = Some(channel),
+        label => log::warn!("Unknown data channel {} from {:?}", label, conn),
+    }
+}
+
+fn frame(kind: u8, payload: &[u8]) -> Bytes {
+    let mut frame = Vec::with_capacity(1 + payload.len());
+    frame.push(kind);
+    frame.extend_from_slice(payload);
+    frame.into()
+}
+
+fn push_event(shared: &Mutex<Shared>, event: RtcEvent) {
+    if let Ok(mut shared) = shared.lock() {
+        shared.events.push_back(event);
+    }
+}
+
+fn push_signal(shared: &Mutex<Shared>, to: NodeId, signal: Signal) {
+    if let Ok(mut shared) = shared.lock() {
+        shared.signals.push_back((to, signal));
+    }
+}
+
+impl Transport for RtcTransport {
+    type Addr = NodeId;
+    type Error = webrtc::Error;
+
+    /// Create an offer for the node, which is available with `pop_signal` after the candidates are gathered
+    fn connect(&mut self, now_ms: u64, node: NodeId) -> Result<(), webrtc::Error> {
+        self.offer(now_ms, node, now_ms + self.config.connect_timeout_ms)
+    }
+
+    fn send(&mut self, conn: Connection, data: &[u8]) {
+        if let Some(peer) = self.peers.get(&conn) {
+            let _ = peer.writer.send((true, frame(KIND_DATA, data)));
+        }
+    }
+
+    fn send_unreliable(&mut self, conn: Connection, data: &[u8]) {
+        if let Some(peer) = self.peers.get(&conn) {
+            let _ = peer.writer.send((false, frame(KIND_DATA, data)));
+        }
+    }
+
+    fn close(&mut self, conn: Connection) {
+        self.remove_peer(conn);
+    }
+
+    fn stats(&self, conn: Connection) -> Option<ConnectionStats> {
+        self.shared.lock().ok()?.stats.get(&conn).copied()
+    }
+
+    fn pop_event(&mut self, now_ms: u64) -> Option<TransportEvent> {
+        if self.events.is_empty() {
+            let events = self
+                .shared
+                .lock()
+                .map(|mut s| s.events.drain(..).collect::<Vec<_>>())
+                .unwrap_or_default();
+            for event in events {
+                self.on_rtc_event(now_ms, event);
+            }
+            self.on_timer(now_ms);
+        }
+        self.events.pop_front()
+    }
+
+    /// Next session description which must be sent to the node, candidates are part of the descriptions
+    fn pop_signal(&mut self) -> Option<(NodeId, Signal)> {
+        self.shared.lock().ok()?.signals.pop_front()
+    }
+
+    /// Apply a session description which was received from the node by the signalling channel
+    fn on_signal(&mut self, now_ms: u64, from: NodeId, signal: Signal) {
+        match signal {
+            Signal::Offer { session, sdp } => {
+                let conn = Connection::from_parts(from, session);
+                if self.peers.contains_key(&conn) {
+                    log::warn!("Duplicated offer for {:?}", conn);
+                    return;
+                }
+                if self.pending_peers() >= self.config.max_pending_peers {
+                    log::warn!("Drop offer for {:?}, too many pending peers", conn);
+                    return;
+                }
+                let deadline_ms = now_ms + self.config.connect_timeout_ms;
+                self.create_peer(now_ms, conn, deadline_ms, Setup::Answer(sdp));
+            }
+            Signal::Answer { session, sdp } => {
+                let conn = Connection::from_parts(from, session);
+                // the offer is only sent after the peer connection is created
+                let pc = match self.peers.get(&conn) {
+                    Some(peer) if peer.outgoing => peer.pc.get().cloned(),
+                    _ => None,
+                };
+                let pc = match pc {
+                    Some(pc) => pc,
+                    None => {
+                        log::warn!("Answer for unknown {:?}", conn);
+                        return;
+                    }
+                };
+                self.runtime.spawn(async move {
+                    let desc = match RTCSessionDescription::answer(sdp) {
+                        Ok(desc) => desc,
+                        Err(e) => {
+                            log::warn!("Invalid answer from {:?}: {:?}", conn, e);
+                            return;
+                        }
+                    };
+                    if let Err(e) = pc.set_remote_description(desc).await {
+                        log::warn!("Set answer from {:?} error {:?}", conn, e);
+                    }
+                });
+            }
+            Signal::Candidate { session, candidate } => {
+                // own candidates are part of the descriptions, but a trickling remote may send them separately
+                let conn = Connection::from_parts(from, session);
+                let pc = match self.peers.get(&conn).and_then(|p| p.pc.get()) {
+                    Some(pc) => pc.clone(),
+                    None => {
+                        log::debug!("Candidate for unknown {:?}", conn);
+                        return;
+                    }
+                };
+                self.runtime.spawn(async move {
+                    let candidate = RTCIceCandidateInit {
+                        candidate,
+                        ..Default::default()
+                    };
+                    if let Err(e) = pc.add_ice_candidate(candidate).await {
+                        log::debug!("Add candidate from {:?} error {:?}", conn, e);
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn connect_over_local_signalling() {
+        let mut a = RtcTransport::new(RtcConfig::default()).expect("runtime");
+        let mut b = RtcTransport::new(RtcConfig::default()).expect("runtime");
+        let started = Instant::now();
+        let now_ms = || started.elapsed().as_millis() as u64;
+        a.connect(now_ms(), 2.into()).expect("connect");
+
+        let mut connected = None;
+        let mut accepted = None;
+        let mut received = vec![];
+        while received.len() < 2 && started.elapsed() < Duration::from_secs(20) {
+            // signalling stand-in, which moves session descriptions between the transports
+            while let Some((_, signal)) = a.pop_signal() {
+                b.on_signal(now_ms(), 1.into(), signal);
+            }
+            while let Some((_, signal)) = b.pop_signal() {
+                a.on_signal(now_ms(), 2.into(), signal);
+            }
+            while let Some(event) = a.pop_event(now_ms()) {
+                if let TransportEvent::Connected(conn) = event {
+                    a.send(conn, &[1]);
+                    a.send_unreliable(conn, &[2]);
+                    connected = Some(conn);
+                }
+            }
+            while let Some(event) = b.pop_event(now_ms()) {
+                match event {
+                    TransportEvent::Accepted(conn) => accepted = Some(conn),
+                    TransportEvent::Recv(pkt) => received.push(pkt.data),
+                    _ => {}
+                }
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let (connected, accepted) = (connected.expect("connected"), accepted.expect("accepted"));
+        assert_eq!(connected.node(), NodeId::from(2));
+        assert_eq!(accepted.node(), NodeId::from(1));
+        assert_eq!(connected.session(), accepted.session());
+        received.sort();
+        assert_eq!(received, vec![vec![1], vec![2]], "{:?}", received);
+    }
+
+    #[test]
+    fn offer_from_inside_runtime() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .expect("runtime");
+        let config = RtcConfig {
+            max_pending_peers: 1,
+            ..Default::default()
+        };
+        let mut a = RtcTransport::with_runtime(config, runtime.handle().clone());
+        let signal = runtime.block_on(async move {
+            a.connect(0, 2.into()).expect("connect");
+            assert!(a.connect(0, 3.into()).is_err(), "should cap pending peers");
+            for _ in 0..500 {
+                if let Some(signal) = a.pop_signal() {
+                    return Some(signal);
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            None
+        });
+        assert!(matches!(signal, Some((node, Signal::Offer { .. })) if node == NodeId::from(2)));
+    }
+}
@@ -0,0 +1,765 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, ErrorKind},
+    net::{SocketAddr, UdpSocket},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use protocol::{Connection, ConnectionStats, NetworkPkt, NodeId, Transport, TransportEvent};
+
+/// Datagram header: kind (1 byte) + session (4 bytes, big endian), followed by the payload
+const HEADER_LEN: usize = 5;
+const MAX_DATAGRAM: usize = 65_507;
+/// Fragment header after the datagram header: message id (4 bytes), index (2 bytes), count (2 bytes)
+const FRAGMENT_HEADER_LEN: usize = 8;
+/// Messages are reassembled from at most this many fragments, larger messages are dropped
+const MAX_FRAGMENTS: usize = 1024;
+/// Partially received messages per connection, the oldest is dropped when more arrive
+const MAX_PARTIALS: usize = 16;
+
+const KIND_HELLO: u8 = 1;
+const KIND_HELLO_ACK: u8 = 2;
+const KIND_DATA: u8 = 3;
+const KIND_PING: u8 = 4;
+const KIND_PONG: u8 = 5;
+const KIND_CLOSE: u8 = 6;
+const KIND_FRAGMENT: u8 = 7;
+
+/// Number of recent pings which are used for the loss estimation
+const PING_HISTORY: usize = 20;
+
+#[derive(Debug, Clone)]
+pub struct UdpConfig {
+    /// Hello is resent with this interval until the remote answers
+    pub hello_interval_ms: u64,
+    pub connect_timeout_ms: u64,
+    pub keepalive_interval_ms: u64,
+    /// Connection is disconnected when nothing is received within this timeout
+    pub timeout_ms: u64,
+    /// UDP can't measure the link capacity, so this value is reported in the stats
+    pub bandwidth_kbps: u32,
+    /// Larger messages are split into fragments of this size, which should fit the path MTU.
+    /// Received fragments which are longer are dropped.
+    pub fragment_len: usize,
+    /// Bytes of partially received messages buffered per connection, the oldest is dropped when more arrive
+    pub max_partial_bytes: usize,
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        Self {
+            hello_interval_ms: 500,
+            connect_timeout_ms: 5000,
+            keepalive_interval_ms: 1000,
+            timeout_ms: 5000,
+            bandwidth_kbps: 100_000,
+            fragment_len: 1200,
+            max_partial_bytes: 4 * 1024 * 1024,
+        }
+    }
+}
+
+/// Counters of the socket, for monitoring
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketStats {
+    pub sent_pkts: u64,
+    pub sent_bytes: u64,
+    pub recv_pkts: u64,
+    pub recv_bytes: u64,
+    pub invalid_pkts: u64,
+}
+
+struct Pending {
+    started_ms: u64,
+    last_hello_ms: u64,
+}
+
+struct Ping {
+    seq: u32,
+    sent_ms: u64,
+    acked: bool,
+}
+
+/// Fragments of a message which is not complete yet
+struct Partial {
+    started_ms: u64,
+    parts: Vec<Option<Vec<u8>>>,
+    missing: usize,
+    bytes: usize,
+}
+
+struct UdpConn {
+    conn: Connection,
+    next_msg_id: u32,
+    partials: HashMap<u32, Partial>,
+    last_recv_ms: u64,
+    last_ping_ms: u64,
+    ping_seq: u32,
+    pings: VecDeque<Ping>,
+    rtt_ms: Option<u32>,
+    jitter_ms: u32,
+}
+
+impl UdpConn {
+    fn new(conn: Connection, now_ms: u64) -> Self {
+        Self {
+            conn,
+            next_msg_id: 0,
+            partials: HashMap::new(),
+            last_recv_ms: now_ms,
+            last_ping_ms: 0,
+            ping_seq: 0,
+            pings: VecDeque::new(),
+            rtt_ms: None,
+            jitter_ms: 0,
+        }
+    }
+
+    fn on_pong(&mut self, now_ms: u64, seq: u32) {
+        if let Some(ping) = self.pings.iter_mut().find(|p| p.seq == seq && !p.acked) {
+            ping.acked = true;
+            let rtt = now_ms.saturating_sub(ping.sent_ms) as u32;
+            // smoothed like TCP, with 1/8 gain for rtt and 1/4 for jitter
+            match self.rtt_ms {
+                Some(old) => {
+                    self.jitter_ms = (self.jitter_ms * 3 + old.abs_diff(rtt)) / 4;
+                    self.rtt_ms = Some((old * 7 + rtt) / 8);
+                }
+                None => self.rtt_ms = Some(rtt),
+            }
+        }
+    }
+
+    /// Stores a fragment, returns the message when it is complete.
+    /// A lost fragment loses the whole message, like a lost datagram, the partial times out.
+    /// At most `max_bytes` are buffered, the oldest other partials are dropped to make room,
+    /// a message which doesn't fit on its own is dropped.
+    fn on_fragment(
+        &mut self,
+        now_ms: u64,
+        id: u32,
+        index: usize,
+        count: usize,
+        chunk: Vec<u8>,
+        max_bytes: usize,
+    ) -> Option<Vec<u8>> {
+        if let Some(partial) = self.partials.get(&id) {
+            if partial.parts.len() != count || partial.parts[index].is_some() {
+                return None;
+            }
+        }
+        if !self.partials.contains_key(&id) && self.partials.len() >= MAX_PARTIALS {
+            self.drop_oldest_partial(id);
+        }
+        while self.partials.values().map(|p| p.bytes).sum::<usize>() + chunk.len() > max_bytes {
+            if !self.drop_oldest_partial(id) {
+                log::debug!("Drop message {} of {:?}, too big to buffer", id, self.conn);
+                self.partials.remove(&id);
+                return None;
+            }
+        }
+        let partial = self.partials.entry(id).or_insert_with(|| Partial {
+            started_ms: now_ms,
+            parts: vec![None; count],
+            missing: count,
+            bytes: 0,
+        });
+        partial.bytes += chunk.len();
+        partial.parts[index] = Some(chunk);
+        partial.missing -= 1;
+        if partial.missing > 0 {
+            return None;
+        }
+        let partial = self.partials.remove(&id)?;
+        Some(partial.parts.into_iter().flatten().flatten().collect())
+    }
+
+    fn drop_oldest_partial(&mut self, keep: u32) -> bool {
+        let oldest = self
+            .partials
+            .iter()
+            .filter(|(id, _)| **id != keep)
+            .min_by_key(|(_, p)| p.started_ms)
+            .map(|(id, _)| *id);
+        match oldest {
+            Some(oldest) => {
+                log::debug!("Drop partial message {} of {:?}", oldest, self.conn);
+                self.partials.remove(&oldest).is_some()
+            }
+            None => false,
+        }
+    }
+
+    /// Pings which are not answered within the timeout are counted as lost
+    fn lost_percent(&self, now_ms: u64, timeout_ms: u64) -> f32 {
+        let expired = self
+            .pings
+            .iter()
+            .filter(|p| p.sent_ms + timeout_ms <= now_ms || p.acked)
+            .collect::<Vec<_>>();
+        if expired.is_empty() {
+            return 0.0;
+        }
+        let lost = expired.iter().filter(|p| !p.acked).count();
+        lost as f32 * 100.0 / expired.len() as f32
+    }
+}
+
+/// Transport over a single nonblocking UDP socket.
+/// Connection setup is a Hello / HelloAck exchange which carries the node ids, the session is chosen by
+/// the connecting side, so a connection is identified by the remote address and the session.
+pub struct UdpTransport {
+    node: NodeId,
+    config: UdpConfig,
+    socket: UdpSocket,
+    next_session: u32,
+    now_ms: u64,
+    pending: HashMap<(SocketAddr, u32), Pending>,
+    conns: HashMap<(SocketAddr, u32), UdpConn>,
+    addrs: HashMap<Connection, (SocketAddr, u32)>,
+    events: VecDeque<TransportEvent>,
+    stats: SocketStats,
+    buf: Vec<u8>,
+}
+
+impl UdpTransport {
+    pub fn bind(node: NodeId, addr: SocketAddr, config: UdpConfig) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        // sessions of different runs should not collide when the remote still remembers the old ones
+        let next_session = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        Ok(Self {
+            node,
+            config,
+            socket,
+            next_session,
+            now_ms: 0,
+            pending: HashMap::new(),
+            conns: HashMap::new(),
+            addrs: HashMap::new(),
+            events: VecDeque::new(),
+            stats: SocketStats::default(),
+            buf: vec![0; MAX_DATAGRAM + HEADER_LEN],
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn socket_stats(&self) -> SocketStats {
+        self.stats
+    }
+
+    /// Payload of a fragment, limited by the datagram size
+    fn fragment_len(&self) -> usize {
+        self.config
+            .fragment_len
+            .clamp(1, MAX_DATAGRAM - HEADER_LEN - FRAGMENT_HEADER_LEN)
+    }
+
+    fn send_to(&mut self, addr: SocketAddr, kind: u8, session: u32, payload: &[u8]) {
+        let mut pkt = Vec::with_capacity(HEADER_LEN + payload.len());
+        pkt.push(kind);
+        pkt.extend_from_slice(&session.to_be_bytes());
+        pkt.extend_from_slice(payload);
+        match self.socket.send_to(&pkt, addr) {
+            Ok(len) => {
+                self.stats.sent_pkts += 1;
+                self.stats.sent_bytes += len as u64;
+            }
+            // UDP is lossy anyway, a full socket buffer is the same as a lost packet
+            Err(e) => log::debug!("Send to {} error {:?}", addr, e),
+        }
+    }
+
+    fn recv_all(&mut self, now_ms: u64) {
+        loop {
+            match self.socket.recv_from(&mut self.buf) {
+                Ok((len, addr)) => {
+                    self.stats.recv_pkts += 1;
+                    self.stats.recv_bytes += len as u64;
+                    if len < HEADER_LEN {
+                        self.stats.invalid_pkts += 1;
+                        continue;
+                    }
+                    let kind = self.buf[0];
+                    let session =
+                        u32::from_be_bytes([self.buf[1], self.buf[2], self.buf[3], self.buf[4]]);
+                    let payload = self.buf[HEADER_LEN..len].to_vec();
+                    self.on_datagram(now_ms, addr, kind, session, payload);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                // ICMP errors of previous sends are reported here on some platforms, keep reading
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => continue,
+                Err(e) => {
+                    log::warn!("Udp recv error {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn on_datagram(
+        &mut self,
+        now_ms: u64,
+        addr: SocketAddr,
+        kind: u8,
+        session: u32,
+        payload: Vec<u8>,
+    ) {
+        let key = (addr, session);
+        match kind {
+            KIND_HELLO => {
+                let node = if let Some(node) = read_u64(&payload) {
+                    NodeId::from(node)
+                } else {
+                    self.stats.invalid_pkts += 1;
+                    return;
+                };
+                if !self.conns.contains_key(&key) {
+                    let conn = Connection::from_parts(node, session);
+                    if self.addrs.contains_key(&conn) {
+                        log::warn!(
+                            "Session {} of {:?} already used by another address",
+                            session,
+                            node
+                        );
+                        return;
+                    }
+                    self.conns.insert(key, UdpConn::new(conn, now_ms));
+                    self.addrs.insert(conn, key);
+                    log::info!("Accepted {:?} from {}", conn, addr);
+                    self.events.push_back(TransportEvent::Accepted(conn));
+                }
+                // HelloAck is also resent when the previous one was lost
+                let ack = self.node.to_be_bytes();
+                self.send_to(addr, KIND_HELLO_ACK, session, &ack);
+            }
+            KIND_HELLO_ACK => {
+                let node = if let Some(node) = read_u64(&payload) {
+                    NodeId::from(node)
+                } else {
+                    self.stats.invalid_pkts += 1;
+                    return;
+                };
+                if self.pending.remove(&key).is_some() {
+                    let conn = Connection::from_parts(node, session);
+                    self.conns.insert(key, UdpConn::new(conn, now_ms));
+                    self.addrs.insert(conn, key);
+                    log::info!("Connected {:?} to {}", conn, addr);
+                    self.events.push_back(TransportEvent::Connected(conn));
+                }
+            }
+            KIND_DATA => {
+                if let Some(udp_conn) = self.conns.get_mut(&key) {
+                    udp_conn.last_recv_ms = now_ms;
+                    self.events.push_back(TransportEvent::Recv(NetworkPkt {
+                        conn: udp_conn.conn,
+                        data: payload,
+                    }));
+                } else {
+                    // the remote believes the session is still alive, tell it to give up quickly
+                    self.send_to(addr, KIND_CLOSE, session, &[]);
+                }
+            }
+            KIND_FRAGMENT => {
+                let (id, index, count) = if let Some(header) = read_fragment_header(&payload) {
+                    header
+                } else {
+                    self.stats.invalid_pkts += 1;
+                    return;
+                };
+                let chunk_len = payload.len() - FRAGMENT_HEADER_LEN;
+                if !(2..=MAX_FRAGMENTS).contains(&count)
+                    || index >= count
+                    || chunk_len > self.fragment_len()
+                {
+                    self.stats.invalid_pkts += 1;
+                    return;
+                }
+                let max_bytes = self.config.max_partial_bytes;
+                if let Some(udp_conn) = self.conns.get_mut(&key) {
+                    udp_conn.last_recv_ms = now_ms;
+                    let chunk = payload[FRAGMENT_HEADER_LEN..].to_vec();
+                    if let Some(data) =
+                        udp_conn.on_fragment(now_ms, id, index, count, chunk, max_bytes)
+                    {
+                        self.events.push_back(TransportEvent::Recv(NetworkPkt {
+                            conn: udp_conn.conn,
+                            data,
+                        }));
+                    }
+                } else {
+                    self.send_to(addr, KIND_CLOSE, session, &[]);
+                }
+            }
+            KIND_PING => {
+                if let Some(udp_conn) = self.conns.get_mut(&key) {
+                    udp_conn.last_recv_ms = now_ms;
+                    self.send_to(addr, KIND_PONG, session, &payload);
+                } else {
+                    self.send_to(addr, KIND_CLOSE, session, &[]);
+                }
+            }
+            KIND_PONG => {
+                if let (Some(udp_conn), Some(seq)) = (self.conns.get_mut(&key), read_u32(&payload))
+                {
+                    udp_conn.last_recv_ms = now_ms;
+                    udp_conn.on_pong(now_ms, seq);
+                }
+            }
+            KIND_CLOSE => {
+                self.pending.remove(&key);
+                self.remove_conn(key);
+            }
+            _ => {
+                self.stats.invalid_pkts += 1;
+            }
+        }
+    }
+
+    fn on_timer(&mut self, now_ms: u64) {
+        let hello = self.node.to_be_bytes();
+        let mut hellos = vec![];
+        let config = &self.config;
+        self.pending.retain(|key, pending| {
+            if now_ms >= pending.started_ms + config.connect_timeout_ms {
+                log::warn!("Connect to {} timeout", key.0);
+                return false;
+            }
+            if now_ms >= pending.last_hello_ms + config.hello_interval_ms {
+                pending.last_hello_ms = now_ms;
+                hellos.push(*key);
+            }
+            true
+        });
+        for (addr, session) in hellos {
+            self.send_to(addr, KIND_HELLO, session, &hello);
+        }
+
+        let mut timeouts = vec![];
+        let mut pings = vec![];
+        for (key, udp_conn) in self.conns.iter_mut() {
+            let timeout_ms = self.config.timeout_ms;
+            udp_conn
+                .partials
+                .retain(|_, partial| now_ms < partial.started_ms + timeout_ms);
+            if now_ms >= udp_conn.last_recv_ms + self.config.timeout_ms {
+                timeouts.push(*key);
+            } else if now_ms >= udp_conn.last_ping_ms + self.config.keepalive_interval_ms {
+                udp_conn.last_ping_ms = now_ms;
+                udp_conn.ping_seq = udp_conn.ping_seq.wrapping_add(1);
+                udp_conn.pings.push_back(Ping {
+                    seq: udp_conn.ping_seq,
+                    sent_ms: now_ms,
+                    acked: false,
+                });
+                while udp_conn.pings.len() > PING_HISTORY {
+                    udp_conn.pings.pop_front();
+                }
+                pings.push((*key, udp_conn.ping_seq));
+            }
+        }
+        for ((addr, session), seq) in pings {
+            self.send_to(addr, KIND_PING, session, &seq.to_be_bytes());
+        }
+        for key in timeouts {
+            log::info!("Connection {} session {} timeout", key.0, key.1);
+            self.remove_conn(key);
+        }
+    }
+
+    fn remove_conn(&mut self, key: (SocketAddr, u32)) {
+        if let Some(udp_conn) = self.conns.remove(&key) {
+            self.addrs.remove(&udp_conn.conn);
+            self.events
+                .push_back(TransportEvent::Disconnected(udp_conn.conn));
+        }
+    }
+}
+
+impl Transport for UdpTransport {
+    type Addr = SocketAddr;
+    type Error = io::Error;
+
+    fn connect(&mut self, now_ms: u64, addr: SocketAddr) -> Result<(), io::Error> {
+        let session = self.next_session;
+        self.next_session = self.next_session.wrapping_add(1);
+        self.pending.insert(
+            (addr, session),
+            Pending {
+                started_ms: now_ms,
+                last_hello_ms: now_ms,
+            },
+        );
+        let hello = self.node.to_be_bytes();
+        self.send_to(addr, KIND_HELLO, session, &hello);
+        Ok(())
+    }
+
+    /// Messages larger than the fragment length are split into fragments, which the remote reassembles
+    fn send(&mut self, conn: Connection, data: &[u8]) {
+        let key = if let Some(key) = self.addrs.get(&conn).copied() {
+            key
+        } else {
+            return;
+        };
+        let fragment_len = self.fragment_len();
+        if data.len() <= fragment_len {
+            self.send_to(key.0, KIND_DATA, key.1, data);
+            return;
+        }
+        let count = data.len().div_ceil(fragment_len);
+        if count > MAX_FRAGMENTS {
+            log::warn!(
+                "Drop message of {} bytes to {:?}, too big for {} fragments",
+                data.len(),
+                conn,
+                MAX_FRAGMENTS
+            );
+            return;
+        }
+        let id = match self.conns.get_mut(&key) {
+            Some(udp_conn) => {
+                udp_conn.next_msg_id = udp_conn.next_msg_id.wrapping_add(1);
+                udp_conn.next_msg_id
+            }
+            None => return,
+        };
+        let mut fragment = Vec::with_capacity(FRAGMENT_HEADER_LEN + fragment_len);
+        for (index, chunk) in data.chunks(fragment_len).enumerate() {
+            fragment.clear();
+            fragment.extend_from_slice(&id.to_be_bytes());
+            fragment.extend_from_slice(&(index as u16).to_be_bytes());
+            fragment.extend_from_slice(&(count as u16).to_be_bytes());
+            fragment.extend_from_slice(chunk);
+            self.send_to(key.0, KIND_FRAGMENT, key.1, &fragment);
+        }
+    }
+
+    fn close(&mut self, conn: Connection) {
+        if let Some((addr, session)) = self.addrs.get(&conn).copied() {
+            self.send_to(addr, KIND_CLOSE, session, &[]);
+            self.remove_conn((addr, session));
+        }
+    }
+
+    fn stats(&self, conn: Connection) -> Option<ConnectionStats> {
+        let udp_conn = self.conns.get(self.addrs.get(&conn)?)?;
+        Some(ConnectionStats {
+            // until the first pong, the rtt is unknown and must not look better than measured ones
+            rtt_ms: udp_conn.rtt_ms.unwrap_or(self.config.timeout_ms as u32),
+            lost_percent: udp_conn
+                .lost_percent(self.now_ms, self.config.timeout_ms)
+                .into(),
+            jitter_ms: udp_conn.jitter_ms,
+            bandwidth_kbps: self.config.bandwidth_kbps,
+        })
+    }
+
+    fn pop_event(&mut self, now_ms: u64) -> Option<TransportEvent> {
+        if self.events.is_empty() {
+            self.now_ms = now_ms;
+            self.recv_all(now_ms);
+            self.on_timer(now_ms);
+        }
+        self.events.pop_front()
+    }
+}
+
+fn read_u32(payload: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(payload.get(0..4)?.try_into().ok()?))
+}
+
+fn read_u64(payload: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(payload.get(0..8)?.try_into().ok()?))
+}
+
+/// Message id, fragment index and fragment count
+fn read_fragment_header(payload: &[u8]) -> Option<(u32, usize, usize)> {
+    let index = u16::from_be_bytes(payload.get(4..6)?.try_into().ok()?);
+    let count = u16::from_be_bytes(payload.get(6..8)?.try_into().ok()?);
+    Some((read_u32(payload)?, index as usize, count as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::{ChannelId, NodeKey, P2pStreamDriver, P2pStreamRunner};
+
+    use super::*;
+
+    fn events(transport: &mut UdpTransport, now_ms: u64) -> Vec<TransportEvent> {
+        let mut events = vec![];
+        // datagrams over loopback may need a moment
+        for _ in 0..50 {
+            while let Some(event) = transport.pop_event(now_ms) {
+                events.push(event);
+            }
+            if !events.is_empty() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        events
+    }
+
+    #[test]
+    fn connect_send_and_timeout() {
+        let local = "127.0.0.1:0".parse().expect("valid addr");
+        let mut a = UdpTransport::bind(1.into(), local, UdpConfig::default()).expect("bind");
+        let mut b = UdpTransport::bind(2.into(), local, UdpConfig::default()).expect("bind");
+        a.connect(0, b.local_addr().expect("addr"))
+            .expect("connect");
+
+        let accepted = match events(&mut b, 0).pop() {
+            Some(TransportEvent::Accepted(conn)) => conn,
+            _ => panic!("should accept"),
+        };
+        assert_eq!(accepted.node(), NodeId::from(1));
+        let connected = match events(&mut a, 0).pop() {
+            Some(TransportEvent::Connected(conn)) => conn,
+            _ => panic!("should connect"),
+        };
+        assert_eq!(connected.node(), NodeId::from(2));
+        assert_eq!(connected.session(), accepted.session());
+
+        a.send(connected, &[1, 2, 3]);
+        match events(&mut b, 0).pop() {
+            Some(TransportEvent::Recv(pkt)) => {
+                assert_eq!(pkt.conn, accepted);
+                assert_eq!(pkt.data, vec![1, 2, 3]);
+            }
+            _ => panic!("should receive"),
+        }
+
+        // b never answers anymore, so a disconnects after the timeout
+        let now_ms = UdpConfig::default().timeout_ms;
+        match a.pop_event(now_ms) {
+            Some(TransportEvent::Disconnected(conn)) => assert_eq!(conn, connected),
+            _ => panic!("should timeout"),
+        }
+    }
+
+    #[test]
+    fn oversized_sync_is_fragmented() {
+        let local = "127.0.0.1:0".parse().expect("valid addr");
+        let mut a = P2pStreamRunner::new(NodeKey::from_secret([1; 32]));
+        let b = P2pStreamRunner::new(NodeKey::from_secret([2; 32]));
+        let (a_node, b_node) = (a.node(), b.node());
+        // the signed rows of these channels don't fit a single datagram
+        let last = ChannelId::from(600);
+        for channel in 1..=600 {
+            a.add_channel(0, ChannelId::from(channel));
+        }
+        let mut a = P2pStreamDriver::new(
+            a,
+            UdpTransport::bind(a_node, local, UdpConfig::default()).expect("bind"),
+        );
+        let mut b = P2pStreamDriver::new(
+            b,
+            UdpTransport::bind(b_node, local, UdpConfig::default()).expect("bind"),
+        );
+        let addr = b.transport().local_addr().expect("addr");
+        a.connect(0, addr).expect("connect");
+
+        for step in 0..500 {
+            let now_ms = step * 10;
+            if now_ms % 1000 == 0 {
+                a.on_tick(now_ms);
+                b.on_tick(now_ms);
+            }
+            a.poll(now_ms);
+            while a.pop_output().is_some() {}
+            b.poll(now_ms);
+            while b.pop_output().is_some() {}
+            if b.runner().next_hop_for(last) == Some(a_node) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert_eq!(b.runner().next_hop_for(last), Some(a_node));
+        let stats = a.transport().socket_stats();
+        assert!(stats.sent_bytes > MAX_DATAGRAM as u64, "{:?}", stats);
+    }
+
+    fn connected_pair(
+        a_config: UdpConfig,
+        b_config: UdpConfig,
+    ) -> (UdpTransport, UdpTransport, Connection) {
+        let local = "127.0.0.1:0".parse().expect("valid addr");
+        let mut a = UdpTransport::bind(1.into(), local, a_config).expect("bind");
+        let mut b = UdpTransport::bind(2.into(), local, b_config).expect("bind");
+        a.connect(0, b.local_addr().expect("addr"))
+            .expect("connect");
+        assert!(matches!(
+            events(&mut b, 0).pop(),
+            Some(TransportEvent::Accepted(_))
+        ));
+        let connected = match events(&mut a, 0).pop() {
+            Some(TransportEvent::Connected(conn)) => conn,
+            _ => panic!("should connect"),
+        };
+        (a, b, connected)
+    }
+
+    #[test]
+    fn oversized_fragments_are_dropped() {
+        let big = UdpConfig {
+            fragment_len: 2000,
+            ..Default::default()
+        };
+        let (mut a, mut b, conn) = connected_pair(big, UdpConfig::default());
+
+        a.send(conn, &[7; 4000]);
+        assert!(events(&mut b, 0).is_empty());
+        assert_eq!(b.socket_stats().invalid_pkts, 2);
+        assert!(b.conns.values().all(|c| c.partials.is_empty()));
+    }
+
+    #[test]
+    fn excess_fragments_evict_partials() {
+        let small = UdpConfig {
+            fragment_len: 1000,
+            max_partial_bytes: 3000,
+            ..Default::default()
+        };
+        let (mut a, mut b, conn) = connected_pair(small.clone(), small);
+
+        // the message doesn't fit the buffer, so its own partial is evicted
+        a.send(conn, &[7; 4000]);
+        assert!(events(&mut b, 0).is_empty());
+        let buffered = b
+            .conns
+            .values()
+            .flat_map(|c| c.partials.values())
+            .map(|p| p.bytes);
+        assert!(buffered.sum::<usize>() <= 3000);
+
+        // messages which fit are still reassembled
+        a.send(conn, &[8; 3000]);
+        match events(&mut b, 0).pop() {
+            Some(TransportEvent::Recv(pkt)) => assert_eq!(pkt.data, vec![8; 3000]),
+            _ => panic!("should receive"),
+        }
+    }
+
+    #[test]
+    fn oldest_partial_is_evicted_for_bytes() {
+        let conn = Connection::from_parts(1.into(), 1);
+        let mut udp_conn = UdpConn::new(conn, 0);
+        assert_eq!(udp_conn.on_fragment(0, 1, 0, 2, vec![1; 100], 250), None);
+        assert_eq!(udp_conn.on_fragment(1, 2, 0, 2, vec![2; 100], 250), None);
+        // each further chunk only fits when the oldest other message is dropped
+        assert_eq!(udp_conn.on_fragment(2, 3, 0, 2, vec![3; 100], 250), None);
+        assert!(!udp_conn.partials.contains_key(&1));
+        assert_eq!(
+            udp_conn.on_fragment(3, 3, 1, 2, vec![3; 100], 250),
+            Some(vec![3; 200])
+        );
+        assert!(udp_conn.partials.is_empty());
+    }
+}
@@ -0,0 +1,611 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, ErrorKind},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::mpsc::{self, Receiver, TryRecvError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use protocol::{Connection, ConnectionStats, NetworkPkt, NodeId, Transport, TransportEvent};
+use tungstenite::{
+    client::IntoClientRequest,
+    error::UrlError,
+    handshake::{
+        client::{ClientHandshake, Request},
+        server::NoCallback,
+        server::ServerHandshake,
+        MidHandshake,
+    },
+    HandshakeError, Message, WebSocket,
+};
+
+/// Binary frame: kind (1 byte) followed by the payload, the same as the browser transport
+const KIND_HELLO: u8 = 1;
+const KIND_DATA: u8 = 2;
+const KIND_PING: u8 = 3;
+const KIND_PONG: u8 = 4;
+
+#[derive(Debug, Clone)]
+pub struct WsConfig {
+    /// Socket which is not opened and greeted within this timeout is closed
+    pub handshake_timeout_ms: u64,
+    pub keepalive_interval_ms: u64,
+    /// Connection is disconnected when nothing is received within this timeout
+    pub timeout_ms: u64,
+    /// WebSocket can't measure the link capacity, so this value is reported in the stats
+    pub bandwidth_kbps: u32,
+}
+
+impl Default for WsConfig {
+    fn default() -> Self {
+        Self {
+            handshake_timeout_ms: 5000,
+            keepalive_interval_ms: 1000,
+            timeout_ms: 5000,
+            bandwidth_kbps: 100_000,
+        }
+    }
+}
+
+enum State {
+    /// TCP connect which runs on a worker thread, so name resolution and a slow remote don't block the poll
+    Connecting(Request, Receiver<io::Result<TcpStream>>),
+    Client(MidHandshake<ClientHandshake<TcpStream>>),
+    Server(MidHandshake<ServerHandshake<TcpStream, NoCallback>>),
+    Open(WebSocket<TcpStream>),
+    Closed,
+}
+
+struct WsSocket {
+    state: State,
+    outgoing: bool,
+    /// Chosen by the connecting side and sent in its Hello
+    session: u32,
+    /// Known after the remote Hello
+    conn: Option<Connection>,
+    started_ms: u64,
+    last_recv_ms: u64,
+    last_ping_ms: u64,
+    rtt_ms: Option<u32>,
+    jitter_ms: u32,
+}
+
+impl WsSocket {
+    fn new(state: State, outgoing: bool, session: u32, now_ms: u64) -> Self {
+        Self {
+            state,
+            outgoing,
+            session,
+            conn: None,
+            started_ms: now_ms,
+            last_recv_ms: now_ms,
+            last_ping_ms: now_ms,
+            rtt_ms: None,
+            jitter_ms: 0,
+        }
+    }
+
+    fn on_pong(&mut self, now_ms: u64, sent_ms: u64) {
+        let rtt = now_ms.saturating_sub(sent_ms) as u32;
+        // smoothed like TCP, with 1/8 gain for rtt and 1/4 for jitter
+        match self.rtt_ms {
+            Some(old) => {
+                self.jitter_ms = (self.jitter_ms * 3 + old.abs_diff(rtt)) / 4;
+                self.rtt_ms = Some((old * 7 + rtt) / 8);
+            }
+            None => self.rtt_ms = Some(rtt),
+        }
+    }
+
+    /// Queue a frame, a socket which can't write anymore is closed
+    fn send(&mut self, kind: u8, payload: &[u8]) {
+        if let State::Open(ws) = &mut self.state {
+            let mut frame = Vec::with_capacity(1 + payload.len());
+            frame.push(kind);
+            frame.extend_from_slice(payload);
+            match ws.send(Message::Binary(frame)) {
+                Ok(()) => {}
+                // the frame is buffered and will be flushed on the next poll
+                Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(tungstenite::Error::WriteBufferFull(_)) => {
+                    log::warn!("Drop frame to {:?}, write buffer is full", self.conn);
+                }
+                Err(e) => {
+                    log::info!("Send to {:?} error {:?}", self.conn, e);
+                    self.state = State::Closed;
+                }
+            }
+        }
+    }
+}
+
+/// Transport over WebSocket binary frames, it can listen for incoming connections and connect to ws:// urls.
+/// After the WebSocket handshake, both sides send a Hello frame with their node id and the session
+/// which is chosen by the connecting side. Sockets are nonblocking, the TCP connect runs on a worker thread
+/// and its result is picked up by the poll. Only ws:// is supported, wss:// needs a TLS terminating proxy.
+pub struct WsTransport {
+    node: NodeId,
+    config: WsConfig,
+    listener: Option<TcpListener>,
+    next_session: u32,
+    next_slot: u64,
+    sockets: HashMap<u64, WsSocket>,
+    slots: HashMap<Connection, u64>,
+    events: VecDeque<TransportEvent>,
+}
+
+impl WsTransport {
+    pub fn new(node: NodeId, config: WsConfig) -> Self {
+        // sessions of different runs should not collide when the remote still remembers the old ones
+        let next_session = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        Self {
+            node,
+            config,
+            listener: None,
+            next_session,
+            next_slot: 0,
+            sockets: HashMap::new(),
+            slots: HashMap::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Accept incoming WebSocket connections on the address
+    pub fn listen(&mut self, addr: SocketAddr) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        self.listener = Some(listener);
+        Ok(())
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.listener.as_ref().and_then(|l| l.local_addr().ok())
+    }
+
+    fn add_socket(&mut self, socket: WsSocket) -> u64 {
+        let slot = self.next_slot;
+        self.next_slot += 1;
+        self.sockets.insert(slot, socket);
+        slot
+    }
+
+    fn accept_all(&mut self, now_ms: u64) {
+        let listener = if let Some(listener) = &self.listener {
+            listener
+        } else {
+            return;
+        };
+        let mut accepted = vec![];
+        loop {
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    if let Err(e) = stream.set_nonblocking(true) {
+                        log::warn!("Set nonblocking for {} error {:?}", addr, e);
+                        continue;
+                    }
+                    accepted.push((stream, addr));
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    log::warn!("Accept error {:?}", e);
+                    break;
+                }
+            }
+        }
+        for (stream, addr) in accepted {
+            let state = match tungstenite::accept(stream) {
+                Ok(ws) => State::Open(ws),
+                Err(HandshakeError::Interrupted(mid)) => State::Server(mid),
+                Err(HandshakeError::Failure(e)) => {
+                    log::info!("Handshake with {} error {:?}", addr, e);
+                    continue;
+                }
+            };
+            // the session is only known after the remote Hello
+            self.add_socket(WsSocket::new(state, false, 0, now_ms));
+        }
+    }
+
+    /// Continue handshakes, read frames and send keepalives of all sockets
+    fn poll_sockets(&mut self, now_ms: u64) {
+        let mut slots = self.sockets.keys().copied().collect::<Vec<_>>();
+        slots.sort();
+        for slot in slots {
+            self.poll_socket(now_ms, slot);
+        }
+    }
+
+    fn poll_socket(&mut self, now_ms: u64, slot: u64) {
+        let node = self.node;
+        let socket = if let Some(socket) = self.sockets.get_mut(&slot) {
+            socket
+        } else {
+            return;
+        };
+        let state = std::mem::replace(&mut socket.state, State::Closed);
+        let mut opened = false;
+        socket.state = match state {
+            State::Connecting(request, result) => match result.try_recv() {
+                Ok(Ok(stream)) => match start_client(request, stream) {
+                    State::Open(ws) => {
+                        opened = true;
+                        State::Open(ws)
+                    }
+                    state => state,
+                },
+                Ok(Err(e)) => {
+                    log::info!("Connect error {:?}", e);
+                    State::Closed
+                }
+                Err(TryRecvError::Empty) => State::Connecting(request, result),
+                Err(TryRecvError::Disconnected) => State::Closed,
+            },
+            State::Client(mid) => match mid.handshake() {
+                Ok((ws, _)) => {
+                    opened = true;
+                    State::Open(ws)
+                }
+                Err(HandshakeError::Interrupted(mid)) => State::Client(mid),
+                Err(HandshakeError::Failure(e)) => {
+                    log::info!("Client handshake error {:?}", e);
+                    State::Closed
+                }
+            },
+            State::Server(mid) => match mid.handshake() {
+                Ok(ws) => State::Open(ws),
+                Err(HandshakeError::Interrupted(mid)) => State::Server(mid),
+                Err(HandshakeError::Failure(e)) => {
+                    log::info!("Server handshake error {:?}", e);
+                    State::Closed
+                }
+            },
+            state => state,
+        };
+        if opened {
+            let hello = hello(node, socket.session);
+            socket.send(KIND_HELLO, &hello);
+        }
+
+        let mut frames = vec![];
+        if let State::Open(ws) = &mut socket.state {
+            loop {
+                match ws.read() {
+                    Ok(Message::Binary(frame)) => frames.push(frame),
+                    Ok(Message::Close(_)) => {
+                        socket.state = State::Closed;
+                        break;
+                    }
+                    // ping and pong frames are answered by tungstenite, text frames are not used
+                    Ok(_) => {}
+                    Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => {
+                        // write the pongs and the frames which were buffered by a blocked send
+                        match ws.flush() {
+                            Ok(()) => {}
+                            Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => {
+                            }
+                            Err(e) => {
+                                log::info!("Flush to {:?} error {:?}", socket.conn, e);
+                                socket.state = State::Closed;
+                            }
+                        }
+                        break;
+                    }
+                    Err(e) => {
+                        log::info!("Read from {:?} error {:?}", socket.conn, e);
+                        socket.state = State::Closed;
+                        break;
+                    }
+                }
+            }
+        }
+        for frame in frames {
+            self.on_frame(now_ms, slot, frame);
+        }
+        self.on_timer(now_ms, slot);
+    }
+
+    fn on_frame(&mut self, now_ms: u64, slot: u64, frame: Vec<u8>) {
+        let socket = if let Some(socket) = self.sockets.get_mut(&slot) {
+            socket
+        } else {
+            return;
+        };
+        socket.last_recv_ms = now_ms;
+        let (kind, payload) = match frame.split_first() {
+            Some((kind, payload)) => (*kind, payload),
+            None => return,
+        };
+        match (kind, socket.conn) {
+            (KIND_HELLO, None) => {
+                let (node, session) = if let Some(hello) = read_hello(payload) {
+                    hello
+                } else {
+                    log::warn!("Invalid hello, close socket");
+                    socket.state = State::Closed;
+                    return;
+                };
+                if socket.outgoing && session != socket.session {
+                    log::warn!("Hello with wrong session {} from {:?}", session, node);
+                    socket.state = State::Closed;
+                    return;
+                }
+                let conn = Connection::from_parts(node, session);
+                if self.slots.contains_key(&conn) {
+                    log::warn!(
+                        "Session {} of {:?} already used, close socket",
+                        session,
+                        node
+                    );
+                    socket.state = State::Closed;
+                    return;
+                }
+                socket.conn = Some(conn);
+                self.slots.insert(conn, slot);
+                if socket.outgoing {
+                    log::info!("Connected {:?}", conn);
+                    self.events.push_back(TransportEvent::Connected(conn));
+                } else {
+                    socket.session = session;
+                    socket.send(KIND_HELLO, &hello(self.node, session));
+                    log::info!("Accepted {:?}", conn);
+                    self.events.push_back(TransportEvent::Accepted(conn));
+                }
+            }
+            (KIND_DATA, Some(conn)) => {
+                self.events.push_back(TransportEvent::Recv(NetworkPkt {
+                    conn,
+                    data: payload.to_vec(),
+                }));
+            }
+            (KIND_PING, Some(_)) => socket.send(KIND_PONG, payload),
+            (KIND_PONG, Some(_)) => {
+                if let Ok(sent_ms) = payload.try_into().map(u64::from_be_bytes) {
+                    socket.on_pong(now_ms, sent_ms);
+                }
+            }
+            _ => log::debug!("Unexpected frame {} from {:?}", kind, socket.conn),
+        }
+    }
+
+    fn on_timer(&mut self, now_ms: u64, slot: u64) {
+        let config = &self.config;
+        let socket = if let Some(socket) = self.sockets.get_mut(&slot) {
+            socket
+        } else {
+            return;
+        };
+        let timeout = match socket.conn {
+            None => now_ms >= socket.started_ms + config.handshake_timeout_ms,
+            Some(_) => now_ms >= socket.last_recv_ms + config.timeout_ms,
+        };
+        if timeout {
+            log::info!("Socket {:?} timeout", socket.conn);
+            socket.state = State::Closed;
+        } else if socket.conn.is_some()
+            && now_ms >= socket.last_ping_ms + config.keepalive_interval_ms
+        {
+            socket.last_ping_ms = now_ms;
+            socket.send(KIND_PING, &now_ms.to_be_bytes());
+        }
+        if matches!(socket.state, State::Closed) {
+            self.remove_socket(slot);
+        }
+    }
+
+    fn remove_socket(&mut self, slot: u64) {
+        if let Some(mut socket) = self.sockets.remove(&slot) {
+            if let State::Open(ws) = &mut socket.state {
+                let _ = ws.close(None);
+                let _ = ws.flush();
+            }
+            if let Some(conn) = socket.conn {
+                self.slots.remove(&conn);
+                self.events.push_back(TransportEvent::Disconnected(conn));
+            }
+        }
+    }
+}
+
+impl Transport for WsTransport {
+    /// Url like ws://127.0.0.1:8080
+    type Addr = String;
+    type Error = tungstenite::Error;
+
+    /// Starts the TCP connect on a worker thread and returns, a failed or timed out connect only closes the socket
+    fn connect(&mut self, now_ms: u64, addr: String) -> Result<(), tungstenite::Error> {
+        let request = addr.into_client_request()?;
+        if request.uri().scheme_str() == Some("wss") {
+            return Err(tungstenite::Error::Url(UrlError::TlsFeatureNotEnabled));
+        }
+        let host = request.uri().host().unwrap_or_default().to_string();
+        let port = request.uri().port_u16().unwrap_or(80);
+        let timeout = Duration::from_millis(self.config.handshake_timeout_ms);
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            // the poll may have given up already, then nobody receives the result
+            let _ = tx.send(connect_tcp(&host, port, timeout));
+        });
+        let session = self.next_session;
+        self.next_session = self.next_session.wrapping_add(1);
+        self.add_socket(WsSocket::new(
+            State::Connecting(request, rx),
+            true,
+            session,
+            now_ms,
+        ));
+        Ok(())
+    }
+
+    fn send(&mut self, conn: Connection, data: &[u8]) {
+        if let Some(socket) = self.slots.get(&conn).and_then(|s| self.sockets.get_mut(s)) {
+            socket.send(KIND_DATA, data);
+        }
+    }
+
+    fn close(&mut self, conn: Connection) {
+        if let Some(slot) = self.slots.get(&conn).copied() {
+            self.remove_socket(slot);
+        }
+    }
+
+    fn stats(&self, conn: Connection) -> Option<ConnectionStats> {
+        let socket = self.sockets.get(self.slots.get(&conn)?)?;
+        Some(ConnectionStats {
+            // until the first pong, the rtt is unknown and must not look better than measured ones
+            rtt_ms: socket.rtt_ms.unwrap_or(self.config.timeout_ms as u32),
+            // TCP retransmits lost packets, which shows up as rtt and jitter instead
+            lost_percent: 0.0.into(),
+            jitter_ms: socket.jitter_ms,
+            bandwidth_kbps: self.config.bandwidth_kbps,
+        })
+    }
+
+    fn pop_event(&mut self, now_ms: u64) -> Option<TransportEvent> {
+        if self.events.is_empty() {
+            self.accept_all(now_ms);
+            self.poll_sockets(now_ms);
+        }
+        self.events.pop_front()
+    }
+}
+
+/// Blocking, runs on the connect worker
+fn connect_tcp(host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(ErrorKind::NotFound, "no address");
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => {
+                stream.set_nonblocking(true)?;
+                stream.set_nodelay(true)?;
+                return Ok(stream);
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+fn start_client(request: Request, stream: TcpStream) -> State {
+    match tungstenite::client(request, stream) {
+        Ok((ws, _)) => State::Open(ws),
+        Err(HandshakeError::Interrupted(mid)) => State::Client(mid),
+        Err(HandshakeError::Failure(e)) => {
+            log::info!("Client handshake error {:?}", e);
+            State::Closed
+        }
+    }
+}
+
+fn hello(node: NodeId, session: u32) -> Vec<u8> {
+    let mut hello = node.to_be_bytes().to_vec();
+    hello.extend_from_slice(&session.to_be_bytes());
+    hello
+}
+
+fn read_hello(payload: &[u8]) -> Option<(NodeId, u32)> {
+    let node = u64::from_be_bytes(payload.get(0..8)?.try_into().ok()?);
+    let session = u32::from_be_bytes(payload.get(8..12)?.try_into().ok()?);
+    Some((node.into(), session))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn next_event(
+        a: &mut WsTransport,
+        b: &mut WsTransport,
+    ) -> (Option<TransportEvent>, Option<TransportEvent>) {
+        // both sides must be polled to progress the handshakes over loopback
+        for _ in 0..500 {
+            let ea = a.pop_event(0);
+            let eb = b.pop_event(0);
+            if ea.is_some() || eb.is_some() {
+                return (ea, eb);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        (None, None)
+    }
+
+    #[test]
+    fn connect_send_and_close() {
+        let mut server = WsTransport::new(1.into(), WsConfig::default());
+        server
+            .listen("127.0.0.1:0".parse().expect("valid addr"))
+            .expect("listen");
+        let url = format!("ws://{}", server.local_addr().expect("addr"));
+        let mut client = WsTransport::new(2.into(), WsConfig::default());
+        client.connect(0, url).expect("connect");
+
+        let mut accepted = None;
+        let mut connected = None;
+        while accepted.is_none() || connected.is_none() {
+            let (server_event, client_event) = next_event(&mut server, &mut client);
+            assert!(
+                server_event.is_some() || client_event.is_some(),
+                "should connect"
+            );
+            if let Some(TransportEvent::Accepted(conn)) = server_event {
+                accepted = Some(conn);
+            }
+            if let Some(TransportEvent::Connected(conn)) = client_event {
+                connected = Some(conn);
+            }
+        }
+        let (accepted, connected) = (accepted.expect("accepted"), connected.expect("connected"));
+        assert_eq!(accepted.node(), NodeId::from(2));
+        assert_eq!(connected.node(), NodeId::from(1));
+        assert_eq!(accepted.session(), connected.session());
+
+        client.send(connected, &[1, 2, 3]);
+        match next_event(&mut server, &mut client) {
+            (Some(TransportEvent::Recv(pkt)), _) => {
+                assert_eq!(pkt.conn, accepted);
+                assert_eq!(pkt.data, vec![1, 2, 3]);
+            }
+            _ => panic!("should receive"),
+        }
+
+        client.close(connected);
+        assert!(matches!(
+            client.pop_event(0),
+            Some(TransportEvent::Disconnected(_))
+        ));
+        match next_event(&mut server, &mut client) {
+            (Some(TransportEvent::Disconnected(conn)), _) => assert_eq!(conn, accepted),
+            _ => panic!("should disconnect"),
+        }
+    }
+
+    #[test]
+    fn failed_connect_closes_socket() {
+        // nothing listens on the port of a dropped listener, so the connect is refused
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .expect("addr");
+        let mut client = WsTransport::new(2.into(), WsConfig::default());
+        client
+            .connect(0, format!("ws://{}", addr))
+            .expect("connect");
+        assert_eq!(client.sockets.len(), 1);
+        for _ in 0..500 {
+            assert!(client.pop_event(0).is_none());
+            if client.sockets.is_empty() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        assert!(client.sockets.is_empty());
+    }
+
+    #[test]
+    fn wss_is_not_supported() {
+        let mut client = WsTransport::new(2.into(), WsConfig::default());
+        assert!(client.connect(0, "wss://127.0.0.1:1".to_string()).is_err());
+        assert!(client.sockets.is_empty());
+    }
+}
@@ -1,23 +1,23 @@
 use std::ops::Deref;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct NodeId(u32);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(u64);
 
-impl From<u32> for NodeId {
-    fn from(value: u32) -> Self {
+impl From<u64> for NodeId {
+    fn from(value: u64) -> Self {
         Self(value)
     }
 }
 
 impl Deref for NodeId {
-    type Target = u32;
+    type Target = u64;
 
     fn deref(&self) -> &Self::Target {
         &self.0
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct ChannelId(u32);
 
 impl From<u32> for ChannelId {
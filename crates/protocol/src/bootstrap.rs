@@ -0,0 +1,60 @@
+#[derive(Debug, Clone)]
+pub struct BootstrapConfig {
+    /// Delay before the next seed is tried, it doubles after each attempt which didn't join
+    pub retry_min_ms: u64,
+    pub retry_max_ms: u64,
+}
+
+impl Default for BootstrapConfig {
+    fn default() -> Self {
+        Self {
+            retry_min_ms: 1000,
+            retry_max_ms: 30_000,
+        }
+    }
+}
+
+/// Join procedure state of a node: seeds are tried one after the other with exponential backoff,
+/// until one of them answers the join request. The seeds are transport addresses, so it is owned by the driver.
+pub struct Bootstrap<A> {
+    seeds: Vec<A>,
+    config: BootstrapConfig,
+    next_seed: usize,
+    next_attempt_ms: u64,
+    backoff_ms: u64,
+}
+
+impl<A: Clone> Bootstrap<A> {
+    pub fn new(seeds: Vec<A>, config: BootstrapConfig) -> Self {
+        Self {
+            seeds,
+            backoff_ms: config.retry_min_ms,
+            config,
+            next_seed: 0,
+            next_attempt_ms: 0,
+        }
+    }
+
+    /// Seed which must be connected now, if the node is not joined yet
+    pub fn next_seed(&mut self, now_ms: u64) -> Option<A> {
+        if self.seeds.is_empty() || now_ms < self.next_attempt_ms {
+            return None;
+        }
+        let seed = self.seeds[self.next_seed % self.seeds.len()].clone();
+        self.next_seed = (self.next_seed + 1) % self.seeds.len();
+        self.next_attempt_ms = now_ms + self.backoff_ms;
+        self.backoff_ms = (self.backoff_ms * 2).min(self.config.retry_max_ms);
+        Some(seed)
+    }
+
+    /// After a join, a later rejoin starts again with the shortest delay
+    pub fn on_joined(&mut self) {
+        self.backoff_ms = self.config.retry_min_ms;
+    }
+
+    /// The node lost all its connections, the seeds are tried again from the next tick
+    pub fn on_left(&mut self) {
+        self.backoff_ms = self.config.retry_min_ms;
+        self.next_attempt_ms = 0;
+    }
+}
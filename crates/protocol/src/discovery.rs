@@ -0,0 +1,587 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use crate::{addr::NodeId, network::ConnectionStats};
+
+use self::{
+    neighbour::{NeighbourKind, NeighbourState},
+    table::KBuckets,
+};
+
+mod neighbour;
+mod table;
+
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    /// Number of neighbours the manager keeps connections to by itself
+    pub target_degree: usize,
+    /// Part of the target degree which is used for random long links, the rest are low latency neighbours
+    pub long_links: usize,
+    /// Above this number of neighbours, which can happen with incoming connections, the least useful ones are closed
+    pub max_degree: usize,
+    /// Kademlia k: nodes per bucket of the table, and nodes returned by a lookup query
+    pub bucket_size: usize,
+    /// Kademlia alpha: queries in flight per lookup
+    pub lookup_parallelism: usize,
+    /// Interval of the lookups which refresh the table
+    pub lookup_interval_ms: u64,
+    /// Queried node which doesn't answer within this timeout is removed from the table
+    pub query_timeout_ms: u64,
+    /// Node which is not connected within this timeout is removed from the table
+    pub connect_timeout_ms: u64,
+    /// Node which failed to connect or was disconnected is not selected as neighbour again within this time
+    pub retry_backoff_ms: u64,
+    /// Interval between two probes of a candidate which may be closer than the current neighbours
+    pub probe_interval_ms: u64,
+    /// A probe is measured for this time before it is compared with the worst local neighbour
+    pub probe_duration_ms: u64,
+    /// A probe replaces the worst local neighbour only if its score is better by this percent
+    pub swap_margin_percent: u32,
+    /// Stats above these limits are poor
+    pub poor_rtt_ms: u32,
+    pub poor_loss_percent: f32,
+    /// Neighbour with poor stats for this many consecutive ticks is replaced
+    pub poor_ticks: u32,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            target_degree: 8,
+            long_links: 2,
+            max_degree: 16,
+            bucket_size: 8,
+            lookup_parallelism: 3,
+            lookup_interval_ms: 10_000,
+            query_timeout_ms: 2000,
+            connect_timeout_ms: 10_000,
+            retry_backoff_ms: 30_000,
+            probe_interval_ms: 10_000,
+            probe_duration_ms: 3000,
+            swap_margin_percent: 20,
+            poor_rtt_ms: 1000,
+            poor_loss_percent: 20.0,
+            poor_ticks: 5,
+        }
+    }
+}
+
+pub enum OutputEvent {
+    /// Intent to connect to a node, the host must resolve it to a transport address
+    Connect(NodeId),
+    /// Intent to close all connections to a neighbour
+    Disconnect(NodeId),
+    FindNode {
+        to: NodeId,
+        lookup: u32,
+        target: NodeId,
+    },
+    FindNodeReply {
+        to: NodeId,
+        lookup: u32,
+        nodes: Vec<NodeId>,
+    },
+}
+
+/// Iterative Kademlia lookup: the closest known nodes to the target are queried for closer ones
+/// until all of the closest nodes found so far have answered or timed out
+struct Lookup {
+    target: NodeId,
+    /// Closest nodes found so far, closest first
+    shortlist: Vec<NodeId>,
+    queried: BTreeSet<NodeId>,
+    /// Queried nodes with the time of the query
+    inflight: BTreeMap<NodeId, u64>,
+}
+
+/// Membership layer: it keeps a Kademlia table of known nodes, which is filled by lookups over the overlay,
+/// and maintains the target degree of neighbours chosen from the table.
+/// Most neighbours are the nodes with the lowest latency, which is estimated from the node routes and measured
+/// by probing candidates, and a few are random long links, which gives a small-world topology.
+/// Neighbours with consistently poor stats are replaced.
+/// Connects and disconnects are only intents, the runner forwards them to the host.
+pub struct NeighbourManager {
+    node: NodeId,
+    config: DiscoveryConfig,
+    table: KBuckets,
+    neighbours: BTreeMap<NodeId, NeighbourState>,
+    /// Nodes which the manager connects to, with the connect deadline
+    connecting: BTreeMap<NodeId, (u64, NeighbourKind)>,
+    /// Route score of known nodes, which estimates their latency before they are connected
+    estimates: BTreeMap<NodeId, u32>,
+    /// Neighbours which the manager disconnects from
+    closing: BTreeSet<NodeId>,
+    /// Nodes which must not be selected until the time
+    backoff: BTreeMap<NodeId, u64>,
+    lookups: BTreeMap<u32, Lookup>,
+    next_lookup: u32,
+    last_lookup_ms: Option<u64>,
+    /// Bucket whose range is refreshed by the next periodic lookup
+    refresh_bucket: usize,
+    last_probe_ms: u64,
+    /// Xorshift state for choosing long links, seeded by the node id so that simulations are reproducible
+    rng: u32,
+    outputs: VecDeque<OutputEvent>,
+}
+
+impl NeighbourManager {
+    pub fn new(node: NodeId, config: DiscoveryConfig) -> Self {
+        Self {
+            node,
+            table: KBuckets::new(node, config.bucket_size),
+            config,
+            neighbours: BTreeMap::new(),
+            connecting: BTreeMap::new(),
+            estimates: BTreeMap::new(),
+            closing: BTreeSet::new(),
+            backoff: BTreeMap::new(),
+            lookups: BTreeMap::new(),
+            next_lookup: 0,
+            last_lookup_ms: None,
+            refresh_bucket: 0,
+            last_probe_ms: 0,
+            rng: (*node as u32) | 1,
+            outputs: VecDeque::new(),
+        }
+    }
+
+    /// Number of nodes in the Kademlia table
+    pub fn known_nodes(&self) -> usize {
+        self.table.len()
+    }
+
+    pub fn neighbours(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.neighbours.keys().copied()
+    }
+
+    /// Known nodes which are closest to the target by XOR distance
+    pub fn closest(&self, target: NodeId, count: usize) -> Vec<NodeId> {
+        self.table.closest(target, count)
+    }
+
+    /// Add a node which is known to exist
+    pub fn learn(&mut self, node: NodeId) {
+        if node != self.node {
+            self.table.insert(node);
+        }
+    }
+
+    /// Replace the latency estimates with the current node routes, their origins are also learned
+    pub fn on_routes(&mut self, routes: impl Iterator<Item = (NodeId, u32)>) {
+        self.estimates.clear();
+        // routes come from a hash map, sorting them keeps the table order reproducible
+        let mut routes = routes.collect::<Vec<_>>();
+        routes.sort();
+        for (node, score) in routes {
+            self.learn(node);
+            self.estimates.insert(node, score);
+        }
+    }
+
+    pub fn on_tick(&mut self, now_ms: u64) {
+        let expired = self
+            .connecting
+            .iter()
+            .filter(|(_, (deadline, _))| now_ms >= *deadline)
+            .map(|(node, _)| *node)
+            .collect::<Vec<_>>();
+        for node in expired {
+            log::info!("Connect to {:?} timeout", node);
+            self.connecting.remove(&node);
+            self.table.remove(node);
+            self.backoff
+                .insert(node, now_ms + self.config.retry_backoff_ms);
+        }
+        self.backoff.retain(|_, until| now_ms < *until);
+
+        let ids = self.lookups.keys().copied().collect::<Vec<_>>();
+        for id in ids {
+            let timeouts = self.lookups[&id]
+                .inflight
+                .iter()
+                .filter(|(_, sent_ms)| now_ms >= **sent_ms + self.config.query_timeout_ms)
+                .map(|(node, _)| *node)
+                .collect::<Vec<_>>();
+            for node in timeouts.iter() {
+                log::debug!("Lookup query to {:?} timeout", node);
+                self.table.remove(*node);
+            }
+            if let Some(lookup) = self.lookups.get_mut(&id) {
+                for node in timeouts {
+                    lookup.inflight.remove(&node);
+                }
+            }
+            self.step_lookup(now_ms, id);
+        }
+
+        if self
+            .last_lookup_ms
+            .is_none_or(|last| now_ms >= last + self.config.lookup_interval_ms)
+        {
+            self.last_lookup_ms = Some(now_ms);
+            // the own id finds the closest nodes, the rotating bucket keeps far parts of the table fresh
+            self.start_lookup(now_ms, self.node);
+            let target = *self.node ^ (1 << self.refresh_bucket);
+            self.refresh_bucket = (self.refresh_bucket + 1) % u64::BITS as usize;
+            self.start_lookup(now_ms, target.into());
+        }
+
+        self.replace_poor();
+        self.evaluate_probes(now_ms);
+        self.maintain_degree(now_ms);
+        self.start_probe(now_ms);
+    }
+
+    /// Neighbours which the manager didn't connect to are local until they are compared with probes.
+    /// The first stats of the connection let a new neighbour be compared before the degree is maintained.
+    pub fn on_connected(&mut self, now_ms: u64, node: NodeId, stats: Option<ConnectionStats>) {
+        let kind = self.connecting.remove(&node).map(|(_, kind)| kind);
+        let neighbour = self.neighbours.entry(node).or_insert_with(|| {
+            let kind = kind.unwrap_or(NeighbourKind::Local);
+            NeighbourState::new(now_ms, kind)
+        });
+        neighbour.conns += 1;
+        if let Some(stats) = stats {
+            neighbour.on_stats(stats);
+        }
+        self.learn(node);
+        self.maintain_degree(now_ms);
+    }
+
+    pub fn on_stats(&mut self, node: NodeId, stats: ConnectionStats) {
+        if let Some(neighbour) = self.neighbours.get_mut(&node) {
+            neighbour.on_stats(stats);
+        }
+    }
+
+    /// A neighbour which lost its last connection is replaced on the next tick
+    pub fn on_disconnected(&mut self, now_ms: u64, node: NodeId) {
+        let neighbour = if let Some(neighbour) = self.neighbours.get_mut(&node) {
+            neighbour
+        } else {
+            return;
+        };
+        neighbour.conns -= 1;
+        if neighbour.conns == 0 {
+            self.neighbours.remove(&node);
+            self.closing.remove(&node);
+            self.backoff
+                .insert(node, now_ms + self.config.retry_backoff_ms);
+        }
+    }
+
+    pub fn on_find_node(&mut self, from: NodeId, lookup: u32, target: NodeId) {
+        self.learn(from);
+        let nodes = self
+            .table
+            .closest(target, self.config.bucket_size + 1)
+            .into_iter()
+            .filter(|n| *n != from)
+            .take(self.config.bucket_size)
+            .collect();
+        self.outputs.push_back(OutputEvent::FindNodeReply {
+            to: from,
+            lookup,
+            nodes,
+        });
+    }
+
+    pub fn on_find_node_reply(
+        &mut self,
+        now_ms: u64,
+        from: NodeId,
+        lookup: u32,
+        nodes: Vec<NodeId>,
+    ) {
+        self.learn(from);
+        for node in nodes.iter() {
+            self.learn(*node);
+        }
+        let lookup_state = match self.lookups.get_mut(&lookup) {
+            Some(l) => l,
+            None => return,
+        };
+        // late replies of timed out queries only fill the table
+        if lookup_state.inflight.remove(&from).is_none() {
+            return;
+        }
+        let target = lookup_state.target;
+        for node in nodes {
+            if node != self.node && !lookup_state.shortlist.contains(&node) {
+                lookup_state.shortlist.push(node);
+            }
+        }
+        lookup_state.shortlist.sort_by_key(|n| **n ^ *target);
+        lookup_state.shortlist.truncate(self.config.bucket_size);
+        self.step_lookup(now_ms, lookup);
+    }
+
+    /// A query which can't be sent, because there is no route to the node yet
+    pub fn on_query_failed(&mut self, now_ms: u64, lookup: u32, to: NodeId) {
+        if let Some(l) = self.lookups.get_mut(&lookup) {
+            l.inflight.remove(&to);
+        }
+        self.step_lookup(now_ms, lookup);
+    }
+
+    pub fn pop_output(&mut self) -> Option<OutputEvent> {
+        self.outputs.pop_front()
+    }
+
+    fn start_lookup(&mut self, now_ms: u64, target: NodeId) {
+        let id = self.next_lookup;
+        self.next_lookup = self.next_lookup.wrapping_add(1);
+        self.lookups.insert(
+            id,
+            Lookup {
+                target,
+                shortlist: self.table.closest(target, self.config.bucket_size),
+                queried: BTreeSet::new(),
+                inflight: BTreeMap::new(),
+            },
+        );
+        self.step_lookup(now_ms, id);
+    }
+
+    /// Query the closest nodes which are not queried yet, the lookup ends when nothing is left to wait for
+    fn step_lookup(&mut self, now_ms: u64, id: u32) {
+        let lookup = if let Some(lookup) = self.lookups.get_mut(&id) {
+            lookup
+        } else {
+            return;
+        };
+        while lookup.inflight.len() < self.config.lookup_parallelism {
+            let next = lookup
+                .shortlist
+                .iter()
+                .find(|n| !lookup.queried.contains(*n))
+                .copied();
+            let node = if let Some(node) = next {
+                node
+            } else {
+                break;
+            };
+            lookup.queried.insert(node);
+            lookup.inflight.insert(node, now_ms);
+            self.outputs.push_back(OutputEvent::FindNode {
+                to: node,
+                lookup: id,
+                target: lookup.target,
+            });
+        }
+        if lookup.inflight.is_empty() {
+            log::debug!(
+                "Lookup {:?} done, {} known nodes",
+                lookup.target,
+                self.table.len()
+            );
+            self.lookups.remove(&id);
+        }
+    }
+
+    /// Connect to new neighbours below the target degree, and close the worst local ones above the max degree
+    fn maintain_degree(&mut self, now_ms: u64) {
+        let local_target = self
+            .config
+            .target_degree
+            .saturating_sub(self.config.long_links);
+        while self.count(NeighbourKind::Local) < local_target {
+            match self.select_local() {
+                Some(node) => self.connect(now_ms, node, NeighbourKind::Local),
+                None => break,
+            }
+        }
+        while self.count(NeighbourKind::Long) < self.config.long_links {
+            match self.select_long() {
+                Some(node) => self.connect(now_ms, node, NeighbourKind::Long),
+                None => break,
+            }
+        }
+
+        // new neighbours may be probes of other nodes, they are only counted after the probe duration
+        let connected_before_ms = now_ms.saturating_sub(self.config.probe_duration_ms);
+        while self.count_connected_before(connected_before_ms) > self.config.max_degree {
+            match self.worst_local(connected_before_ms) {
+                Some((node, _)) => self.close(node, "redundant"),
+                None => break,
+            }
+        }
+    }
+
+    fn replace_poor(&mut self) {
+        let mut poor = vec![];
+        for (node, neighbour) in self.neighbours.iter_mut() {
+            let ticks = neighbour.on_tick(self.config.poor_rtt_ms, self.config.poor_loss_percent);
+            if ticks >= self.config.poor_ticks && !self.closing.contains(node) {
+                poor.push(*node);
+            }
+        }
+        for node in poor {
+            self.close(node, "poor");
+        }
+    }
+
+    /// A probe which was measured long enough replaces the worst local neighbour if it is clearly better
+    fn evaluate_probes(&mut self, now_ms: u64) {
+        let probes = self
+            .neighbours
+            .iter()
+            .filter(|(node, n)| {
+                n.kind == NeighbourKind::Probe
+                    && !self.closing.contains(*node)
+                    && now_ms >= n.connected_ms + self.config.probe_duration_ms
+            })
+            .map(|(node, n)| (*node, n.score()))
+            .collect::<Vec<_>>();
+        for (probe, score) in probes {
+            let worst = self.worst_local(u64::MAX);
+            let better = match (score, worst) {
+                (Some(score), Some((_, Some(worst)))) => {
+                    (score as u64) * 100
+                        < (worst as u64) * (100 - self.config.swap_margin_percent as u64)
+                }
+                (Some(_), Some((_, None))) => true,
+                _ => false,
+            };
+            if better {
+                log::debug!(
+                    "Probe {:?} with score {:?} replaces {:?}",
+                    probe,
+                    score,
+                    worst
+                );
+                if let Some(neighbour) = self.neighbours.get_mut(&probe) {
+                    neighbour.kind = NeighbourKind::Local;
+                }
+                if let Some((worst, _)) = worst {
+                    self.close(worst, "replaced by probe");
+                }
+            } else {
+                self.close(probe, "probe is not better");
+            }
+        }
+    }
+
+    /// Connect to the best local candidate beside the current neighbours, only one probe runs at a time
+    fn start_probe(&mut self, now_ms: u64) {
+        let local_target = self
+            .config
+            .target_degree
+            .saturating_sub(self.config.long_links);
+        if now_ms < self.last_probe_ms + self.config.probe_interval_ms
+            || self.count(NeighbourKind::Probe) > 0
+            || self.count(NeighbourKind::Local) < local_target
+        {
+            return;
+        }
+        self.last_probe_ms = now_ms;
+        if let Some(node) = self.select_local() {
+            self.connect(now_ms, node, NeighbourKind::Probe);
+        }
+    }
+
+    fn connect(&mut self, now_ms: u64, node: NodeId, kind: NeighbourKind) {
+        log::debug!("Connect to {:?} as {:?} neighbour", node, kind);
+        self.connecting
+            .insert(node, (now_ms + self.config.connect_timeout_ms, kind));
+        self.outputs.push_back(OutputEvent::Connect(node));
+    }
+
+    fn close(&mut self, node: NodeId, reason: &str) {
+        log::debug!("Disconnect {} neighbour {:?}", reason, node);
+        self.closing.insert(node);
+        self.outputs.push_back(OutputEvent::Disconnect(node));
+    }
+
+    /// Neighbours and pending connects of a kind, closing neighbours are not counted
+    fn count(&self, kind: NeighbourKind) -> usize {
+        let neighbours = self
+            .neighbours
+            .iter()
+            .filter(|(node, n)| n.kind == kind && !self.closing.contains(*node))
+            .count();
+        neighbours + self.connecting.values().filter(|(_, k)| *k == kind).count()
+    }
+
+    /// Neighbours which connected before the time and are not closing
+    fn count_connected_before(&self, connected_before_ms: u64) -> usize {
+        self.neighbours
+            .iter()
+            .filter(|(node, n)| {
+                n.connected_ms <= connected_before_ms && !self.closing.contains(*node)
+            })
+            .count()
+    }
+
+    fn is_candidate(&self, node: &NodeId) -> bool {
+        !self.neighbours.contains_key(node)
+            && !self.connecting.contains_key(node)
+            && !self.backoff.contains_key(node)
+    }
+
+    /// Neighbours of each bucket which are not closing
+    fn bucket_degrees(&self) -> Vec<usize> {
+        let mut degrees = vec![0; u64::BITS as usize];
+        for node in self.neighbours.keys() {
+            if let Some(b) = self.table.bucket_of(*node) {
+                if !self.closing.contains(node) {
+                    degrees[b] += 1;
+                }
+            }
+        }
+        degrees
+    }
+
+    /// Candidate with the lowest estimated latency, nodes without a route are taken from the bucket
+    /// with the fewest neighbours, closer buckets and older nodes first
+    fn select_local(&self) -> Option<NodeId> {
+        let degrees = self.bucket_degrees();
+        (0..degrees.len())
+            .flat_map(|b| {
+                self.table
+                    .bucket(b)
+                    .iter()
+                    .enumerate()
+                    .map(move |(i, n)| (b, i, *n))
+            })
+            .filter(|(_, _, n)| self.is_candidate(n))
+            .min_by_key(|(b, i, n)| {
+                let estimate = self.estimates.get(n).copied().unwrap_or(u32::MAX);
+                (estimate, degrees[*b], *b, *i)
+            })
+            .map(|(_, _, node)| node)
+    }
+
+    /// Random candidate of the whole table, most of the id space is far away so most long links are far
+    fn select_long(&mut self) -> Option<NodeId> {
+        let candidates = (0..u64::BITS as usize)
+            .flat_map(|b| self.table.bucket(b).iter().copied())
+            .filter(|n| self.is_candidate(n))
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return None;
+        }
+        // xorshift32
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        Some(candidates[self.rng as usize % candidates.len()])
+    }
+
+    /// Local neighbour with the worst score which connected before the time, neighbours without stats yet are the worst.
+    /// Between equal scores, the one in the bucket with the most neighbours is the most redundant.
+    fn worst_local(&self, connected_before_ms: u64) -> Option<(NodeId, Option<u32>)> {
+        let degrees = self.bucket_degrees();
+        self.neighbours
+            .iter()
+            .filter(|(node, n)| {
+                n.kind == NeighbourKind::Local
+                    && !self.closing.contains(*node)
+                    && n.connected_ms <= connected_before_ms
+            })
+            .max_by_key(|(node, n)| {
+                let bucket = self.table.bucket_of(**node).map(|b| degrees[b]);
+                (n.score().is_none(), n.score(), bucket, **node)
+            })
+            .map(|(node, n)| (*node, n.score()))
+    }
+}
@@ -0,0 +1,53 @@
+use crate::{network::ConnectionStats, router::metric::Metric};
+
+/// Why a neighbour is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NeighbourKind {
+    /// Chosen for its low latency, incoming neighbours are also local until they are compared
+    Local,
+    /// Random far node, which keeps the overlay diameter small
+    Long,
+    /// Candidate which is measured before it can replace the worst local neighbour
+    Probe,
+}
+
+pub struct NeighbourState {
+    pub kind: NeighbourKind,
+    pub conns: usize,
+    pub connected_ms: u64,
+    stats: Option<ConnectionStats>,
+    /// Consecutive ticks with poor stats
+    poor_ticks: u32,
+}
+
+impl NeighbourState {
+    pub fn new(now_ms: u64, kind: NeighbourKind) -> Self {
+        Self {
+            kind,
+            conns: 0,
+            connected_ms: now_ms,
+            stats: None,
+            poor_ticks: 0,
+        }
+    }
+
+    pub fn on_stats(&mut self, stats: ConnectionStats) {
+        self.stats = Some(stats);
+    }
+
+    /// Score of the direct connection, in the same unit as route scores
+    pub fn score(&self) -> Option<u32> {
+        self.stats
+            .as_ref()
+            .map(|stats| Metric::local().add_local(stats).score())
+    }
+
+    /// Count the ticks with poor stats, returns the number of consecutive ones
+    pub fn on_tick(&mut self, poor_rtt_ms: u32, poor_loss_percent: f32) -> u32 {
+        let poor = self.stats.as_ref().is_some_and(|stats| {
+            stats.rtt_ms > poor_rtt_ms || f32::from(stats.lost_percent) > poor_loss_percent
+        });
+        self.poor_ticks = if poor { self.poor_ticks + 1 } else { 0 };
+        self.poor_ticks
+    }
+}
@@ -0,0 +1,69 @@
+use crate::addr::NodeId;
+
+const BUCKETS: usize = u64::BITS as usize;
+
+/// Kademlia routing table: bucket `i` holds nodes whose XOR distance to this node has its highest bit at `i`,
+/// so the table knows many close nodes and a few far ones in every part of the id space.
+pub struct KBuckets {
+    node: NodeId,
+    bucket_size: usize,
+    /// Each bucket is ordered from the least to the most recently seen node
+    buckets: Vec<Vec<NodeId>>,
+}
+
+impl KBuckets {
+    pub fn new(node: NodeId, bucket_size: usize) -> Self {
+        Self {
+            node,
+            bucket_size,
+            buckets: vec![vec![]; BUCKETS],
+        }
+    }
+
+    /// Bucket of a node, None for this node itself
+    pub fn bucket_of(&self, node: NodeId) -> Option<usize> {
+        let distance = *self.node ^ *node;
+        (distance != 0).then(|| (u64::BITS - 1 - distance.leading_zeros()) as usize)
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.len()).sum()
+    }
+
+    /// Mark a node as seen, returns false if its bucket is full.
+    /// Like Kademlia, long known nodes are kept over new ones, they are only replaced after they are removed.
+    pub fn insert(&mut self, node: NodeId) -> bool {
+        let bucket = match self.bucket_of(node) {
+            Some(b) => &mut self.buckets[b],
+            None => return false,
+        };
+        if let Some(pos) = bucket.iter().position(|n| *n == node) {
+            bucket.remove(pos);
+            bucket.push(node);
+            true
+        } else if bucket.len() < self.bucket_size {
+            bucket.push(node);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn remove(&mut self, node: NodeId) {
+        if let Some(b) = self.bucket_of(node) {
+            self.buckets[b].retain(|n| *n != node);
+        }
+    }
+
+    pub fn bucket(&self, index: usize) -> &[NodeId] {
+        &self.buckets[index]
+    }
+
+    /// Known nodes which are closest to the target by XOR distance, closest first
+    pub fn closest(&self, target: NodeId, count: usize) -> Vec<NodeId> {
+        let mut nodes = self.buckets.iter().flatten().copied().collect::<Vec<_>>();
+        nodes.sort_by_key(|n| **n ^ *target);
+        nodes.truncate(count);
+        nodes
+    }
+}
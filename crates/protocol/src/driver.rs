@@ -0,0 +1,658 @@
+use std::collections::{HashSet, VecDeque};
+
+use prost::Message;
+
+use crate::{
+    addr::NodeId,
+    bootstrap::{Bootstrap, BootstrapConfig},
+    network::{Connection, NetworkMsg},
+    protocol::{network_message::MessageType, NetworkMessage},
+    runner::{InputEvent, OutputEvent, P2pStreamRunner},
+    signalling::{Signal, SignallingChannel},
+    transport::{Transport, TransportEvent},
+};
+
+/// Glue between a runner and a transport: it encodes runner messages to protobuf and sends them over the transport,
+/// decodes received data back to runner events, and reports disconnects and connection stats.
+/// Signals of the transport are relayed over the overlay, or over the signalling channel for nodes without a route.
+/// Until the runner joined, seeds are connected with backoff and every outgoing connection asks for a join.
+/// Connections which fail the handshake and disconnect intents are closed,
+/// connect intents must be resolved to an address by the host.
+/// Runner outputs which are not network messages or signals are forwarded to the host with `pop_output`.
+/// The time of the host is unix time in milliseconds, since channel announcements expire in it on every node.
+pub struct P2pStreamDriver<T: Transport> {
+    runner: P2pStreamRunner,
+    transport: T,
+    signalling: Option<Box<dyn SignallingChannel>>,
+    bootstrap: Option<Bootstrap<T::Addr>>,
+    conns: HashSet<Connection>,
+    signals: VecDeque<(NodeId, Signal)>,
+    outputs: VecDeque<OutputEvent>,
+}
+
+impl<T: Transport> P2pStreamDriver<T> {
+    pub fn new(runner: P2pStreamRunner, transport: T) -> Self {
+        Self {
+            runner,
+            transport,
+            signalling: None,
+            bootstrap: None,
+            conns: HashSet::new(),
+            signals: VecDeque::new(),
+            outputs: VecDeque::new(),
+        }
+    }
+
+    pub fn runner(&self) -> &P2pStreamRunner {
+        &self.runner
+    }
+
+    pub fn runner_mut(&mut self) -> &mut P2pStreamRunner {
+        &mut self.runner
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Use a signalling channel for the nodes which can't be reached over the overlay yet
+    pub fn set_signalling(&mut self, signalling: Box<dyn SignallingChannel>) {
+        self.signalling = Some(signalling);
+    }
+
+    /// Join the network over one of the seeds, they are tried in order from the next tick until one answers
+    pub fn join(&mut self, seeds: Vec<T::Addr>, config: BootstrapConfig) {
+        self.bootstrap = Some(Bootstrap::new(seeds, config));
+    }
+
+    pub fn connect(&mut self, now_ms: u64, addr: T::Addr) -> Result<(), T::Error> {
+        self.transport.connect(now_ms, addr)
+    }
+
+    /// Must be called periodically, it feeds connection stats to the runner before ticking it
+    pub fn on_tick(&mut self, now_ms: u64) {
+        self.poll(now_ms);
+        if let Some(bootstrap) = self.bootstrap.as_mut() {
+            if !self.runner.is_joined() {
+                if let Some(seed) = bootstrap.next_seed(now_ms) {
+                    log::info!("Join over seed {:?}", seed);
+                    if let Err(e) = self.transport.connect(now_ms, seed) {
+                        log::warn!("Connect seed error {:?}", e);
+                    }
+                }
+            }
+        }
+        for conn in self.conns.iter() {
+            if let Some(stats) = self.transport.stats(*conn) {
+                self.runner.on_msg(
+                    now_ms,
+                    InputEvent::Stats(NetworkMsg {
+                        conn: *conn,
+                        msg: stats,
+                    }),
+                );
+            }
+        }
+        self.runner.on_tick(now_ms);
+        self.flush();
+    }
+
+    /// Process all pending transport events, should be called whenever the transport may have new events
+    pub fn poll(&mut self, now_ms: u64) {
+        while let Some(event) = self.transport.pop_event(now_ms) {
+            match event {
+                TransportEvent::Connected(conn) | TransportEvent::Accepted(conn) => {
+                    self.conns.insert(conn);
+                    let outgoing = matches!(event, TransportEvent::Connected(_));
+                    let input = if outgoing {
+                        InputEvent::ConnectionConnected(conn)
+                    } else {
+                        InputEvent::ConnectionAccepted(conn)
+                    };
+                    self.runner.on_msg(now_ms, input);
+                    if let Some(stats) = self.transport.stats(conn) {
+                        self.runner
+                            .on_msg(now_ms, InputEvent::Stats(NetworkMsg { conn, msg: stats }));
+                    }
+                    if outgoing && self.bootstrap.is_some() && !self.runner.is_joined() {
+                        self.runner.request_join(conn);
+                    }
+                }
+                TransportEvent::Recv(pkt) => match NetworkMessage::decode(pkt.data.as_slice()) {
+                    Ok(NetworkMessage {
+                        message_type: Some(msg),
+                    }) => {
+                        self.runner.on_msg(
+                            now_ms,
+                            InputEvent::ConnectionRecv(NetworkMsg {
+                                conn: pkt.conn,
+                                msg,
+                            }),
+                        );
+                    }
+                    Ok(_) => {
+                        log::warn!("Empty message from {:?}", pkt.conn);
+                    }
+                    Err(e) => {
+                        log::warn!("Invalid message from {:?}: {:?}", pkt.conn, e);
+                    }
+                },
+                TransportEvent::Disconnected(conn) => {
+                    self.conns.remove(&conn);
+                    self.runner
+                        .on_msg(now_ms, InputEvent::ConnectionDisconnected(conn));
+                }
+            }
+            self.flush();
+        }
+        self.poll_signals(now_ms);
+    }
+
+    pub fn pop_output(&mut self) -> Option<OutputEvent> {
+        self.flush();
+        self.outputs.pop_front()
+    }
+
+    fn poll_signals(&mut self, now_ms: u64) {
+        while let Some((to, signal)) = self.transport.pop_signal() {
+            match self.runner.send_signal(to, signal) {
+                Ok(()) => self.flush(),
+                Err(signal) => match self.signalling.as_mut() {
+                    Some(signalling) => signalling.send_signal(now_ms, to, signal),
+                    None => log::warn!("No route and no signalling channel to {:?}", to),
+                },
+            }
+        }
+        if let Some(signalling) = self.signalling.as_mut() {
+            while let Some(received) = signalling.pop_signal(now_ms) {
+                self.signals.push_back(received);
+            }
+        }
+        while let Some((from, signal)) = self.signals.pop_front() {
+            self.transport.on_signal(now_ms, from, signal);
+        }
+    }
+
+    /// Send all pending network messages of the runner, other outputs are kept for the host
+    fn flush(&mut self) {
+        while let Some(event) = self.runner.pop_output() {
+            match event {
+                OutputEvent::ConnectionSend(NetworkMsg { conn, msg }) => {
+                    let unreliable = matches!(msg, MessageType::ChannelData(_));
+                    let data = NetworkMessage {
+                        message_type: Some(msg),
+                    }
+                    .encode_to_vec();
+                    if unreliable {
+                        self.transport.send_unreliable(conn, &data);
+                    } else {
+                        self.transport.send(conn, &data);
+                    }
+                }
+                OutputEvent::OnSignal(from, signal) => self.signals.push_back((from, signal)),
+                OutputEvent::Disconnect(node) => {
+                    let conns = self
+                        .conns
+                        .iter()
+                        .filter(|c| c.node() == node)
+                        .copied()
+                        .collect::<Vec<_>>();
+                    for conn in conns {
+                        self.transport.close(conn);
+                    }
+                }
+                OutputEvent::Close(conn) => self.transport.close(conn),
+                OutputEvent::Joined => {
+                    if let Some(bootstrap) = self.bootstrap.as_mut() {
+                        bootstrap.on_joined();
+                    }
+                    self.outputs.push_back(OutputEvent::Joined);
+                }
+                OutputEvent::Left => {
+                    if let Some(bootstrap) = self.bootstrap.as_mut() {
+                        bootstrap.on_left();
+                    }
+                    self.outputs.push_back(OutputEvent::Left);
+                }
+                event => self.outputs.push_back(event),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{
+        addr::ChannelId,
+        handshake::{Capability, Feature},
+        identity::NodeKey,
+        limit::{LimitConfig, Rate},
+        network::{ConnectionStats, NetworkPkt},
+        protocol::{ChannelData, JoinResponse, NodeData},
+        pubsub::token::issue_token,
+        router::{RouterConfig, RoutingMode},
+        unicast,
+    };
+
+    use super::*;
+
+    /// Pending events of both ends of a loopback link
+    type Wire = Rc<RefCell<[VecDeque<TransportEvent>; 2]>>;
+
+    /// Transport with one connection, to the other end of the wire
+    struct Loopback {
+        side: usize,
+        nodes: [NodeId; 2],
+        wire: Wire,
+        closed: Vec<Connection>,
+    }
+
+    impl Loopback {
+        fn pair(a: NodeId, b: NodeId) -> (Self, Self) {
+            let wire = Wire::default();
+            let end = |side| Self {
+                side,
+                nodes: [a, b],
+                wire: wire.clone(),
+                closed: vec![],
+            };
+            (end(0), end(1))
+        }
+
+        /// Connection to the other end, as this end sees it
+        fn conn(&self) -> Connection {
+            Connection::from_parts(self.nodes[1 - self.side], 0)
+        }
+
+        /// Connection to this end, as the other end sees it
+        fn back(&self) -> Connection {
+            Connection::from_parts(self.nodes[self.side], 0)
+        }
+
+        fn push(&self, side: usize, event: TransportEvent) {
+            self.wire.borrow_mut()[side].push_back(event);
+        }
+
+        fn is_idle(&self) -> bool {
+            self.wire.borrow().iter().all(|events| events.is_empty())
+        }
+    }
+
+    impl Transport for Loopback {
+        type Addr = ();
+        type Error = ();
+
+        fn connect(&mut self, _now_ms: u64, _addr: ()) -> Result<(), ()> {
+            self.push(self.side, TransportEvent::Connected(self.conn()));
+            self.push(1 - self.side, TransportEvent::Accepted(self.back()));
+            Ok(())
+        }
+
+        fn send(&mut self, _conn: Connection, data: &[u8]) {
+            let pkt = NetworkPkt {
+                conn: self.back(),
+                data: data.to_vec(),
+            };
+            self.push(1 - self.side, TransportEvent::Recv(pkt));
+        }
+
+        fn close(&mut self, conn: Connection) {
+            self.closed.push(conn);
+            self.push(self.side, TransportEvent::Disconnected(conn));
+            self.push(1 - self.side, TransportEvent::Disconnected(self.back()));
+        }
+
+        fn stats(&self, _conn: Connection) -> Option<ConnectionStats> {
+            Some(ConnectionStats {
+                rtt_ms: 10,
+                lost_percent: 0.0.into(),
+                jitter_ms: 0,
+                bandwidth_kbps: 10_000,
+            })
+        }
+
+        fn pop_event(&mut self, _now_ms: u64) -> Option<TransportEvent> {
+            self.wire.borrow_mut()[self.side].pop_front()
+        }
+    }
+
+    fn drivers(b_config: RouterConfig) -> (P2pStreamDriver<Loopback>, P2pStreamDriver<Loopback>) {
+        let a = P2pStreamRunner::new(NodeKey::from_secret([1; 32]));
+        let b = P2pStreamRunner::new_with_config(NodeKey::from_secret([2; 32]), b_config)
+            .expect("valid router config");
+        let (ta, tb) = Loopback::pair(a.node(), b.node());
+        (P2pStreamDriver::new(a, ta), P2pStreamDriver::new(b, tb))
+    }
+
+    /// Poll both drivers until nothing is in flight, returns the channel data which the host of b got
+    fn settle(
+        a: &mut P2pStreamDriver<Loopback>,
+        b: &mut P2pStreamDriver<Loopback>,
+        now_ms: u64,
+    ) -> Vec<(ChannelId, Vec<u8>)> {
+        let mut received = vec![];
+        loop {
+            a.poll(now_ms);
+            while a.pop_output().is_some() {}
+            b.poll(now_ms);
+            while let Some(event) = b.pop_output() {
+                if let OutputEvent::OnChannelData(channel, data) = event {
+                    received.push((channel, data));
+                }
+            }
+            if a.transport().is_idle() {
+                return received;
+            }
+        }
+    }
+
+    /// Remove a feature from the hellos which are in flight to a side, as a node without the feature sends them
+    fn strip_feature(transport: &Loopback, side: usize, feature: Feature) {
+        for event in transport.wire.borrow_mut()[side].iter_mut() {
+            let TransportEvent::Recv(pkt) = event else {
+                continue;
+            };
+            let mut msg = NetworkMessage::decode(pkt.data.as_slice()).expect("message");
+            if let Some(MessageType::Hello(hello)) = msg.message_type.as_mut() {
+                hello.features.retain(|f| *f != feature as i32);
+                pkt.data = msg.encode_to_vec();
+            }
+        }
+    }
+
+    /// Frame which b receives from a without a signature or epoch, like a relay would inject it
+    fn inject_frame(b: &P2pStreamDriver<Loopback>, channel: ChannelId) {
+        let frame = NetworkMessage {
+            message_type: Some(MessageType::ChannelData(ChannelData {
+                channel: *channel,
+                data: b"injected".to_vec(),
+                ..Default::default()
+            })),
+        };
+        let pkt = NetworkPkt {
+            conn: b.transport().conn(),
+            data: frame.encode_to_vec(),
+        };
+        b.transport().push(1, TransportEvent::Recv(pkt));
+    }
+
+    fn connected() -> (P2pStreamDriver<Loopback>, P2pStreamDriver<Loopback>) {
+        let (mut a, mut b) = drivers(RouterConfig::default());
+        a.connect(0, ()).expect("connect");
+        settle(&mut a, &mut b, 0);
+        (a, b)
+    }
+
+    #[test]
+    fn messages_go_through_transport() {
+        let (mut a, mut b) = connected();
+        assert!(a.runner().peer(a.transport().conn()).is_some());
+        assert!(b.runner().peer(b.transport().conn()).is_some());
+
+        let channel = ChannelId::from(1);
+        a.runner_mut().add_channel(0, channel);
+        b.runner_mut().sub_channel(channel);
+        for now_ms in [1000, 2000] {
+            a.on_tick(now_ms);
+            b.on_tick(now_ms);
+            settle(&mut a, &mut b, now_ms);
+        }
+        a.runner_mut().pub_channel(channel, b"frame".to_vec());
+        let received = settle(&mut a, &mut b, 2000);
+        assert_eq!(received, vec![(channel, b"frame".to_vec())]);
+    }
+
+    #[test]
+    fn frames_go_only_to_peers_with_their_features() {
+        let (mut a, mut b) = drivers(RouterConfig::default());
+        a.connect(0, ()).expect("connect");
+        // b starts the handshake on the accepted connection before a reads anything
+        b.poll(0);
+        strip_feature(a.transport(), 0, Feature::DataAuth);
+        settle(&mut a, &mut b, 0);
+        let peer = a.runner().peer(a.transport().conn()).expect("peer");
+        assert!(!peer.features.contains(&Feature::DataAuth));
+        assert!(peer.features.contains(&Feature::Encryption));
+
+        let (plain, signed) = (ChannelId::from(1), ChannelId::from(2));
+        a.runner_mut().add_channel(0, plain);
+        a.runner_mut().add_channel(0, signed);
+        a.runner_mut().authenticate_channel(0, signed);
+        b.runner_mut().sub_channel(plain);
+        b.runner_mut().sub_channel(signed);
+        for now_ms in [1000, 2000] {
+            a.on_tick(now_ms);
+            b.on_tick(now_ms);
+            settle(&mut a, &mut b, now_ms);
+        }
+        a.runner_mut().pub_channel(plain, b"plain".to_vec());
+        a.runner_mut().pub_channel(signed, b"signed".to_vec());
+        let received = settle(&mut a, &mut b, 2000);
+        assert_eq!(received, vec![(plain, b"plain".to_vec())]);
+    }
+
+    #[test]
+    fn plaintext_frames_of_encrypted_channel_are_dropped() {
+        let (mut a, mut b) = connected();
+        let (plain, encrypted) = (ChannelId::from(1), ChannelId::from(2));
+        a.runner_mut().add_channel(0, plain);
+        a.runner_mut().add_channel(0, encrypted);
+        a.runner_mut().encrypt_channel(0, encrypted);
+        b.runner_mut().sub_channel(plain);
+        b.runner_mut().sub_channel(encrypted);
+        for now_ms in [1000, 2000] {
+            a.on_tick(now_ms);
+            b.on_tick(now_ms);
+            settle(&mut a, &mut b, now_ms);
+        }
+
+        // a relay in the middle would send frames without epoch in the name of the publisher
+        inject_frame(&b, plain);
+        inject_frame(&b, encrypted);
+        let received = settle(&mut a, &mut b, 2000);
+        assert_eq!(received, vec![(plain, b"injected".to_vec())]);
+    }
+
+    #[test]
+    fn only_frames_of_unsubscribed_channels_are_penalized() {
+        let (mut a, mut b) = connected();
+        b.runner_mut().set_limits(LimitConfig {
+            data: Rate {
+                per_sec: 1,
+                burst: 5,
+            },
+            ..Default::default()
+        });
+        let (subscribed, unsubscribed) = (ChannelId::from(1), ChannelId::from(2));
+        a.runner_mut().add_channel(0, subscribed);
+        b.runner_mut().sub_channel(subscribed);
+        for now_ms in [1000, 2000] {
+            a.on_tick(now_ms);
+            b.on_tick(now_ms);
+            settle(&mut a, &mut b, now_ms);
+        }
+        let publisher = a.runner().node();
+
+        // frames above the rate of a subscribed channel are dropped without a penalty
+        for _ in 0..100 {
+            inject_frame(&b, subscribed);
+        }
+        assert_eq!(settle(&mut a, &mut b, 2000).len(), 5);
+        assert!(!b.runner().is_banned(publisher));
+
+        for _ in 0..20 {
+            inject_frame(&b, unsubscribed);
+        }
+        assert!(settle(&mut a, &mut b, 2000).is_empty());
+        assert!(b.runner().is_banned(publisher));
+    }
+
+    #[test]
+    fn unsigned_frames_of_authenticated_channel_are_dropped_and_penalized() {
+        let (mut a, mut b) = connected();
+        let channel = ChannelId::from(1);
+        a.runner_mut().add_channel(0, channel);
+        a.runner_mut().authenticate_channel(0, channel);
+        b.runner_mut().sub_channel(channel);
+        for now_ms in [1000, 2000] {
+            a.on_tick(now_ms);
+            b.on_tick(now_ms);
+            settle(&mut a, &mut b, now_ms);
+        }
+
+        inject_frame(&b, channel);
+        assert!(settle(&mut a, &mut b, 2000).is_empty());
+        a.runner_mut().pub_channel(channel, b"signed".to_vec());
+        let received = settle(&mut a, &mut b, 2000);
+        assert_eq!(received, vec![(channel, b"signed".to_vec())]);
+
+        // frames are checked on receive, before they are relayed, and the sender of forged ones is banned
+        for _ in 0..4 {
+            inject_frame(&b, channel);
+        }
+        assert!(settle(&mut a, &mut b, 2000).is_empty());
+        assert!(b.runner().is_banned(a.runner().node()));
+    }
+
+    #[test]
+    fn node_data_in_the_name_of_another_node_is_dropped() {
+        let (mut a, mut b) = connected();
+        for now_ms in [1000, 2000] {
+            a.on_tick(now_ms);
+            b.on_tick(now_ms);
+            settle(&mut a, &mut b, now_ms);
+        }
+        let (node_a, node_b) = (a.runner().node(), b.runner().node());
+
+        // a relay claims that another node sent the data, without a signature or with its own one
+        let other = NodeKey::from_secret([3; 32]).node_id();
+        let unsigned = NodeData {
+            from: *other,
+            to: *node_b,
+            ttl: 1,
+            data: b"spoofed".to_vec(),
+            ..Default::default()
+        };
+        let mut signed = unsigned.clone();
+        unicast::sign_node_data(&NodeKey::from_secret([4; 32]), &mut signed);
+        for msg in [unsigned, signed] {
+            let msg = NetworkMessage {
+                message_type: Some(MessageType::NodeData(msg)),
+            };
+            let pkt = NetworkPkt {
+                conn: b.transport().conn(),
+                data: msg.encode_to_vec(),
+            };
+            b.transport().push(1, TransportEvent::Recv(pkt));
+        }
+        assert!(a.runner_mut().send_to(node_b, b"genuine".to_vec()).is_ok());
+
+        while a.pop_output().is_some() {}
+        b.poll(2000);
+        let mut received = vec![];
+        while let Some(event) = b.pop_output() {
+            if let OutputEvent::OnNodeData(from, data) = event {
+                received.push((from, data));
+            }
+        }
+        assert_eq!(received, vec![(node_a, b"genuine".to_vec())]);
+    }
+
+    #[test]
+    fn token_of_another_node_is_refused() {
+        // b announces that it relays, but it can still only use a token which is issued for itself
+        let (mut a, mut b) = drivers(RouterConfig::default());
+        b.runner_mut().add_capability(Capability::Relay);
+        a.connect(0, ()).expect("connect");
+        settle(&mut a, &mut b, 0);
+        let relay = a.runner().peer(a.transport().conn()).expect("peer");
+        assert!(relay.capabilities.contains(&Capability::Relay));
+        let channel = ChannelId::from(1);
+        let authority = NodeKey::from_secret([7; 32]);
+        a.runner_mut().add_channel(0, channel);
+        a.runner_mut()
+            .restrict_channel(0, channel, authority.public_key().to_vec());
+        let run = |a: &mut P2pStreamDriver<Loopback>, b: &mut P2pStreamDriver<Loopback>| {
+            for now_ms in [1000, 2000] {
+                a.on_tick(now_ms);
+                b.on_tick(now_ms);
+                settle(a, b, now_ms);
+            }
+            a.runner_mut().pub_channel(channel, b"frame".to_vec());
+            settle(a, b, 2000)
+        };
+
+        let stolen = issue_token(
+            &authority,
+            NodeKey::from_secret([3; 32]).node_id(),
+            &[channel],
+            u64::MAX,
+        );
+        b.runner_mut().sub_channel_with_token(channel, stolen);
+        assert!(run(&mut a, &mut b).is_empty());
+
+        let own = issue_token(&authority, b.runner().node(), &[channel], u64::MAX);
+        b.runner_mut().sub_channel_with_token(channel, own);
+        assert_eq!(run(&mut a, &mut b), vec![(channel, b"frame".to_vec())]);
+    }
+
+    #[test]
+    fn undecodable_data_is_dropped() {
+        let (mut a, mut b) = connected();
+        let pkt = NetworkPkt {
+            conn: b.transport().conn(),
+            data: vec![0xff; 8],
+        };
+        b.transport().push(1, TransportEvent::Recv(pkt));
+        assert!(settle(&mut a, &mut b, 0).is_empty());
+        assert!(b.runner().peer(b.transport().conn()).is_some());
+    }
+
+    #[test]
+    fn disconnect_reaches_runner() {
+        let (mut a, mut b) = connected();
+        let conn = a.transport().conn();
+        a.transport_mut().close(conn);
+        settle(&mut a, &mut b, 0);
+        assert!(a.runner().peer(conn).is_none());
+        assert!(b.runner().peer(b.transport().conn()).is_none());
+    }
+
+    #[test]
+    fn refused_handshake_closes_connection() {
+        let (mut a, mut b) = drivers(RouterConfig {
+            mode: RoutingMode::Rendezvous,
+            ..Default::default()
+        });
+        a.connect(0, ()).expect("connect");
+        settle(&mut a, &mut b, 0);
+        assert_eq!(a.transport().closed, vec![a.transport().conn()]);
+        assert!(a.runner().peer(a.transport().conn()).is_none());
+    }
+
+    #[test]
+    fn only_requested_join_response_joins() {
+        let (mut a, mut b) = connected();
+        let conn = a.transport().conn();
+        let response = NetworkMessage {
+            message_type: Some(MessageType::JoinResponse(JoinResponse { nodes: vec![] })),
+        };
+        let pkt = NetworkPkt {
+            conn,
+            data: response.encode_to_vec(),
+        };
+        a.transport().push(0, TransportEvent::Recv(pkt));
+        settle(&mut a, &mut b, 0);
+        assert!(!a.runner().is_joined());
+
+        a.runner_mut().request_join(conn);
+        settle(&mut a, &mut b, 0);
+        assert!(a.runner().is_joined());
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::{btree_map::Entry, BTreeMap, BTreeSet, HashMap, VecDeque};
+
+use crate::{
+    addr::{ChannelId, NodeId},
+    identity::{self, NodeKey},
+    protocol::{ChannelData, ChannelKey, ChannelKeyRequest},
+    runner::UNICAST_TTL,
+};
+
+use self::cipher::{FrameHeader, KEY_LEN, NONCE_LEN};
+
+mod cipher;
+
+/// Epochs of a channel whose keys a member keeps, so that frames in flight during a rotation can still be decrypted
+const KEPT_EPOCHS: usize = 2;
+
+pub enum OutputEvent {
+    /// Ask the publisher of a channel for its current key
+    SendRequest(ChannelKeyRequest),
+    /// Current key of a published channel, sealed for one member
+    SendKey(ChannelKey),
+}
+
+/// Key of a channel which this node publishes encrypted, and the nodes which may receive it
+struct Published {
+    epoch: u32,
+    key: [u8; KEY_LEN],
+    authorized: BTreeSet<NodeId>,
+    /// Authorized nodes which asked for the key, with their public key
+    members: BTreeMap<NodeId, Vec<u8>>,
+}
+
+#[derive(Default)]
+struct Received {
+    keys: BTreeMap<u32, [u8; KEY_LEN]>,
+    /// A key request was sent since the last tick
+    requested: bool,
+}
+
+/// End-to-end encryption of channel payloads. The publisher encrypts frames with a per channel key,
+/// which it seals for each authorized subscriber and rotates when the members change.
+/// Keys and nonces are derived from the node key, see `NodeKey::next_random`.
+/// Relays only forward the encrypted frames and can still read their header.
+pub struct ChannelKeys {
+    node: NodeId,
+    published: HashMap<ChannelId, Published>,
+    received: HashMap<ChannelId, Received>,
+    outputs: VecDeque<OutputEvent>,
+}
+
+impl ChannelKeys {
+    pub fn new(node: NodeId) -> Self {
+        Self {
+            node,
+            published: HashMap::new(),
+            received: HashMap::new(),
+            outputs: VecDeque::new(),
+        }
+    }
+
+    /// Encrypt the frames which this node publishes to the channel, no node is authorized yet
+    pub fn encrypt_channel(&mut self, key: &mut NodeKey, channel: ChannelId) {
+        self.published.entry(channel).or_insert_with(|| Published {
+            epoch: 0,
+            key: key.next_random(b"channel-key"),
+            authorized: BTreeSet::new(),
+            members: BTreeMap::new(),
+        });
+    }
+
+    /// Let a node receive the key of an encrypted channel, false if the channel is not encrypted.
+    /// The key is rotated when the node asks for it.
+    pub fn authorize(&mut self, channel: ChannelId, node: NodeId) -> bool {
+        match self.published.get_mut(&channel) {
+            Some(published) => {
+                published.authorized.insert(node);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The key is rotated if the node already has it, so that it can't decrypt the next frames
+    pub fn revoke(&mut self, key: &mut NodeKey, channel: ChannelId, node: NodeId) {
+        if let Some(published) = self.published.get_mut(&channel) {
+            published.authorized.remove(&node);
+            if published.members.remove(&node).is_some() {
+                log::info!("Revoked {:?} from {:?}, rotate key", node, channel);
+                self.rotate(key, channel);
+            }
+        }
+    }
+
+    /// Encrypt the payload of a frame if the channel is encrypted, with the epoch of the key
+    pub fn seal(
+        &self,
+        channel: ChannelId,
+        seq: u64,
+        layer: u32,
+        data: Vec<u8>,
+    ) -> (Option<u32>, Vec<u8>) {
+        match self.published.get(&channel) {
+            Some(published) => {
+                let header = FrameHeader {
+                    channel,
+                    seq,
+                    layer,
+                    epoch: published.epoch,
+                };
+                let data = cipher::encrypt_frame(&published.key, &header, &data);
+                (Some(published.epoch), data)
+            }
+            None => (None, data),
+        }
+    }
+
+    /// Payload of a received frame. A missing key is requested from the publisher,
+    /// at most once per tick, and the frame is dropped.
+    /// A plaintext frame is dropped if the announcement of the channel says that it is encrypted.
+    pub fn open(
+        &mut self,
+        key: &NodeKey,
+        publisher: Option<NodeId>,
+        encrypted: bool,
+        msg: ChannelData,
+    ) -> Option<Vec<u8>> {
+        let Some(epoch) = msg.epoch else {
+            if encrypted {
+                log::warn!(
+                    "Drop plaintext frame {} of encrypted channel {}",
+                    msg.seq(),
+                    msg.channel
+                );
+                return None;
+            }
+            return Some(msg.data);
+        };
+        let channel = ChannelId::from(msg.channel);
+        let header = FrameHeader {
+            channel,
+            seq: msg.seq(),
+            layer: msg.layer(),
+            epoch,
+        };
+        let frame_key = match self.published.get(&channel) {
+            Some(published) if published.epoch == epoch => Some(published.key),
+            _ => self
+                .received
+                .get(&channel)
+                .and_then(|r| r.keys.get(&epoch).copied()),
+        };
+        if let Some(frame_key) = frame_key {
+            let data = cipher::decrypt_frame(&frame_key, &header, &msg.data);
+            if data.is_none() {
+                log::warn!(
+                    "Drop frame {} of {:?} which fails decryption",
+                    msg.seq(),
+                    channel
+                );
+            }
+            return data;
+        }
+
+        let received = self.received.entry(channel).or_default();
+        let newer = received
+            .keys
+            .last_key_value()
+            .is_none_or(|(e, _)| epoch > *e);
+        if let (Some(publisher), true, false) = (publisher, newer, received.requested) {
+            log::debug!(
+                "Request key {} of {:?} from {:?}",
+                epoch,
+                channel,
+                publisher
+            );
+            received.requested = true;
+            self.outputs
+                .push_back(OutputEvent::SendRequest(ChannelKeyRequest {
+                    from: *self.node,
+                    to: *publisher,
+                    ttl: UNICAST_TTL,
+                    channel: *channel,
+                    public_key: key.public_key().to_vec(),
+                }));
+        }
+        None
+    }
+
+    pub fn on_tick(&mut self) {
+        for received in self.received.values_mut() {
+            received.requested = false;
+        }
+    }
+
+    /// A node asks for the key of a channel which this node publishes. Its first request makes it a member,
+    /// which rotates the key, later ones only send the current key again.
+    pub fn on_request(&mut self, key: &mut NodeKey, msg: ChannelKeyRequest) {
+        let from = NodeId::from(msg.from);
+        let channel = ChannelId::from(msg.channel);
+        let Some(published) = self.published.get_mut(&channel) else {
+            log::debug!(
+                "Key request of {:?} from {:?} which is not encrypted here",
+                channel,
+                from
+            );
+            return;
+        };
+        if identity::node_id_of(&msg.public_key) != from {
+            log::warn!(
+                "Key request of {:?} from {:?} with another public key",
+                channel,
+                from
+            );
+            return;
+        }
+        if !published.authorized.contains(&from) {
+            log::debug!("Key request of {:?} from unauthorized {:?}", channel, from);
+            return;
+        }
+        if let Entry::Vacant(entry) = published.members.entry(from) {
+            log::info!("{:?} joins {:?}, rotate key", from, channel);
+            entry.insert(msg.public_key);
+            self.rotate(key, channel);
+        } else {
+            self.send_key(key, channel, from);
+        }
+    }
+
+    /// Key which the publisher of the channel sealed for this node, `publisher` is the announced key of the publisher
+    pub fn on_key(&mut self, key: &NodeKey, publisher: Option<&[u8]>, msg: ChannelKey) {
+        let channel = ChannelId::from(msg.channel);
+        let Some(publisher) =
+            publisher.filter(|p| identity::node_id_of(p) == NodeId::from(msg.from))
+        else {
+            log::warn!(
+                "Key of {:?} from {:?} which doesn't publish it",
+                channel,
+                msg.from
+            );
+            return;
+        };
+        let Some(channel_key) = key.shared_secret(publisher).and_then(|shared| {
+            cipher::open_key(&shared, channel, msg.epoch, &msg.nonce, &msg.sealed)
+        }) else {
+            log::warn!(
+                "Key of {:?} from {:?} which can't be opened",
+                channel,
+                msg.from
+            );
+            return;
+        };
+        let received = self.received.entry(channel).or_default();
+        received.keys.insert(msg.epoch, channel_key);
+        while received.keys.len() > KEPT_EPOCHS {
+            received.keys.pop_first();
+        }
+    }
+
+    pub fn pop_output(&mut self) -> Option<OutputEvent> {
+        self.outputs.pop_front()
+    }
+
+    /// New key for the next frames, it is sent to all members
+    fn rotate(&mut self, key: &mut NodeKey, channel: ChannelId) {
+        let Some(published) = self.published.get_mut(&channel) else {
+            return;
+        };
+        published.epoch += 1;
+        published.key = key.next_random(b"channel-key");
+        let members = published.members.keys().copied().collect::<Vec<_>>();
+        for member in members {
+            self.send_key(key, channel, member);
+        }
+    }
+
+    fn send_key(&mut self, key: &mut NodeKey, channel: ChannelId, member: NodeId) {
+        let Some(published) = self.published.get(&channel) else {
+            return;
+        };
+        let Some(shared) = published
+            .members
+            .get(&member)
+            .and_then(|public_key| key.shared_secret(public_key))
+        else {
+            return;
+        };
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&key.next_random(b"key-nonce")[..NONCE_LEN]);
+        let sealed = cipher::seal_key(&shared, channel, published.epoch, &published.key, nonce);
+        self.outputs.push_back(OutputEvent::SendKey(ChannelKey {
+            from: *self.node,
+            to: *member,
+            ttl: UNICAST_TTL,
+            channel: *channel,
+            epoch: published.epoch,
+            nonce: nonce.to_vec(),
+            sealed,
+        }));
+    }
+}
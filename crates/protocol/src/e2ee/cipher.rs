@@ -0,0 +1,111 @@
+use crate::addr::ChannelId;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+
+/// Header of a channel frame, relays read it in plaintext but can't change it without breaking the tag
+pub struct FrameHeader {
+    pub channel: ChannelId,
+    pub seq: u64,
+    pub layer: u32,
+    pub epoch: u32,
+}
+
+impl FrameHeader {
+    fn aad(&self) -> Vec<u8> {
+        let mut aad = b"frame".to_vec();
+        aad.extend_from_slice(&self.channel.to_be_bytes());
+        aad.extend_from_slice(&self.seq.to_be_bytes());
+        aad.extend_from_slice(&self.layer.to_be_bytes());
+        aad.extend_from_slice(&self.epoch.to_be_bytes());
+        aad
+    }
+
+    /// The sequence number is never reused by a publisher, and each epoch has a fresh key
+    fn nonce(&self) -> [u8; NONCE_LEN] {
+        let mut nonce = [0; NONCE_LEN];
+        nonce[4..].copy_from_slice(&self.seq.to_be_bytes());
+        nonce
+    }
+}
+
+pub fn encrypt_frame(key: &[u8; KEY_LEN], header: &FrameHeader, data: &[u8]) -> Vec<u8> {
+    let aad = header.aad();
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(
+            Nonce::from_slice(&header.nonce()),
+            Payload {
+                msg: data,
+                aad: &aad,
+            },
+        )
+        .expect("encryption of a frame can't fail")
+}
+
+/// None if the frame was altered or encrypted with another key
+pub fn decrypt_frame(key: &[u8; KEY_LEN], header: &FrameHeader, data: &[u8]) -> Option<Vec<u8>> {
+    let aad = header.aad();
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(
+            Nonce::from_slice(&header.nonce()),
+            Payload {
+                msg: data,
+                aad: &aad,
+            },
+        )
+        .ok()
+}
+
+/// Encrypt a channel key with the secret shared by the publisher and a member, under a nonce which is never reused
+pub fn seal_key(
+    shared: &[u8; 32],
+    channel: ChannelId,
+    epoch: u32,
+    key: &[u8; KEY_LEN],
+    nonce: [u8; NONCE_LEN],
+) -> Vec<u8> {
+    let aad = key_aad(channel, epoch);
+    ChaCha20Poly1305::new(Key::from_slice(shared))
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: key,
+                aad: &aad,
+            },
+        )
+        .expect("encryption of a key can't fail")
+}
+
+pub fn open_key(
+    shared: &[u8; 32],
+    channel: ChannelId,
+    epoch: u32,
+    nonce: &[u8],
+    sealed: &[u8],
+) -> Option<[u8; KEY_LEN]> {
+    if nonce.len() != NONCE_LEN {
+        return None;
+    }
+    let aad = key_aad(channel, epoch);
+    let key = ChaCha20Poly1305::new(Key::from_slice(shared))
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: sealed,
+                aad: &aad,
+            },
+        )
+        .ok()?;
+    key.try_into().ok()
+}
+
+fn key_aad(channel: ChannelId, epoch: u32) -> Vec<u8> {
+    let mut aad = b"channel-key".to_vec();
+    aad.extend_from_slice(&channel.to_be_bytes());
+    aad.extend_from_slice(&epoch.to_be_bytes());
+    aad
+}
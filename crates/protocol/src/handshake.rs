@@ -0,0 +1,153 @@
+use std::collections::BTreeSet;
+
+use crate::{
+    addr::NodeId,
+    identity::{self, NodeKey},
+    network::{Connection, ConnectionStats},
+    protocol::{Hello, HelloAck},
+};
+
+pub use crate::protocol::hello::{Capability, Feature};
+
+/// Version of the wire protocol which this node speaks. New messages fields are optional and announced as features,
+/// the version only changes when older nodes can't be understood anymore.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest version which this node can still speak, older nodes are refused.
+/// Version 2 derives node ids from public keys, nodes before it can't prove their id.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// What a remote node announced in its handshake, features are already negotiated with the local ones
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// Version which is used on the connection, the lower one of both nodes
+    pub version: u32,
+    /// Features which both nodes support
+    pub features: BTreeSet<Feature>,
+    pub capabilities: BTreeSet<Capability>,
+    /// Ed25519 public key which the node proved to own, its NodeId is derived from it
+    pub public_key: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeError {
+    UnsupportedVersion(u32),
+    /// The node id in the hello is not the one of the connection, or not the one of the public key
+    WrongNode(NodeId),
+    /// Nodes with different routing modes can't build routes together
+    RoutingModeMismatch,
+    /// The signature doesn't prove that the node owns the private key
+    InvalidSignature,
+}
+
+/// Handshake state of a connection, no other traffic is accepted before it is established.
+/// Both nodes send a hello with a fresh nonce, and answer the hello of the other node with a signature of its nonce.
+pub(crate) enum ConnState {
+    Pending {
+        /// Nonce which the remote node must sign
+        nonce: [u8; 32],
+        /// Remote hello, once it is received and accepted
+        peer: Option<PeerInfo>,
+        /// Signature of the remote node, if it arrived before its hello
+        signature: Option<Vec<u8>>,
+        /// Stats and a join request which arrive before the handshake are applied after it
+        stats: Option<ConnectionStats>,
+        join: bool,
+    },
+    Established(PeerInfo),
+}
+
+impl ConnState {
+    pub fn pending(nonce: [u8; 32]) -> Self {
+        ConnState::Pending {
+            nonce,
+            peer: None,
+            signature: None,
+            stats: None,
+            join: false,
+        }
+    }
+}
+
+/// Handshake fields of the local node
+pub(crate) struct LocalHello {
+    pub node: NodeId,
+    pub public_key: Vec<u8>,
+    pub features: BTreeSet<Feature>,
+    pub capabilities: BTreeSet<Capability>,
+}
+
+impl LocalHello {
+    pub fn hello(&self, nonce: [u8; 32]) -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            node: *self.node,
+            features: self.features.iter().map(|f| *f as i32).collect(),
+            capabilities: self.capabilities.iter().map(|c| *c as i32).collect(),
+            public_key: self.public_key.clone(),
+            nonce: nonce.to_vec(),
+        }
+    }
+
+    /// Check the hello of a remote node, unknown features and capabilities of newer versions are ignored
+    pub fn accept(&self, conn: Connection, hello: &Hello) -> Result<PeerInfo, HandshakeError> {
+        if hello.version < MIN_PROTOCOL_VERSION {
+            return Err(HandshakeError::UnsupportedVersion(hello.version));
+        }
+        let node = NodeId::from(hello.node);
+        if node != conn.node() || identity::node_id_of(&hello.public_key) != node {
+            return Err(HandshakeError::WrongNode(node));
+        }
+        let remote = hello
+            .features
+            .iter()
+            .filter_map(|f| Feature::try_from(*f).ok())
+            .collect::<BTreeSet<_>>();
+        if remote.contains(&Feature::Rendezvous) != self.features.contains(&Feature::Rendezvous) {
+            return Err(HandshakeError::RoutingModeMismatch);
+        }
+        Ok(PeerInfo {
+            version: hello.version.min(PROTOCOL_VERSION),
+            features: self.features.intersection(&remote).copied().collect(),
+            capabilities: hello
+                .capabilities
+                .iter()
+                .filter_map(|c| Capability::try_from(*c).ok())
+                .collect(),
+            public_key: hello.public_key.clone(),
+        })
+    }
+}
+
+/// Answer to the hello of a remote node, it proves that this node owns its private key
+pub(crate) fn ack(key: &NodeKey, peer: NodeId, peer_nonce: &[u8]) -> HelloAck {
+    HelloAck {
+        signature: key
+            .sign(&ack_payload(key.node_id(), peer, peer_nonce))
+            .to_vec(),
+    }
+}
+
+/// Check the answer of a remote node to the local hello, with the public key from the remote hello
+pub(crate) fn verify_ack(
+    node: NodeId,
+    peer_key: &[u8],
+    peer_node: NodeId,
+    nonce: &[u8],
+    signature: &[u8],
+) -> Result<(), HandshakeError> {
+    let payload = ack_payload(peer_node, node, nonce);
+    if identity::verify(peer_key, &payload, signature) {
+        Ok(())
+    } else {
+        Err(HandshakeError::InvalidSignature)
+    }
+}
+
+/// The signed data binds the nonce to both nodes, so a signature can't be replayed toward another node
+fn ack_payload(signer: NodeId, peer: NodeId, nonce: &[u8]) -> Vec<u8> {
+    let mut payload = b"hello".to_vec();
+    payload.extend_from_slice(&signer.to_be_bytes());
+    payload.extend_from_slice(&peer.to_be_bytes());
+    payload.extend_from_slice(nonce);
+    payload
+}
@@ -0,0 +1,147 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::addr::NodeId;
+
+/// Length of an Ed25519 public key
+pub const PUBLIC_KEY_LEN: usize = 32;
+/// Length of an Ed25519 signature
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Ed25519 keypair of a node, the NodeId is derived from its public key,
+/// so a node can only use the NodeId whose private key it owns.
+pub struct NodeKey {
+    signing: SigningKey,
+    /// Seed of the values which the node derives from its key, like handshake nonces and channel keys.
+    /// It is drawn for each key instance so the values don't repeat after a restart.
+    seed: [u8; 32],
+    /// Counter of the derived values
+    derived: u64,
+}
+
+impl NodeKey {
+    /// New random key, it must be stored by the host if the node should keep its NodeId
+    pub fn generate() -> Self {
+        Self::from_secret(SigningKey::generate(&mut OsRng).to_bytes())
+    }
+
+    pub fn from_secret(secret: [u8; 32]) -> Self {
+        let mut seed = [0; 32];
+        OsRng.fill_bytes(&mut seed);
+        Self {
+            signing: SigningKey::from_bytes(&secret),
+            seed,
+            derived: 0,
+        }
+    }
+
+    /// Use a fixed seed for the derived values instead of the OS randomness, for reproducible simulations.
+    /// A seed must not be used again after a restart, or recorded handshakes could be replayed.
+    pub fn with_seed(mut self, seed: [u8; 32]) -> Self {
+        self.seed = seed;
+        self.derived = 0;
+        self
+    }
+
+    pub fn secret(&self) -> [u8; 32] {
+        self.signing.to_bytes()
+    }
+
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_LEN] {
+        self.signing.verifying_key().to_bytes()
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        node_id_of(&self.public_key())
+    }
+
+    pub fn sign(&self, data: &[u8]) -> [u8; SIGNATURE_LEN] {
+        self.signing.sign(data).to_bytes()
+    }
+
+    /// Secret which only this node and the owner of `public_key` can compute, both Ed25519 keys are used
+    /// in their X25519 form. None if the public key is malformed or of low order.
+    pub fn shared_secret(&self, public_key: &[u8]) -> Option<[u8; 32]> {
+        let remote = <[u8; PUBLIC_KEY_LEN]>::try_from(public_key)
+            .ok()
+            .and_then(|key| VerifyingKey::from_bytes(&key).ok())?;
+        let secret = StaticSecret::from(self.signing.to_scalar_bytes());
+        let shared = secret.diffie_hellman(&PublicKey::from(remote.to_montgomery().to_bytes()));
+        if !shared.was_contributory() {
+            return None;
+        }
+        Some(Sha256::digest(shared.as_bytes()).into())
+    }
+
+    /// Handshake nonce which doesn't repeat, also after a restart, and which other nodes can't guess
+    pub(crate) fn next_nonce(&mut self) -> [u8; 32] {
+        self.next_random(b"nonce")
+    }
+
+    /// Value which other nodes can't guess, for nonces and keys. It is derived from the secret key,
+    /// the seed of this key instance and a counter, so that the sans-IO runner doesn't draw randomness itself.
+    pub(crate) fn next_random(&mut self, label: &[u8]) -> [u8; 32] {
+        self.derived += 1;
+        let mut hasher = Sha256::new();
+        hasher.update(label);
+        hasher.update(self.signing.to_bytes());
+        hasher.update(self.seed);
+        hasher.update(self.derived.to_be_bytes());
+        hasher.finalize().into()
+    }
+}
+
+impl std::fmt::Debug for NodeKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("NodeKey").field(&self.node_id()).finish()
+    }
+}
+
+/// NodeId of a public key: the first 8 bytes of its SHA-256 hash
+pub fn node_id_of(public_key: &[u8]) -> NodeId {
+    let hash = Sha256::digest(public_key);
+    let mut id = [0; 8];
+    id.copy_from_slice(&hash[..8]);
+    u64::from_be_bytes(id).into()
+}
+
+/// Check a signature of the data with a public key, false if the key or the signature is malformed
+pub fn verify(public_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    let key = match <[u8; PUBLIC_KEY_LEN]>::try_from(public_key)
+        .ok()
+        .and_then(|key| VerifyingKey::from_bytes(&key).ok())
+    {
+        Some(key) => key,
+        None => return false,
+    };
+    match Signature::from_slice(signature) {
+        Ok(signature) => key.verify(data, &signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nonces_do_not_repeat_after_restart() {
+        let mut key = NodeKey::from_secret([1; 32]);
+        let first = key.next_nonce();
+        assert_ne!(key.next_nonce(), first);
+        // a restarted node has the same secret, but a new seed
+        let mut restarted = NodeKey::from_secret([1; 32]);
+        assert_ne!(restarted.next_nonce(), first);
+    }
+
+    #[test]
+    fn seeded_nonces_are_reproducible() {
+        let mut a = NodeKey::from_secret([1; 32]).with_seed([2; 32]);
+        let mut b = NodeKey::from_secret([1; 32]).with_seed([2; 32]);
+        assert_eq!(a.next_nonce(), b.next_nonce());
+        let mut other = NodeKey::from_secret([3; 32]).with_seed([2; 32]);
+        assert_ne!(a.next_nonce(), other.next_nonce());
+    }
+}
@@ -7,10 +7,33 @@ mod protobuf {
 }
 
 mod addr;
+mod bootstrap;
+mod discovery;
+mod driver;
+mod e2ee;
+mod handshake;
+mod identity;
+mod limit;
 mod network;
 mod pubsub;
 mod router;
 mod runner;
+mod signalling;
+mod transport;
+mod unicast;
+pub use addr::{ChannelId, NodeId};
+pub use bootstrap::BootstrapConfig;
+pub use discovery::{DiscoveryConfig, NeighbourManager};
+pub use driver::P2pStreamDriver;
+pub use handshake::{
+    Capability, Feature, HandshakeError, PeerInfo, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+pub use identity::{node_id_of, verify, NodeKey};
+pub use limit::{LimitConfig, MessageKind, Misbehaviour, Rate};
+pub use network::{Connection, ConnectionStats, NetworkMsg, NetworkPkt};
 pub use protobuf::message::{protocol, Protocol};
-pub use router::metric::Float;
+pub use pubsub::token::{issue_token, verify_token, TokenError};
+pub use router::{metric::Float, RouterConfig, RouterConfigError, RoutingMode};
 pub use runner::{InputEvent, OutputEvent, P2pStreamRunner};
+pub use signalling::{Signal, SignallingChannel, SignallingServer, TransportSignalling};
+pub use transport::{Transport, TransportEvent};
@@ -0,0 +1,363 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    addr::{ChannelId, NodeId},
+    network::Connection,
+};
+
+/// Classes of messages which are rate limited separately, the handshake is limited by its state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    /// RouterSync and RouterSyncRequest
+    Sync,
+    /// ChannelSub and ChannelUnsub
+    Sub,
+    /// Messages which are relayed towards a node: NodeData, Signalling, FindNode, channel keys
+    Unicast,
+    /// JoinRequest and JoinResponse
+    Join,
+}
+
+/// What a neighbour did wrong, each kind adds its penalty to the misbehaviour score of the node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehaviour {
+    RateExceeded(MessageKind),
+    OversizedData,
+    /// A frame of a channel which this node doesn't subscribe to over the connection
+    UnsubscribedData,
+    TooManySubs,
+    /// A route row with a missing or forged announcement, honest neighbours only relay verified ones
+    ForgedRoute,
+    /// A frame of an authenticated channel without a valid publisher signature, honest relays drop them
+    ForgedData,
+}
+
+impl Misbehaviour {
+    fn penalty(&self) -> u32 {
+        match self {
+            Misbehaviour::RateExceeded(_) => 1,
+            Misbehaviour::UnsubscribedData | Misbehaviour::TooManySubs => 5,
+            Misbehaviour::OversizedData | Misbehaviour::ForgedRoute | Misbehaviour::ForgedData => {
+                20
+            }
+        }
+    }
+}
+
+/// Token bucket rate: `per_sec` messages on average, with bursts up to `burst` messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate {
+    pub per_sec: u32,
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct LimitConfig {
+    pub sync: Rate,
+    pub sub: Rate,
+    /// Frames of one subscribed channel over a connection, frames above it are dropped without a penalty
+    pub data: Rate,
+    pub unicast: Rate,
+    pub join: Rate,
+    /// Rows of channels and nodes which are taken from one sync, the rows above it are ignored
+    pub max_sync_rows: usize,
+    /// Payload bytes of one channel frame, larger frames are dropped
+    pub max_data_len: usize,
+    /// Channels which one neighbour can subscribe to over a connection
+    pub max_subs_per_conn: usize,
+    /// A node whose misbehaviour score reaches this is banned
+    pub ban_score: u32,
+    /// Score which is forgiven each second, so rare mistakes of honest nodes never add up to a ban
+    pub score_decay_per_sec: u32,
+    pub ban_duration_ms: u64,
+    /// Frames of a channel which was unsubscribed over a connection are dropped without a penalty for this long,
+    /// since the former next hop sends the frames which were in flight
+    pub unsub_grace_ms: u64,
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        Self {
+            sync: Rate {
+                per_sec: 20,
+                burst: 40,
+            },
+            sub: Rate {
+                per_sec: 1000,
+                burst: 2000,
+            },
+            data: Rate {
+                per_sec: 10_000,
+                burst: 20_000,
+            },
+            unicast: Rate {
+                per_sec: 500,
+                burst: 1000,
+            },
+            join: Rate {
+                per_sec: 1,
+                burst: 5,
+            },
+            max_sync_rows: 16_384,
+            max_data_len: 256 * 1024,
+            max_subs_per_conn: 1024,
+            ban_score: 100,
+            score_decay_per_sec: 1,
+            ban_duration_ms: 600_000,
+            unsub_grace_ms: 2000,
+        }
+    }
+}
+
+struct Bucket {
+    /// Available messages in thousandths, so that refills of a few milliseconds are not lost
+    milli_tokens: u64,
+    last_ms: u64,
+}
+
+impl Bucket {
+    fn new(now_ms: u64, rate: Rate) -> Self {
+        Self {
+            milli_tokens: rate.burst as u64 * 1000,
+            last_ms: now_ms,
+        }
+    }
+
+    fn take(&mut self, now_ms: u64, rate: Rate) -> bool {
+        let elapsed_ms = now_ms.saturating_sub(self.last_ms);
+        self.last_ms = self.last_ms.max(now_ms);
+        self.milli_tokens =
+            (self.milli_tokens + elapsed_ms * rate.per_sec as u64).min(rate.burst as u64 * 1000);
+        if self.milli_tokens >= 1000 {
+            self.milli_tokens -= 1000;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Protection against hostile neighbours: rate limits of each connection, and a misbehaviour score of each node
+/// which bans it for a while when it gets too high
+pub struct Limiter {
+    config: LimitConfig,
+    buckets: HashMap<(Connection, MessageKind), Bucket>,
+    /// Channels which this node subscribed to over each connection
+    subscribed: HashSet<(Connection, ChannelId)>,
+    data: HashMap<(Connection, ChannelId), Bucket>,
+    /// Channels which were unsubscribed over each connection, with the end of their grace time
+    unsubscribed: HashMap<(Connection, ChannelId), u64>,
+    /// Misbehaviour scores in thousandths, so that ticks shorter than a second still decay them
+    scores: HashMap<NodeId, u64>,
+    /// Banned nodes with the end of their ban
+    banned: HashMap<NodeId, u64>,
+    last_tick_ms: u64,
+}
+
+impl Limiter {
+    pub fn new(config: LimitConfig) -> Self {
+        Self {
+            config,
+            buckets: HashMap::new(),
+            subscribed: HashSet::new(),
+            data: HashMap::new(),
+            unsubscribed: HashMap::new(),
+            scores: HashMap::new(),
+            banned: HashMap::new(),
+            last_tick_ms: 0,
+        }
+    }
+
+    pub fn config(&self) -> &LimitConfig {
+        &self.config
+    }
+
+    /// Take a message of the kind from the bucket of the connection, false if it must be dropped
+    pub fn allow(&mut self, now_ms: u64, conn: Connection, kind: MessageKind) -> bool {
+        let rate = match kind {
+            MessageKind::Sync => self.config.sync,
+            MessageKind::Sub => self.config.sub,
+            MessageKind::Unicast => self.config.unicast,
+            MessageKind::Join => self.config.join,
+        };
+        self.buckets
+            .entry((conn, kind))
+            .or_insert_with(|| Bucket::new(now_ms, rate))
+            .take(now_ms, rate)
+    }
+
+    /// Remember that the channel was subscribed over the connection, so its frames are expected from there
+    pub fn on_sub(&mut self, conn: Connection, channel: ChannelId) {
+        self.unsubscribed.remove(&(conn, channel));
+        self.subscribed.insert((conn, channel));
+    }
+
+    /// The channel is no longer subscribed over the connection, so its frames are unexpected from there
+    /// once the grace time is over
+    pub fn on_unsub(&mut self, conn: Connection, channel: ChannelId) {
+        if self.subscribed.remove(&(conn, channel)) {
+            self.data.remove(&(conn, channel));
+            self.unsubscribed.insert(
+                (conn, channel),
+                self.last_tick_ms + self.config.unsub_grace_ms,
+            );
+        }
+    }
+
+    /// Take a frame of the channel from the bucket of the connection, None if the channel is not subscribed
+    /// over the connection
+    pub fn allow_data(
+        &mut self,
+        now_ms: u64,
+        conn: Connection,
+        channel: ChannelId,
+    ) -> Option<bool> {
+        if !self.subscribed.contains(&(conn, channel)) {
+            let in_grace = self
+                .unsubscribed
+                .get(&(conn, channel))
+                .is_some_and(|until_ms| *until_ms > now_ms);
+            return in_grace.then_some(false);
+        }
+        let rate = self.config.data;
+        let allowed = self
+            .data
+            .entry((conn, channel))
+            .or_insert_with(|| Bucket::new(now_ms, rate))
+            .take(now_ms, rate);
+        Some(allowed)
+    }
+
+    /// Add the penalty to the score of the node, true if the node is banned by it
+    pub fn penalize(&mut self, node: NodeId, misbehaviour: Misbehaviour) -> bool {
+        if self.banned.contains_key(&node) {
+            return false;
+        }
+        let score = self.scores.entry(node).or_default();
+        *score += misbehaviour.penalty() as u64 * 1000;
+        if *score < self.config.ban_score as u64 * 1000 {
+            return false;
+        }
+        self.scores.remove(&node);
+        self.banned
+            .insert(node, self.last_tick_ms + self.config.ban_duration_ms);
+        true
+    }
+
+    pub fn is_banned(&self, node: NodeId) -> bool {
+        self.banned.contains_key(&node)
+    }
+
+    pub fn on_disconnected(&mut self, conn: Connection) {
+        self.buckets.retain(|(c, _), _| *c != conn);
+        self.subscribed.retain(|(c, _)| *c != conn);
+        self.data.retain(|(c, _), _| *c != conn);
+        self.unsubscribed.retain(|(c, _), _| *c != conn);
+    }
+
+    /// Scores decay and bans expire once per tick
+    pub fn on_tick(&mut self, now_ms: u64) {
+        let elapsed_ms = now_ms.saturating_sub(self.last_tick_ms);
+        self.last_tick_ms = now_ms;
+        self.unsubscribed.retain(|_, until_ms| *until_ms > now_ms);
+        let decay = elapsed_ms * self.config.score_decay_per_sec as u64;
+        self.scores.retain(|_, score| {
+            *score = score.saturating_sub(decay);
+            *score > 0
+        });
+        self.banned.retain(|node, until_ms| {
+            let banned = *until_ms > now_ms;
+            if !banned {
+                log::info!("Ban of {:?} expired", node);
+            }
+            banned
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limits_each_connection() {
+        let mut limiter = Limiter::new(LimitConfig {
+            sub: Rate {
+                per_sec: 10,
+                burst: 10,
+            },
+            ..Default::default()
+        });
+        let (a, b) = (
+            Connection::from_parts(1.into(), 0),
+            Connection::from_parts(2.into(), 0),
+        );
+        assert!((0..10).all(|_| limiter.allow(0, a, MessageKind::Sub)));
+        assert!(!limiter.allow(0, a, MessageKind::Sub));
+        // other connections and kinds have their own buckets
+        assert!(limiter.allow(0, b, MessageKind::Sub));
+        assert!(limiter.allow(0, a, MessageKind::Unicast));
+        // the bucket refills with the rate
+        assert!(limiter.allow(100, a, MessageKind::Sub));
+        assert!(!limiter.allow(100, a, MessageKind::Sub));
+    }
+
+    #[test]
+    fn rate_limits_data_of_each_subscribed_channel() {
+        let mut limiter = Limiter::new(LimitConfig {
+            data: Rate {
+                per_sec: 10,
+                burst: 10,
+            },
+            ..Default::default()
+        });
+        let conn = Connection::from_parts(1.into(), 0);
+        let (a, b) = (ChannelId::from(1), ChannelId::from(2));
+        assert_eq!(limiter.allow_data(0, conn, a), None);
+        limiter.on_sub(conn, a);
+        limiter.on_sub(conn, b);
+        assert!((0..10).all(|_| limiter.allow_data(0, conn, a) == Some(true)));
+        assert_eq!(limiter.allow_data(0, conn, a), Some(false));
+        // a busy channel doesn't starve the others
+        assert_eq!(limiter.allow_data(0, conn, b), Some(true));
+
+        // frames in flight after the unsub are dropped, later ones are misbehaviour
+        limiter.on_unsub(conn, a);
+        assert_eq!(limiter.allow_data(0, conn, a), Some(false));
+        let grace_ms = LimitConfig::default().unsub_grace_ms;
+        limiter.on_tick(grace_ms);
+        assert_eq!(limiter.allow_data(grace_ms, conn, a), None);
+        limiter.on_disconnected(conn);
+        assert_eq!(limiter.allow_data(0, conn, b), None);
+    }
+
+    #[test]
+    fn flooding_node_is_banned_for_a_while() {
+        let config = LimitConfig::default();
+        let mut limiter = Limiter::new(config.clone());
+        let node = NodeId::from(1);
+        let rate_exceeded = Misbehaviour::RateExceeded(MessageKind::Sub);
+        let banned = (0..config.ban_score).position(|_| limiter.penalize(node, rate_exceeded));
+        assert_eq!(banned, Some(config.ban_score as usize - 1));
+        assert!(limiter.is_banned(node));
+        // a banned node is not penalized again, so its ban is not extended
+        assert!(!limiter.penalize(node, rate_exceeded));
+
+        limiter.on_tick(config.ban_duration_ms - 1);
+        assert!(limiter.is_banned(node));
+        limiter.on_tick(config.ban_duration_ms + 1);
+        assert!(!limiter.is_banned(node));
+    }
+
+    #[test]
+    fn rare_mistakes_decay() {
+        let config = LimitConfig::default();
+        let mut limiter = Limiter::new(config.clone());
+        let node = NodeId::from(1);
+        for second in 0..config.ban_score as u64 * 2 {
+            limiter.on_tick(second * 1000);
+            assert!(!limiter.penalize(node, Misbehaviour::RateExceeded(MessageKind::Unicast)));
+        }
+        assert!(!limiter.is_banned(node));
+    }
+}
@@ -2,14 +2,17 @@ use std::collections::{HashMap, VecDeque};
 
 use crate::{
     addr::ChannelId,
-    network::NetworkMsg,
-    protocol::{ChannelData, ChannelSub, ChannelUnsub},
+    network::{Connection, NetworkMsg},
+    protocol::{ChannelData, ChannelSub, ChannelToken, ChannelUnsub},
 };
 
 use self::channel::PubsubChannel;
 
+pub mod auth;
 mod channel;
+pub mod token;
 
+#[allow(clippy::enum_variant_names)]
 pub enum InputEvent {
     RecvSub(NetworkMsg<ChannelSub>),
     RecvData(NetworkMsg<ChannelData>),
@@ -20,12 +23,16 @@ pub enum OutputEvent {
     SendSub(ChannelSub),
     SendData(NetworkMsg<ChannelData>),
     SendUnsub(ChannelUnsub),
-    OnChannelData(ChannelId, Vec<u8>),
+    OnChannelData(ChannelData),
 }
 
 pub struct Pubsub {
     outputs: VecDeque<OutputEvent>,
     channels: HashMap<ChannelId, PubsubChannel>,
+    /// Sequence number of the next frame which this node publishes to each channel
+    seqs: HashMap<ChannelId, u64>,
+    /// Tokens issued for this node, which it presents for restricted channels that it only relays
+    relay_tokens: Vec<ChannelToken>,
 }
 
 impl Pubsub {
@@ -33,39 +40,86 @@ impl Pubsub {
         Self {
             outputs: VecDeque::new(),
             channels: HashMap::new(),
+            seqs: HashMap::new(),
+            relay_tokens: vec![],
         }
     }
 
-    pub fn sub_channel(&mut self, channel_id: ChannelId) {
+    pub fn sub_channel(&mut self, channel_id: ChannelId, token: Option<ChannelToken>) {
         let channel = self
             .channels
             .entry(channel_id)
             .or_insert_with(PubsubChannel::new);
-        channel.on_local_sub();
-        Self::pop_channel_output(channel_id, channel, &mut self.outputs);
+        channel.on_local_sub(token);
+        Self::pop_channel_output(channel_id, channel, &self.relay_tokens, &mut self.outputs);
     }
 
     pub fn unsub_channel(&mut self, channel_id: ChannelId) {
         if let Some(channel) = self.channels.get_mut(&channel_id) {
             channel.on_local_unsub();
-            Self::pop_channel_output(channel_id, channel, &mut self.outputs);
+            Self::pop_channel_output(channel_id, channel, &self.relay_tokens, &mut self.outputs);
             if channel.is_empty() {
                 self.channels.remove(&channel_id);
             }
         }
     }
 
-    pub fn pub_channel(&mut self, channel_id: ChannelId, data: Vec<u8>) {
+    /// Sequence numbers are never reused, they are the nonces of encrypted frames
+    pub fn next_seq(&mut self, channel_id: ChannelId) -> u64 {
+        let seq = self.seqs.entry(channel_id).or_default();
+        *seq += 1;
+        *seq
+    }
+
+    pub fn pub_channel(&mut self, data: ChannelData) {
+        let channel_id = data.channel.into();
         if let Some(channel) = self.channels.get_mut(&channel_id) {
             channel.relay_data(data);
-            Self::pop_channel_output(channel_id, channel, &mut self.outputs);
+            Self::pop_channel_output(channel_id, channel, &self.relay_tokens, &mut self.outputs);
         }
     }
 
+    pub fn has_remote_sub(&self, channel_id: ChannelId, conn: Connection) -> bool {
+        self.channels
+            .get(&channel_id)
+            .is_some_and(|c| c.has_remote_sub(conn))
+    }
+
+    /// Channels which are subscribed over the connection
+    pub fn remote_sub_count(&self, conn: Connection) -> usize {
+        self.channels
+            .values()
+            .filter(|c| c.has_remote_sub(conn))
+            .count()
+    }
+
+    pub fn add_relay_token(&mut self, token: ChannelToken) {
+        self.relay_tokens.push(token);
+    }
+
+    /// Token which is sent with the subscription of the channel
+    pub fn sub_token(&self, channel_id: ChannelId) -> Option<ChannelToken> {
+        self.channels
+            .get(&channel_id)?
+            .token()
+            .or_else(|| Self::relay_token(&self.relay_tokens, channel_id))
+    }
+
     pub fn on_tick(&mut self, now_ms: u64) {
-        for (channel_id, channel) in &mut self.channels {
-            channel.on_tick(now_ms);
-            Self::pop_channel_output(*channel_id, channel, &mut self.outputs);
+        self.relay_tokens.retain(|t| t.expires_ms > now_ms);
+        // sorted so that outputs order does not depend on the hash map
+        let mut channel_ids = self.channels.keys().copied().collect::<Vec<_>>();
+        channel_ids.sort();
+        for channel_id in channel_ids {
+            if let Some(channel) = self.channels.get_mut(&channel_id) {
+                channel.on_tick(now_ms);
+                Self::pop_channel_output(
+                    channel_id,
+                    channel,
+                    &self.relay_tokens,
+                    &mut self.outputs,
+                );
+            }
         }
     }
 
@@ -78,20 +132,35 @@ impl Pubsub {
                     .entry(channel_id.into())
                     .or_insert_with(PubsubChannel::new);
                 channel.on_remote_sub(now_ms, msg.conn);
-                Self::pop_channel_output(channel_id.into(), channel, &mut self.outputs);
+                Self::pop_channel_output(
+                    channel_id.into(),
+                    channel,
+                    &self.relay_tokens,
+                    &mut self.outputs,
+                );
             }
             InputEvent::RecvData(msg) => {
                 let channel_id = msg.msg.channel.into();
                 if let Some(channel) = self.channels.get_mut(&channel_id) {
-                    channel.relay_data(msg.msg.data);
-                    Self::pop_channel_output(channel_id, channel, &mut self.outputs);
+                    channel.relay_data(msg.msg);
+                    Self::pop_channel_output(
+                        channel_id,
+                        channel,
+                        &self.relay_tokens,
+                        &mut self.outputs,
+                    );
                 }
             }
             InputEvent::RecvUnsub(msg) => {
                 let channel_id = msg.msg.channel.into();
                 if let Some(channel) = self.channels.get_mut(&channel_id) {
                     channel.on_remote_unsub(now_ms, msg.conn);
-                    Self::pop_channel_output(channel_id, channel, &mut self.outputs);
+                    Self::pop_channel_output(
+                        channel_id,
+                        channel,
+                        &self.relay_tokens,
+                        &mut self.outputs,
+                    );
                     if channel.is_empty() {
                         self.channels.remove(&channel_id);
                     }
@@ -104,16 +173,27 @@ impl Pubsub {
         self.outputs.pop_front()
     }
 
+    /// Relay token of the channel which expires last
+    fn relay_token(tokens: &[ChannelToken], channel_id: ChannelId) -> Option<ChannelToken> {
+        tokens
+            .iter()
+            .filter(|t| t.channels.contains(&channel_id))
+            .max_by_key(|t| t.expires_ms)
+            .cloned()
+    }
+
     fn pop_channel_output(
         channel_id: ChannelId,
         channel: &mut PubsubChannel,
+        relay_tokens: &[ChannelToken],
         outputs: &mut VecDeque<OutputEvent>,
     ) {
         if let Some(output) = channel.pop_output() {
             match output {
-                channel::OutputEvent::Sub => {
+                channel::OutputEvent::Sub(token) => {
                     outputs.push_back(OutputEvent::SendSub(ChannelSub {
                         channel: *channel_id,
+                        token: token.or_else(|| Self::relay_token(relay_tokens, channel_id)),
                     }));
                 }
                 channel::OutputEvent::Data {
@@ -124,14 +204,11 @@ impl Pubsub {
                     for conn in remotes {
                         outputs.push_back(OutputEvent::SendData(NetworkMsg {
                             conn,
-                            msg: ChannelData {
-                                channel: *channel_id,
-                                data: data.clone(),
-                            },
+                            msg: data.clone(),
                         }));
                     }
                     if local {
-                        outputs.push_back(OutputEvent::OnChannelData(channel_id, data));
+                        outputs.push_back(OutputEvent::OnChannelData(data));
                     }
                 }
                 channel::OutputEvent::Unsub => {
@@ -0,0 +1,73 @@
+use crate::{identity, identity::NodeKey, protocol::ChannelData};
+
+/// Signature of a frame by its publisher, it covers the header and the payload as sent,
+/// so relays can verify frames of encrypted channels too
+pub fn sign(key: &NodeKey, frame: &ChannelData) -> Vec<u8> {
+    key.sign(&payload(frame)).to_vec()
+}
+
+/// False if the frame is not signed, or was changed after the publisher signed it
+pub fn verify(publisher: &[u8], frame: &ChannelData) -> bool {
+    match frame.signature.as_ref() {
+        Some(signature) => identity::verify(publisher, &payload(frame), signature),
+        None => false,
+    }
+}
+
+fn payload(frame: &ChannelData) -> Vec<u8> {
+    let mut payload = b"data".to_vec();
+    payload.extend_from_slice(&frame.channel.to_be_bytes());
+    payload.extend_from_slice(&frame.seq().to_be_bytes());
+    payload.extend_from_slice(&frame.layer().to_be_bytes());
+    match frame.epoch {
+        Some(epoch) => {
+            payload.push(1);
+            payload.extend_from_slice(&epoch.to_be_bytes());
+        }
+        None => payload.push(0),
+    }
+    payload.extend_from_slice(&frame.data);
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forged_frames_are_rejected() {
+        let key = NodeKey::from_secret([1; 32]);
+        let mut frame = ChannelData {
+            channel: 1,
+            seq: Some(7),
+            data: vec![1, 2, 3],
+            ..Default::default()
+        };
+        frame.signature = Some(sign(&key, &frame));
+        let publisher = key.public_key();
+
+        let forged = [
+            ChannelData {
+                data: vec![6, 6, 6],
+                ..frame.clone()
+            },
+            ChannelData {
+                seq: Some(frame.seq() + 1),
+                ..frame.clone()
+            },
+            ChannelData {
+                epoch: Some(1),
+                ..frame.clone()
+            },
+            ChannelData {
+                signature: None,
+                ..frame.clone()
+            },
+        ];
+        for forged in forged {
+            assert!(!verify(&publisher, &forged));
+        }
+        assert!(!verify(&NodeKey::from_secret([2; 32]).public_key(), &frame));
+        assert!(verify(&publisher, &frame));
+    }
+}
@@ -1,6 +1,9 @@
 use std::collections::{HashMap, VecDeque};
 
-use crate::network::Connection;
+use crate::{
+    network::Connection,
+    protocol::{ChannelData, ChannelToken},
+};
 
 const SUB_TIMEOUT_MS: u64 = 5000;
 
@@ -9,9 +12,10 @@ struct RemoteSub {
 }
 
 pub enum OutputEvent {
-    Sub,
+    /// Subscribe to the next hop, with the token of the local subscription if there is one
+    Sub(Option<ChannelToken>),
     Data {
-        data: Vec<u8>,
+        data: ChannelData,
         remotes: Vec<Connection>,
         local: bool,
     },
@@ -20,6 +24,7 @@ pub enum OutputEvent {
 
 pub struct PubsubChannel {
     local_sub: bool,
+    local_token: Option<ChannelToken>,
     remote_subs: HashMap<Connection, RemoteSub>,
     outputs: VecDeque<OutputEvent>,
 }
@@ -28,31 +33,29 @@ impl PubsubChannel {
     pub fn new() -> Self {
         Self {
             local_sub: false,
+            local_token: None,
             remote_subs: HashMap::new(),
             outputs: VecDeque::new(),
         }
     }
 
     pub fn is_empty(&self) -> bool {
-        !self.local_sub || self.remote_subs.is_empty()
+        !self.local_sub && self.remote_subs.is_empty()
     }
 
     pub fn on_tick(&mut self, now_ms: u64) {
         //clear timeout remote subs
-        let timeout = now_ms - SUB_TIMEOUT_MS;
+        let timeout = now_ms.saturating_sub(SUB_TIMEOUT_MS);
         self.remote_subs.retain(|_, sub| sub.last_sub > timeout);
 
         if self.local_sub || !self.remote_subs.is_empty() {
-            self.outputs.push_back(OutputEvent::Sub);
+            self.outputs.push_back(OutputEvent::Sub(self.token()));
         }
     }
 
-    pub fn relay_data(&mut self, data: Vec<u8>) {
-        let remotes = self
-            .remote_subs
-            .iter()
-            .map(|(conn, _)| *conn)
-            .collect::<Vec<_>>();
+    pub fn relay_data(&mut self, data: ChannelData) {
+        let mut remotes = self.remote_subs.keys().copied().collect::<Vec<_>>();
+        remotes.sort();
         if !remotes.is_empty() || self.local_sub {
             self.outputs.push_back(OutputEvent::Data {
                 data,
@@ -62,11 +65,16 @@ impl PubsubChannel {
         }
     }
 
-    pub fn on_local_sub(&mut self) {
+    pub fn has_remote_sub(&self, conn: Connection) -> bool {
+        self.remote_subs.contains_key(&conn)
+    }
+
+    pub fn on_local_sub(&mut self, token: Option<ChannelToken>) {
+        self.local_token = token;
         if !self.local_sub {
             self.local_sub = true;
             if self.remote_subs.is_empty() {
-                self.outputs.push_back(OutputEvent::Sub);
+                self.outputs.push_back(OutputEvent::Sub(self.token()));
             }
         }
     }
@@ -74,21 +82,20 @@ impl PubsubChannel {
     pub fn on_local_unsub(&mut self) {
         if self.local_sub {
             self.local_sub = false;
+            self.local_token = None;
             if self.remote_subs.is_empty() {
                 self.outputs.push_back(OutputEvent::Unsub);
             }
         }
     }
 
+    /// The token of the subscription is already verified, it is bound to the remote node and can't be forwarded
     pub fn on_remote_sub(&mut self, now_ms: u64, from: Connection) {
-        if let Some(remote) = self.remote_subs.get_mut(&from) {
-            remote.last_sub = now_ms;
-        } else {
-            if !self.local_sub && self.remote_subs.is_empty() {
-                self.outputs.push_back(OutputEvent::Sub);
-            }
-            self.remote_subs
-                .insert(from, RemoteSub { last_sub: now_ms });
+        let first = !self.local_sub && self.remote_subs.is_empty();
+        self.remote_subs
+            .insert(from, RemoteSub { last_sub: now_ms });
+        if first {
+            self.outputs.push_back(OutputEvent::Sub(self.token()));
         }
     }
 
@@ -101,6 +108,11 @@ impl PubsubChannel {
         }
     }
 
+    /// The token of the local subscription
+    pub fn token(&self) -> Option<ChannelToken> {
+        self.local_token.clone()
+    }
+
     pub fn pop_output(&mut self) -> Option<OutputEvent> {
         self.outputs.pop_front()
     }
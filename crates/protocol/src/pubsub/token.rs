@@ -0,0 +1,102 @@
+use crate::{
+    addr::{ChannelId, NodeId},
+    identity::{self, NodeKey},
+    protocol::ChannelToken,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenError {
+    /// The channel requires a token and the subscription carries none
+    Missing,
+    Expired,
+    /// The token doesn't grant the channel
+    OutOfScope,
+    /// The token is not issued by the authority of the channel
+    WrongIssuer,
+    InvalidSignature,
+    /// The token is issued for another node than the sender
+    WrongSubscriber,
+}
+
+/// Token which lets the subscriber node subscribe to the channels until `expires_ms` in unix time.
+/// The key is the one of the publisher, or of a room authority which the publisher announces.
+pub fn issue_token(
+    key: &NodeKey,
+    subscriber: NodeId,
+    channels: &[ChannelId],
+    expires_ms: u64,
+) -> ChannelToken {
+    let mut token = ChannelToken {
+        channels: channels.iter().map(|c| **c).collect(),
+        expires_ms,
+        issuer: key.public_key().to_vec(),
+        signature: vec![],
+        subscriber: *subscriber,
+    };
+    token.signature = key.sign(&payload(&token)).to_vec();
+    token
+}
+
+/// Check the token of a subscription which the handshake-verified node `from` sent, to a channel whose announcement
+/// requires tokens of `authority`. Every node presents a token which is issued for itself, relays included.
+pub fn verify_token(
+    now_ms: u64,
+    channel: ChannelId,
+    authority: &[u8],
+    from: NodeId,
+    token: Option<&ChannelToken>,
+) -> Result<(), TokenError> {
+    let token = token.ok_or(TokenError::Missing)?;
+    if NodeId::from(token.subscriber) != from {
+        return Err(TokenError::WrongSubscriber);
+    }
+    if token.expires_ms <= now_ms {
+        return Err(TokenError::Expired);
+    }
+    if !token.channels.contains(&channel) {
+        return Err(TokenError::OutOfScope);
+    }
+    if token.issuer != authority {
+        return Err(TokenError::WrongIssuer);
+    }
+    if !identity::verify(&token.issuer, &payload(token), &token.signature) {
+        return Err(TokenError::InvalidSignature);
+    }
+    Ok(())
+}
+
+fn payload(token: &ChannelToken) -> Vec<u8> {
+    let mut payload = b"token".to_vec();
+    payload.extend_from_slice(&token.subscriber.to_be_bytes());
+    payload.extend_from_slice(&(token.channels.len() as u32).to_be_bytes());
+    for channel in &token.channels {
+        payload.extend_from_slice(&channel.to_be_bytes());
+    }
+    payload.extend_from_slice(&token.expires_ms.to_be_bytes());
+    payload.extend_from_slice(&token.issuer);
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_is_only_valid_from_its_subscriber() {
+        let authority = NodeKey::from_secret([1; 32]);
+        let (subscriber, relay) = (NodeId::from(2), NodeId::from(3));
+        let channel = ChannelId::from(1);
+        let token = issue_token(&authority, subscriber, &[channel], 1000);
+        let verify = |from, token: &ChannelToken| {
+            verify_token(0, channel, &authority.public_key(), from, Some(token))
+        };
+        assert_eq!(verify(subscriber, &token), Ok(()));
+        // a relay which saw the token can't replay it, whatever capabilities it announces
+        assert_eq!(verify(relay, &token), Err(TokenError::WrongSubscriber));
+        let rebound = ChannelToken {
+            subscriber: *relay,
+            ..token
+        };
+        assert_eq!(verify(relay, &rebound), Err(TokenError::InvalidSignature));
+    }
+}
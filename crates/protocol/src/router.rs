@@ -1,41 +1,155 @@
 use crate::{
     addr::{ChannelId, NodeId},
+    limit::Misbehaviour,
     network::{Connection, ConnectionStats, NetworkMsg},
-    protocol::{self, RouterRow, RouterSync},
+    protocol::{ChannelAnnounce, RouterSync, RouterSyncRequest},
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
-use self::{channel::ChannelRoute, path::ChannelPath};
+use self::{
+    announce::AnnounceError, channel::ChannelRoute, neighbour::Neighbour, path::ChannelPath,
+    sync::NeighbourSync,
+};
 
+pub mod announce;
 mod channel;
 pub mod metric;
+mod neighbour;
 mod path;
+mod sync;
 
 pub enum InputEvent {
-    Recv(NetworkMsg<protocol::RouterSync>),
+    Recv(NetworkMsg<RouterSync>),
+    RecvSyncRequest(NetworkMsg<RouterSyncRequest>),
     ConnectionDisconnected(Connection),
     ConnectionStats(NetworkMsg<ConnectionStats>),
 }
 
+pub enum OutputEvent {
+    Sync(NetworkMsg<RouterSync>),
+    SyncRequest(NetworkMsg<RouterSyncRequest>),
+    /// A neighbour sent rows which honest nodes never send
+    Misbehaved(NodeId, Misbehaviour),
+}
+
 pub enum NextHop {
     Local,
     Remote(Connection),
 }
 
+/// How channel routes are spread over the network, node routes are bounded by `RouterConfig::node_route_radius`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RoutingMode {
+    /// Every channel route is synced to every neighbour
+    #[default]
+    Flood,
+    /// A channel route is only synced toward the channel rendezvous node,
+    /// which is the known node closest to the channel id by XOR distance.
+    /// Subscribers which don't know the channel route send Sub toward the rendezvous node instead,
+    /// which builds a shared tree per channel.
+    Rendezvous,
+}
+
+/// Router configs which can't work
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouterConfigError {
+    /// The rendezvous node of a channel is picked among the known nodes, with a node route radius nodes know
+    /// different nodes and pick different rendezvous nodes, which splits the tree of the channel
+    RendezvousWithRadius,
+}
+
+#[derive(Debug, Clone)]
+pub struct RouterConfig {
+    pub mode: RoutingMode,
+    /// After the first full table, only new, changed and withdrawn rows are synced to neighbours
+    pub delta_sync: bool,
+    /// In delta mode, a row is only synced again if its score changed more than this percent
+    pub delta_threshold_percent: u32,
+    /// In delta mode, the full table is still synced periodically for robustness
+    pub full_sync_interval_ms: u64,
+    /// A route whose best score changed more than this percent is synced immediately with a triggered sync
+    pub trigger_threshold_percent: u32,
+    /// Minimum interval between two triggered syncs to the same neighbour
+    pub trigger_interval_ms: u64,
+    /// Paths with more hops are rejected, which bounds sync message size and relay chain length
+    pub max_hops: usize,
+    /// Score penalty of each hop in a path
+    pub hop_penalty_ms: u32,
+    /// Every hop counts at least this rtt, paths which claim less for their hop count are rejected
+    pub min_hop_rtt_ms: u32,
+    /// Validity of the channel and node announcements which this node signs, they are renewed at half of it
+    pub announce_ttl_ms: u64,
+    /// Paths which are not refreshed by a sync within this time are dropped,
+    /// it must be a few times longer than the tick interval of the neighbours, which is their sync interval
+    pub route_timeout_ms: u64,
+    /// Channel and node routes which one neighbour can add, rows for more destinations are ignored
+    pub max_routes_per_neighbour: usize,
+    /// Node routes are only advertised to nodes within this many hops, so the node table stays bounded in
+    /// large networks. A node beyond it is reached over the known node which is closest to it by XOR distance.
+    /// None advertises node routes to the whole network, which the rendezvous mode needs.
+    pub node_route_radius: Option<usize>,
+}
+
+impl RouterConfig {
+    pub fn validate(&self) -> Result<(), RouterConfigError> {
+        if self.mode == RoutingMode::Rendezvous && self.node_route_radius.is_some() {
+            return Err(RouterConfigError::RendezvousWithRadius);
+        }
+        Ok(())
+    }
+}
+
+impl Default for RouterConfig {
+    fn default() -> Self {
+        Self {
+            mode: RoutingMode::Flood,
+            delta_sync: false,
+            delta_threshold_percent: 10,
+            full_sync_interval_ms: 30_000,
+            trigger_threshold_percent: 10,
+            trigger_interval_ms: 100,
+            max_hops: 16,
+            hop_penalty_ms: 5,
+            min_hop_rtt_ms: 1,
+            announce_ttl_ms: 300_000,
+            route_timeout_ms: 5000,
+            max_routes_per_neighbour: 16_384,
+            node_route_radius: None,
+        }
+    }
+}
+
 pub struct Router {
     node: NodeId,
-    conns: HashMap<Connection, ConnectionStats>,
+    config: RouterConfig,
+    neighbours: HashMap<NodeId, Neighbour>,
+    remote_nodes: HashMap<NodeId, ChannelRoute>,
     remote_channels: HashMap<ChannelId, ChannelRoute>,
-    local_channels: HashMap<ChannelId, ()>,
+    local_channels: HashMap<ChannelId, ChannelAnnounce>,
+    /// Signed announcement of this node, its node path is only advertised with it
+    local_node: Option<ChannelAnnounce>,
+    /// Announcements whose signature was verified, by channel and signature, as long as a path carries them
+    verified: HashMap<(ChannelId, Vec<u8>), ChannelAnnounce>,
+    /// Node announcements whose signature was verified, by node and signature
+    verified_nodes: HashMap<(NodeId, Vec<u8>), ChannelAnnounce>,
+    syncs: HashMap<NodeId, NeighbourSync>,
+    outputs: VecDeque<OutputEvent>,
 }
 
 impl Router {
-    pub fn new(node: NodeId) -> Self {
+    pub fn new(node: NodeId, config: RouterConfig) -> Self {
         Self {
             node,
-            conns: HashMap::new(),
+            config,
+            neighbours: HashMap::new(),
+            remote_nodes: HashMap::new(),
             remote_channels: HashMap::new(),
             local_channels: HashMap::new(),
+            local_node: None,
+            verified: HashMap::new(),
+            verified_nodes: HashMap::new(),
+            syncs: HashMap::new(),
+            outputs: VecDeque::new(),
         }
     }
 
@@ -43,93 +157,1169 @@ impl Router {
         self.node
     }
 
-    pub fn add_channel(&mut self, channel: ChannelId) {
-        self.local_channels.insert(channel, ());
+    /// A new local channel is advertised to neighbours immediately with a triggered sync.
+    /// Adding a channel again renews its announcement, which is sent with the next sync.
+    pub fn add_channel(&mut self, now_ms: u64, channel: ChannelId, announce: ChannelAnnounce) {
+        if self.local_channels.insert(channel, announce).is_none() {
+            for sync in self.syncs.values_mut() {
+                sync.trigger(&[channel], &[]);
+            }
+            self.flush_triggered(now_ms);
+        }
+    }
+
+    /// Local channels whose announcement expires before the time
+    pub fn expiring_channels(&self, before_ms: u64) -> Vec<ChannelId> {
+        let mut channels = self
+            .local_channels
+            .iter()
+            .filter(|(_, announce)| announce.expires_ms < before_ms)
+            .map(|(channel, _)| *channel)
+            .collect::<Vec<_>>();
+        channels.sort();
+        channels
+    }
+
+    /// Set the announcement of this node, which is sent with the next sync. It must be renewed before it expires.
+    pub fn set_node_announce(&mut self, announce: ChannelAnnounce) {
+        self.local_node = Some(announce);
     }
 
+    /// True if this node has no announcement, or it expires before the time
+    pub fn node_announce_expiring(&self, before_ms: u64) -> bool {
+        self.local_node
+            .as_ref()
+            .is_none_or(|announce| announce.expires_ms < before_ms)
+    }
+
+    /// Removing a local channel withdraws it from all neighbours immediately,
+    /// unless we still have a path to another publisher of the same channel
     pub fn remove_channel(&mut self, channel: ChannelId) {
-        self.local_channels.remove(&channel);
+        if self.local_channels.remove(&channel).is_some() && !self.has_remote_channel(channel) {
+            self.send_withdraw(&[channel], &[]);
+        }
     }
 
     pub fn on_tick(&mut self, now_ms: u64) {
+        let timeout_ms = self.config.route_timeout_ms;
         for channel in self.remote_channels.values_mut() {
-            channel.on_tick(now_ms);
+            channel.on_tick(now_ms, timeout_ms);
+        }
+        self.remote_channels.retain(|_, c| !c.is_empty());
+        for node in self.remote_nodes.values_mut() {
+            node.on_tick(now_ms, timeout_ms);
         }
+        self.remote_nodes.retain(|_, n| !n.is_empty());
+        let remote_channels = &self.remote_channels;
+        self.verified.retain(|(channel, _), announce| {
+            remote_channels
+                .get(channel)
+                .is_some_and(|c| c.has_announce(announce))
+        });
+        let remote_nodes = &self.remote_nodes;
+        self.verified_nodes.retain(|(node, _), announce| {
+            remote_nodes
+                .get(node)
+                .is_some_and(|n| n.has_announce(announce))
+        });
+        self.create_sync(now_ms);
+        self.flush_triggered(now_ms);
     }
 
     pub fn on_event(&mut self, now_ms: u64, event: InputEvent) {
         match event {
             InputEvent::Recv(msg) => {
                 let NetworkMsg { conn, msg } = msg;
+                let (channel_scores, node_scores) = self.route_scores();
+                let from = conn.node();
+                // paths are measured with the neighbour best connection, which is the one used for data
+                let stats = if let Some((_, stats)) =
+                    self.neighbours.get(&from).and_then(|n| n.best_conn())
+                {
+                    *stats
+                } else {
+                    log::warn!("Sync from unknown connection {:?}", conn);
+                    return;
+                };
+
+                let sync = self.syncs.entry(from).or_insert_with(NeighbourSync::new);
+                if !sync.on_recv(msg.version, msg.full()) {
+                    log::warn!("Sync version gap from {:?}, request full table", conn);
+                    self.outputs.push_back(OutputEvent::SyncRequest(NetworkMsg {
+                        conn,
+                        msg: RouterSyncRequest {},
+                    }));
+                }
+
+                // full table replaces all paths over the neighbour, delta only refreshes them
+                for route in self
+                    .remote_channels
+                    .values_mut()
+                    .chain(self.remote_nodes.values_mut())
+                {
+                    if msg.full() {
+                        route.on_withdraw(from);
+                    } else {
+                        route.on_refresh(now_ms, from);
+                    }
+                }
+
+                let mut routes = self
+                    .remote_channels
+                    .values()
+                    .chain(self.remote_nodes.values())
+                    .filter(|r| r.has_path(from))
+                    .count();
+                let (mut forged, mut ignored) = (false, 0);
                 for row in msg.rows {
-                    if let Some(channel) = self.remote_channels.get_mut(&(row.channel.into())) {
-                        if let Some(stats) = self.conns.get(&conn) {
-                            let mut path = ChannelPath::from_row(now_ms, row);
-                            path.metric = path.metric.add_local(stats);
-                            path.hops.push(conn.node());
-                            channel.on_sync(now_ms, conn, path);
+                    let channel_id = row.channel.into();
+                    let mut path = ChannelPath::from_row(now_ms, row);
+                    path.hops.push(from);
+                    let mut rejected = !path.metric.is_infinite()
+                        && (!self.is_acceptable(&path)
+                            || match self.check_announce(now_ms, channel_id, &path) {
+                                // an announcement can expire while the row is in flight
+                                Err(AnnounceError::Expired) => true,
+                                Err(_) => {
+                                    forged = true;
+                                    true
+                                }
+                                Ok(()) => false,
+                            });
+                    let known = self
+                        .remote_channels
+                        .get(&channel_id)
+                        .is_some_and(|c| c.has_path(from));
+                    if !rejected && !path.metric.is_infinite() && !known {
+                        if routes >= self.config.max_routes_per_neighbour {
+                            ignored += 1;
+                            rejected = true;
+                        } else {
+                            routes += 1;
                         }
-                    } else {
-                        log::warn!("Unknown channel {}", row.channel);
                     }
+                    if path.metric.is_infinite() || rejected {
+                        if let Some(channel) = self.remote_channels.get_mut(&channel_id) {
+                            channel.on_withdraw(from);
+                        }
+                        continue;
+                    }
+                    let hop_penalty_ms = self.config.hop_penalty_ms;
+                    let channel = self
+                        .remote_channels
+                        .entry(channel_id)
+                        .or_insert_with(|| ChannelRoute::new(hop_penalty_ms));
+                    path.metric = path.metric.add_hop(&stats, self.config.min_hop_rtt_ms);
+                    channel.on_sync(now_ms, from, path);
+                }
+                for row in msg.nodes {
+                    let node_id = row.node.into();
+                    if node_id == self.node {
+                        continue;
+                    }
+                    let mut path = ChannelPath::from_node_row(now_ms, row);
+                    path.hops.push(from);
+                    let known = self
+                        .remote_nodes
+                        .get(&node_id)
+                        .is_some_and(|n| n.has_path(from));
+                    let mut rejected = !path.metric.is_infinite()
+                        && (!self.is_acceptable(&path)
+                            || match self.check_node_announce(now_ms, node_id, &path) {
+                                Err(AnnounceError::Expired) => true,
+                                Err(_) => {
+                                    forged = true;
+                                    true
+                                }
+                                Ok(()) => false,
+                            });
+                    if !rejected && !path.metric.is_infinite() && !known {
+                        if routes >= self.config.max_routes_per_neighbour {
+                            ignored += 1;
+                            rejected = true;
+                        } else {
+                            routes += 1;
+                        }
+                    }
+                    if path.metric.is_infinite() || rejected {
+                        if let Some(node) = self.remote_nodes.get_mut(&node_id) {
+                            node.on_withdraw(from);
+                        }
+                        continue;
+                    }
+                    let hop_penalty_ms = self.config.hop_penalty_ms;
+                    let node = self
+                        .remote_nodes
+                        .entry(node_id)
+                        .or_insert_with(|| ChannelRoute::new(hop_penalty_ms));
+                    path.metric = path.metric.add_hop(&stats, self.config.min_hop_rtt_ms);
+                    node.on_sync(now_ms, from, path);
+                }
+                if forged {
+                    self.outputs
+                        .push_back(OutputEvent::Misbehaved(from, Misbehaviour::ForgedRoute));
+                }
+                // a large network can exceed the limit, so the rows above it are ignored without a penalty
+                if ignored > 0 {
+                    log::warn!("Ignore {} routes of {:?} above the limit", ignored, from);
+                }
+                self.on_routes_changed(now_ms, channel_scores, node_scores);
+            }
+            InputEvent::RecvSyncRequest(msg) => {
+                if let Some(sync) = self.syncs.get_mut(&msg.conn.node()) {
+                    sync.request_full();
                 }
             }
             InputEvent::ConnectionDisconnected(conn) => {
-                self.conns.remove(&conn);
+                let node = conn.node();
+                let neighbour = if let Some(neighbour) = self.neighbours.get_mut(&node) {
+                    neighbour
+                } else {
+                    self.syncs.remove(&node);
+                    return;
+                };
+                neighbour.on_disconnected(conn);
+                if !neighbour.is_empty() {
+                    // fail over to the other connections of the neighbour, routes are not changed
+                    return;
+                }
+
+                let (channel_scores, node_scores) = self.route_scores();
+                self.neighbours.remove(&node);
+                self.syncs.remove(&node);
                 for channel in self.remote_channels.values_mut() {
-                    channel.on_disconnected(conn);
+                    channel.on_disconnected(node);
+                }
+                for remote in self.remote_nodes.values_mut() {
+                    remote.on_disconnected(node);
                 }
+                self.on_routes_changed(now_ms, channel_scores, node_scores);
             }
             InputEvent::ConnectionStats(stats) => {
                 let NetworkMsg { conn, msg } = stats;
-                self.conns.insert(conn, msg);
+                self.neighbours
+                    .entry(conn.node())
+                    .or_insert_with(Neighbour::new)
+                    .on_stats(conn, msg);
             }
         }
     }
 
+    /// Next hop for sending Sub to the channel publisher.
+    /// In Rendezvous mode, if we don't know the channel route, the Sub is sent toward the rendezvous node.
     pub fn next_hop_for(&self, channel: ChannelId) -> Option<NextHop> {
         if self.local_channels.contains_key(&channel) {
-            Some(NextHop::Local)
-        } else {
-            self.remote_channels
-                .get(&channel)
-                .and_then(|c| c.next_hop().map(NextHop::Remote))
+            return Some(NextHop::Local);
+        }
+        if let Some(conn) = self
+            .remote_channels
+            .get(&channel)
+            .and_then(|c| c.next_hop())
+            .and_then(|node| self.best_conn(node))
+        {
+            return Some(NextHop::Remote(conn));
+        }
+        match self.config.mode {
+            RoutingMode::Flood => None,
+            RoutingMode::Rendezvous => self.next_hop_for_node(self.rendezvous_for(channel)),
+        }
+    }
+
+    /// Announcement of the publisher which the best path of the channel leads to, the local one if it is published here
+    pub fn announce_for(&self, channel: ChannelId) -> Option<&ChannelAnnounce> {
+        self.local_channels
+            .get(&channel)
+            .or_else(|| self.remote_channels.get(&channel)?.best_announce())
+    }
+
+    /// A new neighbour is reachable over its direct connection until its node route is synced.
+    /// With a node route radius, a node without route is approached over the closest known node.
+    pub fn next_hop_for_node(&self, node: NodeId) -> Option<NextHop> {
+        if node == self.node {
+            return Some(NextHop::Local);
         }
+        let route = |node: NodeId| {
+            self.remote_nodes
+                .get(&node)
+                .and_then(|n| n.next_hop())
+                .and_then(|next| self.best_conn(next))
+                .or_else(|| self.best_conn(node))
+        };
+        route(node)
+            .or_else(|| {
+                self.config.node_route_radius?;
+                route(self.closest_known(node)?)
+            })
+            .map(NextHop::Remote)
+    }
+
+    /// Known node which is closer to the target by XOR distance than this node, like a step of a lookup,
+    /// the unicast TTL bounds the hops when the target can't be found
+    fn closest_known(&self, target: NodeId) -> Option<NodeId> {
+        self.remote_nodes
+            .iter()
+            .filter(|(_, n)| !n.is_empty())
+            .map(|(id, _)| *id)
+            .chain(self.neighbours.keys().copied())
+            .filter(|id| **id ^ *target < *self.node ^ *target)
+            .min_by_key(|id| **id ^ *target)
+    }
+
+    /// Remote nodes which currently have a route, with the score of their best path
+    pub fn node_routes(&self) -> impl Iterator<Item = (NodeId, u32)> + '_ {
+        self.remote_nodes
+            .iter()
+            .filter_map(|(id, n)| n.best_score().map(|score| (*id, score)))
+    }
+
+    /// Remote channels which currently have a route, in rendezvous mode only the ones this node is on the way to
+    /// their rendezvous node for
+    pub fn channel_routes(&self) -> usize {
+        self.remote_channels
+            .values()
+            .filter(|c| !c.is_empty())
+            .count()
+    }
+
+    pub fn config(&self) -> &RouterConfig {
+        &self.config
+    }
+
+    /// Whether the neighbour negotiated delta sync in its handshake, without it the neighbour gets full tables only
+    pub fn set_delta_sync(&mut self, node: NodeId, delta: bool) {
+        self.syncs
+            .entry(node)
+            .or_insert_with(NeighbourSync::new)
+            .set_delta(delta);
+    }
+
+    /// The rendezvous node of a channel is the known node which is closest to the channel key by XOR distance.
+    /// The channel id is spread over the NodeId space, otherwise all channels would meet at the lowest NodeIds.
+    pub fn rendezvous_for(&self, channel: ChannelId) -> NodeId {
+        let key = u64::from(*channel).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        self.remote_nodes.keys().fold(self.node, |best, node| {
+            if **node ^ key < *best ^ key {
+                *node
+            } else {
+                best
+            }
+        })
+    }
+
+    pub fn pop_output(&mut self) -> Option<OutputEvent> {
+        self.outputs.pop_front()
     }
 
     /// Create sync messages for all channels
-    /// Each sync message contains the best path for the channel without relaying over destination node
+    /// Each sync message contains the best path for the channel without relaying over destination node,
+    /// or an infinite metric if all paths relay over destination node (poison reverse)
     /// If local has channel, it will be included in the sync message, if not it will check remote channels
-    pub fn create_sync(&self) -> Vec<NetworkMsg<RouterSync>> {
-        let mut outputs = vec![];
-        for conn in self.conns.keys() {
-            let mut rows = vec![];
-            for (id, _) in self.local_channels.iter() {
-                rows.push(RouterRow {
-                    channel: **id,
-                    bandwidth: 10_000_000, //10Gbps
-                    rtt: 0,
-                    loss: 0.0,
-                    jitter: 0,
-                    hops: vec![],
-                });
+    /// Node routes are always included, in Rendezvous mode channel routes are only sent toward the channel rendezvous node
+    /// In delta mode, only the rows which changed since the last sync to the connection are included
+    fn create_sync(&mut self, now_ms: u64) {
+        let mut neighbours = self.neighbours.keys().copied().collect::<Vec<_>>();
+        neighbours.sort();
+        for node in neighbours {
+            let conn = if let Some(conn) = self.best_conn(node) {
+                conn
+            } else {
+                continue;
+            };
+            let (channels, nodes) = self.neighbour_table(node);
+            let sync = self.syncs.entry(node).or_insert_with(NeighbourSync::new);
+            if let Some(msg) = sync.create_sync(now_ms, &self.config, channels, nodes) {
+                self.outputs
+                    .push_back(OutputEvent::Sync(NetworkMsg { conn, msg }));
             }
+        }
+    }
 
-            for (id, channel) in self.remote_channels.iter() {
-                if self.local_channels.contains_key(id) {
-                    continue;
+    /// Send pending triggered syncs to the neighbours which are not rate limited,
+    /// the others will send them later or include them in the next periodic sync
+    fn flush_triggered(&mut self, now_ms: u64) {
+        let mut neighbours = self
+            .syncs
+            .iter()
+            .filter(|(_, sync)| sync.can_trigger(now_ms, &self.config))
+            .map(|(node, _)| *node)
+            .collect::<Vec<_>>();
+        neighbours.sort();
+        for node in neighbours {
+            let conn = if let Some(conn) = self.best_conn(node) {
+                conn
+            } else {
+                continue;
+            };
+            let (channels, nodes) = self.neighbour_table(node);
+            if let Some(sync) = self.syncs.get_mut(&node) {
+                if let Some(msg) = sync.create_triggered(now_ms, &self.config, channels, nodes) {
+                    self.outputs
+                        .push_back(OutputEvent::Sync(NetworkMsg { conn, msg }));
                 }
-                if let Some(row) = channel.create_sync(conn.node()) {
-                    rows.push(row.to_row(*id));
+            }
+        }
+    }
+
+    /// The table which should be advertised to a neighbour
+    fn neighbour_table(
+        &self,
+        dest: NodeId,
+    ) -> (
+        HashMap<ChannelId, ChannelPath>,
+        HashMap<NodeId, ChannelPath>,
+    ) {
+        let mut channels = HashMap::new();
+        for (id, announce) in self.local_channels.iter() {
+            if self.should_sync_channel(*id, dest) {
+                channels.insert(*id, ChannelPath::local_channel(announce.clone()));
+            }
+        }
+        for (id, channel) in self.remote_channels.iter() {
+            if self.local_channels.contains_key(id) || !self.should_sync_channel(*id, dest) {
+                continue;
+            }
+            // poison reverse: a neighbour which is in all our paths learns that we can't reach the channel
+            let path = channel
+                .create_sync(dest)
+                .filter(|p| p.hops.len() < self.config.max_hops)
+                .unwrap_or_else(ChannelPath::withdrawn);
+            channels.insert(*id, path);
+        }
+
+        let mut nodes = HashMap::new();
+        if let Some(announce) = self.local_node.as_ref() {
+            nodes.insert(self.node, ChannelPath::local_channel(announce.clone()));
+        }
+        let max_hops = self
+            .config
+            .node_route_radius
+            .map_or(self.config.max_hops, |radius| {
+                radius.min(self.config.max_hops)
+            });
+        for (id, node) in self.remote_nodes.iter() {
+            let path = match node.create_sync(dest) {
+                Some(path) if path.hops.len() < max_hops => path,
+                // a node beyond the radius is left out of the table, like a node without route
+                Some(_) if max_hops < self.config.max_hops => continue,
+                _ => ChannelPath::withdrawn(),
+            };
+            nodes.insert(*id, path);
+        }
+        (channels, nodes)
+    }
+
+    /// A received path is rejected if it is too long, loops over this node,
+    /// or claims a lower rtt than its hops can have
+    fn is_acceptable(&self, path: &ChannelPath) -> bool {
+        if path.hops.len() > self.config.max_hops {
+            log::debug!("Reject path with {} hops", path.hops.len());
+            return false;
+        }
+        if path.hops.contains(&self.node) {
+            log::debug!("Reject path which loops over this node");
+            return false;
+        }
+        // the last hop is the neighbour, whose connection is not in the metric yet
+        let min_rtt = (path.hops.len() as u64 - 1) * self.config.min_hop_rtt_ms as u64;
+        if (path.metric.rtt as u64) < min_rtt {
+            log::warn!(
+                "Reject path over {:?} with rtt {} below {} for its hops",
+                path.hops.last(),
+                path.metric.rtt,
+                min_rtt
+            );
+            return false;
+        }
+        true
+    }
+
+    /// A channel path must carry a valid announcement of the publisher at its origin,
+    /// the signature of an announcement is only verified once
+    fn check_announce(
+        &mut self,
+        now_ms: u64,
+        channel: ChannelId,
+        path: &ChannelPath,
+    ) -> Result<(), AnnounceError> {
+        let origin = path.hops[0];
+        let result = match path.announce.as_ref() {
+            None => Err(AnnounceError::Missing),
+            Some(announce) => {
+                let key = (channel, announce.signature.clone());
+                if self.verified.get(&key) == Some(announce) {
+                    announce::check(now_ms, origin, announce)
+                } else {
+                    let result = announce::verify(now_ms, channel, origin, announce);
+                    if result.is_ok() {
+                        self.verified.insert(key, announce.clone());
+                    }
+                    result
                 }
             }
-            if !rows.is_empty() {
-                outputs.push(NetworkMsg {
-                    conn: conn.clone(),
-                    msg: protocol::RouterSync { rows },
-                });
+        };
+        if let Err(e) = &result {
+            log::warn!(
+                "Reject path of {:?} over {:?}: {:?}",
+                channel,
+                path.hops.last(),
+                e
+            );
+        }
+        result
+    }
+
+    /// A node path must start at the node and carry its valid announcement, like a channel path
+    fn check_node_announce(
+        &mut self,
+        now_ms: u64,
+        node: NodeId,
+        path: &ChannelPath,
+    ) -> Result<(), AnnounceError> {
+        let origin = path.hops[0];
+        let result = match path.announce.as_ref() {
+            None => Err(AnnounceError::Missing),
+            Some(announce) => {
+                let key = (node, announce.signature.clone());
+                if origin == node && self.verified_nodes.get(&key) == Some(announce) {
+                    announce::check(now_ms, origin, announce)
+                } else {
+                    let result = announce::verify_node(now_ms, node, origin, announce);
+                    if result.is_ok() {
+                        self.verified_nodes.insert(key, announce.clone());
+                    }
+                    result
+                }
             }
+        };
+        if let Err(e) = &result {
+            log::warn!(
+                "Reject path of {:?} over {:?}: {:?}",
+                node,
+                path.hops.last(),
+                e
+            );
+        }
+        result
+    }
+
+    fn has_remote_channel(&self, channel: ChannelId) -> bool {
+        self.remote_channels
+            .get(&channel)
+            .is_some_and(|c| !c.is_empty())
+    }
+
+    fn has_remote_node(&self, node: NodeId) -> bool {
+        self.remote_nodes.get(&node).is_some_and(|n| !n.is_empty())
+    }
+
+    /// Best score of the remote channels and nodes which currently have at least one path
+    fn route_scores(&self) -> (HashMap<ChannelId, u32>, HashMap<NodeId, u32>) {
+        let channels = self
+            .remote_channels
+            .iter()
+            .filter_map(|(id, c)| c.best_score().map(|score| (*id, score)))
+            .collect();
+        let nodes = self
+            .remote_nodes
+            .iter()
+            .filter_map(|(id, n)| n.best_score().map(|score| (*id, score)))
+            .collect();
+        (channels, nodes)
+    }
+
+    /// Compare routes with their scores before an event.
+    /// Routes which lost their last path are withdrawn immediately, so that dependent paths are flushed quickly.
+    /// New routes and routes whose best score changed significantly are sent with a triggered sync.
+    fn on_routes_changed(
+        &mut self,
+        now_ms: u64,
+        channel_scores: HashMap<ChannelId, u32>,
+        node_scores: HashMap<NodeId, u32>,
+    ) {
+        let threshold = self.config.trigger_threshold_percent;
+        let lost_channels = channel_scores
+            .keys()
+            .filter(|id| !self.local_channels.contains_key(id) && !self.has_remote_channel(**id))
+            .copied()
+            .collect::<Vec<_>>();
+        let lost_nodes = node_scores
+            .keys()
+            .filter(|id| !self.has_remote_node(**id))
+            .copied()
+            .collect::<Vec<_>>();
+        if !lost_channels.is_empty() || !lost_nodes.is_empty() {
+            self.send_withdraw(&lost_channels, &lost_nodes);
+        }
+
+        let changed_channels = self
+            .remote_channels
+            .iter()
+            .filter(|(id, _)| !self.local_channels.contains_key(id))
+            .filter_map(|(id, c)| c.best_score().map(|score| (*id, score)))
+            .filter(|(id, score)| is_significant(channel_scores.get(id), *score, threshold))
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        let changed_nodes = self
+            .remote_nodes
+            .iter()
+            .filter_map(|(id, n)| n.best_score().map(|score| (*id, score)))
+            .filter(|(id, score)| is_significant(node_scores.get(id), *score, threshold))
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        if !changed_channels.is_empty() || !changed_nodes.is_empty() {
+            for sync in self.syncs.values_mut() {
+                sync.trigger(&changed_channels, &changed_nodes);
+            }
+        }
+        self.flush_triggered(now_ms);
+    }
+
+    fn send_withdraw(&mut self, channels: &[ChannelId], nodes: &[NodeId]) {
+        let mut neighbours = self.syncs.keys().copied().collect::<Vec<_>>();
+        neighbours.sort();
+        for node in neighbours {
+            let conn = if let Some(conn) = self.best_conn(node) {
+                conn
+            } else {
+                continue;
+            };
+            let sync = if let Some(sync) = self.syncs.get_mut(&node) {
+                sync
+            } else {
+                continue;
+            };
+            if let Some(msg) = sync.create_withdraw(channels, nodes) {
+                self.outputs
+                    .push_back(OutputEvent::Sync(NetworkMsg { conn, msg }));
+            }
+        }
+    }
+
+    fn best_conn(&self, node: NodeId) -> Option<Connection> {
+        self.neighbours
+            .get(&node)
+            .and_then(|n| n.best_conn())
+            .map(|(conn, _)| conn)
+    }
+
+    fn should_sync_channel(&self, channel: ChannelId, dest: NodeId) -> bool {
+        match self.config.mode {
+            RoutingMode::Flood => true,
+            RoutingMode::Rendezvous => matches!(
+                self.next_hop_for_node(self.rendezvous_for(channel)),
+                Some(NextHop::Remote(next)) if next.node() == dest
+            ),
+        }
+    }
+}
+
+/// A new route or a score change bigger than threshold percent
+fn is_significant(before: Option<&u32>, after: u32, threshold_percent: u32) -> bool {
+    match before {
+        Some(before) => {
+            let before = *before as u64;
+            before.abs_diff(after as u64) * 100 > before * threshold_percent as u64
+        }
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        identity::NodeKey,
+        protocol::{NodeRow, RouterRow},
+        router::{announce::ChannelPolicy, path::ChannelPath},
+    };
+
+    use super::*;
+
+    fn stats(rtt_ms: u32) -> ConnectionStats {
+        ConnectionStats {
+            rtt_ms,
+            lost_percent: 0.0.into(),
+            jitter_ms: 0,
+            bandwidth_kbps: 10_000,
+        }
+    }
+
+    fn key(i: usize) -> NodeKey {
+        NodeKey::from_secret([i as u8 + 1; 32])
+    }
+
+    /// Routers connected by links, syncs are delivered immediately until nothing is pending
+    struct Net {
+        pub routers: Vec<Router>,
+        /// Nodes of each live link, by session
+        links: BTreeMap<u32, (usize, usize)>,
+        pub now_ms: u64,
+    }
+
+    impl Net {
+        pub fn new(count: usize, config: RouterConfig) -> Self {
+            Self {
+                routers: (0..count)
+                    .map(|i| {
+                        let mut router = Router::new(key(i).node_id(), config.clone());
+                        router.set_node_announce(announce::sign_node(&key(i), u64::MAX));
+                        router
+                    })
+                    .collect(),
+                links: BTreeMap::new(),
+                now_ms: 0,
+            }
+        }
+
+        pub fn node(&self, i: usize) -> NodeId {
+            self.routers[i].node()
+        }
+
+        /// Connection toward a router over the session
+        pub fn conn(&self, to: usize, session: u32) -> Connection {
+            Connection::from_parts(self.node(to), session)
+        }
+
+        pub fn link(&mut self, a: usize, b: usize, rtt_ms: u32) -> u32 {
+            let session = self.links.keys().last().map_or(0, |s| s + 1);
+            self.links.insert(session, (a, b));
+            for (from, to) in [(a, b), (b, a)] {
+                let conn = self.conn(to, session);
+                self.routers[from].on_event(
+                    self.now_ms,
+                    InputEvent::ConnectionStats(NetworkMsg {
+                        conn,
+                        msg: stats(rtt_ms),
+                    }),
+                );
+            }
+            session
+        }
+
+        pub fn unlink(&mut self, session: u32) {
+            if let Some((a, b)) = self.links.remove(&session) {
+                for (from, to) in [(a, b), (b, a)] {
+                    let conn = self.conn(to, session);
+                    self.routers[from]
+                        .on_event(self.now_ms, InputEvent::ConnectionDisconnected(conn));
+                }
+            }
+            self.flush();
+        }
+
+        pub fn add_channel(&mut self, i: usize, channel: ChannelId) {
+            let announce = announce::sign(&key(i), channel, u64::MAX, &ChannelPolicy::default());
+            self.routers[i].add_channel(self.now_ms, channel, announce);
+            self.flush();
+        }
+
+        pub fn tick(&mut self, count: usize) {
+            for _ in 0..count {
+                self.now_ms += 1000;
+                for router in self.routers.iter_mut() {
+                    router.on_tick(self.now_ms);
+                }
+                self.flush();
+            }
+        }
+
+        /// Deliver the outputs of all routers over live links, the others are lost
+        pub fn flush(&mut self) {
+            loop {
+                let mut msgs = vec![];
+                for (i, router) in self.routers.iter_mut().enumerate() {
+                    while let Some(output) = router.pop_output() {
+                        msgs.push((i, output));
+                    }
+                }
+                if msgs.is_empty() {
+                    return;
+                }
+                for (from, output) in msgs {
+                    let (conn, event) = match output {
+                        OutputEvent::Sync(NetworkMsg { conn, msg }) => {
+                            let back = Connection::from_parts(self.node(from), conn.session());
+                            (conn, InputEvent::Recv(NetworkMsg { conn: back, msg }))
+                        }
+                        OutputEvent::SyncRequest(NetworkMsg { conn, msg }) => {
+                            let back = Connection::from_parts(self.node(from), conn.session());
+                            (
+                                conn,
+                                InputEvent::RecvSyncRequest(NetworkMsg { conn: back, msg }),
+                            )
+                        }
+                        OutputEvent::Misbehaved(..) => continue,
+                    };
+                    let to = match self.links.get(&conn.session()) {
+                        Some((a, b)) if *a == from => *b,
+                        Some((a, b)) if *b == from => *a,
+                        _ => continue,
+                    };
+                    self.routers[to].on_event(self.now_ms, event);
+                }
+            }
+        }
+
+        /// Index of the next hop of a router toward the channel, itself for the publisher
+        pub fn next_hop(&self, i: usize, channel: ChannelId) -> Option<usize> {
+            let node = match self.routers[i].next_hop_for(channel)? {
+                NextHop::Local => self.node(i),
+                NextHop::Remote(conn) => conn.node(),
+            };
+            (0..self.routers.len()).find(|j| self.node(*j) == node)
+        }
+
+        pub fn has_path(&self, i: usize, channel: ChannelId, over: usize) -> bool {
+            self.routers[i]
+                .remote_channels
+                .get(&channel)
+                .is_some_and(|c| c.has_path(self.node(over)))
+        }
+    }
+
+    /// Row of a channel which the publisher announces, whose path starts at the publisher and goes over the hops
+    fn channel_row(channel: ChannelId, publisher: usize, hops: &[NodeId], rtt: u32) -> RouterRow {
+        let announce = announce::sign(
+            &key(publisher),
+            channel,
+            u64::MAX,
+            &ChannelPolicy::default(),
+        );
+        let mut path = ChannelPath::local_channel(announce);
+        path.hops = [key(publisher).node_id()]
+            .into_iter()
+            .chain(hops.iter().copied())
+            .collect();
+        path.metric.rtt = rtt;
+        path.to_row(channel)
+    }
+
+    /// Row of a node which announces itself, whose path starts at the node and goes over the hops
+    fn node_row(node: usize, hops: &[NodeId], rtt: u32) -> NodeRow {
+        let mut path = ChannelPath::local_channel(announce::sign_node(&key(node), u64::MAX));
+        path.hops = [key(node).node_id()]
+            .into_iter()
+            .chain(hops.iter().copied())
+            .collect();
+        path.metric.rtt = rtt;
+        path.to_node_row(key(node).node_id())
+    }
+
+    fn full_sync(rows: Vec<RouterRow>) -> RouterSync {
+        RouterSync {
+            rows,
+            nodes: vec![],
+            version: Some(1),
+            full: Some(true),
+        }
+    }
+
+    #[test]
+    fn withdrawn_channel_is_removed_without_timeout() {
+        let mut net = Net::new(3, RouterConfig::default());
+        net.link(0, 1, 20);
+        net.link(1, 2, 20);
+        let channel = ChannelId::from(1);
+        net.add_channel(0, channel);
+        net.tick(3);
+        assert_eq!(net.next_hop(2, channel), Some(1));
+
+        // the withdrawal is sent at once and flushes the dependent paths, no tick passes
+        net.routers[0].remove_channel(channel);
+        net.flush();
+        assert_eq!(net.next_hop(1, channel), None);
+        assert_eq!(net.next_hop(2, channel), None);
+    }
+
+    #[test]
+    fn poison_reverse_withdraws_path_over_receiver() {
+        let mut net = Net::new(3, RouterConfig::default());
+        let (ab, _, bc) = (net.link(0, 1, 20), net.link(0, 2, 20), net.link(1, 2, 20));
+        let channel = ChannelId::from(1);
+        net.add_channel(0, channel);
+        net.tick(3);
+        assert!(net.has_path(1, channel, 2));
+        assert!(net.has_path(2, channel, 1));
+
+        // all paths of 1 now go over 2, so 1 advertises the channel to 2 as unreachable
+        net.unlink(ab);
+        let (channels, _) = net.routers[1].neighbour_table(net.node(2));
+        assert!(channels[&channel].metric.is_infinite());
+        assert_eq!(net.next_hop(1, channel), Some(2));
+        assert!(!net.has_path(2, channel, 1));
+        net.tick(3);
+        assert!(!net.has_path(2, channel, 1));
+        assert_eq!(net.next_hop(2, channel), Some(0));
+
+        net.unlink(bc);
+        assert_eq!(net.next_hop(1, channel), None);
+    }
+
+    #[test]
+    fn triangle_does_not_count_to_infinity() {
+        let mut net = Net::new(3, RouterConfig::default());
+        let (ab, ac, _) = (net.link(0, 1, 20), net.link(0, 2, 20), net.link(1, 2, 20));
+        let channel = ChannelId::from(1);
+        net.add_channel(0, channel);
+        net.tick(3);
+
+        // 1 and 2 each have a path over the other one, which must not keep the channel alive
+        net.unlink(ab);
+        net.unlink(ac);
+        assert_eq!(net.next_hop(1, channel), None);
+        assert_eq!(net.next_hop(2, channel), None);
+        for _ in 0..10 {
+            net.tick(1);
+            assert_eq!(net.next_hop(1, channel), None);
+            assert_eq!(net.next_hop(2, channel), None);
+        }
+    }
+
+    #[test]
+    fn rejects_self_loops_and_long_paths() {
+        let mut net = Net::new(
+            2,
+            RouterConfig {
+                max_hops: 4,
+                ..Default::default()
+            },
+        );
+        let session = net.link(0, 1, 20);
+        let (me, other) = (net.node(0), NodeId::from(7));
+        let (looped, long, fine) = (ChannelId::from(1), ChannelId::from(2), ChannelId::from(3));
+        let sync = full_sync(vec![
+            channel_row(looped, 5, &[me], 100),
+            // with the neighbour, the path has 5 hops
+            channel_row(long, 5, &[other, other, other], 100),
+            channel_row(fine, 5, &[other, other], 100),
+        ]);
+        let conn = net.conn(1, session);
+        net.routers[0].on_event(0, InputEvent::Recv(NetworkMsg { conn, msg: sync }));
+
+        assert_eq!(net.next_hop(0, looped), None);
+        assert_eq!(net.next_hop(0, long), None);
+        assert_eq!(net.next_hop(0, fine), Some(1));
+    }
+
+    #[test]
+    fn rejects_forged_channel_rows() {
+        let mut net = Net::new(2, RouterConfig::default());
+        let session = net.link(0, 1, 20);
+        let channel = ChannelId::from(1);
+        // a genuine announcement of a publisher behind the neighbour
+        let honest = channel_row(channel, 5, &[], 20);
+        let unsigned = RouterRow {
+            announce: None,
+            ..honest.clone()
+        };
+        let originated = RouterRow {
+            hops: vec![],
+            ..honest.clone()
+        };
+        let implausible = RouterRow {
+            rtt: 0,
+            ..honest.clone()
+        };
+        let mut extended = honest.clone();
+        if let Some(announce) = extended.announce.as_mut() {
+            announce.expires_ms -= 1;
+        }
+
+        let conn = net.conn(1, session);
+        let offer = |net: &mut Net, row: RouterRow| {
+            let msg = full_sync(vec![row]);
+            net.routers[0].on_event(0, InputEvent::Recv(NetworkMsg { conn, msg }));
+            net.next_hop(0, channel)
+        };
+        for forged in [unsigned, originated, implausible, extended] {
+            assert_eq!(offer(&mut net, forged), None);
+        }
+        assert_eq!(offer(&mut net, honest), Some(1));
+    }
+
+    #[test]
+    fn rejects_forged_node_rows() {
+        let mut net = Net::new(2, RouterConfig::default());
+        let session = net.link(0, 1, 20);
+        let node = key(5).node_id();
+        let honest = node_row(5, &[], 20);
+        let unsigned = NodeRow {
+            announce: None,
+            ..honest.clone()
+        };
+        // the neighbour claims to be the node, or to be next to it without any latency
+        let originated = NodeRow {
+            hops: vec![],
+            ..honest.clone()
+        };
+        let implausible = NodeRow {
+            rtt: 0,
+            ..honest.clone()
+        };
+        let stolen = NodeRow {
+            announce: node_row(6, &[], 20).announce,
+            ..honest.clone()
+        };
+
+        let conn = net.conn(1, session);
+        let offer = |net: &mut Net, row: NodeRow| {
+            let msg = RouterSync {
+                nodes: vec![row],
+                ..full_sync(vec![])
+            };
+            net.routers[0].on_event(0, InputEvent::Recv(NetworkMsg { conn, msg }));
+            net.routers[0].has_remote_node(node)
+        };
+        for forged in [unsigned, originated, implausible, stolen] {
+            assert!(!offer(&mut net, forged));
+        }
+        assert!(offer(&mut net, honest));
+    }
+
+    #[test]
+    fn ignores_routes_above_the_limit() {
+        let mut net = Net::new(
+            2,
+            RouterConfig {
+                max_routes_per_neighbour: 1,
+                ..Default::default()
+            },
+        );
+        let session = net.link(0, 1, 20);
+        while net.routers[0].pop_output().is_some() {}
+        let conn = net.conn(1, session);
+        let msg = RouterSync {
+            nodes: vec![node_row(5, &[], 20), node_row(6, &[], 20)],
+            ..full_sync(vec![])
+        };
+        net.routers[0].on_event(0, InputEvent::Recv(NetworkMsg { conn, msg }));
+        assert!(net.routers[0].has_remote_node(key(5).node_id()));
+        assert!(!net.routers[0].has_remote_node(key(6).node_id()));
+        // an honest neighbour in a large network can exceed the limit, so it is not penalized
+        while let Some(output) = net.routers[0].pop_output() {
+            assert!(!matches!(output, OutputEvent::Misbehaved(..)));
+        }
+    }
+
+    #[test]
+    fn hop_penalty_prefers_shorter_path() {
+        let prefer = |hop_penalty_ms| {
+            let mut net = Net::new(
+                3,
+                RouterConfig {
+                    hop_penalty_ms,
+                    ..Default::default()
+                },
+            );
+            let sessions = [net.link(0, 1, 20), net.link(0, 2, 20)];
+            // on a tie, the neighbour with the lower id wins, so it gets the longer path
+            let (low, high) = if net.node(1) < net.node(2) {
+                (1, 2)
+            } else {
+                (2, 1)
+            };
+            let channel = ChannelId::from(1);
+            for (neighbour, hops) in [(low, vec![NodeId::from(7)]), (high, vec![])] {
+                let sync = full_sync(vec![channel_row(channel, 5, &hops, 50)]);
+                let conn = net.conn(neighbour, sessions[neighbour - 1]);
+                net.routers[0].on_event(0, InputEvent::Recv(NetworkMsg { conn, msg: sync }));
+            }
+            (net.next_hop(0, channel), low, high)
+        };
+        let (next, _, high) = prefer(5);
+        assert_eq!(next, Some(high));
+        let (next, low, _) = prefer(0);
+        assert_eq!(next, Some(low));
+    }
+
+    #[test]
+    fn lost_connection_fails_over_to_other_connection_of_neighbour() {
+        let mut net = Net::new(2, RouterConfig::default());
+        let fast = net.link(0, 1, 20);
+        let slow = net.link(0, 1, 40);
+        let channel = ChannelId::from(1);
+        net.add_channel(0, channel);
+        net.tick(3);
+        assert!(
+            matches!(net.routers[1].next_hop_for(channel), Some(NextHop::Remote(conn)) if conn.session() == fast)
+        );
+
+        let conn = net.conn(0, fast);
+        net.routers[1].on_event(net.now_ms, InputEvent::ConnectionDisconnected(conn));
+        assert!(
+            net.routers[1].pop_output().is_none(),
+            "nothing is withdrawn or re-synced"
+        );
+        assert!(
+            matches!(net.routers[1].next_hop_for(channel), Some(NextHop::Remote(conn)) if conn.session() == slow)
+        );
+        assert!(net.has_path(1, channel, 0));
+    }
+
+    #[test]
+    fn rendezvous_mode_refuses_node_route_radius() {
+        let rendezvous = RouterConfig {
+            mode: RoutingMode::Rendezvous,
+            ..Default::default()
+        };
+        let radius = RouterConfig {
+            node_route_radius: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(rendezvous.validate(), Ok(()));
+        assert_eq!(radius.validate(), Ok(()));
+        // nodes would know different nodes, and pick different rendezvous nodes for the same channel
+        let both = RouterConfig {
+            node_route_radius: Some(2),
+            ..rendezvous
+        };
+        assert_eq!(
+            both.validate(),
+            Err(RouterConfigError::RendezvousWithRadius)
+        );
+    }
+
+    #[test]
+    fn node_routes_stay_within_radius() {
+        let config = RouterConfig {
+            node_route_radius: Some(2),
+            ..Default::default()
+        };
+        let mut net = Net::new(5, config);
+        for i in 0..4 {
+            net.link(i, i + 1, 10);
+        }
+        net.tick(5);
+        let known = |net: &Net, i: usize| {
+            (0..5)
+                .filter(|j| net.routers[i].has_remote_node(net.node(*j)))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(known(&net, 0), vec![1, 2]);
+        assert_eq!(known(&net, 2), vec![0, 1, 3, 4]);
+
+        // a node beyond the radius is approached over the closest known node, if it is closer than this one
+        let far = net.node(4);
+        let expected = [net.node(1), net.node(2)]
+            .into_iter()
+            .filter(|n| **n ^ *far < *net.node(0) ^ *far)
+            .min_by_key(|n| **n ^ *far);
+        let next = match net.routers[0].next_hop_for_node(far) {
+            Some(NextHop::Remote(conn)) => Some(conn.node()),
+            _ => None,
+        };
+        // both known nodes are behind node 1
+        assert_eq!(next, expected.map(|_| net.node(1)));
+    }
+
+    #[test]
+    fn node_routes_flood_without_radius() {
+        let mut net = Net::new(5, RouterConfig::default());
+        for i in 0..4 {
+            net.link(i, i + 1, 10);
         }
-        outputs
+        net.tick(5);
+        assert!((1..5).all(|j| net.routers[0].has_remote_node(net.node(j))));
     }
 }
@@ -0,0 +1,129 @@
+use crate::{
+    addr::{ChannelId, NodeId},
+    identity::{self, NodeKey},
+    protocol::ChannelAnnounce,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnnounceError {
+    /// A channel row which is not withdrawn must carry the announcement of its publisher
+    Missing,
+    Expired,
+    /// The first hop of the path is not the node of the publisher key
+    WrongOrigin(NodeId),
+    InvalidSignature,
+}
+
+/// What a publisher requires from the nodes of a channel, it is part of the signed announcement
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChannelPolicy {
+    /// Frames must be signed by the publisher
+    pub authenticated: bool,
+    /// Public key which must sign the token of each subscription
+    pub authority: Option<Vec<u8>>,
+    /// Frames are encrypted end-to-end, plaintext frames are dropped
+    pub encrypted: bool,
+}
+
+/// Announcement of a channel by its publisher, which is valid until `expires_ms` in unix time
+pub fn sign(
+    key: &NodeKey,
+    channel: ChannelId,
+    expires_ms: u64,
+    policy: &ChannelPolicy,
+) -> ChannelAnnounce {
+    let mut announce = ChannelAnnounce {
+        publisher: key.public_key().to_vec(),
+        expires_ms,
+        signature: vec![],
+        authenticated: Some(policy.authenticated),
+        authority: policy.authority.clone(),
+        encrypted: Some(policy.encrypted),
+    };
+    announce.signature = key.sign(&channel_payload(channel, &announce)).to_vec();
+    announce
+}
+
+/// Announcement of the node itself, which starts its node paths. It has no policy.
+pub fn sign_node(key: &NodeKey, expires_ms: u64) -> ChannelAnnounce {
+    let mut announce = ChannelAnnounce {
+        publisher: key.public_key().to_vec(),
+        expires_ms,
+        signature: vec![],
+        authenticated: None,
+        authority: None,
+        encrypted: None,
+    };
+    announce.signature = key.sign(&node_payload(key.node_id(), &announce)).to_vec();
+    announce
+}
+
+/// Check the expiry and the origin of an announcement whose signature was already verified
+pub fn check(now_ms: u64, origin: NodeId, announce: &ChannelAnnounce) -> Result<(), AnnounceError> {
+    if announce.expires_ms <= now_ms {
+        return Err(AnnounceError::Expired);
+    }
+    if identity::node_id_of(&announce.publisher) != origin {
+        return Err(AnnounceError::WrongOrigin(origin));
+    }
+    Ok(())
+}
+
+/// Check the announcement of a channel path whose first hop is `origin`
+pub fn verify(
+    now_ms: u64,
+    channel: ChannelId,
+    origin: NodeId,
+    announce: &ChannelAnnounce,
+) -> Result<(), AnnounceError> {
+    check(now_ms, origin, announce)?;
+    let data = channel_payload(channel, announce);
+    if !identity::verify(&announce.publisher, &data, &announce.signature) {
+        return Err(AnnounceError::InvalidSignature);
+    }
+    Ok(())
+}
+
+/// Check the announcement of a node path whose first hop is `origin`, which must be the node itself
+pub fn verify_node(
+    now_ms: u64,
+    node: NodeId,
+    origin: NodeId,
+    announce: &ChannelAnnounce,
+) -> Result<(), AnnounceError> {
+    if origin != node {
+        return Err(AnnounceError::WrongOrigin(origin));
+    }
+    check(now_ms, origin, announce)?;
+    let data = node_payload(node, announce);
+    if !identity::verify(&announce.publisher, &data, &announce.signature) {
+        return Err(AnnounceError::InvalidSignature);
+    }
+    Ok(())
+}
+
+fn channel_payload(channel: ChannelId, announce: &ChannelAnnounce) -> Vec<u8> {
+    let mut payload = b"announce".to_vec();
+    payload.extend_from_slice(&channel.to_be_bytes());
+    with_fields(payload, announce)
+}
+
+/// A node announcement is signed under another prefix, so it can't pass for the announcement of a channel
+fn node_payload(node: NodeId, announce: &ChannelAnnounce) -> Vec<u8> {
+    let mut payload = b"node".to_vec();
+    payload.extend_from_slice(&node.to_be_bytes());
+    with_fields(payload, announce)
+}
+
+/// Append the signed fields of the announcement to the prefix of its subject
+fn with_fields(mut payload: Vec<u8>, announce: &ChannelAnnounce) -> Vec<u8> {
+    payload.extend_from_slice(&announce.publisher);
+    payload.extend_from_slice(&announce.expires_ms.to_be_bytes());
+    payload.push(announce.authenticated() as u8);
+    payload.push(announce.encrypted() as u8);
+    if let Some(authority) = announce.authority.as_ref() {
+        payload.push(1);
+        payload.extend_from_slice(authority);
+    }
+    payload
+}
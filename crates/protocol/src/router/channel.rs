@@ -1,66 +1,94 @@
 use std::collections::HashMap;
 
-use crate::{
-    addr::{ChannelId, NodeId},
-    network::Connection,
-};
+use crate::{addr::NodeId, protocol::ChannelAnnounce};
 
 use super::path::ChannelPath;
 
+/// All known paths toward a destination, which can be a channel publisher or a node.
+/// Paths are keyed by the neighbour node which advertised them, whatever connection was used.
 pub struct ChannelRoute {
-    channel_id: ChannelId,
-    paths: HashMap<Connection, ChannelPath>,
+    paths: HashMap<NodeId, ChannelPath>,
+    hop_penalty_ms: u32,
 }
 
 impl ChannelRoute {
-    pub fn new(channel_id: ChannelId) -> Self {
+    pub fn new(hop_penalty_ms: u32) -> Self {
         Self {
-            channel_id,
             paths: HashMap::new(),
+            hop_penalty_ms,
         }
     }
 
-    pub fn on_tick(&mut self, _now_ms: u64) {}
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    /// A path which is not refreshed by any sync within the timeout is considered dead
+    pub fn on_tick(&mut self, now_ms: u64, timeout_ms: u64) {
+        self.paths
+            .retain(|_, path| path.last_sync + timeout_ms > now_ms && !path.is_expired(now_ms));
+    }
 
-    pub fn on_sync(&mut self, _now_ms: u64, from: Connection, path: ChannelPath) {
+    pub fn on_sync(&mut self, _now_ms: u64, from: NodeId, path: ChannelPath) {
         self.paths.insert(from, path);
     }
 
-    pub fn on_disconnected(&mut self, conn: Connection) {
-        self.paths.remove(&conn);
+    /// Sync without any row for this route still proves that paths over the neighbour are alive
+    pub fn on_refresh(&mut self, now_ms: u64, from: NodeId) {
+        if let Some(path) = self.paths.get_mut(&from) {
+            path.last_sync = now_ms;
+        }
+    }
+
+    pub fn has_path(&self, from: NodeId) -> bool {
+        self.paths.contains_key(&from)
+    }
+
+    pub fn has_announce(&self, announce: &ChannelAnnounce) -> bool {
+        self.paths
+            .values()
+            .any(|p| p.announce.as_ref() == Some(announce))
+    }
+
+    pub fn on_withdraw(&mut self, from: NodeId) {
+        self.paths.remove(&from);
+    }
+
+    pub fn on_disconnected(&mut self, node: NodeId) {
+        self.paths.remove(&node);
     }
 
+    /// Best path to the destination which hops not contains dest,
+    /// ties are broken by the neighbour id so that all nodes choose the same way
     pub fn create_sync(&self, dest: NodeId) -> Option<ChannelPath> {
         //TODO: optimize this with O(1) algorithm
-        //find the best path to the destination which hops not contains dest
-        let mut best_path: Option<&ChannelPath> = None;
-        for path in self.paths.values() {
-            if !path.hops.contains(&dest) {
-                if let Some(best) = best_path {
-                    if path.metric.score() < best.metric.score() {
-                        best_path = Some(path);
-                    }
-                } else {
-                    best_path = Some(path);
-                }
-            }
-        }
-        best_path.map(|p| p.clone())
+        self.paths
+            .iter()
+            .filter(|(_, path)| !path.hops.contains(&dest))
+            .min_by_key(|(node, path)| (path.score(self.hop_penalty_ms), **node))
+            .map(|(_, path)| path.clone())
     }
 
-    pub fn next_hop(&self) -> Option<Connection> {
+    pub fn best_score(&self) -> Option<u32> {
+        self.paths
+            .values()
+            .map(|p| p.score(self.hop_penalty_ms))
+            .min()
+    }
+
+    /// Announcement which the best path carries, it names the publisher which the data comes from
+    pub fn best_announce(&self) -> Option<&ChannelAnnounce> {
+        self.paths
+            .iter()
+            .min_by_key(|(node, path)| (path.score(self.hop_penalty_ms), **node))
+            .and_then(|(_, path)| path.announce.as_ref())
+    }
+
+    pub fn next_hop(&self) -> Option<NodeId> {
         //TODO: optimize this with O(1) algorithm
-        //find the best path to the destination which hops not contains dest
-        let mut best: Option<(&Connection, &ChannelPath)> = None;
-        for (conn, path) in &self.paths {
-            if let Some((_, best_path)) = best {
-                if path.metric.score() < best_path.metric.score() {
-                    best = Some((conn, path));
-                }
-            } else {
-                best = Some((conn, path));
-            }
-        }
-        best.map(|p| *p.0)
+        self.paths
+            .iter()
+            .min_by_key(|(node, path)| (path.score(self.hop_penalty_ms), **node))
+            .map(|(node, _)| *node)
     }
 }
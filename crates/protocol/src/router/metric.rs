@@ -1,4 +1,7 @@
-use crate::{network::ConnectionStats, protocol::RouterRow};
+use crate::{
+    network::ConnectionStats,
+    protocol::{NodeRow, RouterRow},
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Float<const ACC: u8> {
@@ -13,12 +16,15 @@ impl<const ACC: u8> From<f32> for Float<ACC> {
     }
 }
 
-impl<const ACC: u8> Into<f32> for Float<ACC> {
-    fn into(self) -> f32 {
-        self.value as f32 / 10.0_f32.powi(ACC as i32)
+impl<const ACC: u8> From<Float<ACC>> for f32 {
+    fn from(value: Float<ACC>) -> Self {
+        value.value as f32 / 10.0_f32.powi(ACC as i32)
     }
 }
 
+/// Rtt value of a withdrawn route
+pub const INFINITE_RTT: u32 = u32::MAX;
+
 #[derive(Debug, Clone, Copy)]
 pub struct Metric {
     pub rtt: u32,
@@ -51,20 +57,62 @@ impl From<RouterRow> for Metric {
     }
 }
 
+impl From<NodeRow> for Metric {
+    fn from(value: NodeRow) -> Self {
+        Self {
+            rtt: value.rtt,
+            loss: value.loss.into(),
+            jitter: value.jitter,
+            bandwidth: value.bandwidth,
+        }
+    }
+}
+
 impl Metric {
+    /// Metric of a channel or node which is hosted by this node
+    pub fn local() -> Self {
+        Self {
+            rtt: 0,
+            loss: 0.0.into(),
+            jitter: 0,
+            bandwidth: 10_000_000, //10Gbps
+        }
+    }
+
+    /// Metric which is used to withdraw a route
+    pub fn infinite() -> Self {
+        Self {
+            rtt: INFINITE_RTT,
+            loss: 100.0.into(),
+            jitter: 0,
+            bandwidth: 0,
+        }
+    }
+
+    pub fn is_infinite(&self) -> bool {
+        self.rtt == INFINITE_RTT
+    }
+
     pub fn score(&self) -> u32 {
         self.rtt
     }
 
     pub fn add_local(&self, stats: &ConnectionStats) -> Metric {
         let add = Metric {
-            rtt: stats.rtt_ms as u32,
+            rtt: stats.rtt_ms,
             loss: stats.lost_percent,
-            jitter: stats.jitter_ms as u32,
+            jitter: stats.jitter_ms,
             bandwidth: stats.bandwidth_kbps,
         };
         *self + add
     }
+
+    /// Metric of a path over one more connection, which counts at least the minimum rtt of a hop
+    pub fn add_hop(&self, stats: &ConnectionStats, min_rtt_ms: u32) -> Metric {
+        let mut metric = self.add_local(stats);
+        metric.rtt = metric.rtt.max(self.rtt + min_rtt_ms);
+        metric
+    }
 }
 
 fn loss_plus(l1: f32, l2: f32) -> f32 {
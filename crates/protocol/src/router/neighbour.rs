@@ -0,0 +1,39 @@
+use std::collections::HashMap;
+
+use crate::network::{Connection, ConnectionStats};
+
+use super::metric::Metric;
+
+/// All connections to a neighbour node, which can use different transports.
+/// Routes only care about the neighbour node, data and sync use its best connection.
+pub struct Neighbour {
+    conns: HashMap<Connection, ConnectionStats>,
+}
+
+impl Neighbour {
+    pub fn new() -> Self {
+        Self {
+            conns: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conns.is_empty()
+    }
+
+    pub fn on_stats(&mut self, conn: Connection, stats: ConnectionStats) {
+        self.conns.insert(conn, stats);
+    }
+
+    pub fn on_disconnected(&mut self, conn: Connection) {
+        self.conns.remove(&conn);
+    }
+
+    /// Best connection by the same score which is used for paths
+    pub fn best_conn(&self) -> Option<(Connection, &ConnectionStats)> {
+        self.conns
+            .iter()
+            .min_by_key(|(conn, stats)| (Metric::local().add_local(stats).score(), conn.session()))
+            .map(|(conn, stats)| (*conn, stats))
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
     addr::{ChannelId, NodeId},
-    protocol::RouterRow,
+    protocol::{ChannelAnnounce, NodeRow, RouterRow},
 };
 
 use super::metric::Metric;
@@ -10,30 +10,90 @@ pub struct ChannelPath {
     pub last_sync: u64,
     pub metric: Metric,
     pub hops: Vec<NodeId>,
+    /// Signed announcement of the channel publisher, or of the node for a node path
+    pub announce: Option<ChannelAnnounce>,
 }
 
 impl ChannelPath {
+    pub fn local() -> Self {
+        Self {
+            last_sync: 0,
+            metric: Metric::local(),
+            hops: vec![],
+            announce: None,
+        }
+    }
+
+    /// Path of a channel which this node publishes, or of this node itself
+    pub fn local_channel(announce: ChannelAnnounce) -> Self {
+        Self {
+            announce: Some(announce),
+            ..Self::local()
+        }
+    }
+
+    pub fn withdrawn() -> Self {
+        Self {
+            last_sync: 0,
+            metric: Metric::infinite(),
+            hops: vec![],
+            announce: None,
+        }
+    }
+
+    /// Each hop adds a penalty to the score, so that between similar paths the shorter one is preferred
+    pub fn score(&self, hop_penalty_ms: u32) -> u32 {
+        self.metric
+            .score()
+            .saturating_add(hop_penalty_ms.saturating_mul(self.hops.len() as u32))
+    }
+
     pub fn to_row(&self, channel: ChannelId) -> RouterRow {
         RouterRow {
             channel: *channel,
             rtt: self.metric.rtt,
             loss: self.metric.loss.into(),
             jitter: self.metric.jitter,
-            bandwidth: 0,
-            hops: self
-                .hops
-                .clone()
-                .into_iter()
-                .map(|n| *n)
-                .collect::<Vec<u32>>(),
+            bandwidth: self.metric.bandwidth,
+            hops: self.hops.iter().map(|n| **n).collect::<Vec<u64>>(),
+            announce: self.announce.clone(),
         }
     }
 
-    pub fn from_row(now_ms: u64, value: RouterRow) -> Self {
+    pub fn to_node_row(&self, node: NodeId) -> NodeRow {
+        NodeRow {
+            node: *node,
+            rtt: self.metric.rtt,
+            loss: self.metric.loss.into(),
+            jitter: self.metric.jitter,
+            bandwidth: self.metric.bandwidth,
+            hops: self.hops.iter().map(|n| **n).collect::<Vec<u64>>(),
+            announce: self.announce.clone(),
+        }
+    }
+
+    pub fn from_row(now_ms: u64, mut value: RouterRow) -> Self {
+        Self {
+            last_sync: now_ms,
+            hops: value.hops.iter().map(|n| (*n).into()).collect(),
+            announce: value.announce.take(),
+            metric: value.into(),
+        }
+    }
+
+    /// The announcement expired, the publisher stopped renewing it
+    pub fn is_expired(&self, now_ms: u64) -> bool {
+        self.announce
+            .as_ref()
+            .is_some_and(|a| a.expires_ms <= now_ms)
+    }
+
+    pub fn from_node_row(now_ms: u64, mut value: NodeRow) -> Self {
         Self {
             last_sync: now_ms,
             hops: value.hops.iter().map(|n| (*n).into()).collect(),
-            metric: value.clone().into(),
+            announce: value.announce.take(),
+            metric: value.into(),
         }
     }
 }
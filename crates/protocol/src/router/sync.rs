@@ -0,0 +1,501 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    addr::{ChannelId, NodeId},
+    protocol::{NodeRow, RouterRow, RouterSync},
+};
+
+use super::{path::ChannelPath, RouterConfig};
+
+/// Sync state toward a single neighbour connection.
+/// It remembers which rows was sent, so that in delta mode only changed rows are sent again.
+pub struct NeighbourSync {
+    version: u32,
+    /// Version of the last sync which was received from the neighbour
+    remote_version: Option<u32>,
+    last_full_ms: Option<u64>,
+    full_requested: bool,
+    channels: HashMap<ChannelId, ChannelPath>,
+    nodes: HashMap<NodeId, ChannelPath>,
+    last_triggered_ms: Option<u64>,
+    pending_channels: HashSet<ChannelId>,
+    pending_nodes: HashSet<NodeId>,
+    /// False if the neighbour didn't negotiate delta sync, then it gets full tables only
+    delta: bool,
+}
+
+impl NeighbourSync {
+    pub fn new() -> Self {
+        Self {
+            version: 0,
+            remote_version: None,
+            last_full_ms: None,
+            full_requested: false,
+            channels: HashMap::new(),
+            nodes: HashMap::new(),
+            last_triggered_ms: None,
+            pending_channels: HashSet::new(),
+            pending_nodes: HashSet::new(),
+            delta: true,
+        }
+    }
+
+    /// Remember the version of a sync from the neighbour. False if a delta sync doesn't follow the previous one,
+    /// then rows were lost and the full table must be requested.
+    /// Syncs without a version come from a neighbour without delta sync, they are taken as they are.
+    pub fn on_recv(&mut self, version: Option<u32>, full: bool) -> bool {
+        let Some(version) = version else {
+            self.remote_version = None;
+            return true;
+        };
+        let expected = self.remote_version.map(|v| v.wrapping_add(1));
+        self.remote_version = Some(version);
+        full || expected == Some(version)
+    }
+
+    /// Whether the neighbour negotiated delta sync in its handshake
+    pub fn set_delta(&mut self, delta: bool) {
+        self.delta = delta;
+    }
+
+    /// Next sync will contain the full table
+    pub fn request_full(&mut self) {
+        self.full_requested = true;
+    }
+
+    /// Mark routes which changed significantly, they will be sent in the next triggered sync
+    pub fn trigger(&mut self, channels: &[ChannelId], nodes: &[NodeId]) {
+        self.pending_channels.extend(channels);
+        self.pending_nodes.extend(nodes);
+    }
+
+    /// Triggered sync is only sent after the first full table, and at most once per `trigger_interval_ms`
+    pub fn can_trigger(&self, now_ms: u64, config: &RouterConfig) -> bool {
+        let has_pending = !self.pending_channels.is_empty() || !self.pending_nodes.is_empty();
+        has_pending
+            && self.last_full_ms.is_some()
+            && self
+                .last_triggered_ms
+                .is_none_or(|last| now_ms >= last + config.trigger_interval_ms)
+    }
+
+    /// Create a partial sync with only the pending rows from the current table
+    pub fn create_triggered(
+        &mut self,
+        now_ms: u64,
+        config: &RouterConfig,
+        mut channels: HashMap<ChannelId, ChannelPath>,
+        mut nodes: HashMap<NodeId, ChannelPath>,
+    ) -> Option<RouterSync> {
+        self.last_triggered_ms = Some(now_ms);
+        let threshold = config.delta_threshold_percent;
+        let channels = self
+            .pending_channels
+            .drain()
+            .map(|id| (id, channels.remove(&id)))
+            .collect::<Vec<_>>();
+        let nodes = self
+            .pending_nodes
+            .drain()
+            .map(|id| (id, nodes.remove(&id)))
+            .collect::<Vec<_>>();
+        let rows = diff_partial(&mut self.channels, channels, threshold)
+            .into_iter()
+            .map(|(id, p)| p.to_row(id))
+            .collect::<Vec<_>>();
+        let node_rows = diff_partial(&mut self.nodes, nodes, threshold)
+            .into_iter()
+            .map(|(id, p)| p.to_node_row(id))
+            .collect::<Vec<_>>();
+        if rows.is_empty() && node_rows.is_empty() {
+            return None;
+        }
+
+        Some(self.next_msg(rows, node_rows, false))
+    }
+
+    /// Create sync message from the current table which should be advertised to the neighbour.
+    /// Full table is sent on the first sync, when requested, every `full_sync_interval_ms` or when delta sync is disabled
+    /// locally or by the neighbour, otherwise only new, changed and withdrawn rows are sent.
+    pub fn create_sync(
+        &mut self,
+        now_ms: u64,
+        config: &RouterConfig,
+        channels: HashMap<ChannelId, ChannelPath>,
+        nodes: HashMap<NodeId, ChannelPath>,
+    ) -> Option<RouterSync> {
+        let delta = config.delta_sync && self.delta;
+        let full = !delta
+            || self.full_requested
+            || self
+                .last_full_ms
+                .is_none_or(|last| now_ms >= last + config.full_sync_interval_ms);
+
+        // every sync covers the whole table, so nothing is pending anymore
+        self.pending_channels.clear();
+        self.pending_nodes.clear();
+
+        let (rows, node_rows): (Vec<RouterRow>, Vec<NodeRow>) = if full {
+            self.last_full_ms = Some(now_ms);
+            self.full_requested = false;
+            let rows = channels.iter().map(|(id, p)| p.to_row(*id)).collect();
+            let node_rows = nodes.iter().map(|(id, p)| p.to_node_row(*id)).collect();
+            self.channels = channels;
+            self.nodes = nodes;
+            (rows, node_rows)
+        } else {
+            let threshold = config.delta_threshold_percent;
+            let rows = diff(&mut self.channels, channels, threshold)
+                .into_iter()
+                .map(|(id, p)| p.to_row(id))
+                .collect();
+            let node_rows = diff(&mut self.nodes, nodes, threshold)
+                .into_iter()
+                .map(|(id, p)| p.to_node_row(id))
+                .collect();
+            (rows, node_rows)
+        };
+
+        // in full mode an empty table has nothing to refresh, in delta mode an empty sync keeps the paths alive
+        if !delta && rows.is_empty() && node_rows.is_empty() {
+            return None;
+        }
+
+        Some(self.next_msg(rows, node_rows, full))
+    }
+
+    /// Create an immediate sync which withdraws the given channels and nodes.
+    /// Only rows which was advertised to the neighbour are included, None if nothing to withdraw.
+    pub fn create_withdraw(
+        &mut self,
+        channels: &[ChannelId],
+        nodes: &[NodeId],
+    ) -> Option<RouterSync> {
+        let rows = withdraw(&mut self.channels, channels)
+            .into_iter()
+            .map(|(id, p)| p.to_row(id))
+            .collect::<Vec<_>>();
+        let node_rows = withdraw(&mut self.nodes, nodes)
+            .into_iter()
+            .map(|(id, p)| p.to_node_row(id))
+            .collect::<Vec<_>>();
+        if rows.is_empty() && node_rows.is_empty() {
+            return None;
+        }
+
+        Some(self.next_msg(rows, node_rows, false))
+    }
+
+    /// Rows are sorted so that the message does not depend on the hash map order
+    fn next_msg(
+        &mut self,
+        mut rows: Vec<RouterRow>,
+        mut nodes: Vec<NodeRow>,
+        full: bool,
+    ) -> RouterSync {
+        rows.sort_by_key(|r| r.channel);
+        nodes.sort_by_key(|r| r.node);
+        self.version = self.version.wrapping_add(1);
+        RouterSync {
+            rows,
+            nodes,
+            version: Some(self.version),
+            full: Some(full),
+        }
+    }
+}
+
+/// Remove the rows from the sent table and return the ones which the neighbour still believes
+fn withdraw<K: Copy + Eq + std::hash::Hash>(
+    sent: &mut HashMap<K, ChannelPath>,
+    ids: &[K],
+) -> Vec<(K, ChannelPath)> {
+    ids.iter()
+        .filter_map(|id| sent.remove(id).map(|path| (*id, path)))
+        .filter(|(_, path)| !path.metric.is_infinite())
+        .map(|(id, _)| (id, ChannelPath::withdrawn()))
+        .collect()
+}
+
+/// Same as `diff` but only for the given rows, a None path means the row is not in the table anymore
+fn diff_partial<K: Copy + Eq + std::hash::Hash>(
+    sent: &mut HashMap<K, ChannelPath>,
+    current: Vec<(K, Option<ChannelPath>)>,
+    threshold_percent: u32,
+) -> Vec<(K, ChannelPath)> {
+    let mut changes = vec![];
+    for (id, path) in current {
+        match (sent.get(&id), path) {
+            (Some(old), Some(path)) if !is_changed(old, &path, threshold_percent) => {}
+            (_, Some(path)) => {
+                sent.insert(id, path.clone());
+                changes.push((id, path));
+            }
+            (Some(_), None) => changes.extend(withdraw(sent, &[id])),
+            (None, None) => {}
+        }
+    }
+    changes
+}
+
+/// Update the sent table and return the rows which need to be sent
+fn diff<K: Copy + Eq + std::hash::Hash>(
+    sent: &mut HashMap<K, ChannelPath>,
+    current: HashMap<K, ChannelPath>,
+    threshold_percent: u32,
+) -> Vec<(K, ChannelPath)> {
+    let mut changes = vec![];
+    sent.retain(|id, _| {
+        if current.contains_key(id) {
+            true
+        } else {
+            changes.push((*id, ChannelPath::withdrawn()));
+            false
+        }
+    });
+    for (id, path) in current {
+        let changed = match sent.get(&id) {
+            Some(old) => is_changed(old, &path, threshold_percent),
+            None => true,
+        };
+        if changed {
+            sent.insert(id, path.clone());
+            changes.push((id, path));
+        }
+    }
+    changes
+}
+
+/// A renewed announcement is always sent again, so that neighbours don't keep an expiring one
+fn is_changed(old: &ChannelPath, new: &ChannelPath, threshold_percent: u32) -> bool {
+    if old.hops != new.hops || old.announce != new.announce {
+        return true;
+    }
+    let old_score = old.metric.score() as u64;
+    let new_score = new.metric.score() as u64;
+    old_score.abs_diff(new_score) * 100 > old_score * threshold_percent as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        protocol::ChannelAnnounce,
+        router::metric::{self, Metric},
+    };
+
+    use super::*;
+
+    fn path(rtt: u32, hops: &[u64]) -> ChannelPath {
+        ChannelPath {
+            last_sync: 0,
+            metric: Metric {
+                rtt,
+                ..Metric::local()
+            },
+            hops: hops.iter().map(|n| (*n).into()).collect(),
+            announce: None,
+        }
+    }
+
+    /// Channel table with one path over node 1 per channel
+    fn table(rows: &[(u32, u32)]) -> HashMap<ChannelId, ChannelPath> {
+        rows.iter()
+            .map(|(channel, rtt)| ((*channel).into(), path(*rtt, &[1])))
+            .collect()
+    }
+
+    fn delta_config() -> RouterConfig {
+        RouterConfig {
+            delta_sync: true,
+            ..Default::default()
+        }
+    }
+
+    fn rows(sync: &RouterSync) -> Vec<(u32, u32)> {
+        sync.rows.iter().map(|r| (r.channel, r.rtt)).collect()
+    }
+
+    #[test]
+    fn delta_sync_sends_only_changed_rows() {
+        let config = delta_config();
+        let mut sync = NeighbourSync::new();
+        let first = sync
+            .create_sync(
+                0,
+                &config,
+                table(&[(1, 100), (2, 100), (3, 100)]),
+                HashMap::new(),
+            )
+            .expect("sync");
+        assert!(first.full());
+        assert_eq!(rows(&first), vec![(1, 100), (2, 100), (3, 100)]);
+
+        // 1 changes below the threshold, 2 above it, 3 is gone and 4 is new
+        let current = || table(&[(1, 105), (2, 150), (4, 100)]);
+        let second = sync
+            .create_sync(1000, &config, current(), HashMap::new())
+            .expect("sync");
+        assert!(!second.full());
+        assert_eq!(second.version(), first.version() + 1);
+        assert_eq!(
+            rows(&second),
+            vec![(2, 150), (3, metric::INFINITE_RTT), (4, 100)]
+        );
+
+        // an unchanged table still gives an empty delta, which refreshes the paths at the neighbour
+        let third = sync
+            .create_sync(2000, &config, current(), HashMap::new())
+            .expect("sync");
+        assert!(!third.full());
+        assert!(third.rows.is_empty());
+    }
+
+    #[test]
+    fn full_table_when_requested_and_periodically() {
+        let config = delta_config();
+        let mut sync = NeighbourSync::new();
+        let mut full_at = |now_ms| {
+            sync.create_sync(now_ms, &config, table(&[(1, 100)]), HashMap::new())
+                .expect("sync")
+                .full()
+        };
+        assert!(full_at(0));
+        assert!(!full_at(1000));
+        assert!(!full_at(config.full_sync_interval_ms - 1));
+        assert!(full_at(config.full_sync_interval_ms));
+        assert!(!full_at(config.full_sync_interval_ms + 1000));
+
+        sync.request_full();
+        let msg = sync
+            .create_sync(40_000, &config, table(&[(1, 100)]), HashMap::new())
+            .expect("sync");
+        assert!(msg.full());
+        assert_eq!(rows(&msg), vec![(1, 100)]);
+    }
+
+    #[test]
+    fn full_mode_sends_whole_table() {
+        let config = RouterConfig::default();
+        let mut sync = NeighbourSync::new();
+        for now_ms in [0, 1000] {
+            let msg = sync
+                .create_sync(
+                    now_ms,
+                    &config,
+                    table(&[(1, 100), (2, 100)]),
+                    HashMap::new(),
+                )
+                .expect("sync");
+            assert!(msg.full());
+            assert_eq!(msg.rows.len(), 2);
+        }
+        assert!(sync
+            .create_sync(2000, &config, HashMap::new(), HashMap::new())
+            .is_none());
+    }
+
+    #[test]
+    fn neighbour_without_delta_sync_gets_full_tables() {
+        let config = delta_config();
+        let mut sync = NeighbourSync::new();
+        sync.set_delta(false);
+        for now_ms in [0, 1000] {
+            let msg = sync
+                .create_sync(now_ms, &config, table(&[(1, 100)]), HashMap::new())
+                .expect("sync");
+            assert!(msg.full());
+            assert_eq!(rows(&msg), vec![(1, 100)]);
+        }
+    }
+
+    #[test]
+    fn version_gap_needs_full_table() {
+        let mut sync = NeighbourSync::new();
+        assert!(
+            !sync.on_recv(Some(5), false),
+            "a delta without a full table before"
+        );
+        assert!(sync.on_recv(Some(6), true));
+        assert!(sync.on_recv(Some(7), false));
+        assert!(!sync.on_recv(Some(9), false));
+        assert!(sync.on_recv(Some(10), false));
+        assert!(sync.on_recv(Some(u32::MAX), true));
+        assert!(sync.on_recv(Some(0), false), "the version wraps around");
+        assert!(sync.on_recv(None, false), "the neighbour has no delta sync");
+        assert!(!sync.on_recv(Some(5), false));
+    }
+
+    #[test]
+    fn change_threshold() {
+        let old = path(100, &[1]);
+        assert!(!is_changed(&old, &path(110, &[1]), 10));
+        assert!(!is_changed(&old, &path(90, &[1]), 10));
+        assert!(is_changed(&old, &path(111, &[1]), 10));
+        assert!(is_changed(&old, &path(89, &[1]), 10));
+        assert!(is_changed(&old, &path(100, &[2, 1]), 10));
+
+        let mut renewed = path(100, &[1]);
+        renewed.announce = Some(ChannelAnnounce {
+            publisher: vec![],
+            expires_ms: 1000,
+            signature: vec![],
+            authenticated: Some(false),
+            authority: None,
+            encrypted: None,
+        });
+        assert!(is_changed(&old, &renewed, 10));
+    }
+
+    #[test]
+    fn triggered_sync_waits_for_full_table_and_interval() {
+        let config = delta_config();
+        let mut sync = NeighbourSync::new();
+        sync.trigger(&[1.into()], &[]);
+        assert!(!sync.can_trigger(0, &config), "no full table was sent yet");
+        sync.create_sync(0, &config, table(&[(1, 100)]), HashMap::new());
+        assert!(!sync.can_trigger(0, &config), "the full table had the row");
+
+        sync.trigger(&[1.into()], &[]);
+        assert!(sync.can_trigger(10, &config));
+        let msg = sync
+            .create_triggered(10, &config, table(&[(1, 200)]), HashMap::new())
+            .expect("sync");
+        assert!(!msg.full());
+        assert_eq!(rows(&msg), vec![(1, 200)]);
+
+        // changes during the interval wait, and are sent together
+        sync.trigger(&[2.into()], &[]);
+        sync.trigger(&[3.into()], &[]);
+        assert!(!sync.can_trigger(10 + config.trigger_interval_ms - 1, &config));
+        assert!(sync.can_trigger(10 + config.trigger_interval_ms, &config));
+        let current = table(&[(1, 200), (2, 50), (3, 60)]);
+        let msg = sync
+            .create_triggered(
+                10 + config.trigger_interval_ms,
+                &config,
+                current,
+                HashMap::new(),
+            )
+            .expect("sync");
+        assert_eq!(rows(&msg), vec![(2, 50), (3, 60)]);
+    }
+
+    #[test]
+    fn triggered_sync_skips_small_changes_and_withdraws_removed_rows() {
+        let config = delta_config();
+        let mut sync = NeighbourSync::new();
+        sync.create_sync(0, &config, table(&[(1, 100), (2, 100)]), HashMap::new());
+
+        sync.trigger(&[1.into(), 2.into()], &[]);
+        let msg = sync
+            .create_triggered(10, &config, table(&[(1, 105)]), HashMap::new())
+            .expect("sync");
+        assert_eq!(rows(&msg), vec![(2, metric::INFINITE_RTT)]);
+
+        sync.trigger(&[1.into()], &[]);
+        let later = 10 + config.trigger_interval_ms;
+        assert!(sync
+            .create_triggered(later, &config, table(&[(1, 105)]), HashMap::new())
+            .is_none());
+    }
+}
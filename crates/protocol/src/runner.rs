@@ -1,14 +1,42 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 
 use crate::{
     addr::{ChannelId, NodeId},
+    discovery::{self, DiscoveryConfig, NeighbourManager},
+    e2ee::{self, ChannelKeys},
+    handshake::{self, Capability, ConnState, Feature, HandshakeError, LocalHello, PeerInfo},
+    identity::{self, NodeKey},
+    limit::{LimitConfig, Limiter, MessageKind, Misbehaviour},
     network::{Connection, ConnectionStats, NetworkMsg},
-    protocol::{network_message::MessageType, ChannelSub},
-    pubsub::{self, Pubsub},
-    router::{self, NextHop, Router},
+    protocol::{
+        network_message::MessageType, ChannelData, ChannelKey, ChannelKeyRequest, ChannelSub,
+        ChannelToken, ChannelUnsub, FindNode, FindNodeReply, Hello, HelloAck, JoinRequest,
+        JoinResponse, NodeData, Signalling,
+    },
+    pubsub::{
+        self, auth,
+        token::{self, TokenError},
+        Pubsub,
+    },
+    router::{
+        self,
+        announce::{self, ChannelPolicy},
+        NextHop, Router, RouterConfig, RouterConfigError, RoutingMode,
+    },
+    signalling::Signal,
+    unicast,
 };
 
+/// Maximum number of hops a unicast message, like node data or a signal, is relayed over before it is dropped
+pub(crate) const UNICAST_TTL: u32 = 16;
+/// Maximum number of known nodes which are returned to a joining node
+const JOIN_SAMPLE_SIZE: usize = 16;
+
 pub enum InputEvent {
+    /// Outgoing connection is established, the runner starts the handshake on it
+    ConnectionConnected(Connection),
+    /// Incoming connection is established, the handshake is symmetric so the runner also starts it
+    ConnectionAccepted(Connection),
     ConnectionRecv(NetworkMsg<MessageType>),
     ConnectionDisconnected(Connection),
     Stats(NetworkMsg<ConnectionStats>),
@@ -17,73 +45,404 @@ pub enum InputEvent {
 pub enum OutputEvent {
     ConnectionSend(NetworkMsg<MessageType>),
     OnChannelData(ChannelId, Vec<u8>),
+    /// Data which was sent to this node with `send_to` by the node
+    OnNodeData(NodeId, Vec<u8>),
+    /// Signal which was relayed to this node over the overlay
+    OnSignal(NodeId, Signal),
+    /// Neighbour manager wants to connect to the node
+    Connect(NodeId),
+    /// Neighbour manager wants to close all connections to the node
+    Disconnect(NodeId),
+    /// A seed answered the join request, the node is part of the network
+    Joined,
+    /// The node lost its last connection after it joined, it must join again
+    Left,
+    /// Connection which failed the handshake must be closed
+    Close(Connection),
+    /// Node which misbehaved too much, its connections are closed and refused until the ban ends
+    Banned(NodeId),
 }
 
 pub struct P2pStreamRunner {
+    key: NodeKey,
     router: Router,
     pubsub: Pubsub,
-    remote_channels: HashMap<ChannelId, Connection>,
+    keys: ChannelKeys,
+    /// What the published channels require from their nodes
+    policies: HashMap<ChannelId, ChannelPolicy>,
+    limits: Limiter,
+    discovery: Option<NeighbourManager>,
+    /// Channels which need data from upstream, with the connection which their Sub goes to if they have a route
+    remote_channels: HashMap<ChannelId, Option<Connection>>,
+    conns: HashMap<Connection, ConnState>,
+    capabilities: BTreeSet<Capability>,
+    /// Connections whose JoinRequest is not answered yet, other join responses are dropped
+    join_requests: HashSet<Connection>,
+    joined: bool,
     outputs: VecDeque<OutputEvent>,
 }
 
 impl P2pStreamRunner {
-    pub fn new(node: NodeId) -> Self {
+    /// The NodeId of the runner is derived from its key
+    pub fn new(key: NodeKey) -> Self {
+        Self::with_router(key, RouterConfig::default())
+    }
+
+    /// Fails if the router config can't work, see `RouterConfig::validate`
+    pub fn new_with_config(key: NodeKey, config: RouterConfig) -> Result<Self, RouterConfigError> {
+        config.validate()?;
+        Ok(Self::with_router(key, config))
+    }
+
+    fn with_router(key: NodeKey, config: RouterConfig) -> Self {
+        let node = key.node_id();
         Self {
-            router: Router::new(node),
+            key,
+            router: Router::new(node, config),
             pubsub: Pubsub::new(),
+            keys: ChannelKeys::new(node),
+            policies: HashMap::new(),
+            limits: Limiter::new(LimitConfig::default()),
+            discovery: None,
             remote_channels: HashMap::new(),
+            conns: HashMap::new(),
+            capabilities: BTreeSet::new(),
+            join_requests: HashSet::new(),
+            joined: false,
             outputs: VecDeque::new(),
         }
     }
 
+    pub fn node(&self) -> NodeId {
+        self.router.node()
+    }
+
+    /// Let the neighbour manager choose the neighbours, instead of only using the connections made by the host
+    pub fn enable_discovery(&mut self, config: DiscoveryConfig) {
+        self.discovery = Some(NeighbourManager::new(self.node(), config));
+    }
+
+    pub fn discovery(&self) -> Option<&NeighbourManager> {
+        self.discovery.as_ref()
+    }
+
+    /// Replace the default rate limits and ban rules, this resets the misbehaviour scores and bans
+    pub fn set_limits(&mut self, config: LimitConfig) {
+        self.limits = Limiter::new(config);
+    }
+
+    pub fn is_banned(&self, node: NodeId) -> bool {
+        self.limits.is_banned(node)
+    }
+
+    pub fn is_joined(&self) -> bool {
+        self.joined
+    }
+
+    /// Announce a capability in the handshake of the next connections
+    pub fn add_capability(&mut self, capability: Capability) {
+        self.capabilities.insert(capability);
+    }
+
+    /// What the remote node of a connection announced, None until the handshake is done
+    pub fn peer(&self, conn: Connection) -> Option<&PeerInfo> {
+        match self.conns.get(&conn)? {
+            ConnState::Established(info) => Some(info),
+            ConnState::Pending { .. } => None,
+        }
+    }
+
+    /// Ask the node of a new connection, usually a seed, for a sample of the nodes it knows.
+    /// The request is sent once the handshake is done.
+    pub fn request_join(&mut self, conn: Connection) {
+        match self.conns.get_mut(&conn) {
+            Some(ConnState::Pending { join, .. }) => *join = true,
+            Some(ConnState::Established(_)) => {
+                self.join_requests.insert(conn);
+                self.outputs
+                    .push_back(OutputEvent::ConnectionSend(NetworkMsg {
+                        conn,
+                        msg: MessageType::JoinRequest(JoinRequest {}),
+                    }));
+            }
+            None => {}
+        }
+    }
+
+    /// Neighbour which data of the channel is currently pulled from, this node itself if it is the publisher
+    pub fn next_hop_for(&self, channel: ChannelId) -> Option<NodeId> {
+        match self.router.next_hop_for(channel)? {
+            NextHop::Local => Some(self.node()),
+            NextHop::Remote(conn) => Some(conn.node()),
+        }
+    }
+
+    /// Size of the channel table, see `Router::channel_routes`
+    pub fn channel_routes(&self) -> usize {
+        self.router.channel_routes()
+    }
+
+    /// Start publishing a channel from this node, it is announced with a signature of this node
+    pub fn add_channel(&mut self, now_ms: u64, channel: ChannelId) {
+        let expires_ms = now_ms + self.router.config().announce_ttl_ms;
+        let policy = self.policies.get(&channel).cloned().unwrap_or_default();
+        let announce = announce::sign(&self.key, channel, expires_ms, &policy);
+        self.router.add_channel(now_ms, channel, announce);
+        self.pop_router_outputs();
+    }
+
+    /// Sign each frame which this node publishes to the channel. The announcement tells relays and subscribers
+    /// to drop frames of the channel which are not signed by this node.
+    pub fn authenticate_channel(&mut self, now_ms: u64, channel: ChannelId) {
+        self.policies.entry(channel).or_default().authenticated = true;
+        self.announce_policy(now_ms, channel);
+    }
+
+    /// Require a token signed by the authority key to subscribe to the channel, see `issue_token`.
+    /// The authority can be this node or a room authority.
+    pub fn restrict_channel(&mut self, now_ms: u64, channel: ChannelId, authority: Vec<u8>) {
+        self.policies.entry(channel).or_default().authority = Some(authority);
+        self.announce_policy(now_ms, channel);
+    }
+
+    /// Stop publishing a channel from this node
+    pub fn remove_channel(&mut self, channel: ChannelId) {
+        self.policies.remove(&channel);
+        self.router.remove_channel(channel);
+        self.pop_router_outputs();
+    }
+
+    pub fn sub_channel(&mut self, channel: ChannelId) {
+        self.pubsub.sub_channel(channel, None);
+        self.pop_pubsub_outputs();
+    }
+
+    /// Subscribe to a restricted channel with a token which is issued for this node, it is sent with each subscription
+    pub fn sub_channel_with_token(&mut self, channel: ChannelId, token: ChannelToken) {
+        self.pubsub.sub_channel(channel, Some(token));
+        self.pop_pubsub_outputs();
+    }
+
+    /// Token which is issued for this node to relay a restricted channel, it is sent with the subscriptions of the
+    /// channel which this node forwards for its subscribers, since their own tokens are bound to them
+    pub fn add_relay_token(&mut self, token: ChannelToken) {
+        self.pubsub.add_relay_token(token);
+    }
+
+    pub fn unsub_channel(&mut self, channel: ChannelId) {
+        self.pubsub.unsub_channel(channel);
+        self.pop_pubsub_outputs();
+    }
+
+    pub fn pub_channel(&mut self, channel: ChannelId, data: Vec<u8>) {
+        self.pub_channel_layer(channel, 0, data);
+    }
+
+    /// Publish a frame of a media layer, relays can read the layer and sequence number even if the channel is encrypted
+    pub fn pub_channel_layer(&mut self, channel: ChannelId, layer: u32, data: Vec<u8>) {
+        let seq = self.pubsub.next_seq(channel);
+        let (epoch, data) = self.keys.seal(channel, seq, layer, data);
+        let mut frame = ChannelData {
+            channel: *channel,
+            data,
+            seq: Some(seq),
+            layer: Some(layer),
+            epoch,
+            signature: None,
+        };
+        if self.policies.get(&channel).is_some_and(|p| p.authenticated) {
+            frame.signature = Some(auth::sign(&self.key, &frame));
+        }
+        self.pubsub.pub_channel(frame);
+        self.pop_pubsub_outputs();
+    }
+
+    /// Encrypt the frames which this node publishes to the channel end-to-end,
+    /// only the subscribers which are authorized with `authorize_subscriber` can read them.
+    /// The announcement tells subscribers to drop plaintext frames of the channel.
+    pub fn encrypt_channel(&mut self, now_ms: u64, channel: ChannelId) {
+        self.keys.encrypt_channel(&mut self.key, channel);
+        self.policies.entry(channel).or_default().encrypted = true;
+        self.announce_policy(now_ms, channel);
+    }
+
+    /// Let a node receive the key of an encrypted channel, false if the channel is not encrypted
+    pub fn authorize_subscriber(&mut self, channel: ChannelId, node: NodeId) -> bool {
+        self.keys.authorize(channel, node)
+    }
+
+    /// Stop giving the key of an encrypted channel to a node, the key is rotated so it can't read the next frames
+    pub fn revoke_subscriber(&mut self, channel: ChannelId, node: NodeId) {
+        self.keys.revoke(&mut self.key, channel, node);
+        self.pop_keys_outputs();
+    }
+
+    /// Send data to a node, it is relayed hop by hop over the node routes.
+    /// The data is given back if there is no route to the node, the delivery is not guaranteed otherwise.
+    pub fn send_to(&mut self, to: NodeId, data: Vec<u8>) -> Result<(), Vec<u8>> {
+        match self.router.next_hop_for_node(to) {
+            Some(NextHop::Local) => {
+                self.outputs.push_back(OutputEvent::OnNodeData(to, data));
+                Ok(())
+            }
+            Some(NextHop::Remote(conn)) => {
+                let mut msg = NodeData {
+                    from: *self.node(),
+                    to: *to,
+                    ttl: UNICAST_TTL,
+                    data,
+                    ..Default::default()
+                };
+                unicast::sign_node_data(&self.key, &mut msg);
+                self.send_unicast(conn, MessageType::NodeData(msg));
+                Ok(())
+            }
+            None => Err(data),
+        }
+    }
+
+    /// Send a signal to a node over the overlay, the signal is given back if there is no route to the node
+    pub fn send_signal(&mut self, to: NodeId, signal: Signal) -> Result<(), Signal> {
+        match self.router.next_hop_for_node(to) {
+            Some(NextHop::Remote(conn)) => {
+                let mut msg = signal.into_msg(self.node(), to, UNICAST_TTL);
+                unicast::sign_signal(&self.key, &mut msg);
+                self.send_unicast(conn, MessageType::Signalling(msg));
+                Ok(())
+            }
+            _ => Err(signal),
+        }
+    }
+
     pub fn on_tick(&mut self, now_ms: u64) {
+        let ttl_ms = self.router.config().announce_ttl_ms;
+        if self.router.node_announce_expiring(now_ms + ttl_ms / 2) {
+            let announce = announce::sign_node(&self.key, now_ms + ttl_ms);
+            self.router.set_node_announce(announce);
+        }
+        for channel in self.router.expiring_channels(now_ms + ttl_ms / 2) {
+            let policy = self.policies.get(&channel).cloned().unwrap_or_default();
+            let announce = announce::sign(&self.key, channel, now_ms + ttl_ms, &policy);
+            self.router.add_channel(now_ms, channel, announce);
+        }
         self.router.on_tick(now_ms);
         self.pubsub.on_tick(now_ms);
+        self.keys.on_tick();
+        self.limits.on_tick(now_ms);
+        if let Some(discovery) = self.discovery.as_mut() {
+            // node routes give the first known nodes and their latency estimates
+            discovery.on_routes(self.router.node_routes());
+            discovery.on_tick(now_ms);
+        }
 
         self.pop_router_outputs();
         self.pop_pubsub_outputs();
+        self.pop_discovery_outputs(now_ms);
     }
 
     pub fn on_msg(&mut self, now_ms: u64, event: InputEvent) {
         match event {
+            InputEvent::ConnectionConnected(conn) | InputEvent::ConnectionAccepted(conn) => {
+                if self.limits.is_banned(conn.node()) {
+                    log::debug!("Refuse connection {:?} of banned node", conn);
+                    self.outputs.push_back(OutputEvent::Close(conn));
+                    return;
+                }
+                let nonce = self.key.next_nonce();
+                self.conns.insert(conn, ConnState::pending(nonce));
+                let hello = self.local_hello().hello(nonce);
+                self.outputs
+                    .push_back(OutputEvent::ConnectionSend(NetworkMsg {
+                        conn,
+                        msg: MessageType::Hello(hello),
+                    }));
+            }
             InputEvent::Stats(msg) => {
+                match self.conns.get_mut(&msg.conn) {
+                    Some(ConnState::Established(_)) => {}
+                    Some(ConnState::Pending { stats, .. }) => {
+                        *stats = Some(msg.msg);
+                        return;
+                    }
+                    None => return,
+                }
+                if let Some(discovery) = self.discovery.as_mut() {
+                    discovery.on_stats(msg.conn.node(), msg.msg);
+                }
                 self.router
                     .on_event(now_ms, router::InputEvent::ConnectionStats(msg));
             }
             InputEvent::ConnectionDisconnected(conn) => {
+                self.limits.on_disconnected(conn);
+                self.join_requests.remove(&conn);
+                // the other modules only know established connections
+                if !matches!(self.conns.remove(&conn), Some(ConnState::Established(_))) {
+                    return;
+                }
+                let established = self
+                    .conns
+                    .values()
+                    .any(|c| matches!(c, ConnState::Established(_)));
+                if !established && self.joined {
+                    log::info!("Lost all connections, left the network");
+                    self.joined = false;
+                    self.outputs.push_back(OutputEvent::Left);
+                }
                 self.router
                     .on_event(now_ms, router::InputEvent::ConnectionDisconnected(conn));
-                let mut removed_channels = vec![];
-                for (channel_id, remote_conn) in self.remote_channels.iter() {
-                    if *remote_conn == conn {
-                        removed_channels.push(*channel_id);
-                        if let Some(NextHop::Remote(conn)) = self.router.next_hop_for(*channel_id) {
-                            self.outputs
-                                .push_back(OutputEvent::ConnectionSend(NetworkMsg {
-                                    conn,
-                                    msg: MessageType::ChannelSub(ChannelSub {
-                                        channel: **channel_id,
-                                    }),
-                                }));
-                        }
-                    }
-                }
-                for channel in removed_channels {
-                    self.remote_channels.remove(&channel);
+                self.pop_router_outputs();
+                if let Some(discovery) = self.discovery.as_mut() {
+                    discovery.on_disconnected(now_ms, conn.node());
                 }
             }
             InputEvent::ConnectionRecv(NetworkMsg { conn, msg }) => match msg {
-                MessageType::RouterSync(sync) => {
+                _ if self.limits.is_banned(conn.node()) => {
+                    log::debug!("Drop message from banned {:?}", conn);
+                }
+                MessageType::Hello(hello) => self.on_hello(now_ms, conn, hello),
+                MessageType::HelloAck(ack) => self.on_hello_ack(now_ms, conn, ack),
+                _ if !matches!(self.conns.get(&conn), Some(ConnState::Established(_))) => {
+                    log::debug!("Drop message from {:?} before the handshake", conn);
+                }
+                _ if !self.is_allowed(now_ms, conn, &msg) => {}
+                MessageType::RouterSync(mut sync) => {
+                    // a large network can exceed the limit, the rows above it are ignored like unknown routes
+                    let max_rows = self.limits.config().max_sync_rows;
+                    if sync.rows.len() + sync.nodes.len() > max_rows {
+                        log::warn!("Truncate sync of {:?} to {} rows", conn, max_rows);
+                        sync.rows.truncate(max_rows);
+                        sync.nodes.truncate(max_rows - sync.rows.len());
+                    }
                     self.router.on_event(
                         now_ms,
                         router::InputEvent::Recv(NetworkMsg { conn, msg: sync }),
                     );
+                    self.pop_router_outputs();
                 }
-                MessageType::ChannelSub(sub) => {
-                    self.pubsub.on_event(
+                MessageType::RouterSyncRequest(req) => {
+                    self.router.on_event(
                         now_ms,
-                        pubsub::InputEvent::RecvSub(NetworkMsg { conn, msg: sub }),
+                        router::InputEvent::RecvSyncRequest(NetworkMsg { conn, msg: req }),
                     );
+                }
+                MessageType::ChannelSub(sub) => {
+                    let event = match self.check_token(now_ms, conn, &sub) {
+                        Ok(()) => pubsub::InputEvent::RecvSub(NetworkMsg { conn, msg: sub }),
+                        Err(e) => {
+                            // a subscription whose token expired is removed
+                            log::warn!(
+                                "Refuse sub of channel {} from {:?}: {:?}",
+                                sub.channel,
+                                conn,
+                                e
+                            );
+                            let msg = ChannelUnsub {
+                                channel: sub.channel,
+                            };
+                            pubsub::InputEvent::RecvUnsub(NetworkMsg { conn, msg })
+                        }
+                    };
+                    self.pubsub.on_event(now_ms, event);
                     self.pop_pubsub_outputs();
                 }
                 MessageType::ChannelUnsub(unsub) => {
@@ -94,50 +453,649 @@ impl P2pStreamRunner {
                     self.pop_pubsub_outputs();
                 }
                 MessageType::ChannelData(data) => {
+                    if !self.is_authentic(&data) {
+                        log::warn!(
+                            "Drop frame {} of channel {} from {:?} without publisher signature",
+                            data.seq(),
+                            data.channel,
+                            conn
+                        );
+                        self.misbehave(conn.node(), Misbehaviour::ForgedData);
+                        return;
+                    }
                     self.pubsub.on_event(
                         now_ms,
                         pubsub::InputEvent::RecvData(NetworkMsg { conn, msg: data }),
                     );
                     self.pop_pubsub_outputs();
                 }
+                MessageType::NodeData(data) => self.on_node_data(conn, data),
+                MessageType::Signalling(signal) => self.on_signal(conn, signal),
+                MessageType::FindNode(req) => self.on_find_node(now_ms, conn, req),
+                MessageType::FindNodeReply(reply) => self.on_find_node_reply(now_ms, conn, reply),
+                MessageType::JoinRequest(_) => self.on_join_request(conn),
+                MessageType::JoinResponse(res) => self.on_join_response(conn, res),
+                MessageType::ChannelKeyRequest(req) => self.on_channel_key_request(conn, req),
+                MessageType::ChannelKey(key) => self.on_channel_key(conn, key),
             },
         }
     }
 
-    fn pop_router_outputs(&mut self) {
-        let sync_msgs = self.router.create_sync();
-        for sync in sync_msgs {
+    pub fn pop_output(&mut self) -> Option<OutputEvent> {
+        self.outputs.pop_front()
+    }
+
+    fn local_hello(&self) -> LocalHello {
+        let mut features = BTreeSet::new();
+        let router = self.router.config();
+        if router.delta_sync {
+            features.insert(Feature::DeltaSync);
+        }
+        if router.mode == RoutingMode::Rendezvous {
+            features.insert(Feature::Rendezvous);
+        }
+        if self.discovery.is_some() {
+            features.insert(Feature::Discovery);
+        }
+        // fields of channel policies are understood by every node of this version, so they are always offered
+        features.extend([
+            Feature::Encryption,
+            Feature::DataAuth,
+            Feature::AccessControl,
+            Feature::UnicastAuth,
+        ]);
+        LocalHello {
+            node: self.node(),
+            public_key: self.key.public_key().to_vec(),
+            features,
+            capabilities: self.capabilities.clone(),
+        }
+    }
+
+    /// Hello of the remote node, it is answered with a signature of its nonce if it is accepted
+    fn on_hello(&mut self, now_ms: u64, conn: Connection, hello: Hello) {
+        let info = match self.conns.get(&conn) {
+            Some(ConnState::Pending { peer: None, .. }) => self.local_hello().accept(conn, &hello),
+            _ => {
+                log::debug!("Unexpected hello from {:?}", conn);
+                return;
+            }
+        };
+        let info = match info {
+            Ok(info) => info,
+            Err(e) => return self.refuse(conn, e),
+        };
+        self.outputs
+            .push_back(OutputEvent::ConnectionSend(NetworkMsg {
+                conn,
+                msg: MessageType::HelloAck(handshake::ack(&self.key, conn.node(), &hello.nonce)),
+            }));
+        if let Some(ConnState::Pending {
+            peer, signature, ..
+        }) = self.conns.get_mut(&conn)
+        {
+            *peer = Some(info);
+            // the signature of the remote node can arrive before its hello
+            if let Some(signature) = signature.take() {
+                self.on_signature(now_ms, conn, signature);
+            }
+        }
+    }
+
+    fn on_hello_ack(&mut self, now_ms: u64, conn: Connection, ack: HelloAck) {
+        match self.conns.get_mut(&conn) {
+            Some(ConnState::Pending {
+                peer: None,
+                signature,
+                ..
+            }) => {
+                *signature = Some(ack.signature);
+            }
+            Some(ConnState::Pending { .. }) => self.on_signature(now_ms, conn, ack.signature),
+            _ => log::debug!("Unexpected hello ack from {:?}", conn),
+        }
+    }
+
+    /// The remote node proves that it owns the key of its hello, the handshake is done
+    fn on_signature(&mut self, now_ms: u64, conn: Connection, signature: Vec<u8>) {
+        let Some(ConnState::Pending {
+            nonce,
+            peer: Some(info),
+            ..
+        }) = self.conns.get(&conn)
+        else {
+            return;
+        };
+        match handshake::verify_ack(
+            self.node(),
+            &info.public_key,
+            conn.node(),
+            nonce,
+            &signature,
+        ) {
+            Ok(()) => {
+                let info = info.clone();
+                self.on_established(now_ms, conn, info);
+            }
+            Err(e) => self.refuse(conn, e),
+        }
+    }
+
+    fn refuse(&mut self, conn: Connection, error: HandshakeError) {
+        log::warn!("Refuse connection {:?}: {:?}", conn, error);
+        self.conns.remove(&conn);
+        self.outputs.push_back(OutputEvent::Close(conn));
+    }
+
+    /// The connection is handed to the other modules, with the stats and join request which waited for the handshake
+    fn on_established(&mut self, now_ms: u64, conn: Connection, info: PeerInfo) {
+        log::debug!("Handshake with {:?} done: {:?}", conn, info);
+        self.router
+            .set_delta_sync(conn.node(), info.features.contains(&Feature::DeltaSync));
+        let (stats, join) = match self.conns.insert(conn, ConnState::Established(info)) {
+            Some(ConnState::Pending { stats, join, .. }) => (stats, join),
+            _ => (None, false),
+        };
+        if let Some(discovery) = self.discovery.as_mut() {
+            discovery.on_connected(now_ms, conn.node(), stats);
+            self.pop_discovery_outputs(now_ms);
+        }
+        if let Some(stats) = stats {
+            self.router.on_event(
+                now_ms,
+                router::InputEvent::ConnectionStats(NetworkMsg { conn, msg: stats }),
+            );
+            self.pop_router_outputs();
+        }
+        if join {
+            self.request_join(conn);
+        }
+    }
+
+    /// Answer with the known nodes which are closest to the joining node, they are good first neighbours for it
+    fn on_join_request(&mut self, conn: Connection) {
+        let joining = conn.node();
+        let mut nodes = self
+            .router
+            .node_routes()
+            .map(|(node, _)| node)
+            .collect::<Vec<_>>();
+        if let Some(discovery) = self.discovery.as_ref() {
+            nodes.extend(discovery.closest(joining, JOIN_SAMPLE_SIZE));
+        }
+        nodes.retain(|n| *n != joining && *n != self.node());
+        nodes.sort_by_key(|n| **n ^ *joining);
+        nodes.dedup();
+        nodes.truncate(JOIN_SAMPLE_SIZE);
+        log::info!("Node {:?} joins with {} known nodes", joining, nodes.len());
+        self.outputs
+            .push_back(OutputEvent::ConnectionSend(NetworkMsg {
+                conn,
+                msg: MessageType::JoinResponse(JoinResponse {
+                    nodes: nodes.into_iter().map(|n| *n).collect(),
+                }),
+            }));
+    }
+
+    /// The sample of a seed fills the neighbour manager, the first answer joins the network.
+    /// Only one response to an own request is accepted, so a neighbour can't push nodes into the table.
+    fn on_join_response(&mut self, conn: Connection, msg: JoinResponse) {
+        if !self.join_requests.remove(&conn) {
+            log::debug!("Drop unsolicited join response from {:?}", conn);
+            return;
+        }
+        if let Some(discovery) = self.discovery.as_mut() {
+            discovery.learn(conn.node());
+            for node in msg.nodes {
+                discovery.learn(node.into());
+            }
+        }
+        if !self.joined {
+            log::info!("Joined the network over {:?}", conn);
+            self.joined = true;
+            self.outputs.push_back(OutputEvent::Joined);
+        }
+    }
+
+    /// Deliver data which is addressed to this node if its sender signed it, or relay it to the next hop towards
+    /// its destination. Relays don't verify it, the destination does.
+    fn on_node_data(&mut self, conn: Connection, mut msg: NodeData) {
+        let to = NodeId::from(msg.to);
+        if to == self.node() {
+            if !unicast::verify_node_data(&msg) {
+                log::warn!("Drop node data from {:?} without its signature", msg.from);
+                return;
+            }
+            self.outputs
+                .push_back(OutputEvent::OnNodeData(msg.from.into(), msg.data));
+            return;
+        }
+        if let Some(next) = self.relay_hop(conn, to, &mut msg.ttl) {
+            self.send_unicast(next, MessageType::NodeData(msg));
+        }
+    }
+
+    /// Deliver a signal which is addressed to this node if its sender signed it, or relay it to the next hop
+    /// towards its destination
+    fn on_signal(&mut self, conn: Connection, mut msg: Signalling) {
+        let to = NodeId::from(msg.to);
+        if to == self.node() {
+            if !unicast::verify_signal(&msg) {
+                log::warn!("Drop signal from {:?} without its signature", msg.from);
+                return;
+            }
+            let from = msg.from.into();
+            match Signal::from_msg(msg) {
+                Some(signal) => self.outputs.push_back(OutputEvent::OnSignal(from, signal)),
+                None => log::warn!("Invalid signal from {:?}", conn),
+            }
+            return;
+        }
+        if let Some(next) = self.relay_hop(conn, to, &mut msg.ttl) {
+            self.send_unicast(next, MessageType::Signalling(msg));
+        }
+    }
+
+    /// Send signed unicast messages only to neighbours which keep their signature when they relay them
+    fn send_unicast(&mut self, conn: Connection, msg: MessageType) {
+        if !self.supports(conn, Feature::UnicastAuth) {
+            log::debug!(
+                "Drop unicast to {:?} without {:?}",
+                conn,
+                Feature::UnicastAuth
+            );
+            return;
+        }
+        self.outputs
+            .push_back(OutputEvent::ConnectionSend(NetworkMsg { conn, msg }));
+    }
+
+    /// Answer a lookup query which is addressed to this node, or relay it to the next hop towards its destination
+    fn on_find_node(&mut self, now_ms: u64, conn: Connection, mut msg: FindNode) {
+        let to = NodeId::from(msg.to);
+        if to == self.node() {
+            if let Some(discovery) = self.discovery.as_mut() {
+                discovery.on_find_node(msg.from.into(), msg.lookup, msg.target.into());
+                self.pop_discovery_outputs(now_ms);
+            }
+            return;
+        }
+        if let Some(next) = self.relay_hop(conn, to, &mut msg.ttl) {
             self.outputs
                 .push_back(OutputEvent::ConnectionSend(NetworkMsg {
-                    conn: sync.conn,
-                    msg: MessageType::RouterSync(sync.msg),
+                    conn: next,
+                    msg: MessageType::FindNode(msg),
                 }));
         }
     }
 
-    fn pop_pubsub_outputs(&mut self) {
-        while let Some(event) = self.pubsub.pop_output() {
-            match event {
-                pubsub::OutputEvent::SendSub(sub) => {
-                    let channel_id = sub.channel.into();
-                    let conn = if let Some(conn) = self.remote_channels.get(&channel_id) {
-                        *conn
-                    } else {
-                        if let Some(NextHop::Remote(conn)) = self.router.next_hop_for(channel_id) {
-                            conn
-                        } else {
-                            continue;
+    fn on_find_node_reply(&mut self, now_ms: u64, conn: Connection, mut msg: FindNodeReply) {
+        let to = NodeId::from(msg.to);
+        if to == self.node() {
+            if let Some(discovery) = self.discovery.as_mut() {
+                let nodes = msg.nodes.into_iter().map(NodeId::from).collect();
+                discovery.on_find_node_reply(now_ms, msg.from.into(), msg.lookup, nodes);
+                self.pop_discovery_outputs(now_ms);
+            }
+            return;
+        }
+        if let Some(next) = self.relay_hop(conn, to, &mut msg.ttl) {
+            self.outputs
+                .push_back(OutputEvent::ConnectionSend(NetworkMsg {
+                    conn: next,
+                    msg: MessageType::FindNodeReply(msg),
+                }));
+        }
+    }
+
+    /// Check the rate and size limits of a message from an established connection, a message which breaks them
+    /// is dropped and counts as misbehaviour of the node
+    fn is_allowed(&mut self, now_ms: u64, conn: Connection, msg: &MessageType) -> bool {
+        let config = self.limits.config();
+        let (kind, misbehaviour) = match msg {
+            MessageType::Hello(_) | MessageType::HelloAck(_) => return true,
+            MessageType::RouterSync(_) | MessageType::RouterSyncRequest(_) => {
+                (MessageKind::Sync, None)
+            }
+            MessageType::ChannelSub(sub) => {
+                let channel = ChannelId::from(sub.channel);
+                let too_many = !self.pubsub.has_remote_sub(channel, conn)
+                    && self.pubsub.remote_sub_count(conn) >= config.max_subs_per_conn;
+                (
+                    MessageKind::Sub,
+                    too_many.then_some(Misbehaviour::TooManySubs),
+                )
+            }
+            MessageType::ChannelUnsub(_) => (MessageKind::Sub, None),
+            MessageType::ChannelData(data) => {
+                let misbehaviour = if data.data.len() > config.max_data_len {
+                    Some(Misbehaviour::OversizedData)
+                } else {
+                    // a busy channel is only throttled, frames nobody asked for are misbehaviour
+                    match self.limits.allow_data(now_ms, conn, data.channel.into()) {
+                        Some(true) => None,
+                        Some(false) => {
+                            log::debug!("Drop frame of channel {} above the rate", data.channel);
+                            return false;
                         }
-                    };
+                        None => Some(Misbehaviour::UnsubscribedData),
+                    }
+                };
+                if let Some(misbehaviour) = misbehaviour {
+                    self.misbehave(conn.node(), misbehaviour);
+                    return false;
+                }
+                return true;
+            }
+            MessageType::NodeData(_)
+            | MessageType::Signalling(_)
+            | MessageType::FindNode(_)
+            | MessageType::FindNodeReply(_)
+            | MessageType::ChannelKeyRequest(_)
+            | MessageType::ChannelKey(_) => (MessageKind::Unicast, None),
+            MessageType::JoinRequest(_) | MessageType::JoinResponse(_) => (MessageKind::Join, None),
+        };
+        let misbehaviour = if self.limits.allow(now_ms, conn, kind) {
+            misbehaviour
+        } else {
+            Some(Misbehaviour::RateExceeded(kind))
+        };
+        match misbehaviour {
+            Some(misbehaviour) => {
+                self.misbehave(conn.node(), misbehaviour);
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Count the misbehaviour of a node, and ban it when its score is too high
+    fn misbehave(&mut self, node: NodeId, misbehaviour: Misbehaviour) {
+        log::debug!("{:?} misbehaved: {:?}", node, misbehaviour);
+        if !self.limits.penalize(node, misbehaviour) {
+            return;
+        }
+        log::warn!("Ban {:?} after {:?}", node, misbehaviour);
+        self.outputs.push_back(OutputEvent::Banned(node));
+        let mut conns = self
+            .conns
+            .keys()
+            .filter(|c| c.node() == node)
+            .copied()
+            .collect::<Vec<_>>();
+        conns.sort();
+        for conn in conns {
+            // established connections are cleaned up when the host reports them disconnected
+            if let Some(ConnState::Pending { .. }) = self.conns.get(&conn) {
+                self.conns.remove(&conn);
+            }
+            self.outputs.push_back(OutputEvent::Close(conn));
+        }
+    }
+
+    /// Subscriptions to a restricted channel need a token of the authority in its announcement,
+    /// which is issued for the sender
+    fn check_token(
+        &self,
+        now_ms: u64,
+        conn: Connection,
+        sub: &ChannelSub,
+    ) -> Result<(), TokenError> {
+        let channel = ChannelId::from(sub.channel);
+        let Some(authority) = self
+            .router
+            .announce_for(channel)
+            .and_then(|a| a.authority.as_ref())
+        else {
+            return Ok(());
+        };
+        token::verify_token(now_ms, channel, authority, conn.node(), sub.token.as_ref())
+    }
+
+    /// Publish the changed policy of a local channel with a new announcement
+    fn announce_policy(&mut self, now_ms: u64, channel: ChannelId) {
+        if let Some(NextHop::Local) = self.router.next_hop_for(channel) {
+            self.add_channel(now_ms, channel);
+        }
+    }
+
+    /// Frames of an authenticated channel must be signed by the publisher of its announcement,
+    /// other frames are accepted as they are
+    fn is_authentic(&self, frame: &ChannelData) -> bool {
+        match self.router.announce_for(frame.channel.into()) {
+            Some(announce) if announce.authenticated() => auth::verify(&announce.publisher, frame),
+            _ => true,
+        }
+    }
+
+    /// True if the feature was negotiated on the connection
+    fn supports(&self, conn: Connection, feature: Feature) -> bool {
+        self.peer(conn)
+            .is_some_and(|peer| peer.features.contains(&feature))
+    }
+
+    /// Features which a neighbour needs for a frame: a relay would drop the fields which it doesn't know
+    /// when it sends the frame on, and it must enforce the subscription tokens of a restricted channel
+    fn required_features(&self, frame: &ChannelData) -> Vec<Feature> {
+        let mut features = vec![];
+        if frame.epoch.is_some() {
+            features.push(Feature::Encryption);
+        }
+        if frame.signature.is_some() {
+            features.push(Feature::DataAuth);
+        }
+        let restricted = self
+            .router
+            .announce_for(frame.channel.into())
+            .is_some_and(|a| a.authority.is_some());
+        if restricted {
+            features.push(Feature::AccessControl);
+        }
+        features
+    }
+
+    /// Give the key of an encrypted channel which this node publishes, or relay the request towards its destination
+    fn on_channel_key_request(&mut self, conn: Connection, mut msg: ChannelKeyRequest) {
+        let to = NodeId::from(msg.to);
+        if to == self.node() {
+            self.keys.on_request(&mut self.key, msg);
+            self.pop_keys_outputs();
+            return;
+        }
+        if let Some(next) = self.relay_hop(conn, to, &mut msg.ttl) {
+            self.outputs
+                .push_back(OutputEvent::ConnectionSend(NetworkMsg {
+                    conn: next,
+                    msg: MessageType::ChannelKeyRequest(msg),
+                }));
+        }
+    }
+
+    /// Keep a channel key which is sealed for this node, it must come from the announced publisher of the channel
+    fn on_channel_key(&mut self, conn: Connection, mut msg: ChannelKey) {
+        let to = NodeId::from(msg.to);
+        if to == self.node() {
+            let publisher = self
+                .router
+                .announce_for(msg.channel.into())
+                .map(|a| a.publisher.as_slice());
+            self.keys.on_key(&self.key, publisher, msg);
+            return;
+        }
+        if let Some(next) = self.relay_hop(conn, to, &mut msg.ttl) {
+            self.outputs
+                .push_back(OutputEvent::ConnectionSend(NetworkMsg {
+                    conn: next,
+                    msg: MessageType::ChannelKey(msg),
+                }));
+        }
+    }
+
+    /// Next hop for relaying a unicast message which was received from `conn`, it decrements the ttl.
+    /// The message is dropped when the ttl expires, there is no route, or the route goes back to the sender.
+    fn relay_hop(&self, conn: Connection, to: NodeId, ttl: &mut u32) -> Option<Connection> {
+        if *ttl <= 1 {
+            log::debug!("Drop unicast to {:?}, ttl expired", to);
+            return None;
+        }
+        *ttl -= 1;
+        match self.router.next_hop_for_node(to) {
+            Some(NextHop::Remote(next)) if next.node() != conn.node() => Some(next),
+            _ => {
+                log::debug!("Drop unicast to {:?}, no route", to);
+                None
+            }
+        }
+    }
+
+    /// Move the subscriptions of all channels to their current next hop, see `update_upstream`
+    fn update_upstreams(&mut self) {
+        let mut channels = self.remote_channels.keys().copied().collect::<Vec<_>>();
+        channels.sort();
+        for channel in channels {
+            self.update_upstream(channel);
+        }
+    }
+
+    /// When the next hop of a subscribed channel changes, the old upstream gets an Unsub if it is still connected
+    /// and the new one gets a Sub, so no upstream subscription is left behind. True if a Sub was sent.
+    fn update_upstream(&mut self, channel: ChannelId) -> bool {
+        let next = match self.router.next_hop_for(channel) {
+            Some(NextHop::Remote(conn)) => Some(conn),
+            _ => None,
+        };
+        let prev = self.remote_channels.insert(channel, next).flatten();
+        if prev == next {
+            return false;
+        }
+        if let Some(prev) = prev {
+            self.limits.on_unsub(prev, channel);
+            if matches!(self.conns.get(&prev), Some(ConnState::Established(_))) {
+                log::debug!("Move upstream of {:?} away from {:?}", channel, prev);
+                self.outputs
+                    .push_back(OutputEvent::ConnectionSend(NetworkMsg {
+                        conn: prev,
+                        msg: MessageType::ChannelUnsub(ChannelUnsub { channel: *channel }),
+                    }));
+            }
+        }
+        let Some(next) = next else {
+            return false;
+        };
+        self.limits.on_sub(next, channel);
+        self.outputs
+            .push_back(OutputEvent::ConnectionSend(NetworkMsg {
+                conn: next,
+                msg: MessageType::ChannelSub(ChannelSub {
+                    channel: *channel,
+                    token: self.pubsub.sub_token(channel),
+                }),
+            }));
+        true
+    }
+
+    fn pop_router_outputs(&mut self) {
+        while let Some(event) = self.router.pop_output() {
+            match event {
+                router::OutputEvent::Sync(NetworkMsg { conn, msg }) => {
+                    self.outputs
+                        .push_back(OutputEvent::ConnectionSend(NetworkMsg {
+                            conn,
+                            msg: MessageType::RouterSync(msg),
+                        }));
+                }
+                router::OutputEvent::SyncRequest(NetworkMsg { conn, msg }) => {
                     self.outputs
                         .push_back(OutputEvent::ConnectionSend(NetworkMsg {
                             conn,
-                            msg: MessageType::ChannelSub(sub),
+                            msg: MessageType::RouterSyncRequest(msg),
                         }));
                 }
+                router::OutputEvent::Misbehaved(node, misbehaviour) => {
+                    self.misbehave(node, misbehaviour)
+                }
+            }
+        }
+        self.update_upstreams();
+    }
+
+    fn pop_discovery_outputs(&mut self, now_ms: u64) {
+        let node = self.node();
+        let discovery = if let Some(discovery) = self.discovery.as_mut() {
+            discovery
+        } else {
+            return;
+        };
+        while let Some(event) = discovery.pop_output() {
+            match event {
+                discovery::OutputEvent::Connect(node) => {
+                    if !self.limits.is_banned(node) {
+                        self.outputs.push_back(OutputEvent::Connect(node))
+                    }
+                }
+                discovery::OutputEvent::Disconnect(node) => {
+                    self.outputs.push_back(OutputEvent::Disconnect(node))
+                }
+                discovery::OutputEvent::FindNode { to, lookup, target } => {
+                    match self.router.next_hop_for_node(to) {
+                        Some(NextHop::Remote(conn)) => {
+                            self.outputs
+                                .push_back(OutputEvent::ConnectionSend(NetworkMsg {
+                                    conn,
+                                    msg: MessageType::FindNode(FindNode {
+                                        from: *node,
+                                        to: *to,
+                                        ttl: UNICAST_TTL,
+                                        lookup,
+                                        target: *target,
+                                    }),
+                                }));
+                        }
+                        _ => discovery.on_query_failed(now_ms, lookup, to),
+                    }
+                }
+                discovery::OutputEvent::FindNodeReply { to, lookup, nodes } => {
+                    if let Some(NextHop::Remote(conn)) = self.router.next_hop_for_node(to) {
+                        self.outputs
+                            .push_back(OutputEvent::ConnectionSend(NetworkMsg {
+                                conn,
+                                msg: MessageType::FindNodeReply(FindNodeReply {
+                                    from: *node,
+                                    to: *to,
+                                    ttl: UNICAST_TTL,
+                                    lookup,
+                                    nodes: nodes.into_iter().map(|n| *n).collect(),
+                                }),
+                            }));
+                    }
+                }
+            }
+        }
+    }
+
+    fn pop_pubsub_outputs(&mut self) {
+        while let Some(event) = self.pubsub.pop_output() {
+            match event {
+                pubsub::OutputEvent::SendSub(sub) => {
+                    let channel_id = sub.channel.into();
+                    self.remote_channels.entry(channel_id).or_default();
+                    // a new next hop already got its Sub, the current one gets a refresh
+                    if self.update_upstream(channel_id) {
+                        continue;
+                    }
+                    if let Some(Some(conn)) = self.remote_channels.get(&channel_id) {
+                        self.limits.on_sub(*conn, channel_id);
+                        self.outputs
+                            .push_back(OutputEvent::ConnectionSend(NetworkMsg {
+                                conn: *conn,
+                                msg: MessageType::ChannelSub(sub),
+                            }));
+                    }
+                }
                 pubsub::OutputEvent::SendUnsub(unsub) => {
                     let channel_id = unsub.channel.into();
-                    if let Some(conn) = self.remote_channels.remove(&channel_id) {
+                    if let Some(Some(conn)) = self.remote_channels.remove(&channel_id) {
+                        self.limits.on_unsub(conn, channel_id);
                         self.outputs
                             .push_back(OutputEvent::ConnectionSend(NetworkMsg {
                                 conn,
@@ -146,15 +1104,54 @@ impl P2pStreamRunner {
                     }
                 }
                 pubsub::OutputEvent::SendData(NetworkMsg { conn, msg }) => {
+                    if let Some(feature) = self
+                        .required_features(&msg)
+                        .into_iter()
+                        .find(|f| !self.supports(conn, *f))
+                    {
+                        log::debug!(
+                            "Drop frame of channel {} to {:?} without {:?}",
+                            msg.channel,
+                            conn,
+                            feature
+                        );
+                        continue;
+                    }
                     self.outputs
                         .push_back(OutputEvent::ConnectionSend(NetworkMsg {
                             conn,
                             msg: MessageType::ChannelData(msg),
                         }));
                 }
-                pubsub::OutputEvent::OnChannelData(channel_id, data) => self
-                    .outputs
-                    .push_back(OutputEvent::OnChannelData(channel_id, data)),
+                pubsub::OutputEvent::OnChannelData(msg) => {
+                    let channel_id = ChannelId::from(msg.channel);
+                    let announce = self.router.announce_for(channel_id);
+                    let publisher = announce.map(|a| identity::node_id_of(&a.publisher));
+                    let encrypted = announce.is_some_and(|a| a.encrypted());
+                    if let Some(data) = self.keys.open(&self.key, publisher, encrypted, msg) {
+                        self.outputs
+                            .push_back(OutputEvent::OnChannelData(channel_id, data));
+                    }
+                }
+            }
+        }
+        self.pop_keys_outputs();
+    }
+
+    fn pop_keys_outputs(&mut self) {
+        while let Some(event) = self.keys.pop_output() {
+            let (to, msg) = match event {
+                e2ee::OutputEvent::SendRequest(req) => {
+                    (req.to, MessageType::ChannelKeyRequest(req))
+                }
+                e2ee::OutputEvent::SendKey(key) => (key.to, MessageType::ChannelKey(key)),
+            };
+            match self.router.next_hop_for_node(to.into()) {
+                Some(NextHop::Remote(conn)) => {
+                    self.outputs
+                        .push_back(OutputEvent::ConnectionSend(NetworkMsg { conn, msg }));
+                }
+                _ => log::debug!("Drop key message to {:?}, no route", to),
             }
         }
     }
@@ -0,0 +1,358 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+use prost::Message;
+
+use crate::{
+    addr::NodeId,
+    handshake::{self, LocalHello},
+    identity::{self, NodeKey},
+    network::Connection,
+    protocol::{network_message::MessageType, signalling::Kind, Hello, NetworkMessage, Signalling},
+    transport::{Transport, TransportEvent},
+};
+
+/// Signals which are queued while the signalling server is not connected, older ones are dropped
+const MAX_PENDING_SIGNALS: usize = 64;
+/// Interval between two connect attempts to the signalling server
+const RECONNECT_INTERVAL_MS: u64 = 5000;
+
+/// Message which must be delivered to a node before a direct connection can be set up with it,
+/// like the WebRTC session descriptions and candidates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Signal {
+    Offer { session: u32, sdp: String },
+    Answer { session: u32, sdp: String },
+    Candidate { session: u32, candidate: String },
+}
+
+impl Signal {
+    pub(crate) fn into_msg(self, from: NodeId, to: NodeId, ttl: u32) -> Signalling {
+        let (kind, session, data) = match self {
+            Signal::Offer { session, sdp } => (Kind::Offer, session, sdp),
+            Signal::Answer { session, sdp } => (Kind::Answer, session, sdp),
+            Signal::Candidate { session, candidate } => (Kind::Candidate, session, candidate),
+        };
+        Signalling {
+            from: *from,
+            to: *to,
+            ttl,
+            session,
+            kind: kind.into(),
+            data,
+            public_key: None,
+            signature: None,
+        }
+    }
+
+    pub(crate) fn from_msg(msg: Signalling) -> Option<Self> {
+        let Signalling {
+            session,
+            kind,
+            data,
+            ..
+        } = msg;
+        match Kind::try_from(kind).ok()? {
+            Kind::Offer => Some(Signal::Offer { session, sdp: data }),
+            Kind::Answer => Some(Signal::Answer { session, sdp: data }),
+            Kind::Candidate => Some(Signal::Candidate {
+                session,
+                candidate: data,
+            }),
+        }
+    }
+}
+
+/// Channel which carries signals to nodes which are not reachable over the overlay,
+/// like a bootstrap signalling server for the first connection of a node
+pub trait SignallingChannel {
+    /// Send a signal to a node, the delivery is not guaranteed
+    fn send_signal(&mut self, now_ms: u64, to: NodeId, signal: Signal);
+    /// Poll the next signal which was received from a node
+    fn pop_signal(&mut self, now_ms: u64) -> Option<(NodeId, Signal)>;
+}
+
+/// Signalling channel over a connection to a `SignallingServer`, with any transport.
+/// It connects on first use and reconnects when the connection is lost.
+/// The key must be the one of the node, the server only relays signals of clients which prove to own it.
+pub struct TransportSignalling<T: Transport> {
+    key: NodeKey,
+    transport: T,
+    server: T::Addr,
+    conn: Option<Connection>,
+    /// The key is proven to the server, which relays signals from then on
+    ready: bool,
+    last_connect_ms: Option<u64>,
+    pending: VecDeque<(NodeId, Signal)>,
+    received: VecDeque<(NodeId, Signal)>,
+}
+
+impl<T: Transport> TransportSignalling<T> {
+    pub fn new(key: NodeKey, transport: T, server: T::Addr) -> Self {
+        Self {
+            key,
+            transport,
+            server,
+            conn: None,
+            ready: false,
+            last_connect_ms: None,
+            pending: VecDeque::new(),
+            received: VecDeque::new(),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.conn.is_some() && self.ready
+    }
+
+    fn send_to_server(&mut self, conn: Connection, to: NodeId, signal: Signal) {
+        // the server fills the sender and doesn't relay further
+        let msg = NetworkMessage {
+            message_type: Some(MessageType::Signalling(signal.into_msg(0.into(), to, 0))),
+        };
+        self.transport.send(conn, &msg.encode_to_vec());
+    }
+
+    /// Answer the hello of the server with the own hello and a signature of its nonce, signals which
+    /// are sent after it are relayed
+    fn on_server_hello(&mut self, conn: Connection, hello: Hello) {
+        if self.conn != Some(conn) || self.ready {
+            return;
+        }
+        let local = LocalHello {
+            node: self.key.node_id(),
+            public_key: self.key.public_key().to_vec(),
+            features: BTreeSet::new(),
+            capabilities: BTreeSet::new(),
+        };
+        let nonce = self.key.next_nonce();
+        let ack = handshake::ack(&self.key, conn.node(), &hello.nonce);
+        for msg in [
+            MessageType::Hello(local.hello(nonce)),
+            MessageType::HelloAck(ack),
+        ] {
+            let msg = NetworkMessage {
+                message_type: Some(msg),
+            };
+            self.transport.send(conn, &msg.encode_to_vec());
+        }
+        self.ready = true;
+        while let Some((to, signal)) = self.pending.pop_front() {
+            self.send_to_server(conn, to, signal);
+        }
+    }
+
+    fn poll(&mut self, now_ms: u64) {
+        while let Some(event) = self.transport.pop_event(now_ms) {
+            match event {
+                TransportEvent::Connected(conn) | TransportEvent::Accepted(conn) => {
+                    log::info!("Signalling server connected {:?}", conn);
+                    self.conn = Some(conn);
+                    self.ready = false;
+                }
+                TransportEvent::Recv(pkt) => match NetworkMessage::decode(pkt.data.as_slice()) {
+                    Ok(NetworkMessage {
+                        message_type: Some(MessageType::Hello(hello)),
+                    }) => self.on_server_hello(pkt.conn, hello),
+                    Ok(NetworkMessage {
+                        message_type: Some(MessageType::Signalling(msg)),
+                    }) => {
+                        let from = msg.from.into();
+                        match Signal::from_msg(msg) {
+                            Some(signal) => self.received.push_back((from, signal)),
+                            None => log::warn!("Invalid signal from {:?}", from),
+                        }
+                    }
+                    _ => log::warn!("Invalid message from signalling server"),
+                },
+                TransportEvent::Disconnected(conn) => {
+                    if self.conn == Some(conn) {
+                        log::info!("Signalling server disconnected {:?}", conn);
+                        self.conn = None;
+                        self.ready = false;
+                    }
+                }
+            }
+        }
+
+        let retry = self
+            .last_connect_ms
+            .is_none_or(|last| now_ms >= last + RECONNECT_INTERVAL_MS);
+        if self.conn.is_none() && retry {
+            self.last_connect_ms = Some(now_ms);
+            if let Err(e) = self.transport.connect(now_ms, self.server.clone()) {
+                log::warn!("Connect signalling server error {:?}", e);
+            }
+        }
+    }
+}
+
+impl<T: Transport> SignallingChannel for TransportSignalling<T> {
+    fn send_signal(&mut self, now_ms: u64, to: NodeId, signal: Signal) {
+        match self.conn.filter(|_| self.ready) {
+            Some(conn) => self.send_to_server(conn, to, signal),
+            None => {
+                if self.pending.len() >= MAX_PENDING_SIGNALS {
+                    self.pending.pop_front();
+                }
+                self.pending.push_back((to, signal));
+                self.poll(now_ms);
+            }
+        }
+    }
+
+    fn pop_signal(&mut self, now_ms: u64) -> Option<(NodeId, Signal)> {
+        if self.received.is_empty() {
+            self.poll(now_ms);
+        }
+        self.received.pop_front()
+    }
+}
+
+/// Bootstrap signalling server, it relays signals between the nodes which are connected to it.
+/// A client is only registered under its node id after it proved to own the key of it, with the same
+/// hello and signed nonce as the handshake between nodes, so it can't receive the signals of another node
+/// or send signals in its name.
+pub struct SignallingServer<T: Transport> {
+    key: NodeKey,
+    transport: T,
+    /// Connections which didn't prove their node id yet
+    pending: HashMap<Connection, PendingClient>,
+    /// Proven connection of each node
+    clients: HashMap<NodeId, Connection>,
+}
+
+struct PendingClient {
+    /// Nonce which the client must sign
+    nonce: [u8; 32],
+    /// Public key of the client hello, once it is received and matches the node id
+    public_key: Option<Vec<u8>>,
+}
+
+impl<T: Transport> SignallingServer<T> {
+    /// The transport must use the node id of the key, clients sign their proof toward it
+    pub fn new(key: NodeKey, transport: T) -> Self {
+        Self {
+            key,
+            transport,
+            pending: HashMap::new(),
+            clients: HashMap::new(),
+        }
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Clients which proved their node id
+    pub fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn send(&mut self, conn: Connection, msg: MessageType) {
+        let msg = NetworkMessage {
+            message_type: Some(msg),
+        };
+        self.transport.send(conn, &msg.encode_to_vec());
+    }
+
+    fn on_connected(&mut self, conn: Connection) {
+        let nonce = self.key.next_nonce();
+        let local = LocalHello {
+            node: self.key.node_id(),
+            public_key: self.key.public_key().to_vec(),
+            features: BTreeSet::new(),
+            capabilities: BTreeSet::new(),
+        };
+        self.pending.insert(
+            conn,
+            PendingClient {
+                nonce,
+                public_key: None,
+            },
+        );
+        self.send(conn, MessageType::Hello(local.hello(nonce)));
+    }
+
+    fn on_msg(&mut self, conn: Connection, msg: MessageType) {
+        match msg {
+            MessageType::Hello(hello) => {
+                let node = conn.node();
+                let valid = NodeId::from(hello.node) == node
+                    && identity::node_id_of(&hello.public_key) == node;
+                match self.pending.get_mut(&conn) {
+                    Some(pending) if valid => pending.public_key = Some(hello.public_key),
+                    Some(_) => self.refuse(conn),
+                    None => {}
+                }
+            }
+            MessageType::HelloAck(ack) => {
+                let pending = match self.pending.get(&conn) {
+                    Some(pending) => pending,
+                    None => return,
+                };
+                let public_key = pending.public_key.as_deref().unwrap_or_default();
+                let proven = handshake::verify_ack(
+                    self.key.node_id(),
+                    public_key,
+                    conn.node(),
+                    &pending.nonce,
+                    &ack.signature,
+                );
+                if proven.is_err() {
+                    self.refuse(conn);
+                    return;
+                }
+                self.pending.remove(&conn);
+                // only a proven connection replaces the old one, which has the same owner
+                if let Some(old) = self.clients.insert(conn.node(), conn) {
+                    log::info!("Client {:?} replaced by {:?}", old, conn);
+                }
+            }
+            MessageType::Signalling(mut msg) => {
+                if self.clients.get(&conn.node()) != Some(&conn) {
+                    log::debug!("Signal from unproven {:?}", conn);
+                    return;
+                }
+                msg.from = *conn.node();
+                let to = match self.clients.get(&msg.to.into()) {
+                    Some(to) => *to,
+                    None => {
+                        log::debug!("Signal to unknown node {}", msg.to);
+                        return;
+                    }
+                };
+                self.send(to, MessageType::Signalling(msg));
+            }
+            _ => log::debug!("Unexpected message from {:?}", conn),
+        }
+    }
+
+    fn refuse(&mut self, conn: Connection) {
+        log::warn!("Client {:?} can't prove its node id", conn);
+        self.pending.remove(&conn);
+        self.transport.close(conn);
+    }
+
+    /// Process all pending transport events, should be called whenever the transport may have new events
+    pub fn poll(&mut self, now_ms: u64) {
+        while let Some(event) = self.transport.pop_event(now_ms) {
+            match event {
+                TransportEvent::Connected(conn) | TransportEvent::Accepted(conn) => {
+                    self.on_connected(conn)
+                }
+                TransportEvent::Disconnected(conn) => {
+                    self.pending.remove(&conn);
+                    if self.clients.get(&conn.node()) == Some(&conn) {
+                        self.clients.remove(&conn.node());
+                    }
+                }
+                TransportEvent::Recv(pkt) => match NetworkMessage::decode(pkt.data.as_slice()) {
+                    Ok(NetworkMessage {
+                        message_type: Some(msg),
+                    }) => self.on_msg(pkt.conn, msg),
+                    _ => log::warn!("Invalid message from {:?}", pkt.conn),
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use crate::{
+    addr::NodeId,
+    network::{Connection, ConnectionStats, NetworkPkt},
+    signalling::Signal,
+};
+
+pub enum TransportEvent {
+    /// Outgoing connection which was started with `Transport::connect` is established
+    Connected(Connection),
+    /// Incoming connection is established
+    Accepted(Connection),
+    Recv(NetworkPkt),
+    Disconnected(Connection),
+}
+
+/// A transport carries encoded `NetworkMessage`s between nodes, like UDP, WebSocket or WebRTC.
+/// It is driven by polling, so it can be used from native event loops as well as from browser callbacks.
+/// Each established connection must know the remote NodeId, and use a session which is unique
+/// between all connections to that node.
+pub trait Transport {
+    type Addr: Clone + std::fmt::Debug;
+    type Error: std::fmt::Debug;
+
+    /// Start connecting to a remote address, the connection is reported with `TransportEvent::Connected`
+    fn connect(&mut self, now_ms: u64, addr: Self::Addr) -> Result<(), Self::Error>;
+    fn send(&mut self, conn: Connection, data: &[u8]);
+    /// Send channel data which is useless when late, transports which can should send it without retransmission
+    fn send_unreliable(&mut self, conn: Connection, data: &[u8]) {
+        self.send(conn, data);
+    }
+    fn close(&mut self, conn: Connection);
+    fn stats(&self, conn: Connection) -> Option<ConnectionStats>;
+    /// Poll the next event of accepted and connected connections, received data and disconnects
+    fn pop_event(&mut self, now_ms: u64) -> Option<TransportEvent>;
+    /// Poll the next signal which must be delivered to a node before connecting to it, like a WebRTC offer
+    fn pop_signal(&mut self) -> Option<(NodeId, Signal)> {
+        None
+    }
+    /// Handle a signal which was received from a node
+    fn on_signal(&mut self, _now_ms: u64, _from: NodeId, _signal: Signal) {}
+}
@@ -0,0 +1,120 @@
+use crate::{
+    addr::NodeId,
+    identity::{self, NodeKey},
+    protocol::{NodeData, Signalling},
+};
+
+/// Sign data to a node with the key of its sender, the ttl is left out since every hop changes it
+pub fn sign_node_data(key: &NodeKey, msg: &mut NodeData) {
+    msg.public_key = Some(key.public_key().to_vec());
+    msg.signature = Some(key.sign(&node_data_payload(msg)).to_vec());
+}
+
+/// False if the data is not signed by the node which it names as sender, or was changed by a relay
+pub fn verify_node_data(msg: &NodeData) -> bool {
+    is_signed_by(
+        msg.from,
+        &msg.public_key,
+        &msg.signature,
+        &node_data_payload(msg),
+    )
+}
+
+/// Sign a signal to a node with the key of its sender, the ttl is left out since every hop changes it
+pub fn sign_signal(key: &NodeKey, msg: &mut Signalling) {
+    msg.public_key = Some(key.public_key().to_vec());
+    msg.signature = Some(key.sign(&signal_payload(msg)).to_vec());
+}
+
+/// False if the signal is not signed by the node which it names as sender, or was changed by a relay
+pub fn verify_signal(msg: &Signalling) -> bool {
+    is_signed_by(
+        msg.from,
+        &msg.public_key,
+        &msg.signature,
+        &signal_payload(msg),
+    )
+}
+
+fn is_signed_by(
+    from: u64,
+    public_key: &Option<Vec<u8>>,
+    signature: &Option<Vec<u8>>,
+    payload: &[u8],
+) -> bool {
+    match (public_key, signature) {
+        (Some(public_key), Some(signature)) => {
+            identity::node_id_of(public_key) == NodeId::from(from)
+                && identity::verify(public_key, payload, signature)
+        }
+        _ => false,
+    }
+}
+
+fn node_data_payload(msg: &NodeData) -> Vec<u8> {
+    let mut payload = b"node-data".to_vec();
+    payload.extend_from_slice(&msg.from.to_be_bytes());
+    payload.extend_from_slice(&msg.to.to_be_bytes());
+    payload.extend_from_slice(&msg.data);
+    payload
+}
+
+fn signal_payload(msg: &Signalling) -> Vec<u8> {
+    let mut payload = b"signal".to_vec();
+    payload.extend_from_slice(&msg.from.to_be_bytes());
+    payload.extend_from_slice(&msg.to.to_be_bytes());
+    payload.extend_from_slice(&msg.session.to_be_bytes());
+    payload.extend_from_slice(&msg.kind.to_be_bytes());
+    payload.extend_from_slice(msg.data.as_bytes());
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spoofed_node_data_is_rejected() {
+        let key = NodeKey::from_secret([1; 32]);
+        let mut msg = NodeData {
+            from: *key.node_id(),
+            to: 2,
+            ttl: 16,
+            data: vec![1, 2, 3],
+            ..Default::default()
+        };
+        sign_node_data(&key, &mut msg);
+        assert!(verify_node_data(&msg));
+        // relays decrement the ttl
+        assert!(verify_node_data(&NodeData {
+            ttl: 3,
+            ..msg.clone()
+        }));
+
+        let mut impostor = NodeData {
+            from: *NodeKey::from_secret([3; 32]).node_id(),
+            ..msg.clone()
+        };
+        let forged = [
+            NodeData {
+                data: vec![6, 6, 6],
+                ..msg.clone()
+            },
+            NodeData {
+                to: 4,
+                ..msg.clone()
+            },
+            NodeData {
+                signature: None,
+                ..msg.clone()
+            },
+            impostor.clone(),
+        ];
+        for forged in forged {
+            assert!(!verify_node_data(&forged));
+        }
+        // a relay can sign with its own key, but not in the name of another node
+        sign_node_data(&NodeKey::from_secret([2; 32]), &mut impostor);
+        assert!(!verify_node_data(&impostor));
+    }
+}
@@ -0,0 +1,6 @@
+mod link;
+mod simulator;
+mod transport;
+pub use link::LinkConfig;
+pub use simulator::{DeliveryStats, NetworkStats, Simulator, SimulatorConfig};
+pub use transport::MemoryTransport;
@@ -0,0 +1,69 @@
+use protocol::{ConnectionStats, NodeId};
+
+/// Quality of a simulated link, the same in both directions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkConfig {
+    /// One way latency
+    pub latency_ms: u32,
+    /// Percent of packets which are dropped, from 0 to 100
+    pub loss_percent: f32,
+    /// Packets are serialized at this rate, so big or bursty sends are queued
+    pub bandwidth_kbps: u32,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            latency_ms: 20,
+            loss_percent: 0.0,
+            bandwidth_kbps: 10_000,
+        }
+    }
+}
+
+impl LinkConfig {
+    /// Stats which the transports report for the link
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            rtt_ms: self.latency_ms * 2,
+            lost_percent: self.loss_percent.into(),
+            jitter_ms: 0,
+            bandwidth_kbps: self.bandwidth_kbps,
+        }
+    }
+}
+
+/// A bidirectional link between two nodes, the first node is always the smaller id
+pub(crate) struct Link {
+    pub session: u32,
+    pub config: LinkConfig,
+    busy_until_ms: [u64; 2],
+}
+
+impl Link {
+    pub fn new(session: u32, config: LinkConfig) -> Self {
+        Self {
+            session,
+            config,
+            busy_until_ms: [0, 0],
+        }
+    }
+
+    pub fn key(a: NodeId, b: NodeId) -> (NodeId, NodeId) {
+        if a < b {
+            (a, b)
+        } else {
+            (b, a)
+        }
+    }
+
+    /// Arrival time of a packet which is sent now, after waiting for the previous packets in the same direction
+    pub fn schedule(&mut self, now_ms: u64, from: NodeId, to: NodeId, bytes: usize) -> u64 {
+        let dir = usize::from(from > to);
+        // kbps is bits per ms
+        let transmit_ms = (bytes as u64 * 8).div_ceil(self.config.bandwidth_kbps.max(1) as u64);
+        let start = self.busy_until_ms[dir].max(now_ms);
+        self.busy_until_ms[dir] = start + transmit_ms;
+        self.busy_until_ms[dir] + self.config.latency_ms as u64
+    }
+}
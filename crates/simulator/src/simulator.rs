@@ -0,0 +1,1209 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use protocol::{
+    protocol::ChannelToken, BootstrapConfig, ChannelId, Connection, DiscoveryConfig, NodeId,
+    NodeKey, OutputEvent, P2pStreamDriver, P2pStreamRunner, RouterConfig, Signal,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    link::{Link, LinkConfig},
+    transport::{MemoryTransport, TransportRequest},
+};
+
+#[derive(Debug, Clone)]
+pub struct SimulatorConfig {
+    /// Seed of the packet loss, the same seed and scenario always give the same result
+    pub seed: u64,
+    pub tick_interval_ms: u64,
+    pub router: RouterConfig,
+    /// Link which is created when a node connects to another node by itself
+    pub default_link: LinkConfig,
+    /// Nodes choose their neighbours with the neighbour manager, otherwise links are only made by the scenario
+    pub discovery: Option<DiscoveryConfig>,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            tick_interval_ms: 1000,
+            router: RouterConfig::default(),
+            default_link: LinkConfig::default(),
+            discovery: None,
+        }
+    }
+}
+
+/// Delivery of the published data of a channel to the nodes which were subscribed at publish time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeliveryStats {
+    pub expected: usize,
+    pub delivered: usize,
+    pub duplicates: usize,
+}
+
+impl DeliveryStats {
+    pub fn ratio(&self) -> f32 {
+        if self.expected == 0 {
+            return 1.0;
+        }
+        self.delivered as f32 / self.expected as f32
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetworkStats {
+    pub sent_pkts: u64,
+    pub sent_bytes: u64,
+    pub lost_pkts: u64,
+    pub delivered_pkts: u64,
+}
+
+struct SimNode {
+    driver: P2pStreamDriver<MemoryTransport>,
+    received: HashMap<ChannelId, HashMap<u64, usize>>,
+    node_data: Vec<(NodeId, Vec<u8>)>,
+}
+
+struct Packet {
+    from: NodeId,
+    to: NodeId,
+    session: u32,
+    data: Vec<u8>,
+}
+
+struct Published {
+    seq: u64,
+    expected: Vec<NodeId>,
+}
+
+/// Deterministic network of runners over a virtual clock.
+/// Nodes and links are always processed in id order and the only randomness comes from the seeded rng,
+/// so a scenario is reproducible from its seed.
+///
+/// Scenarios address nodes by small ids, while runners use the NodeId of their key.
+/// The key of a node is derived from its scenario id, so the same scenario always gives the same NodeIds.
+pub struct Simulator {
+    config: SimulatorConfig,
+    now_ms: u64,
+    next_tick_ms: u64,
+    rng: StdRng,
+    nodes: BTreeMap<NodeId, SimNode>,
+    /// Scenario id of the NodeId of each runner
+    scenario_ids: HashMap<NodeId, NodeId>,
+    links: BTreeMap<(NodeId, NodeId), Link>,
+    /// Quality of the links which nodes create by themselves, `default_link` if not set
+    link_model: Option<Box<dyn Fn(NodeId, NodeId) -> LinkConfig>>,
+    next_session: u32,
+    packets: BTreeMap<(u64, u64), Packet>,
+    next_packet: u64,
+    dirty: BTreeSet<NodeId>,
+    subscribers: HashMap<ChannelId, BTreeSet<NodeId>>,
+    published: HashMap<ChannelId, Vec<Published>>,
+    next_seq: u64,
+    stats: NetworkStats,
+}
+
+impl Simulator {
+    pub fn new(config: SimulatorConfig) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+            now_ms: 0,
+            next_tick_ms: 0,
+            nodes: BTreeMap::new(),
+            scenario_ids: HashMap::new(),
+            links: BTreeMap::new(),
+            link_model: None,
+            next_session: 0,
+            packets: BTreeMap::new(),
+            next_packet: 0,
+            dirty: BTreeSet::new(),
+            subscribers: HashMap::new(),
+            published: HashMap::new(),
+            next_seq: 0,
+            stats: NetworkStats::default(),
+        }
+    }
+
+    pub fn now_ms(&self) -> u64 {
+        self.now_ms
+    }
+
+    pub fn stats(&self) -> NetworkStats {
+        self.stats
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.nodes.keys().copied()
+    }
+
+    pub fn add_node(&mut self, node: NodeId) {
+        self.add_node_with_config(node, self.config.router.clone());
+    }
+
+    /// Add a node whose router config differs from the simulator one, like a node of another routing mode.
+    /// Panics if the config is invalid, scenarios are expected to use working configs.
+    pub fn add_node_with_config(&mut self, node: NodeId, router: RouterConfig) {
+        let key = node_key(node);
+        self.scenario_ids.insert(key.node_id(), node);
+        let mut runner =
+            P2pStreamRunner::new_with_config(key, router).expect("valid router config");
+        if let Some(config) = &self.config.discovery {
+            runner.enable_discovery(config.clone());
+        }
+        self.nodes.insert(
+            node,
+            SimNode {
+                driver: P2pStreamDriver::new(runner, MemoryTransport::new()),
+                received: HashMap::new(),
+                node_data: vec![],
+            },
+        );
+    }
+
+    pub fn runner(&self, node: NodeId) -> Option<&P2pStreamRunner> {
+        self.nodes.get(&node).map(|n| n.driver.runner())
+    }
+
+    /// NodeId which the runner of a scenario id uses, also for nodes which are not added
+    pub fn node_id(&self, node: NodeId) -> NodeId {
+        match self.nodes.get(&node) {
+            Some(n) => n.driver.runner().node(),
+            None => node_key(node).node_id(),
+        }
+    }
+
+    /// Let a node join the network over seed nodes, which are tried from the next tick until one answers
+    pub fn join(&mut self, node: NodeId, seeds: Vec<NodeId>) {
+        let seeds = seeds.into_iter().map(|s| self.node_id(s)).collect();
+        if let Some(n) = self.nodes.get_mut(&node) {
+            n.driver.join(seeds, BootstrapConfig::default());
+        }
+    }
+
+    /// Connect two nodes, a link which already exists is only updated
+    pub fn add_link(&mut self, a: NodeId, b: NodeId, config: LinkConfig) {
+        if a == b || !self.nodes.contains_key(&a) || !self.nodes.contains_key(&b) {
+            log::warn!("Invalid link {:?} - {:?}", a, b);
+            return;
+        }
+        let (a_id, b_id) = (self.node_id(a), self.node_id(b));
+        if let Some(link) = self.links.get_mut(&Link::key(a, b)) {
+            link.config = config;
+            let session = link.session;
+            self.transport(a)
+                .on_stats(Connection::from_parts(b_id, session), config.stats());
+            self.transport(b)
+                .on_stats(Connection::from_parts(a_id, session), config.stats());
+            return;
+        }
+
+        let session = self.next_session;
+        self.next_session += 1;
+        self.links
+            .insert(Link::key(a, b), Link::new(session, config));
+        self.transport(a)
+            .on_connected(Connection::from_parts(b_id, session), config.stats(), true);
+        self.transport(b).on_connected(
+            Connection::from_parts(a_id, session),
+            config.stats(),
+            false,
+        );
+        self.flush();
+    }
+
+    /// Quality of the links which nodes create by themselves, like a latency which depends on node locations
+    pub fn set_link_model<F: Fn(NodeId, NodeId) -> LinkConfig + 'static>(&mut self, model: F) {
+        self.link_model = Some(Box::new(model));
+    }
+
+    /// Disconnect two nodes, packets which are still in flight are lost
+    pub fn remove_link(&mut self, a: NodeId, b: NodeId) {
+        if let Some(link) = self.links.remove(&Link::key(a, b)) {
+            let (a_id, b_id) = (self.node_id(a), self.node_id(b));
+            self.transport(a)
+                .on_disconnected(Connection::from_parts(b_id, link.session));
+            self.transport(b)
+                .on_disconnected(Connection::from_parts(a_id, link.session));
+            self.flush();
+        }
+    }
+
+    pub fn links(&self) -> impl Iterator<Item = (NodeId, NodeId, LinkConfig)> + '_ {
+        self.links
+            .iter()
+            .map(|((a, b), link)| (*a, *b, link.config))
+    }
+
+    pub fn add_channel(&mut self, node: NodeId, channel: ChannelId) {
+        let now_ms = self.now_ms;
+        if let Some(n) = self.nodes.get_mut(&node) {
+            n.driver.runner_mut().add_channel(now_ms, channel);
+            self.dirty.insert(node);
+            self.flush();
+        }
+    }
+
+    pub fn remove_channel(&mut self, node: NodeId, channel: ChannelId) {
+        if let Some(n) = self.nodes.get_mut(&node) {
+            n.driver.runner_mut().remove_channel(channel);
+            self.dirty.insert(node);
+            self.flush();
+        }
+    }
+
+    /// Sign the frames which the node publishes to the channel, other frames of the channel are dropped
+    pub fn authenticate_channel(&mut self, node: NodeId, channel: ChannelId) {
+        let now_ms = self.now_ms;
+        if let Some(n) = self.nodes.get_mut(&node) {
+            n.driver.runner_mut().authenticate_channel(now_ms, channel);
+            self.dirty.insert(node);
+            self.flush();
+        }
+    }
+
+    /// Require subscriptions to the channel to carry a token of the authority, see `protocol::issue_token`
+    pub fn restrict_channel(&mut self, node: NodeId, channel: ChannelId, authority: &NodeKey) {
+        let now_ms = self.now_ms;
+        if let Some(n) = self.nodes.get_mut(&node) {
+            n.driver.runner_mut().restrict_channel(
+                now_ms,
+                channel,
+                authority.public_key().to_vec(),
+            );
+            self.dirty.insert(node);
+            self.flush();
+        }
+    }
+
+    /// Encrypt the frames which the node publishes to the channel, only authorized subscribers can read them
+    pub fn encrypt_channel(&mut self, node: NodeId, channel: ChannelId) {
+        let now_ms = self.now_ms;
+        if let Some(n) = self.nodes.get_mut(&node) {
+            n.driver.runner_mut().encrypt_channel(now_ms, channel);
+            self.dirty.insert(node);
+            self.flush();
+        }
+    }
+
+    pub fn authorize(&mut self, node: NodeId, channel: ChannelId, subscriber: NodeId) {
+        let subscriber = self.node_id(subscriber);
+        if let Some(n) = self.nodes.get_mut(&node) {
+            n.driver
+                .runner_mut()
+                .authorize_subscriber(channel, subscriber);
+        }
+    }
+
+    pub fn revoke(&mut self, node: NodeId, channel: ChannelId, subscriber: NodeId) {
+        let subscriber = self.node_id(subscriber);
+        if let Some(n) = self.nodes.get_mut(&node) {
+            n.driver.runner_mut().revoke_subscriber(channel, subscriber);
+            self.dirty.insert(node);
+            self.flush();
+        }
+    }
+
+    pub fn subscribe(&mut self, node: NodeId, channel: ChannelId) {
+        if let Some(n) = self.nodes.get_mut(&node) {
+            n.driver.runner_mut().sub_channel(channel);
+            self.subscribers.entry(channel).or_default().insert(node);
+            self.dirty.insert(node);
+            self.flush();
+        }
+    }
+
+    pub fn subscribe_with_token(&mut self, node: NodeId, channel: ChannelId, token: ChannelToken) {
+        if let Some(n) = self.nodes.get_mut(&node) {
+            n.driver.runner_mut().sub_channel_with_token(channel, token);
+            self.subscribers.entry(channel).or_default().insert(node);
+            self.dirty.insert(node);
+            self.flush();
+        }
+    }
+
+    /// Let the node relay subscriptions of restricted channels with a token which is issued for it
+    pub fn add_relay_token(&mut self, node: NodeId, token: ChannelToken) {
+        if let Some(n) = self.nodes.get_mut(&node) {
+            n.driver.runner_mut().add_relay_token(token);
+        }
+    }
+
+    pub fn unsubscribe(&mut self, node: NodeId, channel: ChannelId) {
+        if let Some(n) = self.nodes.get_mut(&node) {
+            n.driver.runner_mut().unsub_channel(channel);
+            if let Some(subscribers) = self.subscribers.get_mut(&channel) {
+                subscribers.remove(&node);
+            }
+            self.dirty.insert(node);
+            self.flush();
+        }
+    }
+
+    /// Publish a tagged packet of `size` bytes, at least 8, it is expected by all current subscribers.
+    /// Returns the sequence number of the packet.
+    pub fn publish(&mut self, node: NodeId, channel: ChannelId, size: usize) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let mut data = seq.to_be_bytes().to_vec();
+        data.resize(size.max(data.len()), 0);
+        let expected = self
+            .subscribers
+            .get(&channel)
+            .map(|s| s.iter().copied().collect())
+            .unwrap_or_default();
+        self.published
+            .entry(channel)
+            .or_default()
+            .push(Published { seq, expected });
+        if let Some(n) = self.nodes.get_mut(&node) {
+            n.driver.runner_mut().pub_channel(channel, data);
+            self.dirty.insert(node);
+            self.flush();
+        }
+        seq
+    }
+
+    /// Send data from a node to another node over the node routes, false if there is no route yet
+    pub fn send_to(&mut self, from: NodeId, to: NodeId, data: Vec<u8>) -> bool {
+        let to = self.node_id(to);
+        let sent = match self.nodes.get_mut(&from) {
+            Some(n) => n.driver.runner_mut().send_to(to, data).is_ok(),
+            None => false,
+        };
+        self.dirty.insert(from);
+        self.flush();
+        sent
+    }
+
+    /// Data which was delivered to the node with its sender, in arrival order
+    pub fn node_data(&self, node: NodeId) -> &[(NodeId, Vec<u8>)] {
+        self.nodes
+            .get(&node)
+            .map(|n| n.node_data.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Send a signal from a node as its transport would, it is relayed over the overlay to the destination
+    pub fn send_signal(&mut self, from: NodeId, to: NodeId, signal: Signal) {
+        if self.nodes.contains_key(&from) {
+            let to = self.node_id(to);
+            self.transport(from).push_signal(to, signal);
+            self.flush();
+        }
+    }
+
+    /// Signals which were delivered to the node, with the scenario id of their sender
+    pub fn signals(&self, node: NodeId) -> Vec<(NodeId, Signal)> {
+        self.nodes
+            .get(&node)
+            .map(|n| n.driver.transport().signals())
+            .unwrap_or_default()
+            .iter()
+            .map(|(from, signal)| (self.scenario_id(*from), signal.clone()))
+            .collect()
+    }
+
+    pub fn next_hop(&self, node: NodeId, channel: ChannelId) -> Option<NodeId> {
+        let next = self.runner(node)?.next_hop_for(channel)?;
+        Some(self.scenario_id(next))
+    }
+
+    /// Every node has a next hop toward the channel over an existing link,
+    /// and following the next hops from any node reaches a publisher without loop
+    pub fn is_converged(&self, channel: ChannelId) -> bool {
+        self.nodes.keys().all(|node| {
+            let mut current = *node;
+            for _ in 0..=self.nodes.len() {
+                match self.next_hop(current, channel) {
+                    Some(next) if next == current => return true,
+                    Some(next) if self.links.contains_key(&Link::key(current, next)) => {
+                        current = next;
+                    }
+                    _ => return false,
+                }
+            }
+            false
+        })
+    }
+
+    /// Run until the channel routes converge, returns the time it took
+    pub fn wait_converged(&mut self, channel: ChannelId, timeout_ms: u64) -> Option<u64> {
+        self.run_until(timeout_ms, |sim| sim.is_converged(channel))
+    }
+
+    pub fn received(&self, node: NodeId, channel: ChannelId) -> Vec<u64> {
+        let mut seqs = self
+            .nodes
+            .get(&node)
+            .and_then(|n| n.received.get(&channel))
+            .map(|r| r.keys().copied().collect::<Vec<_>>())
+            .unwrap_or_default();
+        seqs.sort();
+        seqs
+    }
+
+    pub fn delivery(&self, channel: ChannelId) -> DeliveryStats {
+        let mut stats = DeliveryStats::default();
+        for published in self.published.get(&channel).into_iter().flatten() {
+            for node in &published.expected {
+                stats.expected += 1;
+                let count = self
+                    .nodes
+                    .get(node)
+                    .and_then(|n| n.received.get(&channel))
+                    .and_then(|r| r.get(&published.seq))
+                    .copied()
+                    .unwrap_or(0);
+                if count > 0 {
+                    stats.delivered += 1;
+                }
+            }
+        }
+        stats.duplicates = self
+            .nodes
+            .values()
+            .filter_map(|n| n.received.get(&channel))
+            .flat_map(|r| r.values())
+            .map(|count| count.saturating_sub(1))
+            .sum();
+        stats
+    }
+
+    pub fn run_for(&mut self, duration_ms: u64) {
+        let end_ms = self.now_ms + duration_ms;
+        while self.step(end_ms) {}
+        self.now_ms = end_ms;
+    }
+
+    /// Run until the condition is true, checked after every event, returns the elapsed time
+    pub fn run_until<F: FnMut(&Self) -> bool>(
+        &mut self,
+        timeout_ms: u64,
+        mut condition: F,
+    ) -> Option<u64> {
+        let started_ms = self.now_ms;
+        let end_ms = started_ms + timeout_ms;
+        loop {
+            if condition(self) {
+                return Some(self.now_ms - started_ms);
+            }
+            if !self.step(end_ms) {
+                self.now_ms = end_ms;
+                return None;
+            }
+        }
+    }
+
+    /// Process the next packet or tick if it is not later than `end_ms`.
+    /// Packets which arrive at the same time as a tick are delivered first.
+    fn step(&mut self, end_ms: u64) -> bool {
+        let next_packet_ms = self.packets.keys().next().map(|(at, _)| *at);
+        if let Some(at) = next_packet_ms.filter(|at| *at <= self.next_tick_ms) {
+            if at > end_ms {
+                return false;
+            }
+            self.now_ms = at;
+            if let Some((_, pkt)) = self.packets.pop_first() {
+                self.deliver(pkt);
+            }
+        } else {
+            if self.next_tick_ms > end_ms {
+                return false;
+            }
+            self.now_ms = self.next_tick_ms;
+            self.next_tick_ms += self.config.tick_interval_ms;
+            let now_ms = self.now_ms;
+            for (node, n) in self.nodes.iter_mut() {
+                n.driver.on_tick(now_ms);
+                self.dirty.insert(*node);
+            }
+        }
+        self.flush();
+        true
+    }
+
+    fn deliver(&mut self, pkt: Packet) {
+        // the link may be closed or recreated while the packet was in flight
+        let alive = self
+            .links
+            .get(&Link::key(pkt.from, pkt.to))
+            .is_some_and(|l| l.session == pkt.session);
+        if !alive {
+            self.stats.lost_pkts += 1;
+            return;
+        }
+        self.stats.delivered_pkts += 1;
+        let from = self.node_id(pkt.from);
+        self.transport(pkt.to)
+            .on_recv(Connection::from_parts(from, pkt.session), pkt.data);
+    }
+
+    /// Apply the outputs and transport requests of nodes until nothing is pending
+    fn flush(&mut self) {
+        while let Some(node) = self.dirty.pop_first() {
+            let now_ms = self.now_ms;
+            let mut requests = vec![];
+            if let Some(n) = self.nodes.get_mut(&node) {
+                n.driver.poll(now_ms);
+                while let Some(event) = n.driver.pop_output() {
+                    match event {
+                        OutputEvent::OnChannelData(channel, data) => {
+                            if let Some(seq) = data.get(0..8) {
+                                let seq = u64::from_be_bytes(seq.try_into().expect("8 bytes"));
+                                *n.received
+                                    .entry(channel)
+                                    .or_default()
+                                    .entry(seq)
+                                    .or_default() += 1;
+                            }
+                        }
+                        OutputEvent::OnNodeData(from, data) => {
+                            let from = self.scenario_ids.get(&from).copied().unwrap_or(from);
+                            n.node_data.push((from, data));
+                        }
+                        OutputEvent::Connect(remote) => {
+                            let _ = n.driver.connect(now_ms, remote);
+                        }
+                        _ => {}
+                    }
+                }
+                while let Some(req) = n.driver.transport_mut().pop_request() {
+                    requests.push(req);
+                }
+            }
+            for req in requests {
+                match req {
+                    TransportRequest::Connect(remote) => {
+                        let remote = self.scenario_id(remote);
+                        if !self.links.contains_key(&Link::key(node, remote)) {
+                            let config = match &self.link_model {
+                                Some(model) => model(node, remote),
+                                None => self.config.default_link,
+                            };
+                            self.add_link(node, remote, config);
+                        }
+                    }
+                    TransportRequest::Send(conn, data) => self.send(node, conn, data),
+                    TransportRequest::Close(conn) => {
+                        let remote = self.scenario_id(conn.node());
+                        self.remove_link(node, remote);
+                    }
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, from: NodeId, conn: Connection, data: Vec<u8>) {
+        let to = self.scenario_id(conn.node());
+        let link = match self.links.get_mut(&Link::key(from, to)) {
+            Some(link) if link.session == conn.session() => link,
+            _ => return,
+        };
+        self.stats.sent_pkts += 1;
+        self.stats.sent_bytes += data.len() as u64;
+        if self.rng.gen::<f32>() * 100.0 < link.config.loss_percent {
+            self.stats.lost_pkts += 1;
+            return;
+        }
+        let at = link.schedule(self.now_ms, from, to, data.len());
+        self.packets.insert(
+            (at, self.next_packet),
+            Packet {
+                from,
+                to,
+                session: conn.session(),
+                data,
+            },
+        );
+        self.next_packet += 1;
+    }
+
+    /// Scenario id of a runner NodeId, unknown NodeIds are kept as they are
+    fn scenario_id(&self, node: NodeId) -> NodeId {
+        self.scenario_ids.get(&node).copied().unwrap_or(node)
+    }
+
+    /// Transport of an existing node, marked to be processed in the next flush
+    fn transport(&mut self, node: NodeId) -> &mut MemoryTransport {
+        self.dirty.insert(node);
+        self.nodes
+            .get_mut(&node)
+            .expect("node should exist")
+            .driver
+            .transport_mut()
+    }
+}
+
+/// Key of a scenario node, it only depends on the scenario id, so do its nonces and channel keys
+fn node_key(node: NodeId) -> NodeKey {
+    let mut rng = StdRng::seed_from_u64(*node);
+    NodeKey::from_secret(rng.gen()).with_seed(rng.gen())
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::{
+        issue_token, protocol::network_message::MessageType, Feature, InputEvent, NetworkMsg,
+        RoutingMode, PROTOCOL_VERSION,
+    };
+
+    use super::*;
+
+    fn line(sim: &mut Simulator, count: u64) {
+        for i in 0..count {
+            sim.add_node(i.into());
+        }
+        for i in 1..count {
+            sim.add_link((i - 1).into(), i.into(), LinkConfig::default());
+        }
+    }
+
+    #[test]
+    fn converge_and_deliver_over_line() {
+        let mut sim = Simulator::new(SimulatorConfig::default());
+        line(&mut sim, 5);
+        let channel = ChannelId::from(1);
+        sim.add_channel(0.into(), channel);
+        assert!(sim.wait_converged(channel, 10_000).is_some());
+        assert_eq!(sim.next_hop(4.into(), channel), Some(3.into()));
+
+        sim.subscribe(4.into(), channel);
+        sim.run_for(2000);
+        for _ in 0..10 {
+            sim.publish(0.into(), channel, 100);
+            sim.run_for(100);
+        }
+        let delivery = sim.delivery(channel);
+        assert_eq!(delivery.expected, 10);
+        assert_eq!(delivery.ratio(), 1.0);
+        assert_eq!(delivery.duplicates, 0);
+        assert_eq!(sim.received(4.into(), channel), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reroute_after_link_removed() {
+        let mut sim = Simulator::new(SimulatorConfig::default());
+        line(&mut sim, 4);
+        sim.add_link(0.into(), 3.into(), LinkConfig::default());
+        let channel = ChannelId::from(1);
+        sim.add_channel(0.into(), channel);
+        assert!(sim.wait_converged(channel, 10_000).is_some());
+        assert_eq!(sim.next_hop(3.into(), channel), Some(0.into()));
+
+        sim.remove_link(0.into(), 3.into());
+        assert!(sim.wait_converged(channel, 10_000).is_some());
+        assert_eq!(sim.next_hop(3.into(), channel), Some(2.into()));
+    }
+
+    #[test]
+    fn subscription_follows_next_hop() {
+        let mut sim = Simulator::new(SimulatorConfig::default());
+        for i in 0..4 {
+            sim.add_node(i.into());
+        }
+        for (a, b) in [(0, 1), (0, 2), (1, 3), (2, 3)] {
+            sim.add_link(a.into(), b.into(), LinkConfig::default());
+        }
+        let channel = ChannelId::from(1);
+        sim.add_channel(0.into(), channel);
+        assert!(sim.wait_converged(channel, 10_000).is_some());
+        sim.subscribe(3.into(), channel);
+        sim.run_for(1000);
+
+        // a slow upstream link moves the subscription to the other relay, frames arrive over the fast path
+        let first = sim.next_hop(3.into(), channel).expect("route");
+        let second = NodeId::from(3 - *first);
+        let slow = LinkConfig {
+            latency_ms: 200,
+            ..Default::default()
+        };
+        sim.add_link(first, 3.into(), slow);
+        sim.run_for(3000);
+        assert_eq!(sim.next_hop(3.into(), channel), Some(second));
+        let seq = sim.publish(0.into(), channel, 100);
+        sim.run_for(100);
+        assert_eq!(sim.received(3.into(), channel), vec![seq]);
+
+        // after a failover the new upstream is tracked, so it gets the Unsub
+        sim.remove_link(second, 3.into());
+        assert_eq!(sim.next_hop(3.into(), channel), Some(first));
+        sim.unsubscribe(3.into(), channel);
+        sim.run_for(300);
+        let sent = sim.stats().sent_pkts;
+        sim.publish(0.into(), channel, 100);
+        // only the relay of the lost link still gets the frame, until its subscription times out
+        assert!(sim.stats().sent_pkts - sent <= 1);
+    }
+
+    #[test]
+    fn same_seed_same_result() {
+        let run = |seed| {
+            let mut sim = Simulator::new(SimulatorConfig {
+                seed,
+                ..Default::default()
+            });
+            line(&mut sim, 6);
+            let lossy = LinkConfig {
+                loss_percent: 20.0,
+                ..Default::default()
+            };
+            sim.add_link(0.into(), 5.into(), lossy);
+            sim.add_link(1.into(), 4.into(), lossy);
+            let channel = ChannelId::from(1);
+            sim.add_channel(2.into(), channel);
+            sim.subscribe(5.into(), channel);
+            sim.subscribe(0.into(), channel);
+            sim.run_for(5000);
+            for _ in 0..50 {
+                sim.publish(2.into(), channel, 500);
+                sim.run_for(20);
+            }
+            sim.run_for(1000);
+            (
+                sim.stats(),
+                sim.delivery(channel),
+                sim.received(5.into(), channel),
+            )
+        };
+        assert_eq!(run(42), run(42));
+    }
+
+    /// Links whose handshake is done, a link which was just created is not a neighbour yet
+    fn degrees(sim: &Simulator) -> BTreeMap<NodeId, usize> {
+        let mut degrees = sim.nodes().map(|n| (n, 0)).collect::<BTreeMap<_, _>>();
+        for ((a, b), link) in sim.links.iter() {
+            let conn = Connection::from_parts(sim.node_id(*b), link.session);
+            if sim.runner(*a).and_then(|r| r.peer(conn)).is_some() {
+                *degrees.entry(*a).or_default() += 1;
+                *degrees.entry(*b).or_default() += 1;
+            }
+        }
+        degrees
+    }
+
+    fn is_partitioned(sim: &Simulator) -> bool {
+        let mut reached = BTreeSet::from([NodeId::from(0)]);
+        let mut queue = vec![NodeId::from(0)];
+        while let Some(node) = queue.pop() {
+            for (a, b, _) in sim.links() {
+                let next = if a == node {
+                    b
+                } else if b == node {
+                    a
+                } else {
+                    continue;
+                };
+                if reached.insert(next) {
+                    queue.push(next);
+                }
+            }
+        }
+        reached.len() != sim.nodes().count()
+    }
+
+    fn discovery_sim() -> Simulator {
+        Simulator::new(SimulatorConfig {
+            router: RouterConfig {
+                // node routes don't reach the whole line, so far nodes are only found by lookups
+                max_hops: 3,
+                ..Default::default()
+            },
+            discovery: Some(DiscoveryConfig {
+                target_degree: 4,
+                max_degree: 8,
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn discovery_builds_overlay_from_line() {
+        let mut sim = discovery_sim();
+        line(&mut sim, 32);
+        sim.run_for(60_000);
+
+        for (node, degree) in degrees(&sim) {
+            assert!(
+                (4..=8).contains(&degree),
+                "{:?} has {} neighbours",
+                node,
+                degree
+            );
+        }
+        assert!(!is_partitioned(&sim));
+        assert!(
+            sim.links().any(|(a, b, _)| a.abs_diff(*b) > 3),
+            "lookups should find nodes beyond the node routes"
+        );
+    }
+
+    #[test]
+    fn discovery_replaces_lost_neighbours() {
+        let mut sim = discovery_sim();
+        line(&mut sim, 32);
+        sim.run_for(60_000);
+
+        let lost = sim
+            .links()
+            .filter(|(a, b, _)| *a == 5.into() || *b == 5.into())
+            .map(|(a, b, _)| (a, b))
+            .collect::<Vec<_>>();
+        for (a, b) in lost {
+            sim.remove_link(a, b);
+        }
+        sim.run_for(30_000);
+        assert!(degrees(&sim)[&5.into()] >= 4);
+        assert!(!is_partitioned(&sim));
+    }
+
+    /// Nodes are in three regions by id, links inside a region are fast and links between regions are slow
+    fn region_link(a: NodeId, b: NodeId) -> LinkConfig {
+        LinkConfig {
+            latency_ms: if *a % 3 == *b % 3 { 5 } else { 100 },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn discovery_prefers_low_latency_neighbours() {
+        let mut sim = discovery_sim();
+        sim.set_link_model(region_link);
+        for i in 0..30 {
+            sim.add_node(i.into());
+        }
+        for i in 1..30 {
+            sim.add_link(
+                (i - 1).into(),
+                i.into(),
+                region_link((i - 1).into(), i.into()),
+            );
+        }
+        sim.run_for(60_000);
+
+        // a random choice would give a third of the links in regions, and half of the target degree
+        // are random long links, which mostly go between regions
+        let (near, far): (Vec<_>, Vec<_>) = sim.links().partition(|(a, b, _)| **a % 3 == **b % 3);
+        assert!(
+            near.len() > far.len(),
+            "{} links in regions, {} between regions",
+            near.len(),
+            far.len()
+        );
+        assert!(!far.is_empty(), "long links should connect the regions");
+        assert!(!is_partitioned(&sim));
+    }
+
+    #[test]
+    fn discovery_replaces_poor_neighbour() {
+        let mut sim = discovery_sim();
+        line(&mut sim, 16);
+        sim.run_for(60_000);
+
+        let (a, b, _) = sim.links().next().expect("link");
+        let lossy = LinkConfig {
+            loss_percent: 50.0,
+            ..Default::default()
+        };
+        sim.add_link(a, b, lossy);
+        sim.run_for(20_000);
+        assert!(sim.links().all(|(x, y, _)| (x, y) != (a, b)));
+        assert!(degrees(&sim)[&a] >= 4);
+        assert!(!is_partitioned(&sim));
+    }
+
+    #[test]
+    fn join_over_seed_with_retry() {
+        let mut sim = discovery_sim();
+        line(&mut sim, 16);
+        sim.run_for(30_000);
+
+        // the first seed doesn't exist, so the node retries with the next one after a backoff
+        sim.add_node(100.into());
+        sim.join(100.into(), vec![99.into(), 0.into()]);
+        sim.run_for(500);
+        assert!(!sim.runner(100.into()).expect("node").is_joined());
+        sim.run_for(30_000);
+        assert!(sim.runner(100.into()).expect("node").is_joined());
+        assert!(degrees(&sim)[&100.into()] >= 4);
+        assert!(!is_partitioned(&sim));
+
+        let lost = sim
+            .links()
+            .filter(|(a, b, _)| *a == 100.into() || *b == 100.into())
+            .map(|(a, b, _)| (a, b))
+            .collect::<Vec<_>>();
+        for (a, b) in lost {
+            sim.remove_link(a, b);
+        }
+        assert!(!sim.runner(100.into()).expect("node").is_joined());
+        sim.run_for(10_000);
+        assert!(sim.runner(100.into()).expect("node").is_joined());
+        assert!(!is_partitioned(&sim));
+    }
+
+    #[test]
+    fn handshake_negotiates_features() {
+        let mut sim = Simulator::new(SimulatorConfig {
+            router: RouterConfig {
+                delta_sync: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        sim.add_node(0.into());
+        sim.add_node_with_config(1.into(), RouterConfig::default());
+        sim.add_link(0.into(), 1.into(), LinkConfig::default());
+        sim.run_for(100);
+
+        let conn = Connection::from_parts(sim.node_id(1.into()), 0);
+        let peer = sim
+            .runner(0.into())
+            .and_then(|r| r.peer(conn))
+            .expect("handshake done");
+        assert_eq!(peer.version, PROTOCOL_VERSION);
+        assert!(
+            !peer.features.contains(&Feature::DeltaSync),
+            "delta sync is only supported by node 0"
+        );
+        assert!(peer.features.contains(&Feature::DataAuth));
+    }
+
+    #[test]
+    fn handshake_refuses_other_routing_mode() {
+        let mut sim = Simulator::new(SimulatorConfig::default());
+        line(&mut sim, 2);
+        sim.add_node_with_config(
+            2.into(),
+            RouterConfig {
+                mode: RoutingMode::Rendezvous,
+                ..Default::default()
+            },
+        );
+        sim.add_link(1.into(), 2.into(), LinkConfig::default());
+        sim.run_for(5000);
+
+        assert_eq!(sim.links().count(), 1);
+        assert!(sim
+            .runner(0.into())
+            .and_then(|r| r.peer(Connection::from_parts(sim.node_id(1.into()), 0)))
+            .is_some());
+    }
+
+    /// Messages which the runner sends, and whether it closes a connection
+    fn sent(runner: &mut P2pStreamRunner) -> (Vec<MessageType>, bool) {
+        let (mut msgs, mut closed) = (vec![], false);
+        while let Some(event) = runner.pop_output() {
+            match event {
+                OutputEvent::ConnectionSend(NetworkMsg { msg, .. }) => msgs.push(msg),
+                OutputEvent::Close(_) => closed = true,
+                _ => {}
+            }
+        }
+        (msgs, closed)
+    }
+
+    #[test]
+    fn handshake_refuses_copied_public_key() {
+        let mut node = P2pStreamRunner::new(node_key(0.into()));
+        let mut attacker = P2pStreamRunner::new(node_key(1.into()));
+        let victim = node_key(2.into());
+        let conn = Connection::from_parts(victim.node_id(), 0);
+        node.on_msg(0, InputEvent::ConnectionConnected(conn));
+        let attacker_conn = Connection::from_parts(node.node(), 0);
+        attacker.on_msg(0, InputEvent::ConnectionAccepted(attacker_conn));
+
+        // the attacker claims the id and public key of the victim, but can only sign with its own key
+        let (mut hello, _) = sent(&mut attacker);
+        if let Some(MessageType::Hello(hello)) = hello.first_mut() {
+            hello.node = *victim.node_id();
+            hello.public_key = victim.public_key().to_vec();
+        }
+        let (msgs, _) = sent(&mut node);
+        for msg in msgs {
+            let msg = NetworkMsg {
+                conn: attacker_conn,
+                msg,
+            };
+            attacker.on_msg(0, InputEvent::ConnectionRecv(msg));
+        }
+        let (acks, _) = sent(&mut attacker);
+        for msg in hello.into_iter().chain(acks) {
+            node.on_msg(0, InputEvent::ConnectionRecv(NetworkMsg { conn, msg }));
+        }
+
+        let (_, closed) = sent(&mut node);
+        assert!(closed);
+        assert!(node.peer(conn).is_none());
+    }
+
+    #[test]
+    fn restricted_channel_needs_valid_token() {
+        let mut sim = Simulator::new(SimulatorConfig::default());
+        line(&mut sim, 3);
+        sim.add_node(3.into());
+        sim.add_link(1.into(), 3.into(), LinkConfig::default());
+        let channel = ChannelId::from(1);
+        let authority = NodeKey::from_secret([7; 32]);
+        sim.add_channel(0.into(), channel);
+        sim.restrict_channel(0.into(), channel, &authority);
+        assert!(sim.wait_converged(channel, 10_000).is_some());
+
+        // the relay presents its own token to the publisher, not the one of its subscriber
+        sim.add_relay_token(
+            1.into(),
+            issue_token(&authority, sim.node_id(1.into()), &[channel], u64::MAX),
+        );
+        let expires_ms = sim.now_ms() + 4000;
+        sim.subscribe_with_token(
+            3.into(),
+            channel,
+            issue_token(&authority, sim.node_id(3.into()), &[channel], expires_ms),
+        );
+        let forged = issue_token(
+            &NodeKey::from_secret([8; 32]),
+            sim.node_id(2.into()),
+            &[channel],
+            u64::MAX,
+        );
+        sim.subscribe_with_token(2.into(), channel, forged);
+        sim.run_for(1000);
+        let seq = sim.publish(0.into(), channel, 100);
+        sim.run_for(1000);
+        assert_eq!(sim.received(3.into(), channel), vec![seq]);
+        assert!(sim.received(2.into(), channel).is_empty());
+
+        // the subscription is refused once the token expired
+        sim.run_for(5000);
+        let late = sim.publish(0.into(), channel, 100);
+        sim.run_for(1000);
+        assert!(!sim.received(3.into(), channel).contains(&late));
+    }
+
+    #[test]
+    fn encrypted_channel_reaches_only_authorized_subscribers() {
+        let mut sim = Simulator::new(SimulatorConfig::default());
+        line(&mut sim, 4);
+        let channel = ChannelId::from(1);
+        sim.add_channel(0.into(), channel);
+        sim.encrypt_channel(0.into(), channel);
+        sim.authorize(0.into(), channel, 3.into());
+        sim.subscribe(2.into(), channel);
+        sim.subscribe(3.into(), channel);
+        assert!(sim.wait_converged(channel, 10_000).is_some());
+        sim.run_for(2000);
+
+        // the first frame makes the subscribers ask for the key
+        sim.publish(0.into(), channel, 100);
+        sim.run_for(1000);
+        let seq = sim.publish(0.into(), channel, 100);
+        sim.run_for(1000);
+        assert_eq!(sim.received(3.into(), channel), vec![seq]);
+        assert!(sim.received(2.into(), channel).is_empty());
+
+        // the key is rotated, so the revoked node can't read the next frames
+        sim.revoke(0.into(), channel, 3.into());
+        sim.authorize(0.into(), channel, 2.into());
+        sim.publish(0.into(), channel, 100);
+        sim.run_for(1000);
+        let next = sim.publish(0.into(), channel, 100);
+        sim.run_for(1000);
+        assert_eq!(sim.received(2.into(), channel), vec![next]);
+        assert!(!sim.received(3.into(), channel).contains(&next));
+    }
+
+    #[test]
+    fn send_to_node_over_line() {
+        let mut sim = Simulator::new(SimulatorConfig::default());
+        line(&mut sim, 5);
+        assert!(!sim.send_to(0.into(), 4.into(), vec![0]), "no route yet");
+        sim.run_for(10_000);
+
+        assert!(sim.send_to(0.into(), 4.into(), vec![1]));
+        assert!(sim.send_to(4.into(), 0.into(), vec![2]));
+        sim.run_for(1000);
+        assert_eq!(sim.node_data(4.into()), &[(0.into(), vec![1])]);
+        assert_eq!(sim.node_data(0.into()), &[(4.into(), vec![2])]);
+        for node in 1..4 {
+            assert!(sim.node_data(node.into()).is_empty());
+        }
+    }
+
+    #[test]
+    fn relay_signal_over_line() {
+        let mut sim = Simulator::new(SimulatorConfig {
+            router: RouterConfig {
+                mode: RoutingMode::Rendezvous,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        line(&mut sim, 5);
+        sim.run_for(10_000);
+
+        let offer = Signal::Offer {
+            session: 1,
+            sdp: "sdp".to_string(),
+        };
+        sim.send_signal(0.into(), 4.into(), offer.clone());
+        sim.run_for(1000);
+        assert_eq!(sim.signals(4.into()), &[(0.into(), offer)]);
+        for node in 1..4 {
+            assert!(sim.signals(node.into()).is_empty());
+        }
+    }
+
+    #[test]
+    fn rendezvous_delivers_over_shared_tree_with_bounded_tables() {
+        let mut sim = Simulator::new(SimulatorConfig {
+            router: RouterConfig {
+                mode: RoutingMode::Rendezvous,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        line(&mut sim, 10);
+        for (a, b) in [(0, 5), (2, 8), (4, 9)] {
+            sim.add_link(a.into(), b.into(), LinkConfig::default());
+        }
+        sim.run_for(10_000);
+
+        let publishers = [0, 3, 6, 9];
+        let channels = (1..=publishers.len() as u32)
+            .map(ChannelId::from)
+            .collect::<Vec<_>>();
+        for (publisher, channel) in publishers.iter().zip(&channels) {
+            sim.add_channel((*publisher).into(), *channel);
+        }
+        sim.run_for(5000);
+        for (i, channel) in channels.iter().enumerate() {
+            for subscriber in [(i * 3 + 1) % 10, (i * 3 + 5) % 10] {
+                sim.subscribe((subscriber as u64).into(), *channel);
+            }
+        }
+        sim.run_for(3000);
+        for _ in 0..5 {
+            for (publisher, channel) in publishers.iter().zip(&channels) {
+                sim.publish((*publisher).into(), *channel, 100);
+            }
+            sim.run_for(200);
+        }
+        sim.run_for(1000);
+        for channel in &channels {
+            let delivery = sim.delivery(*channel);
+            assert_eq!(delivery.expected, 10);
+            assert_eq!(delivery.ratio(), 1.0, "channel {:?}", channel);
+        }
+
+        // channel rows only travel toward the rendezvous nodes, so no node learns every channel
+        let routes = sim
+            .nodes()
+            .map(|node| sim.runner(node).map_or(0, |r| r.channel_routes()))
+            .collect::<Vec<_>>();
+        assert!(routes.iter().all(|r| *r < channels.len()), "{:?}", routes);
+        assert!(routes.iter().sum::<usize>() < sim.nodes().count() * channels.len() / 2);
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+};
+
+use protocol::{Connection, ConnectionStats, NodeId, Signal, Transport, TransportEvent};
+
+/// Actions of a node which the simulator must apply to the virtual network
+pub(crate) enum TransportRequest {
+    Connect(NodeId),
+    Send(Connection, Vec<u8>),
+    Close(Connection),
+}
+
+/// Transport of a simulated node, it only queues requests and events,
+/// the simulator moves packets between transports over the virtual links.
+pub struct MemoryTransport {
+    requests: VecDeque<TransportRequest>,
+    events: VecDeque<TransportEvent>,
+    stats: HashMap<Connection, ConnectionStats>,
+    /// Signals which the node wants to send, queued by `Simulator::send_signal`
+    outgoing_signals: VecDeque<(NodeId, Signal)>,
+    /// Signals which were delivered to the node
+    signals: Vec<(NodeId, Signal)>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self {
+            requests: VecDeque::new(),
+            events: VecDeque::new(),
+            stats: HashMap::new(),
+            outgoing_signals: VecDeque::new(),
+            signals: vec![],
+        }
+    }
+
+    pub fn signals(&self) -> &[(NodeId, Signal)] {
+        &self.signals
+    }
+
+    pub(crate) fn push_signal(&mut self, to: NodeId, signal: Signal) {
+        self.outgoing_signals.push_back((to, signal));
+    }
+
+    pub(crate) fn pop_request(&mut self) -> Option<TransportRequest> {
+        self.requests.pop_front()
+    }
+
+    pub(crate) fn on_connected(
+        &mut self,
+        conn: Connection,
+        stats: ConnectionStats,
+        outgoing: bool,
+    ) {
+        self.stats.insert(conn, stats);
+        if outgoing {
+            self.events.push_back(TransportEvent::Connected(conn));
+        } else {
+            self.events.push_back(TransportEvent::Accepted(conn));
+        }
+    }
+
+    pub(crate) fn on_stats(&mut self, conn: Connection, stats: ConnectionStats) {
+        self.stats.insert(conn, stats);
+    }
+
+    pub(crate) fn on_disconnected(&mut self, conn: Connection) {
+        if self.stats.remove(&conn).is_some() {
+            self.events.push_back(TransportEvent::Disconnected(conn));
+        }
+    }
+
+    pub(crate) fn on_recv(&mut self, conn: Connection, data: Vec<u8>) {
+        self.events
+            .push_back(TransportEvent::Recv(protocol::NetworkPkt { conn, data }));
+    }
+}
+
+impl Default for MemoryTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transport for MemoryTransport {
+    type Addr = NodeId;
+    type Error = Infallible;
+
+    fn connect(&mut self, _now_ms: u64, addr: NodeId) -> Result<(), Infallible> {
+        self.requests.push_back(TransportRequest::Connect(addr));
+        Ok(())
+    }
+
+    fn send(&mut self, conn: Connection, data: &[u8]) {
+        self.requests
+            .push_back(TransportRequest::Send(conn, data.to_vec()));
+    }
+
+    fn close(&mut self, conn: Connection) {
+        self.requests.push_back(TransportRequest::Close(conn));
+    }
+
+    fn stats(&self, conn: Connection) -> Option<ConnectionStats> {
+        self.stats.get(&conn).copied()
+    }
+
+    fn pop_event(&mut self, _now_ms: u64) -> Option<TransportEvent> {
+        self.events.pop_front()
+    }
+
+    fn pop_signal(&mut self) -> Option<(NodeId, Signal)> {
+        self.outgoing_signals.pop_front()
+    }
+
+    fn on_signal(&mut self, _now_ms: u64, from: NodeId, signal: Signal) {
+        self.signals.push((from, signal));
+    }
+}
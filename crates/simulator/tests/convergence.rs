@@ -0,0 +1,172 @@
+//! Route sync must converge to loop-free best paths (RFC section 3.4), over random connected graphs
+//! and after random link failures.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use decentralized_p2p_streaming_simulator::{LinkConfig, Simulator, SimulatorConfig};
+use proptest::{prelude::*, sample::Index};
+use protocol::{ChannelId, NodeId, RouterConfig};
+
+const HOP_PENALTY_MS: u32 = 5;
+const CONVERGE_TIMEOUT_MS: u64 = 60_000;
+
+#[derive(Debug, Clone)]
+struct Graph {
+    nodes: u64,
+    /// Links of a spanning tree, which keeps the graph connected
+    tree: Vec<(u64, u64, u32)>,
+    /// Extra links which may fail
+    extra: Vec<(u64, u64, u32)>,
+}
+
+fn graph() -> impl Strategy<Value = Graph> {
+    (2u64..12).prop_flat_map(|nodes| {
+        let tree = prop::collection::vec((any::<Index>(), 1u32..100), nodes as usize - 1);
+        let extra = prop::collection::vec((0..nodes, 0..nodes, 1u32..100), 0..(nodes as usize * 2));
+        (Just(nodes), tree, extra).prop_map(|(nodes, tree, extra)| {
+            let tree = tree
+                .into_iter()
+                .enumerate()
+                .map(|(i, (parent, latency))| {
+                    let child = i as u64 + 1;
+                    (parent.index(child as usize) as u64, child, latency)
+                })
+                .collect::<Vec<_>>();
+            // at most one link between two nodes, so that a failed extra link never breaks the tree
+            let mut pairs = tree
+                .iter()
+                .map(|(a, b, _)| (*a.min(b), *a.max(b)))
+                .collect::<BTreeSet<_>>();
+            let extra = extra
+                .into_iter()
+                .filter(|(a, b, _)| a != b && pairs.insert((*a.min(b), *a.max(b))))
+                .collect();
+            Graph { nodes, tree, extra }
+        })
+    })
+}
+
+fn simulator(graph: &Graph) -> Simulator {
+    let mut sim = Simulator::new(SimulatorConfig {
+        router: RouterConfig {
+            hop_penalty_ms: HOP_PENALTY_MS,
+            delta_threshold_percent: 0,
+            trigger_threshold_percent: 0,
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    for node in 0..graph.nodes {
+        sim.add_node(node.into());
+    }
+    for (a, b, latency_ms) in graph.tree.iter().chain(&graph.extra) {
+        sim.add_link(
+            (*a).into(),
+            (*b).into(),
+            LinkConfig {
+                latency_ms: *latency_ms,
+                ..Default::default()
+            },
+        );
+    }
+    sim
+}
+
+/// Cost of a link as seen by the router score: rtt plus one hop penalty
+fn link_costs(sim: &Simulator) -> BTreeMap<(NodeId, NodeId), u32> {
+    let mut costs = BTreeMap::new();
+    for (a, b, link) in sim.links() {
+        let cost = link.latency_ms * 2 + HOP_PENALTY_MS;
+        costs.insert((a, b), cost);
+        costs.insert((b, a), cost);
+    }
+    costs
+}
+
+/// Dijkstra from the publisher, which gives the best score of every node toward the channel
+fn shortest_paths(sim: &Simulator, source: NodeId) -> BTreeMap<NodeId, u32> {
+    let costs = link_costs(sim);
+    let mut dist = BTreeMap::from([(source, 0)]);
+    let mut queue = BTreeSet::from([(0, source)]);
+    while let Some((d, node)) = queue.pop_first() {
+        if dist.get(&node).is_some_and(|best| *best < d) {
+            continue;
+        }
+        for ((_, to), cost) in costs.range((node, NodeId::from(0))..=(node, NodeId::from(u64::MAX)))
+        {
+            let next = d + cost;
+            if dist.get(to).is_none_or(|best| next < *best) {
+                dist.insert(*to, next);
+                queue.insert((next, *to));
+            }
+        }
+    }
+    dist
+}
+
+/// Every node forwards to a neighbour which lies on one of its shortest paths, and the forwarding chain
+/// reaches the publisher without loop
+fn is_best_paths(sim: &Simulator, publisher: NodeId, channel: ChannelId) -> bool {
+    if !sim.is_converged(channel) {
+        return false;
+    }
+    let costs = link_costs(sim);
+    let dist = shortest_paths(sim, publisher);
+    sim.nodes().all(|node| {
+        if node == publisher {
+            return sim.next_hop(node, channel) == Some(publisher);
+        }
+        let next = match sim.next_hop(node, channel) {
+            Some(next) => next,
+            None => return false,
+        };
+        match (costs.get(&(node, next)), dist.get(&node), dist.get(&next)) {
+            (Some(cost), Some(d), Some(next_d)) => *d == cost + next_d,
+            _ => false,
+        }
+    })
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn converge_to_shortest_paths(graph in graph(), publisher in any::<Index>()) {
+        let mut sim = simulator(&graph);
+        let publisher = NodeId::from(publisher.index(graph.nodes as usize) as u64);
+        let channel = ChannelId::from(1000);
+        sim.add_channel(publisher, channel);
+
+        let converged = sim.run_until(CONVERGE_TIMEOUT_MS, |sim| is_best_paths(sim, publisher, channel));
+        prop_assert!(converged.is_some(), "not converged on {:?}", graph);
+
+        // converged routes must be stable
+        sim.run_for(10_000);
+        prop_assert!(is_best_paths(&sim, publisher, channel), "routes changed after convergence on {:?}", graph);
+    }
+
+    #[test]
+    fn reconverge_after_link_failures(
+        graph in graph(),
+        publisher in any::<Index>(),
+        failures in prop::collection::vec(any::<Index>(), 1..4),
+    ) {
+        let mut sim = simulator(&graph);
+        let publisher = NodeId::from(publisher.index(graph.nodes as usize) as u64);
+        let channel = ChannelId::from(1000);
+        sim.add_channel(publisher, channel);
+        let converged = sim.run_until(CONVERGE_TIMEOUT_MS, |sim| is_best_paths(sim, publisher, channel));
+        prop_assert!(converged.is_some(), "not converged on {:?}", graph);
+
+        // only extra links fail, so the spanning tree keeps the graph connected
+        if graph.extra.is_empty() {
+            return Ok(());
+        }
+        for failure in failures {
+            let (a, b, _) = graph.extra[failure.index(graph.extra.len())];
+            sim.remove_link(a.into(), b.into());
+        }
+        let reconverged = sim.run_until(CONVERGE_TIMEOUT_MS, |sim| is_best_paths(sim, publisher, channel));
+        prop_assert!(reconverged.is_some(), "not reconverged on {:?}", graph);
+    }
+}
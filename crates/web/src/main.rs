@@ -1,24 +1,158 @@
-use decentralized_p2p_streaming_web::add;
-use protocol::P2pStreamRunner;
+use decentralized_p2p_streaming_web::{RtcConfig, RtcTransport, WsConfig, WsTransport};
+use protocol::{
+    DiscoveryConfig, NodeId, NodeKey, OutputEvent, P2pStreamDriver, P2pStreamRunner, Transport,
+    TransportSignalling,
+};
+use wasm_bindgen::{closure::Closure, JsCast};
+use web_sys::HtmlInputElement;
 use yew::prelude::*;
 
+const TICK_INTERVAL_MS: u64 = 1000;
+/// Transport events are polled more often than the runner ticks, so that data is forwarded quickly
+const POLL_INTERVAL_MS: i32 = 20;
+
+/// Node of the demo page, signals to nodes without a route over the overlay go through the signalling server.
+/// After the first connection, more neighbours are found and connected by the neighbour manager.
+struct Node {
+    id: NodeId,
+    /// Secret of the node key, the signalling server needs a proof of it
+    secret: [u8; 32],
+    driver: P2pStreamDriver<RtcTransport>,
+    next_tick_ms: u64,
+}
+
+impl Node {
+    fn new() -> Self {
+        // a new key on each page load, the page has no storage for it
+        let key = NodeKey::generate();
+        let id = key.node_id();
+        let secret = key.secret();
+        let mut runner = P2pStreamRunner::new(key);
+        runner.enable_discovery(DiscoveryConfig::default());
+        Self {
+            id,
+            secret,
+            driver: P2pStreamDriver::new(runner, RtcTransport::new(RtcConfig::default())),
+            next_tick_ms: 0,
+        }
+    }
+
+    /// Unix time, channel announcements of other nodes expire in it
+    fn now_ms(&self) -> u64 {
+        js_sys::Date::now() as u64
+    }
+
+    /// Returns true when the runner ticked, so the page should be rendered again
+    fn on_interval(&mut self) -> bool {
+        let now_ms = self.now_ms();
+        let ticked = now_ms >= self.next_tick_ms;
+        if ticked {
+            self.next_tick_ms = now_ms + TICK_INTERVAL_MS;
+            self.driver.on_tick(now_ms);
+        } else {
+            self.driver.poll(now_ms);
+        }
+        while let Some(event) = self.driver.pop_output() {
+            // WebRTC is addressed by node id, so the neighbour manager intents can be used as they are
+            if let OutputEvent::Connect(node) = event {
+                if let Err(e) = self.driver.connect(now_ms, node) {
+                    log::warn!("Connect {:?} error {:?}", node, e);
+                }
+            }
+        }
+        ticked
+    }
+}
+
 #[function_component]
 fn App() -> Html {
-    let counter = use_state(|| 0);
-    let onclick = {
-        let counter = counter.clone();
+    let node = use_mut_ref(Node::new);
+    let update = use_force_update();
+    let signalling_url = use_state(|| "ws://127.0.0.1:3000".to_string());
+    let remote_node = use_state(String::new);
+
+    {
+        let node = node.clone();
+        use_effect_with((), move |_| {
+            let window = web_sys::window().expect("window");
+            let callback = Closure::<dyn FnMut()>::new(move || {
+                if node.borrow_mut().on_interval() {
+                    update.force_update();
+                }
+            });
+            let interval = window
+                .set_interval_with_callback_and_timeout_and_arguments_0(
+                    callback.as_ref().unchecked_ref(),
+                    POLL_INTERVAL_MS,
+                )
+                .expect("set interval");
+            move || {
+                window.clear_interval_with_handle(interval);
+                drop(callback);
+            }
+        });
+    }
+
+    let on_signalling_url = {
+        let signalling_url = signalling_url.clone();
+        move |e: InputEvent| {
+            signalling_url.set(e.target_unchecked_into::<HtmlInputElement>().value())
+        }
+    };
+    let on_use_signalling = {
+        let (node, signalling_url) = (node.clone(), signalling_url.clone());
         move |_| {
-            let runner = P2pStreamRunner::new(1.into());
-            let value = add(*counter, 1);
-            counter.set(value);
+            let mut node = node.borrow_mut();
+            let transport = WsTransport::new(node.id, WsConfig::default());
+            let key = NodeKey::from_secret(node.secret);
+            let signalling = TransportSignalling::new(key, transport, (*signalling_url).clone());
+            node.driver.set_signalling(Box::new(signalling));
+            log::info!("Use signalling server {}", *signalling_url);
         }
     };
+    let on_remote_node = {
+        let remote_node = remote_node.clone();
+        move |e: InputEvent| remote_node.set(e.target_unchecked_into::<HtmlInputElement>().value())
+    };
+    let on_connect = {
+        let (node, remote_node) = (node.clone(), remote_node.clone());
+        move |_| match remote_node.parse::<u64>() {
+            Ok(remote) => {
+                let mut node = node.borrow_mut();
+                let now_ms = node.now_ms();
+                if let Err(e) = node.driver.connect(now_ms, remote.into()) {
+                    log::warn!("Connect {} error {:?}", remote, e);
+                }
+            }
+            Err(_) => log::warn!("Invalid node id {}", *remote_node),
+        }
+    };
+
+    let node = node.borrow();
+    let transport = node.driver.transport();
+    let conns = transport.connections().map(|conn| {
+        let stats = transport.stats(conn);
+        html! {
+            <li>
+                { *conn.node() }
+                { stats.map(|s| format!(" rtt {} ms", s.rtt_ms)).unwrap_or_default() }
+            </li>
+        }
+    });
 
     html! {
         <div>
-            <button {onclick}>{ "+1" }</button>
-            <span>{ "Demo2" }</span>
-            <p>{ *counter }</p>
+            <h3>{ format!("Node {}", *node.id) }</h3>
+            <div>
+                <input placeholder="Signalling server url" value={(*signalling_url).clone()} oninput={on_signalling_url} />
+                <button onclick={on_use_signalling}>{ "Use" }</button>
+            </div>
+            <div>
+                <input placeholder="Remote node id" value={(*remote_node).clone()} oninput={on_remote_node} />
+                <button onclick={on_connect}>{ "Connect" }</button>
+            </div>
+            <p>{ "Connections" }</p>
+            <ul>{ for conns }</ul>
         </div>
     }
 }
@@ -0,0 +1,522 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    rc::Rc,
+};
+
+use js_sys::{Array, Reflect, Uint8Array};
+use protocol::{
+    Connection, ConnectionStats, NetworkPkt, NodeId, Signal, Transport, TransportEvent,
+};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_sys::{
+    MessageEvent, RtcConfiguration, RtcDataChannel, RtcDataChannelInit, RtcDataChannelType,
+    RtcIceConnectionState, RtcIceServer, RtcPeerConnection, RtcPeerConnectionIceEvent, RtcSdpType,
+    RtcSessionDescriptionInit, RtcStatsReport,
+};
+
+/// Channels are negotiated with fixed ids, the same as the native transport
+const CONTROL_LABEL: &str = "control";
+const DATA_LABEL: &str = "data";
+const CONTROL_ID: u16 = 0;
+const DATA_ID: u16 = 1;
+
+/// Frame: kind (1 byte) followed by the payload, the same as the native transport
+const KIND_HELLO: u8 = 1;
+const KIND_DATA: u8 = 2;
+const KIND_HELLO_ACK: u8 = 3;
+
+#[derive(Debug, Clone)]
+pub struct RtcConfig {
+    /// STUN and TURN urls, only host candidates are used when empty
+    pub ice_servers: Vec<String>,
+    /// Peer which is not connected within this timeout is closed
+    pub connect_timeout_ms: u64,
+    /// Offer which doesn't give a working connection within this timeout is replaced by a new one
+    pub attempt_timeout_ms: u64,
+    pub stats_interval_ms: u64,
+    /// Data channels can't measure the link capacity, so this value is reported in the stats
+    pub bandwidth_kbps: u32,
+}
+
+impl Default for RtcConfig {
+    fn default() -> Self {
+        Self {
+            ice_servers: vec![],
+            connect_timeout_ms: 10_000,
+            attempt_timeout_ms: 3000,
+            stats_interval_ms: 1000,
+            bandwidth_kbps: 10_000,
+        }
+    }
+}
+
+enum RtcEvent {
+    /// One of the data channels is open
+    Opened(Connection),
+    /// Candidates are gathered, the local description can be sent
+    Gathered(Connection),
+    Recv(Connection, Vec<u8>),
+    /// Peer connection failed or a negotiation step was rejected
+    Closed(Connection),
+    Stats(Connection, ConnectionStats),
+}
+
+type RtcEvents = Rc<RefCell<VecDeque<RtcEvent>>>;
+
+struct Peer {
+    pc: RtcPeerConnection,
+    control: RtcDataChannel,
+    data: RtcDataChannel,
+    outgoing: bool,
+    opened: u8,
+    connected: bool,
+    started_ms: u64,
+    deadline_ms: u64,
+    stats: Option<ConnectionStats>,
+    _callbacks: Vec<Closure<dyn FnMut(JsValue)>>,
+}
+
+impl Peer {
+    fn send(&self, reliable: bool, kind: u8, payload: &[u8]) {
+        let mut frame = Vec::with_capacity(1 + payload.len());
+        frame.push(kind);
+        frame.extend_from_slice(payload);
+        let channel = if reliable { &self.control } else { &self.data };
+        if let Err(e) = channel.send_with_u8_array(&frame) {
+            log::debug!("Send on {} error {:?}", channel.label(), e);
+        }
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        for channel in [&self.control, &self.data] {
+            channel.set_onopen(None);
+            channel.set_onmessage(None);
+            channel.close();
+        }
+        self.pc.set_onicecandidate(None);
+        self.pc.set_oniceconnectionstatechange(None);
+        self.pc.close();
+    }
+}
+
+/// Browser transport over WebRTC data channels, it can connect to other browsers and to native nodes
+/// which use the native RtcTransport. Connecting to a node creates an offer which must be delivered to the node
+/// as a signal, the driver relays it over the overlay or its signalling channel.
+/// Callbacks and promises only queue events, which are processed when the driver polls the transport.
+pub struct RtcTransport {
+    config: RtcConfig,
+    next_session: u32,
+    last_stats_ms: u64,
+    peers: HashMap<Connection, Peer>,
+    rtc_events: RtcEvents,
+    signals: VecDeque<(NodeId, Signal)>,
+    events: VecDeque<TransportEvent>,
+}
+
+impl RtcTransport {
+    pub fn new(config: RtcConfig) -> Self {
+        Self {
+            config,
+            next_session: (js_sys::Math::random() * u32::MAX as f64) as u32,
+            last_stats_ms: 0,
+            peers: HashMap::new(),
+            rtc_events: Rc::new(RefCell::new(VecDeque::new())),
+            signals: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Connections which are established
+    pub fn connections(&self) -> impl Iterator<Item = Connection> + '_ {
+        self.peers
+            .iter()
+            .filter(|(_, p)| p.connected)
+            .map(|(conn, _)| *conn)
+    }
+
+    /// Create the peer connection with its data channels and callbacks
+    fn create_peer(
+        &mut self,
+        now_ms: u64,
+        conn: Connection,
+        outgoing: bool,
+        deadline_ms: u64,
+    ) -> Result<RtcPeerConnection, JsValue> {
+        let ice_servers = self
+            .config
+            .ice_servers
+            .iter()
+            .map(|url| {
+                let mut server = RtcIceServer::new();
+                server.urls(&JsValue::from_str(url));
+                server
+            })
+            .collect::<Array>();
+        let mut config = RtcConfiguration::new();
+        config.ice_servers(&ice_servers);
+        let pc = RtcPeerConnection::new_with_configuration(&config)?;
+
+        let control = pc.create_data_channel_with_data_channel_dict(
+            CONTROL_LABEL,
+            RtcDataChannelInit::new().negotiated(true).id(CONTROL_ID),
+        );
+        let data = pc.create_data_channel_with_data_channel_dict(
+            DATA_LABEL,
+            RtcDataChannelInit::new()
+                .negotiated(true)
+                .id(DATA_ID)
+                .ordered(false)
+                .max_retransmits(0),
+        );
+
+        let mut callbacks = vec![];
+        for channel in [&control, &data] {
+            channel.set_binary_type(RtcDataChannelType::Arraybuffer);
+            let events = self.rtc_events.clone();
+            let onopen = Closure::<dyn FnMut(JsValue)>::new(move |_| {
+                events.borrow_mut().push_back(RtcEvent::Opened(conn));
+            });
+            let events = self.rtc_events.clone();
+            let onmessage = Closure::<dyn FnMut(JsValue)>::new(move |e: JsValue| {
+                if let Ok(e) = e.dyn_into::<MessageEvent>() {
+                    let frame = Uint8Array::new(&e.data()).to_vec();
+                    events.borrow_mut().push_back(RtcEvent::Recv(conn, frame));
+                }
+            });
+            channel.set_onopen(Some(onopen.as_ref().unchecked_ref()));
+            channel.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+            callbacks.push(onopen);
+            callbacks.push(onmessage);
+        }
+
+        let events = self.rtc_events.clone();
+        let onicecandidate = Closure::<dyn FnMut(JsValue)>::new(move |e: JsValue| {
+            // the last candidate event has no candidate
+            let done = e
+                .dyn_into::<RtcPeerConnectionIceEvent>()
+                .is_ok_and(|e| e.candidate().is_none());
+            if done {
+                events.borrow_mut().push_back(RtcEvent::Gathered(conn));
+            }
+        });
+        let events = self.rtc_events.clone();
+        let state_pc = pc.clone();
+        let onstatechange = Closure::<dyn FnMut(JsValue)>::new(move |_| {
+            if matches!(
+                state_pc.ice_connection_state(),
+                RtcIceConnectionState::Disconnected
+                    | RtcIceConnectionState::Failed
+                    | RtcIceConnectionState::Closed
+            ) {
+                events.borrow_mut().push_back(RtcEvent::Closed(conn));
+            }
+        });
+        pc.set_onicecandidate(Some(onicecandidate.as_ref().unchecked_ref()));
+        pc.set_oniceconnectionstatechange(Some(onstatechange.as_ref().unchecked_ref()));
+        callbacks.push(onicecandidate);
+        callbacks.push(onstatechange);
+
+        self.peers.insert(
+            conn,
+            Peer {
+                pc: pc.clone(),
+                control,
+                data,
+                outgoing,
+                opened: 0,
+                connected: false,
+                started_ms: now_ms,
+                deadline_ms,
+                stats: None,
+                _callbacks: callbacks,
+            },
+        );
+        Ok(pc)
+    }
+
+    fn offer(&mut self, now_ms: u64, node: NodeId, deadline_ms: u64) -> Result<(), JsValue> {
+        let session = self.next_session;
+        self.next_session = self.next_session.wrapping_add(1);
+        let conn = Connection::from_parts(node, session);
+        let pc = self.create_peer(now_ms, conn, true, deadline_ms)?;
+        let events = self.rtc_events.clone();
+        spawn_local(async move {
+            let offer = async {
+                let offer = JsFuture::from(pc.create_offer()).await?;
+                JsFuture::from(pc.set_local_description(offer.unchecked_ref())).await
+            };
+            if let Err(e) = offer.await {
+                log::warn!("Offer for {:?} error {:?}", conn, e);
+                events.borrow_mut().push_back(RtcEvent::Closed(conn));
+            }
+        });
+        Ok(())
+    }
+
+    fn on_rtc_event(&mut self, now_ms: u64, event: RtcEvent) {
+        match event {
+            RtcEvent::Opened(conn) => {
+                if let Some(peer) = self.peers.get_mut(&conn) {
+                    peer.opened += 1;
+                    if peer.opened == 2 {
+                        peer.send(true, KIND_HELLO, &[]);
+                    }
+                }
+            }
+            RtcEvent::Gathered(conn) => {
+                let peer = match self.peers.get(&conn) {
+                    Some(peer) => peer,
+                    None => return,
+                };
+                if let Some(desc) = peer.pc.local_description() {
+                    let (session, sdp) = (conn.session(), desc.sdp());
+                    let signal = if peer.outgoing {
+                        Signal::Offer { session, sdp }
+                    } else {
+                        Signal::Answer { session, sdp }
+                    };
+                    self.signals.push_back((conn.node(), signal));
+                }
+            }
+            RtcEvent::Recv(conn, frame) => match frame.split_first() {
+                Some((&KIND_HELLO, _)) => {
+                    if let Some(peer) = self.peers.get(&conn) {
+                        peer.send(true, KIND_HELLO_ACK, &[]);
+                    }
+                }
+                Some((&KIND_HELLO_ACK, _)) => self.on_connected(conn),
+                Some((&KIND_DATA, data)) => {
+                    // the remote only sends data when connected, and data may overtake its Hello ack
+                    self.on_connected(conn);
+                    if self.peers.contains_key(&conn) {
+                        self.events.push_back(TransportEvent::Recv(NetworkPkt {
+                            conn,
+                            data: data.to_vec(),
+                        }));
+                    }
+                }
+                _ => log::debug!("Unexpected frame from {:?}", conn),
+            },
+            RtcEvent::Closed(conn) => match self.peers.get(&conn) {
+                Some(peer) if peer.outgoing && !peer.connected => self.retry(now_ms, conn),
+                _ => self.remove_peer(conn),
+            },
+            RtcEvent::Stats(conn, stats) => {
+                if let Some(peer) = self.peers.get_mut(&conn) {
+                    peer.stats = Some(stats);
+                }
+            }
+        }
+    }
+
+    fn on_connected(&mut self, conn: Connection) {
+        if let Some(peer) = self.peers.get_mut(&conn) {
+            if !peer.connected {
+                peer.connected = true;
+                log::info!("Connected {:?}", conn);
+                if peer.outgoing {
+                    self.events.push_back(TransportEvent::Connected(conn));
+                } else {
+                    self.events.push_back(TransportEvent::Accepted(conn));
+                }
+            }
+        }
+    }
+
+    /// Replace the outgoing peer with a new offer, an answer may be lost by the signalling channel
+    fn retry(&mut self, now_ms: u64, conn: Connection) {
+        let deadline_ms = match self.peers.get(&conn) {
+            Some(peer) => peer.deadline_ms,
+            None => return,
+        };
+        self.remove_peer(conn);
+        if now_ms >= deadline_ms {
+            log::info!("Connect {:?} timeout", conn);
+            return;
+        }
+        log::debug!("Retry connect {:?}", conn);
+        if let Err(e) = self.offer(now_ms, conn.node(), deadline_ms) {
+            log::warn!("Retry connect {:?} error {:?}", conn, e);
+        }
+    }
+
+    fn on_timer(&mut self, now_ms: u64) {
+        let attempt_timeout_ms = self.config.attempt_timeout_ms;
+        let timeouts = self
+            .peers
+            .iter()
+            .filter(|(_, p)| {
+                !p.connected
+                    && (now_ms >= p.deadline_ms
+                        || (p.outgoing && now_ms >= p.started_ms + attempt_timeout_ms))
+            })
+            .map(|(conn, _)| *conn)
+            .collect::<Vec<_>>();
+        for conn in timeouts {
+            match self.peers.get(&conn) {
+                Some(peer) if peer.outgoing => self.retry(now_ms, conn),
+                _ => {
+                    log::info!("Accept {:?} timeout", conn);
+                    self.remove_peer(conn);
+                }
+            }
+        }
+
+        if now_ms >= self.last_stats_ms + self.config.stats_interval_ms {
+            self.last_stats_ms = now_ms;
+            let bandwidth_kbps = self.config.bandwidth_kbps;
+            for (conn, peer) in self.peers.iter().filter(|(_, p)| p.connected) {
+                let (conn, pc, events) = (*conn, peer.pc.clone(), self.rtc_events.clone());
+                spawn_local(async move {
+                    let report = match JsFuture::from(pc.get_stats()).await {
+                        Ok(report) => report.unchecked_into::<RtcStatsReport>(),
+                        Err(e) => {
+                            log::debug!("Stats of {:?} error {:?}", conn, e);
+                            return;
+                        }
+                    };
+                    if let Some(rtt_ms) = nominated_rtt_ms(&report) {
+                        let stats = ConnectionStats {
+                            rtt_ms,
+                            // SCTP doesn't expose the loss, it shows up as rtt instead
+                            lost_percent: 0.0.into(),
+                            jitter_ms: 0,
+                            bandwidth_kbps,
+                        };
+                        events.borrow_mut().push_back(RtcEvent::Stats(conn, stats));
+                    }
+                });
+            }
+        }
+    }
+
+    fn remove_peer(&mut self, conn: Connection) {
+        if let Some(peer) = self.peers.remove(&conn) {
+            if peer.connected {
+                self.events.push_back(TransportEvent::Disconnected(conn));
+            }
+        }
+    }
+}
+
+/// Round trip time of the candidate pair which is used by the connection
+fn nominated_rtt_ms(report: &RtcStatsReport) -> Option<u32> {
+    let field = |stats: &JsValue, name: &str| Reflect::get(stats, &JsValue::from_str(name)).ok();
+    report.values().into_iter().flatten().find_map(|stats| {
+        let pair = field(&stats, "type")?.as_string()? == "candidate-pair";
+        let nominated = field(&stats, "nominated")?.as_bool()?;
+        let rtt = field(&stats, "currentRoundTripTime")?.as_f64()?;
+        (pair && nominated).then_some((rtt * 1000.0) as u32)
+    })
+}
+
+impl Transport for RtcTransport {
+    type Addr = NodeId;
+    type Error = JsValue;
+
+    /// Create an offer for the node, which is available with `pop_signal` after the candidates are gathered
+    fn connect(&mut self, now_ms: u64, node: NodeId) -> Result<(), JsValue> {
+        self.offer(now_ms, node, now_ms + self.config.connect_timeout_ms)
+    }
+
+    fn send(&mut self, conn: Connection, data: &[u8]) {
+        if let Some(peer) = self.peers.get(&conn) {
+            peer.send(true, KIND_DATA, data);
+        }
+    }
+
+    fn send_unreliable(&mut self, conn: Connection, data: &[u8]) {
+        if let Some(peer) = self.peers.get(&conn) {
+            peer.send(false, KIND_DATA, data);
+        }
+    }
+
+    fn close(&mut self, conn: Connection) {
+        self.remove_peer(conn);
+    }
+
+    fn stats(&self, conn: Connection) -> Option<ConnectionStats> {
+        self.peers.get(&conn)?.stats
+    }
+
+    fn pop_event(&mut self, now_ms: u64) -> Option<TransportEvent> {
+        if self.events.is_empty() {
+            loop {
+                let event = self.rtc_events.borrow_mut().pop_front();
+                match event {
+                    Some(event) => self.on_rtc_event(now_ms, event),
+                    None => break,
+                }
+            }
+            self.on_timer(now_ms);
+        }
+        self.events.pop_front()
+    }
+
+    /// Next session description which must be sent to the node, candidates are part of the descriptions
+    fn pop_signal(&mut self) -> Option<(NodeId, Signal)> {
+        self.signals.pop_front()
+    }
+
+    /// Apply a session description which was received from the node by the signalling channel
+    fn on_signal(&mut self, now_ms: u64, from: NodeId, signal: Signal) {
+        match signal {
+            Signal::Offer { session, sdp } => {
+                let conn = Connection::from_parts(from, session);
+                if self.peers.contains_key(&conn) {
+                    log::warn!("Duplicated offer for {:?}", conn);
+                    return;
+                }
+                let deadline_ms = now_ms + self.config.connect_timeout_ms;
+                let pc = match self.create_peer(now_ms, conn, false, deadline_ms) {
+                    Ok(pc) => pc,
+                    Err(e) => {
+                        log::warn!("Create peer for {:?} error {:?}", conn, e);
+                        return;
+                    }
+                };
+                let events = self.rtc_events.clone();
+                spawn_local(async move {
+                    let answer = async {
+                        let mut offer = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
+                        offer.sdp(&sdp);
+                        JsFuture::from(pc.set_remote_description(&offer)).await?;
+                        let answer = JsFuture::from(pc.create_answer()).await?;
+                        JsFuture::from(pc.set_local_description(answer.unchecked_ref())).await
+                    };
+                    if let Err(e) = answer.await {
+                        log::warn!("Answer for {:?} error {:?}", conn, e);
+                        events.borrow_mut().push_back(RtcEvent::Closed(conn));
+                    }
+                });
+            }
+            Signal::Answer { session, sdp } => {
+                let conn = Connection::from_parts(from, session);
+                let pc = match self.peers.get(&conn) {
+                    Some(peer) if peer.outgoing => peer.pc.clone(),
+                    _ => {
+                        log::warn!("Answer for unknown {:?}", conn);
+                        return;
+                    }
+                };
+                let events = self.rtc_events.clone();
+                spawn_local(async move {
+                    let mut answer = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
+                    answer.sdp(&sdp);
+                    if let Err(e) = JsFuture::from(pc.set_remote_description(&answer)).await {
+                        log::warn!("Set answer from {:?} error {:?}", conn, e);
+                        events.borrow_mut().push_back(RtcEvent::Closed(conn));
+                    }
+                });
+            }
+            Signal::Candidate { session, .. } => {
+                // candidates are already part of the session descriptions
+                log::debug!("Ignore candidate from {:?} for session {}", from, session);
+            }
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    rc::Rc,
+};
+
+use js_sys::Uint8Array;
+use protocol::{Connection, ConnectionStats, NetworkPkt, NodeId, Transport, TransportEvent};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use web_sys::{BinaryType, MessageEvent, WebSocket};
+
+/// Binary frame: kind (1 byte) followed by the payload, the same as the native transport
+const KIND_HELLO: u8 = 1;
+const KIND_DATA: u8 = 2;
+const KIND_PING: u8 = 3;
+const KIND_PONG: u8 = 4;
+
+#[derive(Debug, Clone)]
+pub struct WsConfig {
+    /// Socket which is not opened and greeted within this timeout is closed
+    pub handshake_timeout_ms: u64,
+    pub keepalive_interval_ms: u64,
+    /// Connection is disconnected when nothing is received within this timeout
+    pub timeout_ms: u64,
+    /// WebSocket can't measure the link capacity, so this value is reported in the stats
+    pub bandwidth_kbps: u32,
+}
+
+impl Default for WsConfig {
+    fn default() -> Self {
+        Self {
+            handshake_timeout_ms: 5000,
+            keepalive_interval_ms: 1000,
+            timeout_ms: 5000,
+            bandwidth_kbps: 10_000,
+        }
+    }
+}
+
+enum SocketEvent {
+    Open,
+    Frame(Vec<u8>),
+    Closed,
+}
+
+type SocketEvents = Rc<RefCell<VecDeque<(u64, SocketEvent)>>>;
+
+struct WsSocket {
+    ws: WebSocket,
+    session: u32,
+    /// Known after the remote Hello
+    conn: Option<Connection>,
+    started_ms: u64,
+    last_recv_ms: u64,
+    last_ping_ms: u64,
+    rtt_ms: Option<u32>,
+    jitter_ms: u32,
+    _callbacks: Vec<Closure<dyn FnMut(JsValue)>>,
+}
+
+impl WsSocket {
+    fn send(&self, kind: u8, payload: &[u8]) {
+        let mut frame = Vec::with_capacity(1 + payload.len());
+        frame.push(kind);
+        frame.extend_from_slice(payload);
+        if let Err(e) = self.ws.send_with_u8_array(&frame) {
+            log::warn!("Send to {:?} error {:?}", self.conn, e);
+        }
+    }
+
+    fn on_pong(&mut self, now_ms: u64, sent_ms: u64) {
+        let rtt = now_ms.saturating_sub(sent_ms) as u32;
+        // smoothed like TCP, with 1/8 gain for rtt and 1/4 for jitter
+        match self.rtt_ms {
+            Some(old) => {
+                self.jitter_ms = (self.jitter_ms * 3 + old.abs_diff(rtt)) / 4;
+                self.rtt_ms = Some((old * 7 + rtt) / 8);
+            }
+            None => self.rtt_ms = Some(rtt),
+        }
+    }
+}
+
+impl Drop for WsSocket {
+    fn drop(&mut self) {
+        self.ws.set_onopen(None);
+        self.ws.set_onmessage(None);
+        self.ws.set_onclose(None);
+        self.ws.set_onerror(None);
+        let _ = self.ws.close();
+    }
+}
+
+/// Browser transport over WebSocket, it can only connect to relays which listen with the native WsTransport.
+/// Socket callbacks only queue events, which are processed when the driver polls the transport.
+pub struct WsTransport {
+    node: NodeId,
+    config: WsConfig,
+    next_session: u32,
+    next_slot: u64,
+    sockets: HashMap<u64, WsSocket>,
+    slots: HashMap<Connection, u64>,
+    socket_events: SocketEvents,
+    events: VecDeque<TransportEvent>,
+}
+
+impl WsTransport {
+    pub fn new(node: NodeId, config: WsConfig) -> Self {
+        Self {
+            node,
+            config,
+            next_session: (js_sys::Math::random() * u32::MAX as f64) as u32,
+            next_slot: 0,
+            sockets: HashMap::new(),
+            slots: HashMap::new(),
+            socket_events: Rc::new(RefCell::new(VecDeque::new())),
+            events: VecDeque::new(),
+        }
+    }
+
+    fn on_socket_event(&mut self, now_ms: u64, slot: u64, event: SocketEvent) {
+        let socket = if let Some(socket) = self.sockets.get_mut(&slot) {
+            socket
+        } else {
+            return;
+        };
+        match event {
+            SocketEvent::Open => socket.send(KIND_HELLO, &hello(self.node, socket.session)),
+            SocketEvent::Frame(frame) => {
+                socket.last_recv_ms = now_ms;
+                let (kind, payload) = match frame.split_first() {
+                    Some((kind, payload)) => (*kind, payload),
+                    None => return,
+                };
+                match (kind, socket.conn) {
+                    (KIND_HELLO, None) => match read_hello(payload) {
+                        Some((node, session)) if session == socket.session => {
+                            let conn = Connection::from_parts(node, session);
+                            socket.conn = Some(conn);
+                            self.slots.insert(conn, slot);
+                            log::info!("Connected {:?}", conn);
+                            self.events.push_back(TransportEvent::Connected(conn));
+                        }
+                        _ => {
+                            log::warn!("Invalid hello, close socket");
+                            self.remove_socket(slot);
+                        }
+                    },
+                    (KIND_DATA, Some(conn)) => {
+                        self.events.push_back(TransportEvent::Recv(NetworkPkt {
+                            conn,
+                            data: payload.to_vec(),
+                        }));
+                    }
+                    (KIND_PING, Some(_)) => socket.send(KIND_PONG, payload),
+                    (KIND_PONG, Some(_)) => {
+                        if let Ok(sent_ms) = payload.try_into().map(u64::from_be_bytes) {
+                            socket.on_pong(now_ms, sent_ms);
+                        }
+                    }
+                    _ => log::debug!("Unexpected frame {} from {:?}", kind, socket.conn),
+                }
+            }
+            SocketEvent::Closed => self.remove_socket(slot),
+        }
+    }
+
+    fn on_timer(&mut self, now_ms: u64) {
+        let mut timeouts = vec![];
+        for (slot, socket) in self.sockets.iter_mut() {
+            let timeout = match socket.conn {
+                None => now_ms >= socket.started_ms + self.config.handshake_timeout_ms,
+                Some(_) => now_ms >= socket.last_recv_ms + self.config.timeout_ms,
+            };
+            if timeout {
+                timeouts.push(*slot);
+            } else if socket.conn.is_some()
+                && now_ms >= socket.last_ping_ms + self.config.keepalive_interval_ms
+            {
+                socket.last_ping_ms = now_ms;
+                socket.send(KIND_PING, &now_ms.to_be_bytes());
+            }
+        }
+        for slot in timeouts {
+            log::info!("Socket {} timeout", slot);
+            self.remove_socket(slot);
+        }
+    }
+
+    fn remove_socket(&mut self, slot: u64) {
+        if let Some(socket) = self.sockets.remove(&slot) {
+            if let Some(conn) = socket.conn {
+                self.slots.remove(&conn);
+                self.events.push_back(TransportEvent::Disconnected(conn));
+            }
+        }
+    }
+}
+
+impl Transport for WsTransport {
+    /// Url like ws://127.0.0.1:8080
+    type Addr = String;
+    type Error = JsValue;
+
+    fn connect(&mut self, now_ms: u64, addr: String) -> Result<(), JsValue> {
+        let ws = WebSocket::new(&addr)?;
+        ws.set_binary_type(BinaryType::Arraybuffer);
+
+        let slot = self.next_slot;
+        self.next_slot += 1;
+        let session = self.next_session;
+        self.next_session = self.next_session.wrapping_add(1);
+
+        let events = self.socket_events.clone();
+        let onopen = Closure::<dyn FnMut(JsValue)>::new(move |_| {
+            events.borrow_mut().push_back((slot, SocketEvent::Open));
+        });
+        let events = self.socket_events.clone();
+        let onmessage = Closure::<dyn FnMut(JsValue)>::new(move |e: JsValue| {
+            if let Ok(e) = e.dyn_into::<MessageEvent>() {
+                let frame = Uint8Array::new(&e.data()).to_vec();
+                events
+                    .borrow_mut()
+                    .push_back((slot, SocketEvent::Frame(frame)));
+            }
+        });
+        let events = self.socket_events.clone();
+        let onclose = Closure::<dyn FnMut(JsValue)>::new(move |_| {
+            events.borrow_mut().push_back((slot, SocketEvent::Closed));
+        });
+        ws.set_onopen(Some(onopen.as_ref().unchecked_ref()));
+        ws.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+        // an error is always followed by a close event
+        ws.set_onclose(Some(onclose.as_ref().unchecked_ref()));
+
+        self.sockets.insert(
+            slot,
+            WsSocket {
+                ws,
+                session,
+                conn: None,
+                started_ms: now_ms,
+                last_recv_ms: now_ms,
+                last_ping_ms: now_ms,
+                rtt_ms: None,
+                jitter_ms: 0,
+                _callbacks: vec![onopen, onmessage, onclose],
+            },
+        );
+        Ok(())
+    }
+
+    fn send(&mut self, conn: Connection, data: &[u8]) {
+        if let Some(socket) = self.slots.get(&conn).and_then(|s| self.sockets.get(s)) {
+            socket.send(KIND_DATA, data);
+        }
+    }
+
+    fn close(&mut self, conn: Connection) {
+        if let Some(slot) = self.slots.get(&conn).copied() {
+            self.remove_socket(slot);
+        }
+    }
+
+    fn stats(&self, conn: Connection) -> Option<ConnectionStats> {
+        let socket = self.sockets.get(self.slots.get(&conn)?)?;
+        Some(ConnectionStats {
+            // until the first pong, the rtt is unknown and must not look better than measured ones
+            rtt_ms: socket.rtt_ms.unwrap_or(self.config.timeout_ms as u32),
+            // TCP retransmits lost packets, which shows up as rtt and jitter instead
+            lost_percent: 0.0.into(),
+            jitter_ms: socket.jitter_ms,
+            bandwidth_kbps: self.config.bandwidth_kbps,
+        })
+    }
+
+    fn pop_event(&mut self, now_ms: u64) -> Option<TransportEvent> {
+        if self.events.is_empty() {
+            loop {
+                let event = self.socket_events.borrow_mut().pop_front();
+                match event {
+                    Some((slot, event)) => self.on_socket_event(now_ms, slot, event),
+                    None => break,
+                }
+            }
+            self.on_timer(now_ms);
+        }
+        self.events.pop_front()
+    }
+}
+
+fn hello(node: NodeId, session: u32) -> Vec<u8> {
+    let mut hello = node.to_be_bytes().to_vec();
+    hello.extend_from_slice(&session.to_be_bytes());
+    hello
+}
+
+fn read_hello(payload: &[u8]) -> Option<(NodeId, u32)> {
+    let node = u64::from_be_bytes(payload.get(0..8)?.try_into().ok()?);
+    let session = u32::from_be_bytes(payload.get(8..12)?.try_into().ok()?);
+    Some((node.into(), session))
+}